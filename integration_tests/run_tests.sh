@@ -1,5 +1,9 @@
 if [ "$MARKER" = "durability" ]; then
   py.test -v -m durability
+elif [ "$MARKER" = "cluster" ]; then
+  py.test -v -m cluster
+elif [ "$MARKER" = "sharding" ]; then
+  py.test -v -m sharding
 else
-  py.test -v -m "not durability"
+  py.test -v -m "not durability and not cluster and not sharding"
 fi
@@ -0,0 +1,69 @@
+import grpc
+import pytest
+from proto import spq_pb2
+from helpers import drain_queue
+
+
+def batch_item(sent_item):
+    return spq_pb2.BatchItem(
+        item=sent_item, features=[{"name": "feature_name", "value": 0}]
+    )
+
+
+def test_enqueue_and_dequeue_batch(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_items = [bytes("batch item {}".format(i), "utf-8") for i in range(0, 3)]
+
+    enqueue_result = spq_client.EnqueueBatch(
+        spq_pb2.EnqueueBatchRequest(
+            queueName=queue_name, items=[batch_item(item) for item in sent_items]
+        )
+    )
+
+    assert enqueue_result.size == 3
+
+    result = spq_client.DequeueBatch(
+        spq_pb2.DequeueBatchRequest(queueName=queue_name, count=5)
+    )
+
+    assert [item.item for item in result.items] == sent_items
+    assert result.size == 0
+
+
+def test_dequeue_batch_with_lease(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_items = [bytes("leased batch item {}".format(i), "utf-8") for i in range(0, 2)]
+
+    spq_client.EnqueueBatch(
+        spq_pb2.EnqueueBatchRequest(
+            queueName=queue_name, items=[batch_item(item) for item in sent_items]
+        )
+    )
+
+    result = spq_client.DequeueBatch(
+        spq_pb2.DequeueBatchRequest(queueName=queue_name, count=2, leaseTimeoutMs=60000)
+    )
+
+    assert [item.item for item in result.items] == sent_items
+    assert all(item.leaseDeadlineMs > 0 for item in result.items)
+
+    for item in result.items:
+        spq_client.Ack(spq_pb2.AckRequest(queueName=queue_name, leaseId=item.leaseId))
+
+
+def test_rejects_a_batch_with_unknown_feature_names(spq_client, queue_name):
+    request = spq_pb2.EnqueueBatchRequest(
+        items=[
+            batch_item(bytes("item", "utf-8")),
+            spq_pb2.BatchItem(
+                item=bytes("item", "utf-8"),
+                features=[{"name": "unknown_feature", "value": 0}],
+            ),
+        ],
+        queueName=queue_name,
+    )
+
+    with pytest.raises(grpc.RpcError) as error:
+        spq_client.EnqueueBatch(request)
+
+    assert error.value.code() == grpc.StatusCode.INVALID_ARGUMENT
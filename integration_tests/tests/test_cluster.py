@@ -0,0 +1,146 @@
+import time
+
+import grpc
+import pytest
+from proto import spq_pb2, spq_pb2_grpc
+
+NODES = {1: "spq-1:9090", 2: "spq-2:9090", 3: "spq-3:9090"}
+
+ELECTION_TIMEOUT_SECONDS = 20
+
+
+def client_for(address):
+    return spq_pb2_grpc.SortingPriorityQueueServiceStub(
+        grpc.insecure_channel(address)
+    )
+
+
+@pytest.fixture(scope="module")
+def cluster_clients():
+    return {node_id: client_for(address) for node_id, address in NODES.items()}
+
+
+def wait_for_leader(cluster_clients):
+    deadline = time.time() + ELECTION_TIMEOUT_SECONDS
+
+    while time.time() < deadline:
+        leaders = set()
+
+        for client in cluster_clients.values():
+            status = client.ClusterStatus(spq_pb2.ClusterStatusRequest())
+
+            if status.hasLeader:
+                leaders.add(status.leaderId)
+
+        if len(leaders) == 1:
+            return leaders.pop()
+
+        time.sleep(0.5)
+
+    raise AssertionError("No single leader was elected")
+
+
+def eventually(check):
+    deadline = time.time() + ELECTION_TIMEOUT_SECONDS
+
+    while not check():
+        assert time.time() < deadline
+        time.sleep(0.2)
+
+
+@pytest.mark.cluster
+def test_every_node_reports_the_seed_list(cluster_clients):
+    leader_id = wait_for_leader(cluster_clients)
+
+    for node_id, client in cluster_clients.items():
+        status = client.ClusterStatus(spq_pb2.ClusterStatusRequest())
+
+        assert status.isReplicated
+        assert status.nodeId == node_id
+        assert status.leaderId == leader_id
+        assert [member.id for member in status.members] == [1, 2, 3]
+        assert [member.id for member in status.members if member.isSelf] == [node_id]
+        assert [member.id for member in status.members if member.isLeader] == [
+            leader_id
+        ]
+
+
+@pytest.mark.cluster
+def test_leader_reaches_every_node(cluster_clients):
+    leader_id = wait_for_leader(cluster_clients)
+
+    def all_reachable():
+        status = cluster_clients[leader_id].ClusterStatus(
+            spq_pb2.ClusterStatusRequest()
+        )
+        return all(member.isReachable for member in status.members)
+
+    eventually(all_reachable)
+
+
+@pytest.mark.cluster
+def test_names_the_leader_as_owner_of_a_queue(cluster_clients):
+    leader_id = wait_for_leader(cluster_clients)
+
+    for client in cluster_clients.values():
+        status = client.ClusterStatus(
+            spq_pb2.ClusterStatusRequest(queueName="clustered queue")
+        )
+
+        assert status.ownerAddress == "http://" + NODES[leader_id]
+
+
+@pytest.mark.cluster
+def test_redirects_writes_from_followers_to_the_leader(cluster_clients):
+    leader_id = wait_for_leader(cluster_clients)
+    follower_id = next(node_id for node_id in NODES if node_id != leader_id)
+
+    with pytest.raises(grpc.RpcError) as error:
+        cluster_clients[follower_id].CreateQueue(
+            spq_pb2.CreateQueueRequest(
+                name="redirected queue", queueType=spq_pb2.DURABLE, features=["a"]
+            )
+        )
+
+    metadata = dict(error.value.trailing_metadata())
+
+    assert error.value.code() == grpc.StatusCode.UNAVAILABLE
+    assert metadata["spq-leader-id"] == str(leader_id)
+    assert metadata["spq-leader-address"] == "http://" + NODES[leader_id]
+
+
+@pytest.mark.cluster
+def test_reads_a_write_back_from_the_leader_at_once(cluster_clients):
+    leader_id = wait_for_leader(cluster_clients)
+    leader = cluster_clients[leader_id]
+
+    leader.CreateQueue(
+        spq_pb2.CreateQueueRequest(
+            name="replicated queue", queueType=spq_pb2.DURABLE, features=["a"]
+        )
+    )
+    leader.Enqueue(
+        spq_pb2.EnqueueRequest(
+            queueName="replicated queue",
+            item=bytes("item", "utf-8"),
+            features=[{"name": "a", "value": 1}],
+        )
+    )
+
+    size = leader.GetSize(spq_pb2.GetSizeRequest(queueName="replicated queue")).size
+
+    assert size == 1
+
+
+@pytest.mark.cluster
+def test_redirects_reads_from_followers_to_the_leader(cluster_clients):
+    leader_id = wait_for_leader(cluster_clients)
+    follower_id = next(node_id for node_id in NODES if node_id != leader_id)
+
+    with pytest.raises(grpc.RpcError) as error:
+        cluster_clients[follower_id].ListQueues(spq_pb2.ListQueuesRequest())
+
+    metadata = dict(error.value.trailing_metadata())
+
+    assert error.value.code() == grpc.StatusCode.UNAVAILABLE
+    assert metadata["spq-leader-id"] == str(leader_id)
@@ -0,0 +1,49 @@
+from concurrent.futures import ThreadPoolExecutor
+from proto import spq_pb2
+from helpers import drain_queue
+
+
+def enqueue_item(spq_client, queue_name, sent_item):
+    return spq_client.Enqueue(
+        spq_pb2.EnqueueRequest(
+            queueName=queue_name,
+            item=sent_item,
+            features=[{"name": "feature_name", "value": 0}],
+        )
+    )
+
+
+def test_concurrent_requests_all_succeed(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_items = [bytes("concurrent item {}".format(i), "utf-8") for i in range(0, 50)]
+
+    with ThreadPoolExecutor(max_workers=10) as executor:
+        enqueues = [
+            executor.submit(enqueue_item, spq_client, queue_name, sent_item)
+            for sent_item in sent_items
+        ]
+        sizes = [
+            executor.submit(
+                spq_client.GetSize, spq_pb2.GetSizeRequest(queueName=queue_name)
+            )
+            for _ in sent_items
+        ]
+        queue_lists = [
+            executor.submit(spq_client.ListQueues, spq_pb2.ListQueuesRequest())
+            for _ in sent_items
+        ]
+
+        for future in enqueues + sizes + queue_lists:
+            future.result()
+
+    with ThreadPoolExecutor(max_workers=10) as executor:
+        dequeues = [
+            executor.submit(
+                spq_client.Dequeue, spq_pb2.DequeueRequest(queueName=queue_name)
+            )
+            for _ in sent_items
+        ]
+
+        received_items = [future.result().item for future in dequeues]
+
+    assert sorted(received_items) == sorted(sent_items)
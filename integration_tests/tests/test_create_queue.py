@@ -1,3 +1,5 @@
+import grpc
+import pytest
 from proto import spq_pb2
 
 
@@ -11,3 +13,81 @@ def test_create_queue(spq_client):
     created_queue_response = spq_client.CreateQueue(request)
 
     assert created_queue_response.name == "other queue"
+
+
+def test_create_queue_is_idempotent(spq_client):
+    request = spq_pb2.CreateQueueRequest(
+        name="repeated queue",
+        queueType=spq_pb2.IN_MEMORY,
+        features=["first_feature"],
+    )
+
+    spq_client.CreateQueue(request)
+    created_queue_response = spq_client.CreateQueue(request)
+
+    assert created_queue_response.name == "repeated queue"
+
+
+def test_create_queue_rejects_different_type(spq_client):
+    spq_client.CreateQueue(
+        spq_pb2.CreateQueueRequest(
+            name="typed queue",
+            queueType=spq_pb2.IN_MEMORY,
+            features=["first_feature"],
+        )
+    )
+
+    with pytest.raises(grpc.RpcError) as error:
+        spq_client.CreateQueue(
+            spq_pb2.CreateQueueRequest(
+                name="typed queue",
+                queueType=spq_pb2.DURABLE,
+                features=["first_feature"],
+            )
+        )
+
+    assert error.value.code() == grpc.StatusCode.ALREADY_EXISTS
+
+
+def test_create_queue_with_the_server_default_type(spq_client):
+    spq_client.CreateQueue(
+        spq_pb2.CreateQueueRequest(
+            name="default typed queue",
+            queueType=spq_pb2.SERVER_DEFAULT,
+            features=["first_feature"],
+        )
+    )
+
+    description = spq_client.DescribeQueue(
+        spq_pb2.DescribeQueueRequest(name="default typed queue")
+    )
+
+    assert description.queueType == spq_pb2.DURABLE
+
+
+def test_create_queue_without_a_type_is_durable_by_default(spq_client):
+    spq_client.CreateQueue(
+        spq_pb2.CreateQueueRequest(name="untyped queue", features=["first_feature"])
+    )
+
+    description = spq_client.DescribeQueue(
+        spq_pb2.DescribeQueueRequest(name="untyped queue")
+    )
+
+    assert description.queueType == spq_pb2.DURABLE
+
+
+@pytest.mark.parametrize(
+    "name", ["", ".", "..", "../escaped", "a/b", "a\\b", ".hidden"]
+)
+def test_create_queue_rejects_names_that_are_not_a_folder(spq_client, name):
+    request = spq_pb2.CreateQueueRequest(
+        name=name,
+        queueType=spq_pb2.DURABLE,
+        features=["first_feature"],
+    )
+
+    with pytest.raises(grpc.RpcError) as error:
+        spq_client.CreateQueue(request)
+
+    assert error.value.code() == grpc.StatusCode.INVALID_ARGUMENT
@@ -1,4 +1,5 @@
 from proto import spq_pb2
+from helpers import drain_queue
 
 
 def test_dequeue(spq_client, queue_name):
@@ -17,3 +18,26 @@ def test_dequeue(spq_client, queue_name):
     assert result.hasItem == True
     assert result.item == sent_item
     assert result.size == add_item_result.size - 1
+
+
+def test_dequeue_returns_enqueue_details(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_item = bytes("tenant item", "utf-8")
+
+    spq_client.Enqueue(
+        spq_pb2.EnqueueRequest(
+            queueName=queue_name,
+            item=sent_item,
+            features=[{"name": "feature_name", "value": 7}],
+        )
+    )
+
+    epoch = spq_client.GetEpoch(spq_pb2.GetEpochRequest(queueName=queue_name)).epoch
+    result = spq_client.Dequeue(spq_pb2.DequeueRequest(queueName=queue_name))
+
+    assert result.item == sent_item
+    assert [(feature.name, feature.value) for feature in result.features] == [
+        ("feature_name", 7)
+    ]
+    assert result.enqueueEpoch == epoch
+    assert result.enqueuedAtMs > 0
@@ -2,6 +2,13 @@ import pytest
 from proto import spq_pb2
 
 
+@pytest.mark.durability
+def test_reloads_queue_without_create(spq_client):
+    result = spq_client.GetSize(spq_pb2.GetSizeRequest(queueName="test queue"))
+
+    assert result.size > 0, result.size
+
+
 @pytest.mark.durability
 def test_starts_with_incremented_epoch(spq_client, queue_name):
     result = spq_client.GetEpoch(spq_pb2.GetEpochRequest(queueName=queue_name))
@@ -1,3 +1,5 @@
+import grpc
+import pytest
 from proto import spq_pb2
 
 
@@ -10,3 +12,45 @@ def test_enqueue(spq_client, queue_name):
     result = spq_client.Enqueue(request)
 
     assert result.size == 1
+
+
+def test_rejects_items_larger_than_the_max_item_size(spq_client, queue_name):
+    request = spq_pb2.EnqueueRequest(
+        item=bytes(1048577),
+        features=[{"name": "feature_name", "value": 0}],
+        queueName=queue_name,
+    )
+
+    with pytest.raises(grpc.RpcError) as error:
+        spq_client.Enqueue(request)
+
+    assert error.value.code() == grpc.StatusCode.INVALID_ARGUMENT
+
+
+def test_rejects_items_with_the_wrong_number_of_features(spq_client, queue_name):
+    request = spq_pb2.EnqueueRequest(
+        item=bytes("item", "utf-8"),
+        features=[
+            {"name": "feature_name", "value": 0},
+            {"name": "extra_feature", "value": 0},
+        ],
+        queueName=queue_name,
+    )
+
+    with pytest.raises(grpc.RpcError) as error:
+        spq_client.Enqueue(request)
+
+    assert error.value.code() == grpc.StatusCode.INVALID_ARGUMENT
+
+
+def test_rejects_items_with_unknown_feature_names(spq_client, queue_name):
+    request = spq_pb2.EnqueueRequest(
+        item=bytes("item", "utf-8"),
+        features=[{"name": "unknown_feature", "value": 0}],
+        queueName=queue_name,
+    )
+
+    with pytest.raises(grpc.RpcError) as error:
+        spq_client.Enqueue(request)
+
+    assert error.value.code() == grpc.StatusCode.INVALID_ARGUMENT
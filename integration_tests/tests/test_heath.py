@@ -1,17 +1,56 @@
+import grpc
+import pytest
 from proto import spq_pb2
 
 
+def create_queue(spq_client, name):
+    return spq_client.CreateQueue(
+        spq_pb2.CreateQueueRequest(
+            name=name, queueType=spq_pb2.DURABLE, features=["feature_name"]
+        )
+    )
+
+
 def test_check_health(health_client):
     response = health_client.Check(spq_pb2.HealthCheckRequest())
-    assert response.status == 1
+    assert response.status == spq_pb2.HealthCheckResponse.SERVING
+
+
+def test_check_queue_health(health_client, spq_client):
+    create_queue(spq_client, "healthy queue")
+
+    response = health_client.Check(spq_pb2.HealthCheckRequest(service="healthy queue"))
+    assert response.status == spq_pb2.HealthCheckResponse.SERVING
+
+
+def test_check_unknown_queue_health(health_client):
+    with pytest.raises(grpc.RpcError) as error:
+        health_client.Check(spq_pb2.HealthCheckRequest(service="unknown queue"))
+
+    assert error.value.code() == grpc.StatusCode.NOT_FOUND
 
 
 def test_watch_health(health_client):
-    total_events = 0
+    responses = health_client.Watch(spq_pb2.HealthCheckRequest())
+
+    assert next(responses).status == spq_pb2.HealthCheckResponse.SERVING
+
+    responses.cancel()
+
+
+def test_watch_sends_only_transitions(health_client, spq_client):
+    responses = health_client.Watch(
+        spq_pb2.HealthCheckRequest(service="watched health queue")
+    )
+
+    assert next(responses).status == spq_pb2.HealthCheckResponse.SERVICE_UNKNOWN
+
+    create_queue(spq_client, "watched health queue")
+
+    assert next(responses).status == spq_pb2.HealthCheckResponse.SERVING
+
+    spq_client.DeleteQueue(spq_pb2.DeleteQueueRequest(name="watched health queue"))
 
-    for response in health_client.Watch(spq_pb2.HealthCheckRequest()):
-        total_events += 1
-        assert response.status == 1
+    assert next(responses).status == spq_pb2.HealthCheckResponse.SERVICE_UNKNOWN
 
-        if total_events >= 2:
-            break
+    responses.cancel()
@@ -0,0 +1,85 @@
+import grpc
+import pytest
+from proto import spq_pb2
+from helpers import drain_queue
+
+
+def enqueue_item(spq_client, queue_name, sent_item):
+    request = spq_pb2.EnqueueRequest(
+        queueName=queue_name,
+        item=sent_item,
+        features=[{"name": "feature_name", "value": 0}],
+    )
+
+    return spq_client.Enqueue(request)
+
+
+def test_leased_item_is_removed_on_ack(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_item = bytes("leased item", "utf-8")
+
+    enqueue_item(spq_client, queue_name, sent_item)
+
+    result = spq_client.Dequeue(
+        spq_pb2.DequeueRequest(queueName=queue_name, leaseTimeoutMs=60000)
+    )
+
+    assert result.hasItem == True
+    assert result.item == sent_item
+    assert result.leaseDeadlineMs > 0
+    assert result.size == 0
+
+    ack_result = spq_client.Ack(
+        spq_pb2.AckRequest(queueName=queue_name, leaseId=result.leaseId)
+    )
+
+    assert ack_result.size == 0
+
+
+def test_leased_item_is_returned_on_nack(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_item = bytes("nacked item", "utf-8")
+
+    enqueue_item(spq_client, queue_name, sent_item)
+
+    result = spq_client.Dequeue(
+        spq_pb2.DequeueRequest(queueName=queue_name, leaseTimeoutMs=60000)
+    )
+
+    nack_result = spq_client.Nack(
+        spq_pb2.NackRequest(queueName=queue_name, leaseId=result.leaseId)
+    )
+
+    assert nack_result.size == 1
+
+    result = spq_client.Dequeue(spq_pb2.DequeueRequest(queueName=queue_name))
+
+    assert result.item == sent_item
+
+
+def test_lease_can_be_extended(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+
+    enqueue_item(spq_client, queue_name, bytes("extended item", "utf-8"))
+
+    result = spq_client.Dequeue(
+        spq_pb2.DequeueRequest(queueName=queue_name, leaseTimeoutMs=60000)
+    )
+
+    extend_result = spq_client.ExtendLease(
+        spq_pb2.ExtendLeaseRequest(
+            queueName=queue_name, leaseId=result.leaseId, leaseTimeoutMs=120000
+        )
+    )
+
+    assert extend_result.leaseId == result.leaseId
+    assert extend_result.leaseDeadlineMs > result.leaseDeadlineMs
+
+    spq_client.Ack(spq_pb2.AckRequest(queueName=queue_name, leaseId=result.leaseId))
+
+
+def test_ack_of_an_unknown_lease_is_not_found(spq_client, queue_name):
+    with pytest.raises(grpc.RpcError) as error:
+        spq_client.Ack(spq_pb2.AckRequest(queueName=queue_name, leaseId=123456789))
+
+    assert error.value.code() == grpc.StatusCode.NOT_FOUND
@@ -0,0 +1,63 @@
+import grpc
+import pytest
+from proto import spq_pb2
+
+
+def create_queue(spq_client, name):
+    return spq_client.CreateQueue(
+        spq_pb2.CreateQueueRequest(
+            name=name, queueType=spq_pb2.DURABLE, features=["tenant", "user"]
+        )
+    )
+
+
+def test_list_queues(spq_client, queue_name):
+    create_queue(spq_client, "listed queue")
+
+    result = spq_client.ListQueues(spq_pb2.ListQueuesRequest())
+
+    names = [queue.name for queue in result.queues]
+
+    assert "listed queue" in names
+    assert queue_name in names
+
+
+def test_describe_queue(spq_client):
+    create_queue(spq_client, "described queue")
+
+    spq_client.Enqueue(
+        spq_pb2.EnqueueRequest(
+            queueName="described queue",
+            item=bytes("item", "utf-8"),
+            features=[{"name": "tenant", "value": 1}, {"name": "user", "value": 2}],
+        )
+    )
+
+    result = spq_client.DescribeQueue(
+        spq_pb2.DescribeQueueRequest(name="described queue")
+    )
+
+    assert result.name == "described queue"
+    assert result.queueType == spq_pb2.DURABLE
+    assert list(result.features) == ["tenant", "user"]
+    assert result.dimension == 2
+    assert result.hasRootIndex == True
+    counts = {
+        (count.name, count.value, count.count) for count in result.featureValueCounts
+    }
+    assert counts == {("tenant", 1, 1), ("user", 2, 1)}
+
+    spq_client.DeleteQueue(spq_pb2.DeleteQueueRequest(name="described queue"))
+
+
+def test_delete_queue(spq_client):
+    create_queue(spq_client, "deleted queue")
+
+    result = spq_client.DeleteQueue(spq_pb2.DeleteQueueRequest(name="deleted queue"))
+
+    assert result.name == "deleted queue"
+
+    with pytest.raises(grpc.RpcError) as error:
+        spq_client.GetSize(spq_pb2.GetSizeRequest(queueName="deleted queue"))
+
+    assert error.value.code() == grpc.StatusCode.NOT_FOUND
@@ -0,0 +1,58 @@
+from urllib.request import urlopen
+
+from proto import spq_pb2
+
+METRICS_URL = "http://spq:9091/metrics"
+
+
+def scrape():
+    with urlopen(METRICS_URL) as response:
+        return response.read().decode("utf-8")
+
+
+def test_metrics_report_queue_stats(spq_client):
+    spq_client.CreateQueue(
+        spq_pb2.CreateQueueRequest(
+            name="metrics queue", queueType=spq_pb2.DURABLE, features=["tenant"]
+        )
+    )
+    spq_client.Enqueue(
+        spq_pb2.EnqueueRequest(
+            queueName="metrics queue",
+            item=b"item",
+            features=[spq_pb2.Feature(name="tenant", value=7)],
+        )
+    )
+
+    metrics = scrape()
+
+    assert 'spq_queue_size{queue="metrics queue"} 1' in metrics
+    assert 'spq_queue_disk_bytes{queue="metrics queue"}' in metrics
+    assert (
+        'spq_feature_value_items{feature="tenant",queue="metrics queue",value="7"} 1'
+        in metrics
+    )
+
+
+def test_metrics_count_operations(spq_client):
+    spq_client.CreateQueue(
+        spq_pb2.CreateQueueRequest(
+            name="counted queue", queueType=spq_pb2.IN_MEMORY, features=["tenant"]
+        )
+    )
+    spq_client.Peek(spq_pb2.PeekRequest(queueName="counted queue"))
+
+    metrics = scrape()
+
+    assert (
+        'spq_operations_total{code="Ok",operation="peek",queue="counted queue"} 1'
+        in metrics
+    )
+    assert (
+        'spq_operation_duration_seconds_count{operation="peek",queue="counted queue"} 1'
+        in metrics
+    )
+    assert (
+        'spq_lock_wait_seconds_count{operation="peek",queue="counted queue"} 1'
+        in metrics
+    )
@@ -0,0 +1,161 @@
+import time
+
+import grpc
+import pytest
+from proto import spq_pb2, spq_pb2_grpc
+
+NODES = {1: "shard-1:9090", 2: "shard-2:9090"}
+
+QUEUE_NAMES = ["sharded queue {}".format(i) for i in range(20)]
+
+ITEMS_PER_QUEUE = 3
+
+HANDOFF_TIMEOUT_SECONDS = 20
+
+
+def address_of(node_id):
+    return "http://" + NODES[node_id]
+
+
+@pytest.fixture(scope="module")
+def clients():
+    return {
+        node_id: spq_pb2_grpc.SortingPriorityQueueServiceStub(
+            grpc.insecure_channel(address)
+        )
+        for node_id, address in NODES.items()
+    }
+
+
+@pytest.fixture(scope="module")
+def shard_client():
+    return spq_pb2_grpc.ShardServiceStub(grpc.insecure_channel(NODES[1]))
+
+
+def eventually(check):
+    deadline = time.time() + HANDOFF_TIMEOUT_SECONDS
+
+    while not check():
+        assert time.time() < deadline
+        time.sleep(0.2)
+
+
+def owner_of(clients, queue_name):
+    status = clients[1].ClusterStatus(
+        spq_pb2.ClusterStatusRequest(queueName=queue_name)
+    )
+
+    return next(
+        node_id for node_id in NODES if address_of(node_id) == status.ownerAddress
+    )
+
+
+@pytest.fixture(scope="module")
+def filled_queues(clients):
+    for queue_name in QUEUE_NAMES:
+        clients[1].CreateQueue(
+            spq_pb2.CreateQueueRequest(
+                name=queue_name, queueType=spq_pb2.DURABLE, features=["a"]
+            )
+        )
+
+        for i in range(ITEMS_PER_QUEUE):
+            clients[1].Enqueue(
+                spq_pb2.EnqueueRequest(
+                    queueName=queue_name,
+                    item=bytes([i]),
+                    features=[{"name": "a", "value": i}],
+                )
+            )
+
+    return QUEUE_NAMES
+
+
+@pytest.fixture(scope="module")
+def joined(clients, shard_client, filled_queues):
+    shard_client.UpdateMembers(
+        spq_pb2.UpdateMembersRequest(
+            version=1,
+            members=[
+                spq_pb2.ShardMember(id=node_id, address=address_of(node_id))
+                for node_id in NODES
+            ],
+        )
+    )
+
+    def every_node_has_the_members():
+        return all(
+            client.ClusterStatus(spq_pb2.ClusterStatusRequest()).membersVersion == 1
+            for client in clients.values()
+        )
+
+    eventually(every_node_has_the_members)
+
+    return filled_queues
+
+
+@pytest.mark.sharding
+def test_a_single_node_owns_every_queue(clients, filled_queues):
+    status = clients[1].ClusterStatus(spq_pb2.ClusterStatusRequest())
+
+    assert status.isSharded
+    assert [member.id for member in status.members] == [1]
+
+    for queue_name in filled_queues:
+        assert owner_of(clients, queue_name) == 1
+
+
+@pytest.mark.sharding
+def test_moves_queues_to_a_joining_node_without_losing_items(clients, joined):
+    owners = {queue_name: owner_of(clients, queue_name) for queue_name in joined}
+
+    assert set(owners.values()) == {1, 2}
+
+    def handed_off():
+        for queue_name, owner in owners.items():
+            try:
+                size = (
+                    clients[owner]
+                    .GetSize(spq_pb2.GetSizeRequest(queueName=queue_name))
+                    .size
+                )
+            except grpc.RpcError:
+                return False
+
+            if size != ITEMS_PER_QUEUE:
+                return False
+
+        return True
+
+    eventually(handed_off)
+
+    for queue_name, owner in owners.items():
+        items = [
+            clients[owner]
+            .Dequeue(spq_pb2.DequeueRequest(queueName=queue_name))
+            .item
+            for _ in range(ITEMS_PER_QUEUE)
+        ]
+
+        assert sorted(items) == [bytes([i]) for i in range(ITEMS_PER_QUEUE)]
+
+
+@pytest.mark.sharding
+def test_redirects_requests_to_the_owner(clients, joined):
+    queue_name = next(name for name in joined if owner_of(clients, name) == 2)
+
+    def redirected():
+        try:
+            clients[1].GetSize(spq_pb2.GetSizeRequest(queueName=queue_name))
+        except grpc.RpcError as error:
+            metadata = dict(error.trailing_metadata())
+
+            return (
+                error.code() == grpc.StatusCode.UNAVAILABLE
+                and metadata.get("spq-owner-id") == "2"
+                and metadata.get("spq-owner-address") == address_of(2)
+            )
+
+        return False
+
+    eventually(redirected)
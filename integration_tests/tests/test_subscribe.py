@@ -0,0 +1,93 @@
+from proto import spq_pb2
+from helpers import drain_queue
+
+
+def enqueue_item(spq_client, queue_name, sent_item):
+    return spq_client.Enqueue(
+        spq_pb2.EnqueueRequest(
+            queueName=queue_name,
+            item=sent_item,
+            features=[{"name": "feature_name", "value": 0}],
+        )
+    )
+
+
+def test_subscriber_receives_items_enqueued_after_subscribing(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_item = bytes("subscribed item", "utf-8")
+
+    stream = spq_client.Subscribe(
+        spq_pb2.SubscribeRequest(queueName=queue_name, maxInFlight=1)
+    )
+
+    enqueue_item(spq_client, queue_name, sent_item)
+
+    result = next(stream)
+
+    assert result.hasItem == True
+    assert result.item == sent_item
+    assert result.leaseId == 0
+
+    stream.cancel()
+
+
+def test_subscriber_can_lease_items(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_item = bytes("subscribed leased item", "utf-8")
+
+    enqueue_item(spq_client, queue_name, sent_item)
+
+    stream = spq_client.Subscribe(
+        spq_pb2.SubscribeRequest(
+            queueName=queue_name, maxInFlight=1, leaseTimeoutMs=60000
+        )
+    )
+
+    result = next(stream)
+
+    assert result.item == sent_item
+    assert result.leaseDeadlineMs > 0
+
+    stream.cancel()
+
+    spq_client.Ack(spq_pb2.AckRequest(queueName=queue_name, leaseId=result.leaseId))
+
+
+def test_subscribers_share_items_within_their_quota(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+
+    first_stream = spq_client.Subscribe(
+        spq_pb2.SubscribeRequest(
+            queueName=queue_name, maxInFlight=1, leaseTimeoutMs=60000
+        )
+    )
+    second_stream = spq_client.Subscribe(
+        spq_pb2.SubscribeRequest(
+            queueName=queue_name, maxInFlight=1, leaseTimeoutMs=60000
+        )
+    )
+
+    for i in range(0, 3):
+        enqueue_item(spq_client, queue_name, bytes("shared item {}".format(i), "utf-8"))
+
+    first_result = next(first_stream)
+    second_result = next(second_stream)
+
+    assert first_result.item != second_result.item
+
+    # Each subscriber is at its quota so the last item waits for a completion
+    assert spq_client.GetSize(spq_pb2.GetSizeRequest(queueName=queue_name)).size == 1
+
+    spq_client.Ack(
+        spq_pb2.AckRequest(queueName=queue_name, leaseId=first_result.leaseId)
+    )
+
+    third_result = next(first_stream)
+
+    assert third_result.item not in [first_result.item, second_result.item]
+
+    first_stream.cancel()
+    second_stream.cancel()
+
+    for result in [second_result, third_result]:
+        spq_client.Ack(spq_pb2.AckRequest(queueName=queue_name, leaseId=result.leaseId))
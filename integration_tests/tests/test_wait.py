@@ -0,0 +1,62 @@
+import threading
+import time
+from proto import spq_pb2
+from helpers import drain_queue
+
+
+def enqueue_item_later(spq_client, queue_name, sent_item):
+    def enqueue():
+        time.sleep(0.5)
+        spq_client.Enqueue(
+            spq_pb2.EnqueueRequest(
+                queueName=queue_name,
+                item=sent_item,
+                features=[{"name": "feature_name", "value": 0}],
+            )
+        )
+
+    thread = threading.Thread(target=enqueue)
+    thread.start()
+
+    return thread
+
+
+def test_dequeue_waits_for_enqueued_item(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_item = bytes("awaited item", "utf-8")
+
+    thread = enqueue_item_later(spq_client, queue_name, sent_item)
+
+    result = spq_client.Dequeue(
+        spq_pb2.DequeueRequest(queueName=queue_name, waitTimeoutMs=10000)
+    )
+    thread.join()
+
+    assert result.hasItem == True
+    assert result.item == sent_item
+
+
+def test_peek_waits_for_enqueued_item(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+    sent_item = bytes("awaited peeked item", "utf-8")
+
+    thread = enqueue_item_later(spq_client, queue_name, sent_item)
+
+    result = spq_client.Peek(spq_pb2.PeekRequest(queueName=queue_name, waitTimeoutMs=10000))
+    thread.join()
+
+    assert result.hasItem == True
+    assert result.item == sent_item
+    assert result.size == 1
+
+    drain_queue(spq_client, queue_name)
+
+
+def test_dequeue_returns_nothing_after_wait_timeout(spq_client, queue_name):
+    drain_queue(spq_client, queue_name)
+
+    result = spq_client.Dequeue(
+        spq_pb2.DequeueRequest(queueName=queue_name, waitTimeoutMs=100)
+    )
+
+    assert result.hasItem == False
@@ -0,0 +1,127 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use sp_queue::feature_space::FeatureValue;
+use sp_queue::SortingPriorityQueue;
+use std::time::Instant;
+
+const DIMENSIONS: [usize; 3] = [1, 3, 5];
+
+const VALUES_PER_FEATURE: usize = 4;
+
+const PEEK_QUEUE_SIZE: usize = 100;
+
+fn feature_names(dimension: usize) -> Vec<String> {
+    (0..dimension)
+        .map(|feature| format!("feature_{}", feature))
+        .collect()
+}
+
+/// Spreads items over every combination of feature values so that dequeues
+/// have to walk a populated tree rather than a single path.
+fn features(dimension: usize, item: usize) -> Vec<FeatureValue> {
+    (0..dimension)
+        .map(|feature| {
+            FeatureValue::new(
+                format!("feature_{}", feature),
+                (item / VALUES_PER_FEATURE.pow(feature as u32)) % VALUES_PER_FEATURE,
+            )
+        })
+        .collect()
+}
+
+fn create_queue(durable: bool, dimension: usize) -> SortingPriorityQueue {
+    if durable {
+        let directory = format!("/tmp/spq_bench/{}", dimension);
+
+        match std::fs::remove_dir_all(directory.clone()) {
+            Ok(_) => (),
+            Err(e) => println!("{:?}", e),
+        }
+        std::fs::create_dir_all(directory.clone()).unwrap();
+
+        SortingPriorityQueue::new_durable(feature_names(dimension), directory).unwrap()
+    } else {
+        SortingPriorityQueue::new(feature_names(dimension)).unwrap()
+    }
+}
+
+fn storage_name(durable: bool) -> &'static str {
+    if durable {
+        "durable"
+    } else {
+        "memory"
+    }
+}
+
+fn enqueue_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("enqueue");
+
+    for durable in [false, true].iter() {
+        for dimension in DIMENSIONS.iter() {
+            let queue = create_queue(*durable, *dimension);
+            let mut item = 0;
+
+            group.bench_function(BenchmarkId::new(storage_name(*durable), dimension), |b| {
+                b.iter(|| {
+                    item += 1;
+                    queue.enqueue(vec![1], features(*dimension, item)).unwrap()
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn dequeue_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dequeue");
+
+    for durable in [false, true].iter() {
+        for dimension in DIMENSIONS.iter() {
+            let queue = create_queue(*durable, *dimension);
+
+            group.bench_function(BenchmarkId::new(storage_name(*durable), dimension), |b| {
+                b.iter_custom(|iterations| {
+                    for item in 0..iterations as usize {
+                        queue.enqueue(vec![1], features(*dimension, item)).unwrap();
+                    }
+
+                    let start = Instant::now();
+                    for _ in 0..iterations {
+                        queue.dequeue().unwrap();
+                    }
+                    start.elapsed()
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn peek_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("peek");
+
+    for durable in [false, true].iter() {
+        for dimension in DIMENSIONS.iter() {
+            let queue = create_queue(*durable, *dimension);
+
+            for item in 0..PEEK_QUEUE_SIZE {
+                queue.enqueue(vec![1], features(*dimension, item)).unwrap();
+            }
+
+            group.bench_function(BenchmarkId::new(storage_name(*durable), dimension), |b| {
+                b.iter(|| queue.peek().unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    enqueue_benchmark,
+    dequeue_benchmark,
+    peek_benchmark
+);
+criterion_main!(benches);
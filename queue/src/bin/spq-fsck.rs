@@ -0,0 +1,83 @@
+use sp_queue::fsck::{self, Report};
+use std::process;
+use structopt::StructOpt;
+
+/// Exit codes so that scripts can tell a damaged queue from a failure to check it
+const CONSISTENT: i32 = 0;
+const INCONSISTENT: i32 = 1;
+const FAILED: i32 = 2;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "spq-fsck",
+    about = "Checks that the counts of a durable queue agree with the items it holds"
+)]
+struct Args {
+    /// Rebuild the counts from the items actually held. The queue must not be open in a server
+    #[structopt(long)]
+    repair: bool,
+
+    /// The folder the durable queue is kept in i.e. the data root joined with the queue name
+    folder_path: String,
+}
+
+fn print(report: &Report, repaired: bool) {
+    for problem in report.problems.iter() {
+        let outcome = match (problem.repairable, repaired) {
+            (true, true) => "repaired",
+            (true, false) => "repairable",
+            (false, _) => "not repairable",
+        };
+
+        println!("{} ({})", problem.description, outcome);
+    }
+}
+
+fn run(args: Args) -> Result<i32, String> {
+    if !args.repair {
+        let report = fsck::check(args.folder_path.clone()).map_err(String::from)?;
+        print(&report, false);
+
+        return Ok(if report.is_consistent() {
+            println!("{:?} is consistent", args.folder_path);
+            CONSISTENT
+        } else {
+            println!(
+                "{:?} has {} problems",
+                args.folder_path,
+                report.problems.len()
+            );
+            INCONSISTENT
+        });
+    }
+
+    let report = fsck::repair(args.folder_path.clone()).map_err(String::from)?;
+    print(&report, true);
+
+    // What a repair could not fix is still there to be found
+    let remaining = fsck::check(args.folder_path.clone()).map_err(String::from)?;
+
+    Ok(if remaining.is_consistent() {
+        println!("{:?} is consistent", args.folder_path);
+        CONSISTENT
+    } else {
+        println!(
+            "{:?} still has {} problems",
+            args.folder_path,
+            remaining.problems.len()
+        );
+        INCONSISTENT
+    })
+}
+
+fn main() {
+    let code = match run(Args::from_args()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Failed to check the queue: {}", e);
+            FAILED
+        }
+    };
+
+    process::exit(code);
+}
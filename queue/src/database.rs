@@ -0,0 +1,469 @@
+use crate::error::Error;
+use crate::layout;
+use lazy_static::lazy_static;
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, DBIterator, IteratorMode, Options, SliceTransform,
+    WriteBatch, WriteOptions, DB,
+};
+use std::collections::BTreeMap;
+use std::path::Path;
+#[cfg(feature = "fault-injection")]
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use tracing::{debug_span, Span};
+
+pub const METADATA: &str = "metadata";
+pub const FEATURE_NAMES: &str = "feature_names";
+pub const NODE_HAS_LEAVES: &str = "node_has_leaves";
+pub const NODE_VALUE_ITEMS_AT_INDEX: &str = "node_value_items_at_index";
+pub const NODE_VALUE_CHILD_INDEX: &str = "node_value_child_index";
+pub const VALUE_TO_EPOCH: &str = "value_to_epoch";
+pub const LEAF_VALUES: &str = "leaf_values";
+pub const ITEMS: &str = "items";
+pub const LEASES: &str = "leases";
+
+/// Every column family a queue uses alongside whether its keys start with an
+/// eight byte prefix that is iterated over.
+const COLUMN_FAMILIES: [(&str, bool); 9] = [
+    (METADATA, false),
+    (FEATURE_NAMES, false),
+    (NODE_HAS_LEAVES, false),
+    (NODE_VALUE_ITEMS_AT_INDEX, true),
+    (NODE_VALUE_CHILD_INDEX, true),
+    (VALUE_TO_EPOCH, false),
+    (LEAF_VALUES, false),
+    (ITEMS, true),
+    (LEASES, false),
+];
+
+const PREFIX_LENGTH: usize = 8;
+
+/// Whether the name is one of the column families a queue uses
+pub fn is_column_family(name: &str) -> bool {
+    COLUMN_FAMILIES.iter().any(|(column, _)| *column == name)
+}
+
+/// Setting this variable to N aborts the process at the Nth write staged or
+/// committed. The fault injection tests use it to crash a queue's process
+/// between every step of an operation. Only builds with the fault-injection
+/// feature read it.
+#[cfg(feature = "fault-injection")]
+pub const FAULT_AFTER_WRITES_VARIABLE: &str = "SPQ_FAULT_AFTER_WRITES";
+
+#[cfg(feature = "fault-injection")]
+lazy_static! {
+    static ref FAULT_AFTER_WRITES: Option<usize> = std::env::var(FAULT_AFTER_WRITES_VARIABLE)
+        .ok()
+        .and_then(|writes| writes.parse().ok());
+}
+
+#[cfg(feature = "fault-injection")]
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// RocksDB options applied to every database the process opens. The
+/// defaults are RocksDB's own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tuning {
+    /// -1 keeps every file open
+    pub max_open_files: i32,
+    /// Bytes written to a column family before its memtable is flushed
+    pub write_buffer_size: usize,
+    pub max_write_buffer_number: i32,
+    pub max_background_jobs: i32,
+    /// Whether each commit waits for the write ahead log to reach the disk.
+    /// Without it a commit survives the process crashing but not the
+    /// machine losing power.
+    pub sync_writes: bool,
+}
+
+impl Default for Tuning {
+    fn default() -> Tuning {
+        Tuning {
+            max_open_files: -1,
+            write_buffer_size: 64 << 20,
+            max_write_buffer_number: 2,
+            max_background_jobs: 2,
+            sync_writes: true,
+        }
+    }
+}
+
+lazy_static! {
+    static ref TUNING: RwLock<Tuning> = RwLock::new(Tuning::default());
+}
+
+/// Sets the options databases opened from now on use. Databases that are
+/// already open keep the options they were opened with.
+pub fn set_tuning(tuning: Tuning) {
+    *TUNING
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = tuning;
+}
+
+fn tuning() -> Tuning {
+    *TUNING
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(feature = "fault-injection")]
+fn fault_point() {
+    if let Some(fault_after_writes) = *FAULT_AFTER_WRITES {
+        if WRITES.fetch_add(1, SeqCst) + 1 >= fault_after_writes {
+            std::process::abort();
+        }
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn fault_point() {}
+
+/// Every call into RocksDB runs in its own span so that traces show the time
+/// spent in storage
+fn rocksdb_span(operation: &'static str, column: &str) -> Span {
+    debug_span!("rocksdb", operation, column)
+}
+
+/// A key and its value
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// A `None` value marks a staged delete
+type StagedWrites = BTreeMap<(&'static str, Vec<u8>), Option<Vec<u8>>>;
+
+/// Opens the RocksDB instance at the folder path with every column family a
+/// queue uses and any others named. Missing column families are only created
+/// alongside a missing database when create is set.
+pub fn open_db(folder_path: &str, others: &[String], create: bool) -> Result<DB, Error> {
+    let tuning = tuning();
+
+    let mut options = Options::default();
+    options.create_if_missing(create);
+    options.create_missing_column_families(create);
+    options.set_max_open_files(tuning.max_open_files);
+    options.set_max_background_jobs(tuning.max_background_jobs);
+
+    let descriptors = COLUMN_FAMILIES
+        .iter()
+        .map(|(name, is_prefixed)| {
+            let mut column_options = Options::default();
+            column_options.set_write_buffer_size(tuning.write_buffer_size);
+            column_options.set_max_write_buffer_number(tuning.max_write_buffer_number);
+
+            if *is_prefixed {
+                column_options
+                    .set_prefix_extractor(SliceTransform::create_fixed_prefix(PREFIX_LENGTH));
+            }
+
+            ColumnFamilyDescriptor::new(*name, column_options)
+        })
+        .chain(
+            others
+                .iter()
+                .map(|name| ColumnFamilyDescriptor::new(name, Options::default())),
+        );
+
+    rocksdb_span("open", "")
+        .in_scope(|| DB::open_cf_descriptors(&options, folder_path, descriptors))
+        .map_err(|e| Error::Storage {
+            message: e.to_string(),
+        })
+}
+
+/// All of a durable queue's state lives in one RocksDB instance with a column
+/// family per logical store. Writes are staged in memory, visible to reads
+/// straight away, until they are committed together as a single write batch.
+/// A commit is durable once it returns: by default the write ahead log is
+/// synced to disk before then, otherwise it is only durable against the
+/// process crashing. Memtables are flushed by RocksDB in the background and
+/// on close.
+///
+/// Staged writes are shared by every store rather than kept per transaction
+/// so a commit or discard takes all of them whichever operation staged them.
+/// Callers must serialize transactions on a database.
+pub struct Database {
+    db: DB,
+    staged: Mutex<StagedWrites>,
+    maybe_failure: Mutex<Option<String>>,
+    sync_writes: bool,
+}
+
+impl Database {
+    /// Opens the database at the folder path creating it in the current
+    /// layout if needed
+    pub fn create(folder_path: String) -> Result<Arc<Database>, Error> {
+        if Path::new(&folder_path).join("CURRENT").exists() {
+            return Database::open(folder_path);
+        }
+
+        let database = Database::open_with(folder_path, true)?;
+        layout::stamp(&database.db)?;
+
+        Ok(database)
+    }
+
+    /// Opens a database that must already exist at the folder path in the
+    /// current layout. Column families that are missing are not created.
+    pub fn open(folder_path: String) -> Result<Arc<Database>, Error> {
+        // RocksDB writes the CURRENT file when it creates a database
+        if !Path::new(&folder_path).join("CURRENT").exists() {
+            return Err(Error::NotInitialised { folder_path });
+        }
+
+        let database = Database::open_with(folder_path.clone(), false)?;
+        layout::check(&database.db, &folder_path)?;
+
+        Ok(database)
+    }
+
+    /// Opens a database that must already exist without writing to it. It
+    /// may be opened this way while another process has it open. Any commit
+    /// fails.
+    pub fn open_read_only(folder_path: String) -> Result<Arc<Database>, Error> {
+        if !Path::new(&folder_path).join("CURRENT").exists() {
+            return Err(Error::NotInitialised { folder_path });
+        }
+
+        let names = COLUMN_FAMILIES.iter().map(|(name, _)| *name);
+
+        let db = rocksdb_span("open", "")
+            .in_scope(|| {
+                DB::open_cf_for_read_only(&Options::default(), folder_path.clone(), names, false)
+            })
+            .map_err(|e| Error::Storage {
+                message: e.to_string(),
+            })?;
+        layout::check(&db, &folder_path)?;
+
+        Ok(Arc::new(Database {
+            db,
+            staged: Mutex::new(BTreeMap::new()),
+            maybe_failure: Mutex::new(None),
+            sync_writes: tuning().sync_writes,
+        }))
+    }
+
+    fn open_with(folder_path: String, create: bool) -> Result<Arc<Database>, Error> {
+        let db = open_db(&folder_path, &[], create)?;
+
+        Ok(Arc::new(Database {
+            db,
+            staged: Mutex::new(BTreeMap::new()),
+            maybe_failure: Mutex::new(None),
+            sync_writes: tuning().sync_writes,
+        }))
+    }
+
+    fn cf_handle(&self, name: &str) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| Error::corruption(format!("Missing column family {:?}", name)))
+    }
+
+    fn staged(&self) -> MutexGuard<'_, StagedWrites> {
+        // Staged writes are plain data so they remain usable if a holder panicked
+        self.staged
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn maybe_failure(&self) -> MutexGuard<'_, Option<String>> {
+        self.maybe_failure
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Remembers a RocksDB failure so that it is reported until a later
+    /// write succeeds
+    fn failed(&self, e: rocksdb::Error) -> Error {
+        let message = e.to_string();
+        *self.maybe_failure() = Some(message.clone());
+
+        Error::Storage { message }
+    }
+
+    /// The last RocksDB failure if no write has succeeded since
+    pub fn failure(&self) -> Option<String> {
+        self.maybe_failure().clone()
+    }
+
+    /// Writes every staged change in one batch so that either all of them
+    /// reach disk or none of them do.
+    pub fn commit(&self) -> Result<(), Error> {
+        let mut staged = self.staged();
+
+        if staged.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::default();
+
+        for ((name, key), maybe_value) in staged.iter() {
+            let cf_handle = self.cf_handle(name)?;
+
+            match maybe_value {
+                Some(value) => batch.put_cf(cf_handle, key, value),
+                None => batch.delete_cf(cf_handle, key),
+            }
+        }
+
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(self.sync_writes);
+
+        fault_point();
+        rocksdb_span("write", "")
+            .in_scope(|| self.db.write_opt(batch, &write_options))
+            .map_err(|e| self.failed(e))?;
+        fault_point();
+        staged.clear();
+        *self.maybe_failure() = None;
+
+        Ok(())
+    }
+
+    /// Writes every column family's memtable out to disk so that nothing
+    /// needs to be recovered from the write ahead log when the database is
+    /// next opened.
+    pub fn flush(&self) -> Result<(), Error> {
+        for (name, _) in COLUMN_FAMILIES.iter() {
+            let cf_handle = self.cf_handle(name)?;
+
+            rocksdb_span("flush", name)
+                .in_scope(|| self.db.flush_cf(cf_handle))
+                .map_err(|e| self.failed(e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops every staged change leaving the database as it was at the last commit
+    pub fn discard(&self) {
+        self.staged().clear();
+    }
+}
+
+/// The named column of the database when the queue is durable
+pub fn column(maybe_database: &Option<Arc<Database>>, name: &'static str) -> Option<Column> {
+    maybe_database
+        .as_ref()
+        .map(|database| Column::new(database.clone(), name))
+}
+
+/// A handle on one column family of a shared database
+#[derive(Clone)]
+pub struct Column {
+    database: Arc<Database>,
+    name: &'static str,
+}
+
+impl Column {
+    pub fn new(database: Arc<Database>, name: &'static str) -> Column {
+        Column { database, name }
+    }
+
+    fn stored_with_prefix(&self, prefix: &[u8]) -> Result<DBIterator<'_>, Error> {
+        let cf_handle = self.database.cf_handle(self.name)?;
+
+        Ok(rocksdb_span("iterate", self.name).in_scope(|| {
+            if prefix.is_empty() {
+                self.database.db.iterator_cf(cf_handle, IteratorMode::Start)
+            } else {
+                self.database.db.prefix_iterator_cf(cf_handle, prefix)
+            }
+        }))
+    }
+
+    fn staged_with_prefix(
+        &self,
+        staged: &StagedWrites,
+        prefix: &[u8],
+    ) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        staged
+            .range((self.name, prefix.to_vec())..)
+            .take_while(|((name, key), _)| *name == self.name && key.starts_with(prefix))
+            .map(|((_, key), maybe_value)| (key.clone(), maybe_value.clone()))
+            .collect()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(maybe_value) = self.database.staged().get(&(self.name, key.to_vec())) {
+            return Ok(maybe_value.clone());
+        }
+
+        let cf_handle = self.database.cf_handle(self.name)?;
+
+        rocksdb_span("get", self.name)
+            .in_scope(|| self.database.db.get_cf(cf_handle, key))
+            .map_err(|e| self.database.failed(e))
+    }
+
+    pub fn put(&self, key: &[u8], value: Vec<u8>) {
+        fault_point();
+        self.database
+            .staged()
+            .insert((self.name, key.to_vec()), Some(value));
+    }
+
+    pub fn delete(&self, key: &[u8]) {
+        fault_point();
+        self.database
+            .staged()
+            .insert((self.name, key.to_vec()), None);
+    }
+
+    /// Every entry whose key starts with the prefix in key order. An empty
+    /// prefix returns the whole column.
+    pub fn entries_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Entry>, Error> {
+        let staged = self.database.staged();
+        let mut entries: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+
+        for (key, value) in self.stored_with_prefix(prefix)? {
+            if !key.starts_with(prefix) {
+                break;
+            }
+
+            entries.insert(key.to_vec(), value.to_vec());
+        }
+
+        for (key, maybe_value) in self.staged_with_prefix(&staged, prefix) {
+            match maybe_value {
+                Some(value) => entries.insert(key, value),
+                None => entries.remove(&key),
+            };
+        }
+
+        Ok(entries.into_iter().collect())
+    }
+
+    /// The entry with the lowest key that starts with the prefix
+    pub fn first_with_prefix(&self, prefix: &[u8]) -> Result<Option<Entry>, Error> {
+        let staged = self.database.staged();
+        let staged_at_prefix = self.staged_with_prefix(&staged, prefix);
+
+        // Stored entries with a staged change are superseded by that change
+        let mut maybe_first_stored: Option<Entry> = None;
+
+        for (key, value) in self.stored_with_prefix(prefix)? {
+            if !key.starts_with(prefix) {
+                break;
+            }
+
+            if staged_at_prefix
+                .iter()
+                .all(|(staged_key, _)| staged_key.as_slice() != &*key)
+            {
+                maybe_first_stored = Some((key.to_vec(), value.to_vec()));
+                break;
+            }
+        }
+
+        let maybe_first_staged = staged_at_prefix
+            .into_iter()
+            .find_map(|(key, maybe_value)| maybe_value.map(|value| (key, value)));
+
+        Ok(match (maybe_first_stored, maybe_first_staged) {
+            (Some(stored), Some(staged)) if staged.0 < stored.0 => Some(staged),
+            (Some(stored), _) => Some(stored),
+            (None, maybe_staged) => maybe_staged,
+        })
+    }
+}
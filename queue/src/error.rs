@@ -3,8 +3,44 @@ use std::fmt;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Error {
-    Standard { message: String },
-    Empty { message: String },
+    Standard {
+        message: String,
+    },
+    /// A lookup found nothing
+    Empty {
+        message: String,
+    },
+    /// An item has a different number of features than the queue
+    InvalidDimension {
+        expected: u64,
+        actual: u64,
+    },
+    /// An item's feature names differ from the ones the queue was created with
+    UnknownFeatureNames {
+        expected: Vec<String>,
+        actual: Vec<String>,
+    },
+    /// There is no queue at the folder path
+    NotInitialised {
+        folder_path: String,
+    },
+    /// RocksDB failed to open, read or write
+    Storage {
+        message: String,
+    },
+    /// What the queue stored contradicts itself or could not be decoded
+    Corruption {
+        message: String,
+    },
+    /// The queue is stored in a layout this version neither reads nor upgrades
+    UnsupportedLayout {
+        folder_path: String,
+        message: String,
+    },
+    /// The lease was acked, nacked, expired or never handed out
+    LeaseNotFound {
+        lease_id: u64,
+    },
 }
 
 impl Error {
@@ -13,18 +49,23 @@ impl Error {
         Error::Standard { message }
     }
 
+    pub fn corruption(message: String) -> Error {
+        Error::Corruption { message }
+    }
+
+    /// Whether RocksDB failed to open, read or write
+    pub fn is_storage(&self) -> bool {
+        matches!(self, Error::Storage { .. })
+    }
+
     pub fn into_string(self) -> String {
         self.into()
     }
 }
 
-impl AsRef<str> for Error {
-    fn as_ref(&self) -> &str {
-        match &self {
-            Error::Standard { message } => message,
-            Error::Empty { message } => message,
-        }
-    }
+/// Stored bytes that could not be decoded
+pub fn undecodable(e: impl fmt::Display) -> Error {
+    Error::corruption(format!("Failed to decode stored bytes: {}", e))
 }
 
 impl<E: error::Error> From<E> for Error {
@@ -35,10 +76,7 @@ impl<E: error::Error> From<E> for Error {
 
 impl From<Error> for String {
     fn from(e: Error) -> String {
-        match e {
-            Error::Standard { message } => message,
-            Error::Empty { message } => message,
-        }
+        e.to_string()
     }
 }
 
@@ -47,6 +85,34 @@ impl fmt::Display for Error {
         match self {
             Error::Standard { message } => message.fmt(formatter),
             Error::Empty { message } => message.fmt(formatter),
+            Error::InvalidDimension { expected, actual } => write!(
+                formatter,
+                "Invalid feature vector of {} features the queue has {}",
+                actual, expected
+            ),
+            Error::UnknownFeatureNames { expected, actual } => write!(
+                formatter,
+                "Invalid feature names {:?} the queue has {:?}",
+                actual, expected
+            ),
+            Error::NotInitialised { folder_path } => {
+                write!(formatter, "No queue has been created at {:?}", folder_path)
+            }
+            Error::Storage { message } => write!(formatter, "Storage failed: {}", message),
+            Error::Corruption { message } => write!(formatter, "Corrupt queue: {}", message),
+            Error::UnsupportedLayout {
+                folder_path,
+                message,
+            } => write!(
+                formatter,
+                "Queue at {:?} cannot be opened: {}",
+                folder_path, message
+            ),
+            Error::LeaseNotFound { lease_id } => write!(
+                formatter,
+                "No active lease with id {:?}. It may have been acked, nacked or expired",
+                lease_id
+            ),
         }
     }
 }
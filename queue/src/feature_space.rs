@@ -1,11 +1,17 @@
-use crate::error::Error;
+use crate::database::{self, column, Database, Entry};
+use crate::error::{undecodable, Error};
 use crate::prefix_storage::PrefixStorage;
-use crate::storage::Storage;
+use crate::storage::{DeserializeFn, SerializeFn, Storage};
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::instrument;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FeatureValue {
     feature_name: String,
     value: usize,
@@ -24,6 +30,10 @@ impl FeatureValue {
         &self.feature_name
     }
 
+    pub fn get_value(&self) -> usize {
+        self.value
+    }
+
     pub fn get_hash(&self) -> u64 {
         let mut hasher = DefaultHasher::new();
 
@@ -40,6 +50,16 @@ impl Hash for FeatureValue {
     }
 }
 
+const FEATURE_VALUES_TO_BYTES: DeserializeFn<Vec<FeatureValue>> =
+    |feature_values| Ok(bincode::serialize(&feature_values)?);
+pub const FEATURE_VALUES_FROM_BYTES: SerializeFn<Vec<FeatureValue>> =
+    |bytes| bincode::deserialize(&bytes).map_err(undecodable);
+
+const FEATURE_NAMES_TO_BYTES: DeserializeFn<Vec<String>> =
+    |feature_names| Ok(bincode::serialize(&feature_names)?);
+const FEATURE_NAMES_FROM_BYTES: SerializeFn<Vec<String>> =
+    |bytes| bincode::deserialize(&bytes).map_err(undecodable);
+
 pub fn create_hash<H: Hash>(features: &[H]) -> u64 {
     let mut hasher = DefaultHasher::new();
 
@@ -50,28 +70,113 @@ pub fn create_hash<H: Hash>(features: &[H]) -> u64 {
     hasher.finish()
 }
 
-const TOTAL_ITEMS_KEY: u64 = 0;
+fn expect_present<V>(result: Result<V, Error>, folder_path: &str, name: &str) -> Result<V, Error> {
+    result.map_err(|err| match err {
+        Error::Empty { .. } => Error::corruption(format!(
+            "Feature space at {:?} is incomplete. Missing {}",
+            folder_path, name
+        )),
+        err => err,
+    })
+}
+
+/// Fails if any of the metadata is missing or the stored feature names no
+/// longer match the stored hash and dimension. Queues created before the
+/// feature names were stored only have the hash of them.
+fn check_metadata(
+    metadata: &Storage<u64>,
+    feature_names: &Storage<Vec<String>>,
+    folder_path: &str,
+) -> Result<(), Error> {
+    let feature_names_hash = expect_present(
+        metadata.get(&FEATURE_NAMES_KEY),
+        folder_path,
+        "feature names hash",
+    )?;
+    let dimension = expect_present(metadata.get(&DIMENSION_KEY), folder_path, "dimension")?;
+    expect_present(metadata.get(&EPOCH_STEP_KEY), folder_path, "epoch step")?;
+    expect_present(metadata.get(&TOTAL_ITEMS_KEY), folder_path, "total items")?;
+
+    let features = match feature_names.get(&FEATURE_NAMES_KEY) {
+        Ok(features) => features,
+        Err(Error::Empty { .. }) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if create_hash(&features) != feature_names_hash {
+        return Err(Error::corruption(format!(
+            "Feature space at {:?} has feature names {:?} that do not match the stored hash",
+            folder_path, features
+        )));
+    }
+
+    if features.len() as u64 != dimension {
+        return Err(Error::corruption(format!(
+            "Feature space at {:?} has {:?} feature names but a dimension of {:?}",
+            folder_path,
+            features.len(),
+            dimension
+        )));
+    }
+
+    Ok(())
+}
+
+pub const TOTAL_ITEMS_KEY: u64 = 0;
 
 const EPOCH_STEP_KEY: u64 = 1;
 
-const ROOT_INDEX_KEY: u64 = 2;
+pub const ROOT_INDEX_KEY: u64 = 2;
 
-const DIMENSION_KEY: u64 = 3;
+pub const DIMENSION_KEY: u64 = 3;
 
 const FEATURE_NAMES_KEY: u64 = 4;
 
-pub struct FeatureSpace {
+/// The replicated log entry last applied to the queue. Only replicated
+/// queues store it.
+pub const APPLIED_INDEX_KEY: u64 = 5;
+
+fn poisoned<T>(_: T) -> Error {
+    Error::new("Feature space was poisoned by a panic during an earlier change".to_string())
+}
+
+/// The storages making up the feature space. They are only ever changed while
+/// holding the write lock on the whole graph.
+struct FeatureGraph {
     metadata: Storage<u64>,
+    feature_names: Storage<Vec<String>>,
     feature_node_has_leaves: Storage<bool>,
     feature_node_value_items_at_index: PrefixStorage,
     feature_node_value_child_index: PrefixStorage,
     feature_value_to_epoch_step: Storage<u64>,
+    feature_leaf_values: Storage<Vec<FeatureValue>>,
+}
+
+/// The node and value chosen at each layer on the way to a leaf
+struct Walk {
+    steps: Vec<(u64, u64)>,
+    leaf_index: u64,
+}
+
+/// May be shared between threads. Reads take the read lock so they run
+/// alongside each other. Each change holds the write lock from the walk it
+/// plans until it is applied so changes never interleave, and adding or using
+/// an item advances the epoch step by exactly one.
+///
+/// A durable feature space only stages its changes in the `Database`, which
+/// commits or discards everything staged by anyone. Operations on it must
+/// therefore be serialized by the caller from their first write until the
+/// commit, as `SortingPriorityQueue` does by holding its lock throughout. Its
+/// own locks only keep concurrent changes from corrupting the graph.
+pub struct FeatureSpace {
+    graph: RwLock<FeatureGraph>,
+    has_feature_names: AtomicBool,
 }
 
 impl FeatureSpace {
     pub fn new(
         features: Vec<String>,
-        maybe_folder_path: Option<String>,
+        maybe_database: Option<Arc<Database>>,
     ) -> Result<FeatureSpace, Error> {
         let mut hasher = DefaultHasher::new();
 
@@ -81,11 +186,8 @@ impl FeatureSpace {
 
         let feature_names_hash = hasher.finish();
 
-        let mut metadata_storage = Storage::<u64>::new_integer(
-            maybe_folder_path
-                .clone()
-                .map(|folder_path| folder_path + "/metadata"),
-        );
+        let mut metadata_storage =
+            Storage::<u64>::new_integer(column(&maybe_database, database::METADATA))?;
 
         metadata_storage.put_if_absent(&FEATURE_NAMES_KEY, feature_names_hash)?;
 
@@ -95,79 +197,386 @@ impl FeatureSpace {
 
         metadata_storage.put_if_absent(&DIMENSION_KEY, features.len() as u64)?;
 
-        let feature_space = FeatureSpace {
-            metadata: metadata_storage,
-            feature_node_has_leaves: Storage::<bool>::new_bool(
-                maybe_folder_path
-                    .clone()
-                    .map(|folder_path| folder_path + "/node_has_leaves"),
+        let mut feature_names_storage = Storage::new(
+            column(&maybe_database, database::FEATURE_NAMES),
+            FEATURE_NAMES_TO_BYTES,
+            FEATURE_NAMES_FROM_BYTES,
+        )?;
+
+        feature_names_storage.put_if_absent(&FEATURE_NAMES_KEY, features)?;
+
+        FeatureSpace::from_storage(metadata_storage, feature_names_storage, maybe_database)
+    }
+
+    /// Reopens a durable feature space from the feature names recorded when
+    /// it was created. Fails if any of the metadata is missing or the stored
+    /// feature names no longer match the stored hash and dimension.
+    pub fn open(database: Arc<Database>, folder_path: &str) -> Result<FeatureSpace, Error> {
+        let maybe_database = Some(database);
+
+        let metadata_storage =
+            Storage::<u64>::new_integer(column(&maybe_database, database::METADATA))?;
+        let feature_names_storage = Storage::new(
+            column(&maybe_database, database::FEATURE_NAMES),
+            FEATURE_NAMES_TO_BYTES,
+            FEATURE_NAMES_FROM_BYTES,
+        )?;
+
+        check_metadata(&metadata_storage, &feature_names_storage, folder_path)?;
+
+        FeatureSpace::from_storage(metadata_storage, feature_names_storage, maybe_database)
+    }
+
+    /// Rebuilds a feature space from the columns of an export taking each
+    /// column it uses out of the map. The metadata is checked as on open.
+    pub fn import(
+        columns: &mut BTreeMap<String, Vec<Entry>>,
+        maybe_database: Option<Arc<Database>>,
+    ) -> Result<FeatureSpace, Error> {
+        let mut take = |name: &str| columns.remove(name).unwrap_or_default();
+
+        let mut metadata_storage =
+            Storage::<u64>::new_integer(column(&maybe_database, database::METADATA))?;
+        let mut feature_names_storage = Storage::new(
+            column(&maybe_database, database::FEATURE_NAMES),
+            FEATURE_NAMES_TO_BYTES,
+            FEATURE_NAMES_FROM_BYTES,
+        )?;
+
+        metadata_storage.import(take(database::METADATA))?;
+        feature_names_storage.import(take(database::FEATURE_NAMES))?;
+        check_metadata(&metadata_storage, &feature_names_storage, "import")?;
+
+        let feature_space =
+            FeatureSpace::from_storage(metadata_storage, feature_names_storage, maybe_database)?;
+
+        {
+            let mut graph = feature_space.write()?;
+
+            graph
+                .feature_node_has_leaves
+                .import(take(database::NODE_HAS_LEAVES))?;
+            graph
+                .feature_node_value_items_at_index
+                .import(take(database::NODE_VALUE_ITEMS_AT_INDEX))?;
+            graph
+                .feature_node_value_child_index
+                .import(take(database::NODE_VALUE_CHILD_INDEX))?;
+            graph
+                .feature_value_to_epoch_step
+                .import(take(database::VALUE_TO_EPOCH))?;
+            graph
+                .feature_leaf_values
+                .import(take(database::LEAF_VALUES))?;
+        }
+
+        Ok(feature_space)
+    }
+
+    /// Every column of the feature space as a durable queue stores it
+    pub fn export(&self) -> Result<Vec<(&'static str, Vec<Entry>)>, Error> {
+        let graph = self.read()?;
+
+        Ok(vec![
+            (database::METADATA, graph.metadata.export()?),
+            (database::FEATURE_NAMES, graph.feature_names.export()?),
+            (
+                database::NODE_HAS_LEAVES,
+                graph.feature_node_has_leaves.export()?,
             ),
-            feature_node_value_items_at_index: PrefixStorage::new_integer(
-                maybe_folder_path
-                    .clone()
-                    .map(|folder_path| folder_path + "/node_value_items_at_index"),
+            (
+                database::NODE_VALUE_ITEMS_AT_INDEX,
+                graph.feature_node_value_items_at_index.export()?,
             ),
-            feature_node_value_child_index: PrefixStorage::new_integer(
-                maybe_folder_path
-                    .clone()
-                    .map(|folder_path| folder_path + "/node_value_child_index"),
+            (
+                database::NODE_VALUE_CHILD_INDEX,
+                graph.feature_node_value_child_index.export()?,
             ),
-            feature_value_to_epoch_step: Storage::<u64>::new_integer(
-                maybe_folder_path.map(|folder_path| folder_path + "/value_to_epoch"),
+            (
+                database::VALUE_TO_EPOCH,
+                graph.feature_value_to_epoch_step.export()?,
             ),
+            (database::LEAF_VALUES, graph.feature_leaf_values.export()?),
+        ])
+    }
+
+    fn from_storage(
+        metadata: Storage<u64>,
+        feature_names: Storage<Vec<String>>,
+        maybe_database: Option<Arc<Database>>,
+    ) -> Result<FeatureSpace, Error> {
+        let graph = FeatureGraph {
+            metadata,
+            feature_names,
+            feature_node_has_leaves: Storage::<bool>::new_bool(column(
+                &maybe_database,
+                database::NODE_HAS_LEAVES,
+            ))?,
+            feature_node_value_items_at_index: PrefixStorage::new_integer(column(
+                &maybe_database,
+                database::NODE_VALUE_ITEMS_AT_INDEX,
+            ))?,
+            feature_node_value_child_index: PrefixStorage::new_integer(column(
+                &maybe_database,
+                database::NODE_VALUE_CHILD_INDEX,
+            ))?,
+            feature_value_to_epoch_step: Storage::<u64>::new_integer(column(
+                &maybe_database,
+                database::VALUE_TO_EPOCH,
+            ))?,
+            feature_leaf_values: Storage::new(
+                column(&maybe_database, database::LEAF_VALUES),
+                FEATURE_VALUES_TO_BYTES,
+                FEATURE_VALUES_FROM_BYTES,
+            )?,
         };
 
-        Ok(feature_space)
+        let has_feature_names = match graph.feature_names.get(&FEATURE_NAMES_KEY) {
+            Ok(_) => true,
+            Err(Error::Empty { .. }) => false,
+            Err(e) => return Err(e),
+        };
+
+        Ok(FeatureSpace {
+            graph: RwLock::new(graph),
+            has_feature_names: AtomicBool::new(has_feature_names),
+        })
     }
 
-    pub fn epoch_step(&self) -> Result<u64, Error> {
-        self.metadata.get(&EPOCH_STEP_KEY)
+    /// Stores the names of the features if the queue does not know them yet.
+    /// The features must already have been checked against the stored hash.
+    pub fn record_feature_names(&self, features: &[FeatureValue]) -> Result<(), Error> {
+        if self.has_feature_names.load(SeqCst) {
+            return Ok(());
+        }
+
+        let feature_names = features
+            .iter()
+            .map(|feature| feature.get_name().clone())
+            .collect();
+
+        self.write()?
+            .feature_names
+            .put_if_absent(&FEATURE_NAMES_KEY, feature_names)?;
+        self.has_feature_names.store(true, SeqCst);
+
+        Ok(())
     }
 
-    fn increment_epoch_step(&mut self) -> Result<u64, Error> {
-        self.metadata
-            .update(&EPOCH_STEP_KEY, |epoch_step| epoch_step + 1)
+    fn read(&self) -> Result<RwLockReadGuard<'_, FeatureGraph>, Error> {
+        self.graph.read().map_err(poisoned)
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, FeatureGraph>, Error> {
+        self.graph.write().map_err(poisoned)
+    }
+
+    pub fn epoch_step(&self) -> Result<u64, Error> {
+        self.read()?.epoch_step()
     }
 
     pub fn dimension(&self) -> Result<u64, Error> {
-        self.metadata.get(&DIMENSION_KEY)
+        self.read()?.dimension()
     }
 
     pub fn feature_names_hash(&self) -> Result<u64, Error> {
-        self.metadata.get(&FEATURE_NAMES_KEY)
+        self.read()?.metadata.get(&FEATURE_NAMES_KEY)
+    }
+
+    /// The names the queue was created with. A queue created before they
+    /// were stored has none until an item is enqueued.
+    pub fn feature_names(&self) -> Result<Vec<String>, Error> {
+        match self.read()?.feature_names.get(&FEATURE_NAMES_KEY) {
+            Err(Error::Empty { .. }) => Ok(vec![]),
+            result => result,
+        }
+    }
+
+    /// Whether the queue was created with the feature names. This holds for
+    /// queues that only stored the hash of their names.
+    pub fn has_feature_names(&self, feature_names: &[String]) -> Result<bool, Error> {
+        Ok(feature_names.len() as u64 == self.dimension()?
+            && create_hash(feature_names) == self.feature_names_hash()?)
     }
 
     pub fn total_items(&self) -> Result<u64, Error> {
-        match self.root_index() {
-            Ok(root_index) => {
-                let items_at_each_root_value = self
+        let graph = self.read()?;
+
+        match graph.maybe_root_index()? {
+            Some(root_index) => {
+                let items_at_each_root_value = graph
                     .feature_node_value_items_at_index
                     .get_at_prefix(&root_index)?;
 
                 Ok(items_at_each_root_value.iter().sum())
             }
-            Err(Error::Empty { .. }) => Ok(0),
-            Err(e) => Err(e),
+            None => Ok(0),
         }
     }
 
-    pub fn increment_total_items(&mut self) -> Result<u64, Error> {
-        self.metadata
+    pub fn increment_total_items(&self) -> Result<u64, Error> {
+        self.write()?
+            .metadata
             .update(&TOTAL_ITEMS_KEY, |total_items| total_items + 1)
     }
 
-    pub fn decrement_total_items(&mut self) -> Result<u64, Error> {
-        self.metadata
+    pub fn decrement_total_items(&self) -> Result<u64, Error> {
+        let mut graph = self.write()?;
+
+        if graph.metadata.get(&TOTAL_ITEMS_KEY)? == 0 {
+            return Err(Error::corruption(
+                "Feature space has lost track of the total items. Found no items to remove"
+                    .to_string(),
+            ));
+        }
+
+        graph
+            .metadata
             .update(&TOTAL_ITEMS_KEY, |total_items| total_items - 1)
     }
 
+    pub fn root_index(&self) -> Result<u64, Error> {
+        self.read()?.root_index()
+    }
+
+    /// Zero until an entry of a replicated log is applied
+    pub fn applied_index(&self) -> Result<u64, Error> {
+        match self.read()?.metadata.get(&APPLIED_INDEX_KEY) {
+            Err(Error::Empty { .. }) => Ok(0),
+            result => result,
+        }
+    }
+
+    pub fn set_applied_index(&self, index: u64) -> Result<(), Error> {
+        self.write()?.metadata.put(&APPLIED_INDEX_KEY, index)
+    }
+
+    /// Every item stored under a leaf shares the same feature values as the
+    /// leaf index is the hash of the full feature vector.
+    pub fn leaf_feature_values(&self, leaf_index: u64) -> Result<Vec<FeatureValue>, Error> {
+        self.read()?.feature_leaf_values.get(&leaf_index)
+    }
+
+    /// Counts the items waiting under each feature value. Every leaf knows the
+    /// full set of values its items were enqueued with so the count at the
+    /// leaf is added to each of those values.
+    pub fn feature_value_counts(&self) -> Result<Vec<(FeatureValue, u64)>, Error> {
+        let graph = self.read()?;
+        let root_index = match graph.maybe_root_index()? {
+            Some(root_index) => root_index,
+            None => return Ok(vec![]),
+        };
+
+        let mut counts: Vec<(FeatureValue, u64)> = vec![];
+
+        for (_, feature_values) in graph.feature_leaf_values.get_all()? {
+            let (leaf_value, parent_values) = match feature_values.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+
+            // Below the root a node is identified by as many of the leading
+            // values as its height so the leaf's values sit under the first
+            let parent_index = if parent_values.is_empty() {
+                root_index
+            } else {
+                create_hash(&parent_values[..1])
+            };
+
+            let leaf_count = match graph
+                .feature_node_value_items_at_index
+                .get(&parent_index, &leaf_value.get_hash())
+            {
+                Ok(count) => count,
+                Err(Error::Empty { .. }) => 0,
+                Err(e) => return Err(e),
+            };
+
+            for feature_value in feature_values.iter() {
+                match counts.iter_mut().find(|(value, _)| value == feature_value) {
+                    Some((_, count)) => *count += leaf_count,
+                    None => counts.push((feature_value.clone(), leaf_count)),
+                }
+            }
+        }
+
+        counts.sort_by(|(a, _), (b, _)| {
+            (a.get_name(), a.get_value()).cmp(&(b.get_name(), b.get_value()))
+        });
+
+        Ok(counts)
+    }
+
+    pub fn peek_next_leaf_feature(&self) -> Result<Option<u64>, Error> {
+        let graph = self.read()?;
+        let epoch_step = graph.epoch_step()?;
+
+        Ok(graph.walk(epoch_step)?.map(|walk| walk.leaf_index))
+    }
+
+    /// Takes the leaf the next item should come from marking every value on
+    /// the way to it as used at the next epoch step. Returns the leaf
+    /// alongside the epoch step claimed for it.
+    #[instrument(
+        level = "debug",
+        name = "feature_space.use_next_leaf_feature",
+        skip(self)
+    )]
+    pub fn use_next_leaf_feature(&self) -> Result<Option<(u64, u64)>, Error> {
+        let mut graph = self.write()?;
+        let next_epoch_step = graph.epoch_step()? + 1;
+
+        let walk = match graph.walk(next_epoch_step)? {
+            Some(walk) => walk,
+            None => return Ok(None),
+        };
+
+        graph.metadata.put(&EPOCH_STEP_KEY, next_epoch_step)?;
+
+        for (node, key) in walk.steps.iter() {
+            graph
+                .feature_value_to_epoch_step
+                .put(key, next_epoch_step)?;
+            graph
+                .feature_node_value_items_at_index
+                .update(node, key, |count| count - 1)?;
+        }
+
+        Ok(Some((walk.leaf_index, next_epoch_step)))
+    }
+
+    /// Adds an item under the leaf returning the epoch step the addition
+    /// claimed
+    #[instrument(
+        level = "debug",
+        name = "feature_space.add_item",
+        skip(self, feature_values)
+    )]
+    pub fn add_item(
+        &self,
+        feature_values: Vec<FeatureValue>,
+        leaf_index: u64,
+    ) -> Result<u64, Error> {
+        self.write()?.add_item(feature_values, leaf_index)
+    }
+}
+
+impl FeatureGraph {
+    fn epoch_step(&self) -> Result<u64, Error> {
+        self.metadata.get(&EPOCH_STEP_KEY)
+    }
+
+    fn dimension(&self) -> Result<u64, Error> {
+        self.metadata.get(&DIMENSION_KEY)
+    }
+
     fn set_root_index(&mut self, index: u64) -> Result<(), Error> {
         let was_put = self.metadata.put_if_absent(&ROOT_INDEX_KEY, index)?;
 
         if was_put {
             Ok(())
         } else {
-            Err(Error::new(format!(
+            Err(Error::corruption(format!(
                 "Queue already initialized with root node at {:?}",
                 ROOT_INDEX_KEY
             )))
@@ -178,102 +587,78 @@ impl FeatureSpace {
         self.metadata.get(&ROOT_INDEX_KEY)
     }
 
-    pub fn peek_next_leaf_feature(&self) -> Result<Option<u64>, Error> {
-        let mut current_node = self.root_index()?;
-
-        for feature_space_layer in 0..self.dimension()? {
-            let keys_greater_than_zero: Vec<u64> = self
-                .feature_node_value_items_at_index
-                .filter_keys_by_prefix(&current_node, |count| count > 0)?;
-
-            let mut maybe_next_key: Option<&u64> = None;
-            let mut lowest_epoch_step: u64 = self.epoch_step()?;
-
-            for key in keys_greater_than_zero.iter() {
-                let value_last_used_epoch_step = self.feature_value_to_epoch_step.get(key)?;
-
-                if value_last_used_epoch_step < lowest_epoch_step {
-                    maybe_next_key = Some(key);
-                    lowest_epoch_step = value_last_used_epoch_step;
-                }
-            }
-
-            match maybe_next_key {
-                Some(next_key) if self.feature_node_has_leaves.get(&current_node)? => {
-                    let leaf = self.feature_node_value_child_index.get(&current_node, &next_key)?;
-                    return Ok(Some(leaf));
-                },
-                Some(next_key) => {
-                    current_node = self.feature_node_value_child_index.get(&current_node, &next_key)?;
-                },
-                None if feature_space_layer != 0 => panic!(
-                    "Feature space has lost track of number of values for each feature. Found node that should contain values but contains none {:?}",
-                    current_node
-                ),
-                None => return Ok(None),
-            }
+    /// The root index is only assigned by the first item added so until then
+    /// there is nothing to walk.
+    fn maybe_root_index(&self) -> Result<Option<u64>, Error> {
+        match self.root_index() {
+            Ok(root_index) => Ok(Some(root_index)),
+            Err(Error::Empty { .. }) => Ok(None),
+            Err(e) => Err(e),
         }
-
-        Ok(None)
     }
 
-    pub fn use_next_leaf_feature(&mut self) -> Result<Option<u64>, Error> {
-        let next_epoch_step = self.epoch_step()? + 1;
-        let mut current_node = self.root_index()?;
-        let mut leaf_index: Option<u64> = None;
+    /// Plans the path to the next leaf choosing at each layer the value with
+    /// items that was used longest ago. Only values last used before the
+    /// epoch step given are considered.
+    fn walk(&self, epoch_step: u64) -> Result<Option<Walk>, Error> {
+        let mut current_node = match self.maybe_root_index()? {
+            Some(root_index) => root_index,
+            None => return Ok(None),
+        };
+        let mut steps: Vec<(u64, u64)> = vec![];
 
         for feature_space_layer in 0..self.dimension()? {
             let keys_greater_than_zero: Vec<u64> = self
                 .feature_node_value_items_at_index
                 .filter_keys_by_prefix(&current_node, |count| count > 0)?;
 
-            let mut maybe_next_key: Option<&u64> = None;
-            let mut lowest_epoch_step: u64 = next_epoch_step;
+            let mut maybe_next_key: Option<u64> = None;
+            let mut lowest_epoch_step: u64 = epoch_step;
 
             for key in keys_greater_than_zero.iter() {
                 let value_last_used_epoch_step = self.feature_value_to_epoch_step.get(key)?;
 
                 if value_last_used_epoch_step < lowest_epoch_step {
-                    maybe_next_key = Some(key);
+                    maybe_next_key = Some(*key);
                     lowest_epoch_step = value_last_used_epoch_step;
                 }
             }
 
             match maybe_next_key {
-                Some(next_key) if self.feature_node_has_leaves.get(&current_node)? => {
-                    self.feature_value_to_epoch_step.put(next_key, next_epoch_step)?;
-                    self.feature_node_value_items_at_index.update(&current_node, &next_key, |count| count-1)?;
-                    leaf_index = Some(self.feature_node_value_child_index.get(&current_node, &next_key)?);
-                    //FIXME: When implementing concurrent access we need to check
-                    //that this incremented epoch step matches the one we are using
-                    //during this use action.
-                    self.increment_epoch_step()?;
-                },
                 Some(next_key) => {
-                    self.feature_value_to_epoch_step.put(next_key, next_epoch_step)?;
-                    self.feature_node_value_items_at_index.update(&current_node, &next_key, |count| count-1)?;
-                    current_node = self.feature_node_value_child_index.get(&current_node, &next_key)?;
+                    let child_index = self.feature_node_value_child_index.get(&current_node, &next_key)?;
+                    steps.push((current_node, next_key));
+
+                    if self.feature_node_has_leaves.get(&current_node)? {
+                        return Ok(Some(Walk { steps, leaf_index: child_index }));
+                    }
+
+                    current_node = child_index;
                 },
-                None if feature_space_layer != 0 => panic!(
-                    "Feature space has lost track of number of values for each feature. Found node that should contain values but contains none {:?}",
-                    current_node
-                ),
-                None => break,
+                None if feature_space_layer != 0 => {
+                    return Err(Error::corruption(format!(
+                        "Feature space has lost track of number of values for each feature. Found node that should contain values but contains none {:?}",
+                        current_node
+                    )))
+                }
+                None => return Ok(None),
             }
         }
 
-        Ok(leaf_index)
+        Ok(None)
     }
 
-    pub fn add_item(
+    fn add_item(
         &mut self,
         feature_values: Vec<FeatureValue>,
         leaf_index: u64,
-    ) -> Result<(), Error> {
+    ) -> Result<u64, Error> {
         let mut child_index = leaf_index;
-        let mut height = 1;
         let currently_empty = self.epoch_step()? == 0;
 
+        self.feature_leaf_values
+            .put_if_absent(&leaf_index, feature_values.clone())?;
+
         let all_feature_values_natural_order = feature_values.clone();
 
         let mut current_node_index;
@@ -296,7 +681,8 @@ impl FeatureSpace {
         let mut reversed_feature_values = feature_values.clone();
         reversed_feature_values.reverse();
 
-        for feature_value in reversed_feature_values.iter() {
+        for (index, feature_value) in reversed_feature_values.iter().enumerate() {
+            let height = index + 1;
             let mut next_feature_values = all_feature_values_natural_order.clone();
             next_feature_values.truncate(height);
 
@@ -359,11 +745,9 @@ impl FeatureSpace {
             }
 
             child_index = current_node_index;
-            height += 1;
         }
 
-        self.increment_epoch_step()?;
-
-        Ok(())
+        self.metadata
+            .update(&EPOCH_STEP_KEY, |epoch_step| epoch_step + 1)
     }
 }
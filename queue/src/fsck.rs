@@ -0,0 +1,319 @@
+use crate::database::{self, Column, Database, Entry};
+use crate::error::Error;
+use crate::feature_space::{DIMENSION_KEY, ROOT_INDEX_KEY, TOTAL_ITEMS_KEY};
+use crate::prefix_storage::create_composite_key;
+use crate::storage::{
+    expect_key_length, BOOL_FROM_BYTES, BOOL_TO_BYTES, INTEGER_FROM_BYTES, INTEGER_TO_BYTES,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// An invariant a durable queue breaks
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub description: String,
+    /// Whether rebuilding the counts from the items fixes it
+    pub repairable: bool,
+}
+
+/// Everything found wrong with a durable queue
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Whether every problem found can be fixed by a repair
+    pub fn is_repairable(&self) -> bool {
+        self.problems.iter().all(|problem| problem.repairable)
+    }
+}
+
+/// A write that brings a stored value in line with the items. A `None`
+/// value deletes the key.
+struct Fix {
+    column: &'static str,
+    key: Vec<u8>,
+    maybe_value: Option<Vec<u8>>,
+}
+
+/// The columns of the feature space decoded alongside the items held under
+/// each leaf
+struct Graph {
+    metadata: BTreeMap<u64, u64>,
+    has_leaves: BTreeMap<u64, bool>,
+    items_at_index: BTreeMap<(u64, u64), u64>,
+    children: BTreeMap<u64, Vec<(u64, u64)>>,
+    leaf_items: BTreeMap<u64, u64>,
+}
+
+fn read_column(database: &Arc<Database>, name: &'static str) -> Result<Vec<Entry>, Error> {
+    Column::new(database.clone(), name).entries_with_prefix(&[])
+}
+
+fn integers(entries: Vec<Entry>) -> Result<BTreeMap<u64, u64>, Error> {
+    entries
+        .into_iter()
+        .map(|(key, value)| Ok((INTEGER_FROM_BYTES(key)?, INTEGER_FROM_BYTES(value)?)))
+        .collect()
+}
+
+fn composite_integers(entries: Vec<Entry>) -> Result<BTreeMap<(u64, u64), u64>, Error> {
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            expect_key_length(&key, 16)?;
+
+            Ok((
+                (
+                    INTEGER_FROM_BYTES(key[..8].to_vec())?,
+                    INTEGER_FROM_BYTES(key[8..].to_vec())?,
+                ),
+                INTEGER_FROM_BYTES(value)?,
+            ))
+        })
+        .collect()
+}
+
+impl Graph {
+    fn read(database: &Arc<Database>) -> Result<Graph, Error> {
+        let mut has_leaves = BTreeMap::new();
+
+        for (key, value) in read_column(database, database::NODE_HAS_LEAVES)? {
+            has_leaves.insert(INTEGER_FROM_BYTES(key)?, BOOL_FROM_BYTES(value)?);
+        }
+
+        let mut children: BTreeMap<u64, Vec<(u64, u64)>> = BTreeMap::new();
+
+        for ((node, value), child) in
+            composite_integers(read_column(database, database::NODE_VALUE_CHILD_INDEX)?)?
+        {
+            children.entry(node).or_default().push((value, child));
+        }
+
+        let mut leaf_items: BTreeMap<u64, u64> = BTreeMap::new();
+
+        for (key, _) in read_column(database, database::ITEMS)? {
+            expect_key_length(&key, 16)?;
+
+            *leaf_items
+                .entry(INTEGER_FROM_BYTES(key[..8].to_vec())?)
+                .or_default() += 1;
+        }
+
+        Ok(Graph {
+            metadata: integers(read_column(database, database::METADATA)?)?,
+            has_leaves,
+            items_at_index: composite_integers(read_column(
+                database,
+                database::NODE_VALUE_ITEMS_AT_INDEX,
+            )?)?,
+            children,
+            leaf_items,
+        })
+    }
+
+    fn metadata(&self, key: u64, name: &str) -> Result<u64, Error> {
+        self.metadata
+            .get(&key)
+            .copied()
+            .ok_or_else(|| Error::corruption(format!("Metadata is missing the {}", name)))
+    }
+}
+
+/// Works out what a queue's counts should be from the items it holds and
+/// the writes that would make them so
+struct Checker<'a> {
+    graph: &'a Graph,
+    dimension: u64,
+    problems: Vec<Problem>,
+    fixes: Vec<Fix>,
+    reached_leaves: BTreeSet<u64>,
+    reached_counts: BTreeSet<(u64, u64)>,
+}
+
+impl<'a> Checker<'a> {
+    fn problem(&mut self, description: String, maybe_fix: Option<Fix>) {
+        self.problems.push(Problem {
+            description,
+            repairable: maybe_fix.is_some(),
+        });
+        self.fixes.extend(maybe_fix);
+    }
+
+    /// The items under the node at the layer, checking the count kept for
+    /// each of its values along the way. The nodes of the last layer lead to
+    /// leaves rather than nodes.
+    fn check_node(&mut self, node: u64, layer: u64) -> Result<u64, Error> {
+        if layer >= self.dimension {
+            return Err(Error::corruption(format!(
+                "Node {:?} is deeper than the {} features of the queue",
+                node, self.dimension
+            )));
+        }
+
+        let graph = self.graph;
+        let leads_to_leaves = layer + 1 == self.dimension;
+
+        if graph.has_leaves.get(&node) != Some(&leads_to_leaves) {
+            self.problem(
+                format!(
+                    "Node {:?} at layer {} is recorded as {:?} leading to leaves",
+                    node,
+                    layer,
+                    graph.has_leaves.get(&node)
+                ),
+                Some(Fix {
+                    column: database::NODE_HAS_LEAVES,
+                    key: node.to_be_bytes().to_vec(),
+                    maybe_value: Some(BOOL_TO_BYTES(leads_to_leaves)?),
+                }),
+            );
+        }
+
+        let mut items = 0;
+
+        for (value, child) in graph.children.get(&node).into_iter().flatten().copied() {
+            let expected = if leads_to_leaves {
+                self.reached_leaves.insert(child);
+                graph.leaf_items.get(&child).copied().unwrap_or(0)
+            } else {
+                self.check_node(child, layer + 1)?
+            };
+
+            self.reached_counts.insert((node, value));
+            let maybe_count = graph.items_at_index.get(&(node, value)).copied();
+
+            if maybe_count != Some(expected) {
+                self.problem(
+                    format!(
+                        "Node {:?} counts {:?} items under value {:?} but holds {}",
+                        node, maybe_count, value, expected
+                    ),
+                    Some(Fix {
+                        column: database::NODE_VALUE_ITEMS_AT_INDEX,
+                        key: create_composite_key(&node, &value).to_vec(),
+                        maybe_value: Some(INTEGER_TO_BYTES(expected)?),
+                    }),
+                );
+            }
+
+            items += expected;
+        }
+
+        Ok(items)
+    }
+
+    fn check(mut self) -> Result<(Report, Vec<Fix>), Error> {
+        let graph = self.graph;
+        let total_items = match graph.metadata.get(&ROOT_INDEX_KEY) {
+            Some(root_index) => self.check_node(*root_index, 0)?,
+            None => 0,
+        };
+
+        let stored_total_items = graph.metadata(TOTAL_ITEMS_KEY, "total items")?;
+
+        if stored_total_items != total_items {
+            self.problem(
+                format!(
+                    "Metadata counts {} items but the root holds {}",
+                    stored_total_items, total_items
+                ),
+                Some(Fix {
+                    column: database::METADATA,
+                    key: TOTAL_ITEMS_KEY.to_be_bytes().to_vec(),
+                    maybe_value: Some(INTEGER_TO_BYTES(total_items)?),
+                }),
+            );
+        }
+
+        // A count without a child would send a dequeue to a value it cannot follow
+        for ((node, value), count) in graph.items_at_index.iter() {
+            if !self.reached_counts.contains(&(*node, *value)) {
+                self.problem(
+                    format!(
+                        "Node {:?} counts {} items under value {:?} that leads nowhere",
+                        node, count, value
+                    ),
+                    Some(Fix {
+                        column: database::NODE_VALUE_ITEMS_AT_INDEX,
+                        key: create_composite_key(node, value).to_vec(),
+                        maybe_value: None,
+                    }),
+                );
+            }
+        }
+
+        for (leaf, items) in graph.leaf_items.iter() {
+            if !self.reached_leaves.contains(leaf) {
+                self.problem(
+                    format!(
+                        "{} items are held under leaf {:?} that no node leads to",
+                        items, leaf
+                    ),
+                    None,
+                );
+            }
+        }
+
+        Ok((
+            Report {
+                problems: self.problems,
+            },
+            self.fixes,
+        ))
+    }
+}
+
+fn check_database(database: &Arc<Database>) -> Result<(Report, Vec<Fix>), Error> {
+    let graph = Graph::read(database)?;
+
+    Checker {
+        dimension: graph.metadata(DIMENSION_KEY, "dimension")?,
+        graph: &graph,
+        problems: vec![],
+        fixes: vec![],
+        reached_leaves: BTreeSet::new(),
+        reached_counts: BTreeSet::new(),
+    }
+    .check()
+}
+
+/// Checks that the counts of the durable queue at the folder path agree with
+/// the items it holds without writing to it.
+///
+/// Each node's count for a value must equal the items under that value's
+/// subtree, every leaf must hold as many items as its parent counts and the
+/// total items in the metadata must equal the root's counts summed.
+pub fn check(folder_path: String) -> Result<Report, Error> {
+    let database = Database::open_read_only(folder_path)?;
+
+    Ok(check_database(&database)?.0)
+}
+
+/// Rebuilds the counts of the durable queue at the folder path from the
+/// items it holds in one write batch, returning what was wrong beforehand.
+/// Items under leaves that no node leads to are reported but left as they
+/// are. Fails while the queue is open elsewhere.
+pub fn repair(folder_path: String) -> Result<Report, Error> {
+    let database = Database::open(folder_path)?;
+    let (report, fixes) = check_database(&database)?;
+
+    for fix in fixes {
+        let column = Column::new(database.clone(), fix.column);
+
+        match fix.maybe_value {
+            Some(value) => column.put(&fix.key, value),
+            None => column.delete(&fix.key),
+        }
+    }
+
+    database.commit()?;
+    database.flush()?;
+
+    Ok(report)
+}
@@ -0,0 +1,57 @@
+use crate::error::undecodable;
+use crate::feature_space::FeatureValue;
+use crate::storage::{DeserializeFn, SerializeFn};
+use serde::{Deserialize, Serialize};
+
+pub const ITEM_TO_BYTES: DeserializeFn<DequeuedItem> = |item| Ok(bincode::serialize(&item)?);
+pub const ITEM_FROM_BYTES: SerializeFn<DequeuedItem> =
+    |bytes| bincode::deserialize(&bytes).map_err(undecodable);
+
+/// An item as it was enqueued. The feature values, epoch and time it was
+/// enqueued at are stored alongside the data so that consumers can see them
+/// without packing them into the data themselves.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DequeuedItem {
+    data: Vec<u8>,
+    features: Vec<FeatureValue>,
+    epoch: u64,
+    enqueued_at: u64,
+}
+
+impl DequeuedItem {
+    pub fn new(
+        data: Vec<u8>,
+        features: Vec<FeatureValue>,
+        epoch: u64,
+        enqueued_at: u64,
+    ) -> DequeuedItem {
+        DequeuedItem {
+            data,
+            features,
+            epoch,
+            enqueued_at,
+        }
+    }
+
+    pub fn get_data(&self) -> &Vec<u8> {
+        &self.data
+    }
+
+    pub fn get_features(&self) -> &Vec<FeatureValue> {
+        &self.features
+    }
+
+    /// The epoch the item was enqueued at which orders it within its leaf
+    pub fn get_epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Milliseconds since the unix epoch when the item was enqueued
+    pub fn get_enqueued_at(&self) -> u64 {
+        self.enqueued_at
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
@@ -0,0 +1,222 @@
+use crate::database::{
+    self, open_db, Entry, ITEMS, METADATA, NODE_HAS_LEAVES, NODE_VALUE_CHILD_INDEX,
+    NODE_VALUE_ITEMS_AT_INDEX, VALUE_TO_EPOCH,
+};
+use crate::error::Error;
+use crate::item::{DequeuedItem, ITEM_TO_BYTES};
+use crate::prefix_storage::create_composite_key;
+use crate::storage::{expect_key_length, INTEGER_FROM_BYTES};
+use rocksdb::{ColumnFamily, IteratorMode, Options, WriteBatch, DB};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// The layouts durable queues have been stored in:
+///
+/// 0. A RocksDB instance per feature space store in a subdirectory named
+///    after it. The items sit in the folder itself with a column family per
+///    leaf holding the raw data of each item.
+/// 1. One RocksDB instance with a column family per store. Items and leases
+///    hold a `DequeuedItem` with the features, epoch and enqueue time
+///    alongside the data.
+///
+/// Queues in layout 1 record their version in the default column family,
+/// which holds nothing else, so that it is never exported.
+pub const LAYOUT_VERSION: u64 = 1;
+
+const LAYOUT_VERSION_KEY: &[u8] = b"layout_version";
+
+/// Every store that layout 0 kept in a subdirectory
+const STORES: [&str; 5] = [
+    METADATA,
+    NODE_HAS_LEAVES,
+    NODE_VALUE_ITEMS_AT_INDEX,
+    NODE_VALUE_CHILD_INDEX,
+    VALUE_TO_EPOCH,
+];
+
+fn storage(e: rocksdb::Error) -> Error {
+    Error::Storage {
+        message: e.to_string(),
+    }
+}
+
+fn unsupported(folder_path: &str, message: String) -> Error {
+    Error::UnsupportedLayout {
+        folder_path: folder_path.to_string(),
+        message,
+    }
+}
+
+fn stored_version(db: &DB) -> Result<Option<u64>, Error> {
+    match db.get(LAYOUT_VERSION_KEY).map_err(storage)? {
+        Some(bytes) => {
+            expect_key_length(&bytes, 8)?;
+
+            Ok(Some(INTEGER_FROM_BYTES(bytes)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Records that a database is stored in the current layout
+pub fn stamp(db: &DB) -> Result<(), Error> {
+    db.put(LAYOUT_VERSION_KEY, LAYOUT_VERSION.to_be_bytes())
+        .map_err(storage)
+}
+
+/// Fails unless the database is stored in the current layout
+pub fn check(db: &DB, folder_path: &str) -> Result<(), Error> {
+    match stored_version(db)? {
+        Some(LAYOUT_VERSION) => Ok(()),
+        Some(version) if version > LAYOUT_VERSION => Err(unsupported(
+            folder_path,
+            format!(
+                "it is stored in layout {} which is newer than layout {} this version reads",
+                version, LAYOUT_VERSION
+            ),
+        )),
+        maybe_version => Err(unsupported(
+            folder_path,
+            format!(
+                "it is stored in layout {} and must be upgraded by opening it as a queue",
+                maybe_version.unwrap_or(0)
+            ),
+        )),
+    }
+}
+
+/// Brings a queue stored in layout 0 up to the current one in place. The
+/// move is committed in a single write batch so a crash part way leaves the
+/// queue in layout 0 for the next upgrade to start over from. Items moved
+/// from layout 0 have no features and an enqueue time of zero as neither was
+/// stored. Folders that hold no database or are already current are left as
+/// they are.
+pub fn upgrade(folder_path: &str) -> Result<(), Error> {
+    let folder = Path::new(folder_path);
+
+    if !folder.join("CURRENT").exists() {
+        return Ok(());
+    }
+
+    let maybe_version = {
+        let db =
+            DB::open_for_read_only(&Options::default(), folder_path, false).map_err(storage)?;
+
+        stored_version(&db)?
+    };
+    let has_stores = folder.join(METADATA).join("CURRENT").exists();
+
+    match maybe_version {
+        Some(_) if has_stores => remove_stores(folder),
+        Some(_) => Ok(()),
+        None if has_stores => upgrade_stores(folder_path),
+        // Created in the current layout but closed before its version was
+        // recorded, so any column family it is missing is empty
+        None => stamp(&open_db(folder_path, &[], true)?),
+    }
+}
+
+fn column<'a>(db: &'a DB, name: &str) -> Result<&'a ColumnFamily, Error> {
+    db.cf_handle(name)
+        .ok_or_else(|| Error::corruption(format!("Missing column family {:?}", name)))
+}
+
+fn entries(db: &DB, name: &str) -> Result<Vec<Entry>, Error> {
+    let cf_handle = column(db, name)?;
+
+    Ok(db
+        .iterator_cf(cf_handle, IteratorMode::Start)
+        .map(|(key, value)| (key.to_vec(), value.to_vec()))
+        .collect())
+}
+
+/// The entries of a layout 0 store. A store the queue never wrote to is
+/// missing and has none.
+fn store_entries(folder: &Path, name: &str) -> Result<Vec<Entry>, Error> {
+    let store = folder.join(name);
+
+    if !store.join("CURRENT").exists() {
+        return Ok(vec![]);
+    }
+
+    let db = DB::open_for_read_only(&Options::default(), store, false).map_err(storage)?;
+
+    Ok(db
+        .iterator(IteratorMode::Start)
+        .map(|(key, value)| (key.to_vec(), value.to_vec()))
+        .collect())
+}
+
+/// Layout 0 to 1. The stores are copied into column families of the database
+/// the items were kept in and each leaf's column family is moved into the
+/// items column family.
+fn upgrade_stores(folder_path: &str) -> Result<(), Error> {
+    let folder = Path::new(folder_path);
+
+    let stores = STORES
+        .iter()
+        .map(|name| Ok((*name, store_entries(folder, name)?)))
+        .collect::<Result<HashMap<&str, Vec<Entry>>, Error>>()?;
+
+    let shards: Vec<String> = DB::list_cf(&Options::default(), folder_path)
+        .map_err(storage)?
+        .into_iter()
+        .filter(|name| name != "default" && !database::is_column_family(name))
+        .collect();
+    let mut db = open_db(folder_path, &shards, true)?;
+
+    let mut batch = WriteBatch::default();
+
+    for (name, entries) in stores {
+        let cf_handle = column(&db, name)?;
+
+        for (key, value) in entries {
+            batch.put_cf(cf_handle, key, value);
+        }
+    }
+
+    let items_handle = column(&db, ITEMS)?;
+
+    for shard in shards.iter() {
+        let leaf = shard
+            .parse::<u64>()
+            .map_err(|_| Error::corruption(format!("Column family {:?} is not a leaf", shard)))?;
+
+        for (key, data) in entries(&db, shard)? {
+            expect_key_length(&key, 8)?;
+            let epoch = INTEGER_FROM_BYTES(key)?;
+
+            batch.put_cf(
+                items_handle,
+                create_composite_key(&leaf, &epoch),
+                ITEM_TO_BYTES(DequeuedItem::new(data, vec![], epoch, 0))?,
+            );
+        }
+    }
+
+    db.write(batch).map_err(storage)?;
+
+    // Until the version is stamped an upgrade starts over from the stores so
+    // the shards are only dropped once their items are in place
+    for shard in shards.iter() {
+        db.drop_cf(shard).map_err(storage)?;
+    }
+
+    stamp(&db)?;
+    drop(db);
+
+    remove_stores(folder)
+}
+
+fn remove_stores(folder: &Path) -> Result<(), Error> {
+    for name in STORES.iter() {
+        let store = folder.join(name);
+
+        if store.exists() {
+            fs::remove_dir_all(store)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use crate::error::undecodable;
+use crate::feature_space::FeatureValue;
+use crate::item::DequeuedItem;
+use crate::storage::{DeserializeFn, SerializeFn};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const LEASE_TO_BYTES: DeserializeFn<Lease> = |lease| Ok(bincode::serialize(&lease)?);
+pub const LEASE_FROM_BYTES: SerializeFn<Lease> =
+    |bytes| bincode::deserialize(&bytes).map_err(undecodable);
+
+thread_local! {
+    static FIXED_NOW: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Milliseconds since the unix epoch. Lease deadlines are stored in this form
+/// so that they survive a restart of a durable queue.
+pub fn now_millis() -> u64 {
+    if let Some(now) = FIXED_NOW.with(|fixed_now| fixed_now.get()) {
+        return now;
+    }
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Runs the operation on this thread as if the time were fixed at now.
+/// Replicas apply the same operation at different times so they use the time
+/// it was proposed at to agree on lease deadlines and which leases expired.
+pub fn at_time<T>(now: u64, operation: impl FnOnce() -> T) -> T {
+    let previous = FIXED_NOW.with(|fixed_now| fixed_now.replace(Some(now)));
+    let result = operation();
+    FIXED_NOW.with(|fixed_now| fixed_now.set(previous));
+
+    result
+}
+
+pub fn deadline_from_now(lease_duration: Duration) -> u64 {
+    now_millis() + lease_duration.as_millis() as u64
+}
+
+/// An item that has been handed out to a consumer but not yet acknowledged.
+/// The item is hidden from the queue until it is acked, nacked or the
+/// deadline passes, at which point it is returned to the shard it came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    id: u64,
+    item: DequeuedItem,
+    leaf: u64,
+    deadline: u64,
+}
+
+impl Lease {
+    pub fn new(id: u64, item: DequeuedItem, leaf: u64, deadline: u64) -> Lease {
+        Lease {
+            id,
+            item,
+            leaf,
+            deadline,
+        }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_item(&self) -> &DequeuedItem {
+        &self.item
+    }
+
+    pub fn get_item_epoch(&self) -> u64 {
+        self.item.get_epoch()
+    }
+
+    pub fn get_leaf(&self) -> u64 {
+        self.leaf
+    }
+
+    pub fn get_features(&self) -> &Vec<FeatureValue> {
+        self.item.get_features()
+    }
+
+    pub fn get_deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.deadline <= now
+    }
+
+    pub fn with_deadline(self, deadline: u64) -> Lease {
+        Lease { deadline, ..self }
+    }
+}
@@ -1,91 +1,415 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path};
 use std::result::Result;
 use std::result::Result::{Err, Ok};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+pub mod database;
+use database::{column, Database, Entry};
 pub mod feature_space;
+pub mod fsck;
 use feature_space::{create_hash, FeatureSpace, FeatureValue};
 pub mod sharded_heap;
 use sharded_heap::ShardedHeap;
 pub mod error;
+pub mod item;
+use item::DequeuedItem;
+pub mod layout;
+pub mod lease;
+use lease::{deadline_from_now, now_millis, Lease, LEASE_FROM_BYTES, LEASE_TO_BYTES};
 pub mod prefix_storage;
+pub mod snapshot;
+use snapshot::QueueSnapshot;
 pub mod storage;
 use error::Error;
+use storage::{Storage, StorageType};
 
-#[allow(dead_code)]
-pub struct SortingPriorityQueue {
+/// Fails unless the folder path names a queue's own folder
+fn check_removable(folder_path: &str) -> Result<(), Error> {
+    let path = Path::new(folder_path);
+    let is_named = match path.components().next_back() {
+        Some(Component::Normal(name)) => !name.to_string_lossy().starts_with('.'),
+        _ => false,
+    };
+
+    if !is_named {
+        return Err(Error::new(format!(
+            "Refusing to remove {:?} as it is not a queue folder",
+            folder_path
+        )));
+    }
+
+    if !path.join("CURRENT").exists() {
+        return Err(Error::NotInitialised {
+            folder_path: folder_path.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Every lease's deadline and id ordered so that the leases to expire first
+/// come first. It is rebuilt from the stored leases when a queue is loaded.
+type LeaseDeadlines = BTreeSet<(u64, u64)>;
+
+/// Everything a queue stores. The feature space is safe to share on its own
+/// but the items, the leases and the writes a transaction stages are not so
+/// only one thread may use the state at a time.
+struct QueueState {
     feature_space: FeatureSpace,
     items: ShardedHeap,
+    leases: Storage<Lease>,
+    lease_deadlines: LeaseDeadlines,
+    maybe_database: Option<Arc<Database>>,
 }
 
-#[allow(dead_code)]
-impl SortingPriorityQueue {
-    pub fn new(features: Vec<String>) -> Result<SortingPriorityQueue, Error> {
-        Ok(SortingPriorityQueue {
+impl QueueState {
+    fn new(features: Vec<String>) -> Result<QueueState, Error> {
+        let queue = QueueState {
             feature_space: FeatureSpace::new(features, None)?,
             items: ShardedHeap::new(None)?,
-        })
+            leases: Storage::new(None, LEASE_TO_BYTES, LEASE_FROM_BYTES)?,
+            lease_deadlines: LeaseDeadlines::new(),
+            maybe_database: None,
+        };
+
+        queue.commit()?;
+
+        Ok(queue)
     }
 
-    pub fn new_durable(
-        features: Vec<String>,
-        folder_path: String,
-    ) -> Result<SortingPriorityQueue, Error> {
-        Ok(SortingPriorityQueue {
-            feature_space: FeatureSpace::new(features, Some(folder_path.clone()))?,
-            items: ShardedHeap::new(Some(folder_path))?,
+    fn new_durable(features: Vec<String>, folder_path: String) -> Result<QueueState, Error> {
+        layout::upgrade(&folder_path)?;
+        let maybe_database = Some(Database::create(folder_path.clone())?);
+
+        let feature_space = FeatureSpace::new(features, maybe_database.clone())?;
+
+        QueueState::from_feature_space(feature_space, maybe_database)
+    }
+
+    fn open_durable(folder_path: String) -> Result<QueueState, Error> {
+        layout::upgrade(&folder_path)?;
+        let database = Database::open(folder_path.clone())?;
+
+        let feature_space = FeatureSpace::open(database.clone(), &folder_path)?;
+
+        QueueState::from_feature_space(feature_space, Some(database))
+    }
+
+    fn from_feature_space(
+        feature_space: FeatureSpace,
+        maybe_database: Option<Arc<Database>>,
+    ) -> Result<QueueState, Error> {
+        let mut queue = QueueState {
+            feature_space,
+            items: ShardedHeap::new(column(&maybe_database, database::ITEMS))?,
+            leases: Storage::new(
+                column(&maybe_database, database::LEASES),
+                LEASE_TO_BYTES,
+                LEASE_FROM_BYTES,
+            )?,
+            lease_deadlines: LeaseDeadlines::new(),
+            maybe_database,
+        };
+
+        queue.index_leases()?;
+
+        // Creating a feature space stages its metadata
+        queue.commit()?;
+
+        Ok(queue)
+    }
+
+    /// Rebuilds the state a snapshot was exported from. A durable snapshot is
+    /// written to a new database at the folder path.
+    fn import(snapshot: QueueSnapshot, folder_path: String) -> Result<QueueState, Error> {
+        let maybe_database = match snapshot.storage_type {
+            StorageType::Memory => None,
+            StorageType::Durable => Some(Database::create(folder_path)?),
+        };
+
+        let mut columns = snapshot.columns;
+
+        let feature_space = FeatureSpace::import(&mut columns, maybe_database.clone())?;
+
+        let mut queue = QueueState {
+            feature_space,
+            items: ShardedHeap::new(column(&maybe_database, database::ITEMS))?,
+            leases: Storage::new(
+                column(&maybe_database, database::LEASES),
+                LEASE_TO_BYTES,
+                LEASE_FROM_BYTES,
+            )?,
+            lease_deadlines: LeaseDeadlines::new(),
+            maybe_database,
+        };
+
+        queue
+            .items
+            .import(columns.remove(database::ITEMS).unwrap_or_default())?;
+        queue
+            .leases
+            .import(columns.remove(database::LEASES).unwrap_or_default())?;
+        queue.index_leases()?;
+
+        if let Some(name) = columns.keys().next() {
+            return Err(Error::corruption(format!(
+                "Snapshot has a column {:?} that queues do not store",
+                name
+            )));
+        }
+
+        queue.commit()?;
+
+        Ok(queue)
+    }
+
+    fn export(&self) -> Result<QueueSnapshot, Error> {
+        let mut columns: BTreeMap<String, Vec<Entry>> = self
+            .feature_space
+            .export()?
+            .into_iter()
+            .map(|(name, entries)| (name.to_string(), entries))
+            .collect();
+
+        columns.insert(database::ITEMS.to_string(), self.items.export()?);
+        columns.insert(database::LEASES.to_string(), self.leases.export()?);
+
+        Ok(QueueSnapshot {
+            storage_type: self.storage_type(),
+            columns,
         })
     }
 
-    pub fn enqueue(&mut self, data: Vec<u8>, features: Vec<FeatureValue>) -> Result<u64, Error> {
-        if features.len() as u64 != self.feature_space.dimension()? {
-            Err(Error::new(
-                "Invalid feature vector must have same size as feature space".to_string(),
-            ))
-        } else {
-            let feature_names: Vec<&String> =
-                features.iter().map(|feature| feature.get_name()).collect();
-            let feature_names_hash = create_hash(&feature_names);
-
-            if feature_names_hash != self.feature_space.feature_names_hash()? {
-                return Err(Error::new(
-                    "Invalid feature vector must have same feature names as initialization"
-                        .to_string(),
-                ));
+    fn flush(&self) -> Result<(), Error> {
+        match self.maybe_database {
+            Some(ref database) => database.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn storage_failure(&self) -> Option<String> {
+        self.maybe_database
+            .as_ref()
+            .and_then(|database| database.failure())
+    }
+
+    /// Commits the staged writes along with the index of the log entry this
+    /// thread is applying, if any
+    fn commit(&self) -> Result<(), Error> {
+        if let Some(index) = APPLYING.with(|applying| applying.get()) {
+            self.feature_space.set_applied_index(index)?;
+        }
+
+        match self.maybe_database {
+            Some(ref database) => database.commit(),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs an operation committing every write it staged in one batch when it
+    /// succeeds and discarding them all when it fails. A failed operation
+    /// still commits the index of the log entry being applied. The lease
+    /// deadlines are rebuilt from the leases left stored when either fails.
+    fn transaction<T>(
+        &mut self,
+        operation: impl FnOnce(&mut QueueState) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let result = operation(self);
+
+        if result.is_err() {
+            if let Some(ref database) = self.maybe_database {
+                database.discard();
             }
+        }
 
-            let hash = create_hash(&features);
+        let committed = self.commit();
+
+        if self.maybe_database.is_some() && (result.is_err() || committed.is_err()) {
+            self.index_leases()?;
+        }
 
-            self.feature_space.add_item(features, hash)?;
+        committed?;
 
-            let current_epoch_step = self.feature_space.epoch_step()?;
+        result
+    }
+
+    fn index_leases(&mut self) -> Result<(), Error> {
+        self.lease_deadlines = self
+            .leases
+            .get_all()?
+            .into_iter()
+            .map(|(lease_id, lease)| (lease.get_deadline(), lease_id))
+            .collect();
+
+        Ok(())
+    }
 
-            self.items.push(current_epoch_step, hash, data)?;
-            self.feature_space.increment_total_items()?;
+    fn put_lease(&mut self, lease: &Lease) -> Result<(), Error> {
+        self.leases.put(&lease.get_id(), lease.clone())?;
+        self.lease_deadlines
+            .insert((lease.get_deadline(), lease.get_id()));
 
-            Ok(current_epoch_step)
+        Ok(())
+    }
+
+    fn delete_lease(&mut self, lease: &Lease) -> Result<(), Error> {
+        self.leases.delete(&lease.get_id())?;
+        self.lease_deadlines
+            .remove(&(lease.get_deadline(), lease.get_id()));
+
+        Ok(())
+    }
+
+    /// The lease that expires first if it has expired by now
+    fn next_expired_lease(&self, now: u64) -> Result<Option<Lease>, Error> {
+        match self.lease_deadlines.iter().next() {
+            Some((deadline, lease_id)) if *deadline <= now => match self.leases.get(lease_id) {
+                Ok(lease) => Ok(Some(lease)),
+                Err(Error::Empty { .. }) => Err(Error::corruption(format!(
+                    "Lease {:?} has a deadline but is not stored",
+                    lease_id
+                ))),
+                Err(e) => Err(e),
+            },
+            _ => Ok(None),
         }
     }
 
-    pub fn size(&self) -> Result<u64, Error> {
+    fn storage_type(&self) -> StorageType {
+        self.leases.storage_type()
+    }
+
+    fn feature_names(&self) -> Result<Vec<String>, Error> {
+        self.feature_space.feature_names()
+    }
+
+    fn has_feature_names(&self, feature_names: &[String]) -> Result<bool, Error> {
+        self.feature_space.has_feature_names(feature_names)
+    }
+
+    fn dimension(&self) -> Result<u64, Error> {
+        self.feature_space.dimension()
+    }
+
+    fn root_index(&self) -> Result<Option<u64>, Error> {
+        match self.feature_space.root_index() {
+            Ok(root_index) => Ok(Some(root_index)),
+            Err(Error::Empty { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn feature_value_counts(&self) -> Result<Vec<(FeatureValue, u64)>, Error> {
+        self.feature_space.feature_value_counts()
+    }
+
+    fn enqueue(&mut self, data: Vec<u8>, features: Vec<FeatureValue>) -> Result<u64, Error> {
+        self.validate_features(&features)?;
+
+        self.transaction(|queue| queue._enqueue(data, features))
+    }
+
+    fn enqueue_batch(
+        &mut self,
+        items: Vec<(Vec<u8>, Vec<FeatureValue>)>,
+    ) -> Result<Vec<u64>, Error> {
+        for (_, features) in items.iter() {
+            self.validate_features(features)?;
+        }
+
+        self.transaction(|queue| {
+            items
+                .into_iter()
+                .map(|(data, features)| queue._enqueue(data, features))
+                .collect()
+        })
+    }
+
+    fn validate_features(&self, features: &[FeatureValue]) -> Result<(), Error> {
+        let dimension = self.feature_space.dimension()?;
+
+        if features.len() as u64 != dimension {
+            return Err(Error::InvalidDimension {
+                expected: dimension,
+                actual: features.len() as u64,
+            });
+        }
+
+        let feature_names: Vec<&String> =
+            features.iter().map(|feature| feature.get_name()).collect();
+        let feature_names_hash = create_hash(&feature_names);
+
+        if feature_names_hash != self.feature_space.feature_names_hash()? {
+            return Err(Error::UnknownFeatureNames {
+                expected: self.feature_space.feature_names()?,
+                actual: feature_names.into_iter().cloned().collect(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Adds an item whose features have already been validated
+    fn _enqueue(&mut self, data: Vec<u8>, features: Vec<FeatureValue>) -> Result<u64, Error> {
+        self.feature_space.record_feature_names(&features)?;
+
+        let hash = create_hash(&features);
+
+        let current_epoch_step = self.feature_space.add_item(features.clone(), hash)?;
+
+        self.items.push(
+            hash,
+            DequeuedItem::new(data, features, current_epoch_step, now_millis()),
+        )?;
+        self.feature_space.increment_total_items()?;
+
+        Ok(current_epoch_step)
+    }
+
+    fn size(&self) -> Result<u64, Error> {
         self.feature_space.total_items()
     }
 
-    pub fn peek(&self) -> Result<Option<Vec<u8>>, Error> {
+    fn can_dequeue(&self) -> Result<bool, Error> {
+        if self.size()? > 0 {
+            return Ok(true);
+        }
+
+        Ok(matches!(
+            self.lease_deadlines.iter().next(),
+            Some((deadline, _)) if *deadline <= now_millis()
+        ))
+    }
+
+    fn peek(&self) -> Result<Option<DequeuedItem>, Error> {
         let maybe_next_leaf_feature = self.feature_space.peek_next_leaf_feature()?;
 
         let mut maybe_item = None;
 
         if let Some(next_leaf_feature) = maybe_next_leaf_feature {
-            maybe_item = self.items.peek(next_leaf_feature)?;
+            maybe_item = Some(
+                self.items
+                    .peek(next_leaf_feature)?
+                    .ok_or_else(|| missing_item(next_leaf_feature))?,
+            );
         }
 
         Ok(maybe_item)
     }
 
-    pub fn dequeue(&mut self) -> Result<(Option<Vec<u8>>, u64), Error> {
-        let mut next_item: Option<Vec<u8>> = None;
+    fn dequeue(&mut self) -> Result<(Option<DequeuedItem>, u64), Error> {
+        self.transaction(|queue| queue._dequeue())
+    }
+
+    fn _dequeue(&mut self) -> Result<(Option<DequeuedItem>, u64), Error> {
+        self._requeue_expired_leases()?;
 
-        if let Some(next) = self.feature_space.use_next_leaf_feature()? {
-            let maybe_next_item = self.items.pop(next)?;
-            next_item = maybe_next_item;
+        let mut next_item: Option<DequeuedItem> = None;
+
+        if let Some((next, _)) = self.feature_space.use_next_leaf_feature()? {
+            next_item = Some(self.items.pop(next)?.ok_or_else(|| missing_item(next))?);
 
             self.feature_space.decrement_total_items()?;
         }
@@ -95,7 +419,458 @@ impl SortingPriorityQueue {
         Ok((next_item, epoch_step))
     }
 
-    pub fn get_epoch(&self) -> Result<u64, Error> {
+    fn dequeue_many(&mut self, count: usize) -> Result<(Vec<DequeuedItem>, u64), Error> {
+        self.transaction(|queue| {
+            let mut items: Vec<DequeuedItem> = vec![];
+
+            while items.len() < count {
+                match queue._dequeue()? {
+                    (Some(item), _) => items.push(item),
+                    (None, _) => break,
+                }
+            }
+
+            Ok((items, queue.feature_space.epoch_step()?))
+        })
+    }
+
+    fn dequeue_with_lease(
+        &mut self,
+        lease_duration: Duration,
+    ) -> Result<(Option<Lease>, u64), Error> {
+        self.transaction(|queue| queue._dequeue_with_lease(lease_duration))
+    }
+
+    fn _dequeue_with_lease(
+        &mut self,
+        lease_duration: Duration,
+    ) -> Result<(Option<Lease>, u64), Error> {
+        self._requeue_expired_leases()?;
+
+        let mut next_lease: Option<Lease> = None;
+
+        if let Some((next, lease_id)) = self.feature_space.use_next_leaf_feature()? {
+            // The epoch step claimed is unique to this dequeue so doubles as the lease id
+            let item = self.items.pop(next)?.ok_or_else(|| missing_item(next))?;
+            let lease = Lease::new(lease_id, item, next, deadline_from_now(lease_duration));
+
+            self.put_lease(&lease)?;
+            next_lease = Some(lease);
+
+            self.feature_space.decrement_total_items()?;
+        }
+
+        let epoch_step = self.feature_space.epoch_step()?;
+
+        Ok((next_lease, epoch_step))
+    }
+
+    fn dequeue_many_with_lease(
+        &mut self,
+        count: usize,
+        lease_duration: Duration,
+    ) -> Result<(Vec<Lease>, u64), Error> {
+        self.transaction(|queue| {
+            let mut leases: Vec<Lease> = vec![];
+
+            while leases.len() < count {
+                match queue._dequeue_with_lease(lease_duration)? {
+                    (Some(lease), _) => leases.push(lease),
+                    (None, _) => break,
+                }
+            }
+
+            Ok((leases, queue.feature_space.epoch_step()?))
+        })
+    }
+
+    fn get_active_lease(&mut self, lease_id: u64) -> Result<Lease, Error> {
+        self._requeue_expired_leases()?;
+
+        match self.leases.get(&lease_id) {
+            Err(Error::Empty { .. }) => Err(Error::LeaseNotFound { lease_id }),
+            result => result,
+        }
+    }
+
+    fn ack(&mut self, lease_id: u64) -> Result<(), Error> {
+        self.transaction(|queue| {
+            let lease = queue.get_active_lease(lease_id)?;
+
+            queue.delete_lease(&lease)
+        })
+    }
+
+    fn nack(&mut self, lease_id: u64) -> Result<u64, Error> {
+        self.transaction(|queue| {
+            let lease = queue.get_active_lease(lease_id)?;
+
+            queue.requeue(lease)
+        })
+    }
+
+    fn extend_lease(&mut self, lease_id: u64, lease_duration: Duration) -> Result<Lease, Error> {
+        self.transaction(|queue| {
+            let lease = queue.get_active_lease(lease_id)?;
+            let extended_lease = lease
+                .clone()
+                .with_deadline(deadline_from_now(lease_duration));
+
+            queue.delete_lease(&lease)?;
+            queue.put_lease(&extended_lease)?;
+
+            Ok(extended_lease)
+        })
+    }
+
+    fn requeue_expired_leases(&mut self) -> Result<usize, Error> {
+        self.transaction(|queue| queue._requeue_expired_leases())
+    }
+
+    fn _requeue_expired_leases(&mut self) -> Result<usize, Error> {
+        let now = now_millis();
+        let mut requeued = 0;
+
+        while let Some(lease) = self.next_expired_lease(now)? {
+            self.requeue(lease)?;
+            requeued += 1;
+        }
+
+        Ok(requeued)
+    }
+
+    fn requeue(&mut self, lease: Lease) -> Result<u64, Error> {
+        let epoch_step = self
+            .feature_space
+            .add_item(lease.get_features().clone(), lease.get_leaf())?;
+        self.items
+            .push(lease.get_leaf(), lease.get_item().clone())?;
+        self.feature_space.increment_total_items()?;
+        self.delete_lease(&lease)?;
+
+        Ok(epoch_step)
+    }
+
+    fn get_epoch(&self) -> Result<u64, Error> {
         self.feature_space.epoch_step()
     }
+
+    /// The soonest deadline of any active lease
+    fn next_lease_deadline(&self) -> Result<Option<u64>, Error> {
+        Ok(self
+            .lease_deadlines
+            .iter()
+            .next()
+            .map(|(deadline, _)| *deadline))
+    }
+}
+
+/// A queue that may be shared between threads. Each operation holds the lock
+/// on the queue's state for its whole transaction so operations never
+/// interleave. Operations that add items wake every thread in dequeue_wait.
+pub struct SortingPriorityQueue {
+    state: Mutex<QueueState>,
+    items_added: Condvar,
+    storage_type: StorageType,
+    maybe_folder_path: Option<String>,
+}
+
+thread_local! {
+    static LOCK_WAIT: Cell<Duration> = const { Cell::new(Duration::from_secs(0)) };
+    static APPLYING: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Runs an operation on this thread as the entry of a replicated log at the
+/// index. Every queue it creates or writes to records the index in the same
+/// commit as its writes so that a replica restarted with its queues on disk
+/// can tell which entries they already hold.
+pub fn applying_entry<T>(index: u64, operation: impl FnOnce() -> T) -> T {
+    let previous = APPLYING.with(|applying| applying.replace(Some(index)));
+    let result = operation();
+    APPLYING.with(|applying| applying.set(previous));
+
+    result
+}
+
+/// Runs an operation returning how long it spent waiting for queue locks on
+/// this thread alongside its result
+pub fn timing_lock_waits<T>(operation: impl FnOnce() -> T) -> (T, Duration) {
+    let previous = LOCK_WAIT.with(|lock_wait| lock_wait.replace(Duration::from_secs(0)));
+    let result = operation();
+    let waited = LOCK_WAIT.with(|lock_wait| lock_wait.replace(previous + lock_wait.get()));
+
+    (result, waited)
+}
+
+/// The feature space counts an item under the leaf that the items do not hold
+fn missing_item(leaf: u64) -> Error {
+    Error::corruption(format!(
+        "Feature space counts an item under leaf {:?} but none is stored",
+        leaf
+    ))
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::new("Queue state was poisoned by a panic during an earlier operation".to_string())
+}
+
+impl SortingPriorityQueue {
+    pub fn new(features: Vec<String>) -> Result<SortingPriorityQueue, Error> {
+        Ok(SortingPriorityQueue::from_state(
+            QueueState::new(features)?,
+            None,
+        ))
+    }
+
+    pub fn new_durable(
+        features: Vec<String>,
+        folder_path: String,
+    ) -> Result<SortingPriorityQueue, Error> {
+        Ok(SortingPriorityQueue::from_state(
+            QueueState::new_durable(features, folder_path.clone())?,
+            Some(folder_path),
+        ))
+    }
+
+    /// Reopens a durable queue that was previously created at the folder path
+    /// without needing to be told its features.
+    pub fn open_durable(folder_path: String) -> Result<SortingPriorityQueue, Error> {
+        Ok(SortingPriorityQueue::from_state(
+            QueueState::open_durable(folder_path.clone())?,
+            Some(folder_path),
+        ))
+    }
+
+    /// Recreates an exported queue with the same storage type it had. The
+    /// folder path is only used when the snapshot is of a durable queue.
+    pub fn import(
+        snapshot: QueueSnapshot,
+        folder_path: String,
+    ) -> Result<SortingPriorityQueue, Error> {
+        let maybe_folder_path = match snapshot.storage_type {
+            StorageType::Memory => None,
+            StorageType::Durable => Some(folder_path.clone()),
+        };
+
+        Ok(SortingPriorityQueue::from_state(
+            QueueState::import(snapshot, folder_path)?,
+            maybe_folder_path,
+        ))
+    }
+
+    /// Everything the queue stores including its leases as of one moment
+    pub fn export(&self) -> Result<QueueSnapshot, Error> {
+        self.lock()?.export()
+    }
+
+    fn from_state(state: QueueState, maybe_folder_path: Option<String>) -> SortingPriorityQueue {
+        SortingPriorityQueue {
+            storage_type: state.storage_type(),
+            state: Mutex::new(state),
+            items_added: Condvar::new(),
+            maybe_folder_path,
+        }
+    }
+
+    /// Flushes everything the queue has written to disk and closes it.
+    pub fn close(self) -> Result<(), Error> {
+        self.lock()?.flush()?;
+
+        Ok(())
+    }
+
+    /// Removes the queue and everything it has written to disk. Refuses to
+    /// remove a folder that is not named by its last component, such as one
+    /// ending in `..`, or that holds no database.
+    pub fn destroy(self) -> Result<(), Error> {
+        let maybe_folder_path = self.maybe_folder_path.clone();
+
+        if let Some(ref folder_path) = maybe_folder_path {
+            check_removable(folder_path)?;
+        }
+
+        // The database must be closed before its directory is removed
+        drop(self);
+
+        if let Some(folder_path) = maybe_folder_path {
+            std::fs::remove_dir_all(folder_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, QueueState>, Error> {
+        let started = Instant::now();
+        let state = self.state.lock().map_err(poisoned)?;
+        LOCK_WAIT.with(|lock_wait| lock_wait.set(lock_wait.get() + started.elapsed()));
+
+        Ok(state)
+    }
+
+    /// Runs an operation that adds items waking every waiting dequeuer
+    fn adding_items<T>(
+        &self,
+        operation: impl FnOnce(&mut QueueState) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let result = operation(&mut *self.lock()?)?;
+
+        self.items_added.notify_all();
+
+        Ok(result)
+    }
+
+    pub fn storage_type(&self) -> StorageType {
+        self.storage_type
+    }
+
+    /// The last time a durable queue failed to read or write its database if
+    /// no write has succeeded since
+    pub fn storage_failure(&self) -> Result<Option<String>, Error> {
+        Ok(self.lock()?.storage_failure())
+    }
+
+    /// Empty for a queue created before feature names were stored until an
+    /// item is enqueued to it
+    pub fn feature_names(&self) -> Result<Vec<String>, Error> {
+        self.lock()?.feature_names()
+    }
+
+    /// Whether the queue was created with these feature names in this order
+    pub fn has_feature_names(&self, feature_names: &[String]) -> Result<bool, Error> {
+        self.lock()?.has_feature_names(feature_names)
+    }
+
+    pub fn dimension(&self) -> Result<u64, Error> {
+        self.lock()?.dimension()
+    }
+
+    /// The root index is only assigned once the first item is enqueued
+    pub fn root_index(&self) -> Result<Option<u64>, Error> {
+        self.lock()?.root_index()
+    }
+
+    pub fn feature_value_counts(&self) -> Result<Vec<(FeatureValue, u64)>, Error> {
+        self.lock()?.feature_value_counts()
+    }
+
+    pub fn enqueue(&self, data: Vec<u8>, features: Vec<FeatureValue>) -> Result<u64, Error> {
+        self.adding_items(|state| state.enqueue(data, features))
+    }
+
+    /// Enqueues every item or none of them. All feature vectors are checked
+    /// before anything is added. Returns the epoch each item was enqueued at.
+    pub fn enqueue_batch(
+        &self,
+        items: Vec<(Vec<u8>, Vec<FeatureValue>)>,
+    ) -> Result<Vec<u64>, Error> {
+        self.adding_items(|state| state.enqueue_batch(items))
+    }
+
+    pub fn size(&self) -> Result<u64, Error> {
+        self.lock()?.size()
+    }
+
+    /// Whether a dequeue now would find an item, counting the items of
+    /// expired leases that it would requeue first
+    pub fn can_dequeue(&self) -> Result<bool, Error> {
+        self.lock()?.can_dequeue()
+    }
+
+    pub fn peek(&self) -> Result<Option<DequeuedItem>, Error> {
+        self.lock()?.peek()
+    }
+
+    /// Removes the next item returning it with the feature values, epoch and
+    /// time it was enqueued with alongside the epoch of the queue.
+    pub fn dequeue(&self) -> Result<(Option<DequeuedItem>, u64), Error> {
+        self.lock()?.dequeue()
+    }
+
+    /// Dequeues the next item waiting up to the timeout for one to be added
+    /// if the queue is empty. Threads waiting together are woken together so
+    /// which of them gets the next item is up to the scheduler. Returns no
+    /// item if the timeout passes first.
+    pub fn dequeue_wait(&self, timeout: Duration) -> Result<(Option<DequeuedItem>, u64), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock()?;
+
+        loop {
+            let (maybe_item, epoch) = state.dequeue()?;
+            let now = Instant::now();
+
+            if maybe_item.is_some() || now >= deadline {
+                return Ok((maybe_item, epoch));
+            }
+
+            // A lease expiring returns an item so wake for that too
+            let wait = match state.next_lease_deadline()? {
+                Some(lease_deadline) => (deadline - now).min(Duration::from_millis(
+                    lease_deadline.saturating_sub(now_millis()),
+                )),
+                None => deadline - now,
+            };
+
+            state = self
+                .items_added
+                .wait_timeout(state, wait)
+                .map_err(poisoned)?
+                .0;
+        }
+    }
+
+    /// Dequeues up to count items in exactly the order that as many calls to
+    /// dequeue would return them, stopping early if the queue runs out.
+    pub fn dequeue_many(&self, count: usize) -> Result<(Vec<DequeuedItem>, u64), Error> {
+        self.lock()?.dequeue_many(count)
+    }
+
+    /// Removes the next item from the queue and holds it under a lease.
+    /// Until the lease is acked the item is only hidden. A nack or the lease
+    /// expiring returns it to the queue with its original feature values.
+    pub fn dequeue_with_lease(
+        &self,
+        lease_duration: Duration,
+    ) -> Result<(Option<Lease>, u64), Error> {
+        self.lock()?.dequeue_with_lease(lease_duration)
+    }
+
+    /// Leases up to count items in the order dequeue_many would return them
+    pub fn dequeue_many_with_lease(
+        &self,
+        count: usize,
+        lease_duration: Duration,
+    ) -> Result<(Vec<Lease>, u64), Error> {
+        self.lock()?.dequeue_many_with_lease(count, lease_duration)
+    }
+
+    /// Completes a lease permanently removing the leased item.
+    pub fn ack(&self, lease_id: u64) -> Result<(), Error> {
+        self.lock()?.ack(lease_id)
+    }
+
+    /// Abandons a lease returning the item to the front of its shard.
+    pub fn nack(&self, lease_id: u64) -> Result<u64, Error> {
+        self.adding_items(|state| state.nack(lease_id))
+    }
+
+    pub fn extend_lease(&self, lease_id: u64, lease_duration: Duration) -> Result<Lease, Error> {
+        self.lock()?.extend_lease(lease_id, lease_duration)
+    }
+
+    /// Returns every item whose lease deadline has passed to the queue.
+    /// This runs before each dequeue so expired items are never lost but
+    /// callers that only peek may wish to run it themselves.
+    pub fn requeue_expired_leases(&self) -> Result<usize, Error> {
+        self.adding_items(|state| state.requeue_expired_leases())
+    }
+
+    pub fn get_epoch(&self) -> Result<u64, Error> {
+        self.lock()?.get_epoch()
+    }
+
+    /// The last replicated log entry applied to the queue or zero if it was
+    /// never written to under applying_entry
+    pub fn applied_index(&self) -> Result<u64, Error> {
+        self.lock()?.feature_space.applied_index()
+    }
 }
@@ -1,11 +1,11 @@
+use crate::database::{Column, Entry};
 use crate::error::Error;
-use crate::storage::{StorageType, INTEGER_FROM_BYTES};
-use rocksdb::{Options, SliceTransform, DB};
+use crate::storage::{expect_key_length, INTEGER_FROM_BYTES};
+use std::collections::BTreeMap;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
-use uuid::Uuid;
 
-fn create_composite_key(prefix: &u64, key: &u64) -> [u8; 16] {
+pub fn create_composite_key(prefix: &u64, key: &u64) -> [u8; 16] {
     let mut composite_key: [u8; 16] = [0; 16];
     let prefix_bytes = prefix.to_be_bytes();
     let key_bytes = key.to_be_bytes();
@@ -16,91 +16,72 @@ fn create_composite_key(prefix: &u64, key: &u64) -> [u8; 16] {
     composite_key
 }
 
+fn no_element() -> Error {
+    Error::Empty {
+        message: "No element present".to_string(),
+    }
+}
+
+/// Memory keys are ordered by (prefix, key) which matches the ordering of the
+/// big endian composite keys used on disk.
+enum Backend {
+    Memory(BTreeMap<(u64, u64), u64>),
+    Durable(Column),
+}
+
 pub struct PrefixStorage {
-    storage_type: StorageType,
+    backend: Backend,
     size: AtomicUsize,
-    folder_path: String,
 }
 
 impl PrefixStorage {
-    pub fn new_integer(maybe_folder_path: Option<String>) -> PrefixStorage {
-        match maybe_folder_path {
-            Some(folder_path) => PrefixStorage {
-                storage_type: StorageType::Durable,
-                size: AtomicUsize::new(0),
-                folder_path,
-            },
-            None => PrefixStorage {
-                storage_type: StorageType::Memory,
-                size: AtomicUsize::new(0),
-                folder_path: format!("/tmp/spqr/{:?}", Uuid::new_v4()),
-            },
-        }
+    pub fn new_integer(maybe_column: Option<Column>) -> Result<PrefixStorage, Error> {
+        let backend = match maybe_column {
+            Some(column) => Backend::Durable(column),
+            None => Backend::Memory(BTreeMap::new()),
+        };
+
+        Ok(PrefixStorage {
+            backend,
+            size: AtomicUsize::new(0),
+        })
     }
 
-    fn get_db(&self) -> Result<DB, Error> {
-        let mut opts = Options::default();
+    fn _get(&self, column: &Column, key: [u8; 16]) -> Result<u64, Error> {
+        let maybe_bytes = column.get(&key)?;
 
-        let slice_transformer = SliceTransform::create_fixed_prefix(8);
-        opts.set_prefix_extractor(slice_transformer);
-        opts.create_if_missing(true);
-
-        let db = DB::open(&opts, self.folder_path.clone())?;
-
-        Ok(db)
-    }
-
-    fn _get(&self, db: &DB, key: [u8; 16]) -> Result<u64, Error> {
-        let maybe_bytes = db.get(&key)?;
-
-        let bytes = maybe_bytes.ok_or_else(|| Error::Empty {
-            message: "No element present".to_string(),
-        })?;
+        let bytes = maybe_bytes.ok_or_else(no_element)?;
 
         (INTEGER_FROM_BYTES)(bytes)
     }
 
     pub fn get(&self, prefix: &u64, key: &u64) -> Result<u64, Error> {
-        let db = &self.get_db()?;
-
-        let maybe_bytes = db.get(&create_composite_key(prefix, key))?;
-
-        let bytes = maybe_bytes.ok_or_else(|| Error::Empty {
-            message: "No element present".to_string(),
-        })?;
-
-        (INTEGER_FROM_BYTES)(bytes)
-    }
-
-    fn maybe_flush(&self, db: &DB) -> Result<(), Error> {
-        match self.storage_type {
-            StorageType::Memory => Ok(()),
-            StorageType::Durable => {
-                db.flush()?;
-
-                Ok(())
-            }
+        match self.backend {
+            Backend::Memory(ref map) => map.get(&(*prefix, *key)).copied().ok_or_else(no_element),
+            Backend::Durable(ref column) => self._get(column, create_composite_key(prefix, key)),
         }
     }
 
-    fn _put(&mut self, db: &DB, key: [u8; 16], value: u64) -> Result<(), Error> {
+    fn _put(&self, column: &Column, key: [u8; 16], value: u64) -> Result<(), Error> {
         self.size.fetch_add(1, Relaxed);
 
-        db.put(&key, value.to_be_bytes())?;
+        column.put(&key, value.to_be_bytes().to_vec());
 
         Ok(())
     }
 
     pub fn put(&mut self, prefix: &u64, key: &u64, value: u64) -> Result<(), Error> {
-        let db = &self.get_db()?;
-
-        self.size.fetch_add(1, Relaxed);
-
-        let bytes = value.to_be_bytes();
+        match self.backend {
+            Backend::Memory(ref mut map) => {
+                self.size.fetch_add(1, Relaxed);
+                map.insert((*prefix, *key), value);
 
-        db.put(&create_composite_key(prefix, key), bytes)?;
-
-        Ok(())
+                Ok(())
+            }
+            Backend::Durable(ref column) => {
+                self._put(column, create_composite_key(prefix, key), value)
+            }
+        }
     }
 
     pub fn update(
@@ -109,23 +90,60 @@ impl PrefixStorage {
         key: &u64,
         f: fn(value: u64) -> u64,
     ) -> Result<(), Error> {
-        let db = &self.get_db()?;
-        let composite_key = create_composite_key(prefix, key);
-        let value = self._get(db, composite_key)?;
+        match self.backend {
+            Backend::Memory(ref mut map) => {
+                let value = map.get_mut(&(*prefix, *key)).ok_or_else(no_element)?;
+
+                *value = (f)(*value);
 
-        let new_value = (f)(value);
+                Ok(())
+            }
+            Backend::Durable(ref column) => {
+                let composite_key = create_composite_key(prefix, key);
+                let value = self._get(column, composite_key)?;
 
-        self._put(db, composite_key, new_value)?;
+                let new_value = (f)(value);
 
-        self.maybe_flush(db)
+                self._put(column, composite_key, new_value)
+            }
+        }
     }
 
-    pub fn has_prefix(&self, prefix: &u64) -> Result<bool, Error> {
-        let db = &self.get_db()?;
+    fn entries_at_prefix(&self, prefix: &u64) -> Result<Vec<(u64, u64)>, Error> {
+        match self.backend {
+            Backend::Memory(ref map) => Ok(map
+                .range((*prefix, u64::MIN)..=(*prefix, u64::MAX))
+                .map(|((_, key), value)| (*key, *value))
+                .collect()),
+            Backend::Durable(ref column) => {
+                let mut entries: Vec<(u64, u64)> = vec![];
+
+                for (key, value) in column.entries_with_prefix(&prefix.to_be_bytes())? {
+                    expect_key_length(&key, 16)?;
 
-        let has_prefix = db.prefix_iterator(prefix.to_be_bytes()).next().is_some();
+                    let integer_value = (INTEGER_FROM_BYTES)(value)?;
+                    let integer_key = (INTEGER_FROM_BYTES)(key[8..16].to_vec())?;
 
-        Ok(has_prefix)
+                    entries.push((integer_key, integer_value));
+                }
+
+                Ok(entries)
+            }
+        }
+    }
+
+    pub fn has_prefix(&self, prefix: &u64) -> Result<bool, Error> {
+        match self.backend {
+            Backend::Memory(ref map) => Ok(map
+                .range((*prefix, u64::MIN)..=(*prefix, u64::MAX))
+                .next()
+                .is_some()),
+            Backend::Durable(ref column) => {
+                let has_prefix = column.first_with_prefix(&prefix.to_be_bytes())?.is_some();
+
+                Ok(has_prefix)
+            }
+        }
     }
 
     pub fn filter_keys_by_prefix(
@@ -133,50 +151,63 @@ impl PrefixStorage {
         prefix: &u64,
         check: fn(value: u64) -> bool,
     ) -> Result<Vec<u64>, Error> {
-        let db = &self.get_db()?;
-
-        let mut keys: Vec<u64> = vec![];
-
-        for (key, value) in db.prefix_iterator(prefix.to_be_bytes()) {
-            let integer_value = (INTEGER_FROM_BYTES)(value.to_vec())?;
-            let integer_key = (INTEGER_FROM_BYTES)(key[8..16].to_vec())?;
-
-            if (check)(integer_value) {
-                keys.push(integer_key);
-            }
-        }
-
-        Ok(keys)
+        Ok(self
+            .entries_at_prefix(prefix)?
+            .into_iter()
+            .filter(|(_, value)| (check)(*value))
+            .map(|(key, _)| key)
+            .collect())
     }
 
     pub fn get_at_prefix(&self, prefix: &u64) -> Result<Vec<u64>, Error> {
-        let db = &self.get_db()?;
-
-        let mut values: Vec<u64> = vec![];
+        Ok(self
+            .entries_at_prefix(prefix)?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect())
+    }
 
-        for (_, value) in db.prefix_iterator(prefix.to_be_bytes()) {
-            let integer_value = (INTEGER_FROM_BYTES)(value.to_vec())?;
+    /// Every entry as the composite key and value bytes a durable column
+    /// holds them as
+    pub fn export(&self) -> Result<Vec<Entry>, Error> {
+        match self.backend {
+            Backend::Memory(ref map) => Ok(map
+                .iter()
+                .map(|((prefix, key), value)| {
+                    (
+                        create_composite_key(prefix, key).to_vec(),
+                        value.to_be_bytes().to_vec(),
+                    )
+                })
+                .collect()),
+            Backend::Durable(ref column) => column.entries_with_prefix(&[]),
+        }
+    }
 
-            values.push(integer_value);
+    /// Puts every entry of an export
+    pub fn import(&mut self, entries: Vec<Entry>) -> Result<(), Error> {
+        for (key, value) in entries {
+            expect_key_length(&key, 16)?;
+            expect_key_length(&value, 8)?;
+
+            match self.backend {
+                Backend::Memory(_) => {
+                    let prefix = (INTEGER_FROM_BYTES)(key[..8].to_vec())?;
+                    let integer_key = (INTEGER_FROM_BYTES)(key[8..].to_vec())?;
+
+                    self.put(&prefix, &integer_key, (INTEGER_FROM_BYTES)(value)?)?;
+                }
+                Backend::Durable(ref column) => {
+                    self.size.fetch_add(1, Relaxed);
+                    column.put(&key, value);
+                }
+            }
         }
 
-        Ok(values)
+        Ok(())
     }
 
     pub fn is_empty(&self) -> bool {
         self.size.load(Relaxed) == 0
     }
 }
-
-impl Drop for PrefixStorage {
-    fn drop(&mut self) {
-        match self.storage_type {
-            StorageType::Memory => match DB::destroy(&Options::default(), self.folder_path.clone())
-            {
-                Ok(_) => (),
-                Err(e) => println!("failed to delete storage dir: {:?}", e),
-            },
-            StorageType::Durable => (),
-        }
-    }
-}
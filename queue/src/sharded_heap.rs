@@ -1,133 +1,145 @@
+use crate::database::{Column, Entry};
 use crate::error::Error;
-use crate::storage::StorageType;
-use log::debug;
-use rocksdb::{ColumnFamily, IteratorMode, Options, DB};
-use uuid::Uuid;
+use crate::item::{DequeuedItem, ITEM_FROM_BYTES, ITEM_TO_BYTES};
+use crate::storage::{expect_key_length, INTEGER_FROM_BYTES};
+use std::collections::{BTreeMap, HashMap};
+use tracing::instrument;
+
+fn create_item_key(key: u64, epoch: u64) -> [u8; 16] {
+    let mut item_key: [u8; 16] = [0; 16];
+
+    item_key[..8].clone_from_slice(&key.to_be_bytes());
+    item_key[8..].clone_from_slice(&epoch.to_be_bytes());
+
+    item_key
+}
+
+/// Each shard holds the items for one leaf ordered by the epoch they were
+/// enqueued at. In memory the shards are kept in a map of ordered maps. On disk
+/// every shard shares one column prefixed by the shard key so the big endian
+/// epoch keeps the items of a shard in order.
+enum Backend {
+    Memory(HashMap<u64, BTreeMap<u64, DequeuedItem>>),
+    Durable(Column),
+}
 
 pub struct ShardedHeap {
-    storage_type: StorageType,
-    folder_path: String,
-    options: Options,
+    backend: Backend,
 }
 
 impl ShardedHeap {
-    pub fn new(maybe_folder_path: Option<String>) -> Result<ShardedHeap, Error> {
-        let mut options = Options::default();
-        options.create_missing_column_families(true);
-        options.create_if_missing(true);
-
-        let heap = match maybe_folder_path {
-            Some(folder_path) => ShardedHeap {
-                storage_type: StorageType::Durable,
-                folder_path,
-                options,
-            },
-            None => ShardedHeap {
-                storage_type: StorageType::Memory,
-                folder_path: format!("/tmp/spqr/{:?}", Uuid::new_v4()),
-                options,
-            },
+    pub fn new(maybe_column: Option<Column>) -> Result<ShardedHeap, Error> {
+        let backend = match maybe_column {
+            Some(column) => Backend::Durable(column),
+            None => Backend::Memory(HashMap::new()),
         };
 
-        //TODO: We should check to see if the table exists and if it doesn't create it
-        match DB::open(&heap.options, heap.folder_path.clone()) {
-            Ok(_) => debug!("Either created a fresh items table or found an empty table at {:?}", heap.folder_path),
-            Err(_) => debug!("Encountered an error opening the items table. Assuming an existing items table was found at {:?}", heap.folder_path)
-        }
-
-        Ok(heap)
+        Ok(ShardedHeap { backend })
     }
 
-    fn maybe_flush(&self, db: &DB, cf_handle: &ColumnFamily) -> Result<(), Error> {
-        match self.storage_type {
-            StorageType::Memory => Ok(()),
-            StorageType::Durable => {
-                db.flush_cf(cf_handle)?;
+    /// Adds the item to the shard at the epoch it was enqueued at. Pushing an
+    /// item that was popped returns it to the same position.
+    #[instrument(level = "debug", name = "sharded_heap.push", skip(self, item))]
+    pub fn push(&mut self, key: u64, item: DequeuedItem) -> Result<(), Error> {
+        let epoch = item.get_epoch();
 
-                Ok(())
+        match self.backend {
+            Backend::Memory(ref mut shards) => {
+                shards.entry(key).or_default().insert(epoch, item);
             }
-        }
-    }
-
-    pub fn push(&mut self, epoch: u64, key: u64, value: Vec<u8>) -> Result<(), Error> {
-        let cfs = &DB::list_cf(&self.options, self.folder_path.clone())?;
-        let db = &mut DB::open_cf(&self.options, self.folder_path.clone(), cfs)?;
-
-        match db.cf_handle(&key.to_string()) {
-            Some(cf_handle) => {
-                db.put_cf(cf_handle, epoch.to_be_bytes(), value)?;
-            }
-            None => {
-                db.create_cf(key.to_string(), &self.options)?;
-                match db.cf_handle(&key.to_string()) {
-                    Some(cf_handle) => {
-                        db.put_cf(cf_handle, epoch.to_be_bytes(), value)?;
-
-                        self.maybe_flush(db, cf_handle)?;
-                    }
-                    None => {
-                        panic!("Cannot get created column family");
-                    }
-                }
+            Backend::Durable(ref column) => {
+                column.put(&create_item_key(key, epoch), ITEM_TO_BYTES(item)?);
             }
         }
 
         Ok(())
     }
 
-    pub fn peek(&self, key: u64) -> Result<Option<Vec<u8>>, Error> {
-        let cfs = &DB::list_cf(&self.options, self.folder_path.clone())?;
-        let db = &mut DB::open_cf(&self.options, self.folder_path.clone(), cfs)?;
-
-        let mut result: Option<Vec<u8>> = None;
-
-        match db.cf_handle(&key.to_string()) {
-            Some(cf_handle) => {
-                if let Some((_, value)) = db.iterator_cf(cf_handle, IteratorMode::Start).next() {
-                    result = Some(value.to_vec());
-                }
-            }
-            None => {
-                return Err(Error::new(format!("No shard for key {:?}", key)));
-            }
+    /// The oldest item in the shard. A shard that never held an item has none
+    /// as on disk it cannot be told apart from one that was emptied.
+    pub fn peek(&self, key: u64) -> Result<Option<DequeuedItem>, Error> {
+        match self.backend {
+            Backend::Memory(ref shards) => Ok(shards
+                .get(&key)
+                .and_then(|shard| shard.values().next().cloned())),
+            Backend::Durable(ref column) => column
+                .first_with_prefix(&key.to_be_bytes())?
+                .map(|(_, value)| ITEM_FROM_BYTES(value))
+                .transpose(),
         }
-
-        Ok(result)
     }
 
-    pub fn pop(&mut self, key: u64) -> Result<Option<Vec<u8>>, Error> {
-        let cfs = &DB::list_cf(&self.options, self.folder_path.clone())?;
-        let db = &mut DB::open_cf(&self.options, self.folder_path.clone(), cfs)?;
+    /// Removes the oldest item in the shard
+    #[instrument(level = "debug", name = "sharded_heap.pop", skip(self))]
+    pub fn pop(&mut self, key: u64) -> Result<Option<DequeuedItem>, Error> {
+        match self.backend {
+            Backend::Memory(ref mut shards) => {
+                let shard = match shards.get_mut(&key) {
+                    Some(shard) => shard,
+                    None => return Ok(None),
+                };
 
-        let mut result: Option<Vec<u8>> = None;
+                let maybe_epoch = shard.keys().next().copied();
 
-        match db.cf_handle(&key.to_string()) {
-            Some(cf_handle) => {
-                if let Some((key, value)) = db.iterator_cf(cf_handle, IteratorMode::Start).next() {
-                    result = Some(value.to_vec());
-                    db.delete_cf(cf_handle, key)?;
+                Ok(maybe_epoch.and_then(|epoch| shard.remove(&epoch)))
+            }
+            Backend::Durable(ref column) => match column.first_with_prefix(&key.to_be_bytes())? {
+                Some((item_key, value)) => {
+                    expect_key_length(&item_key, 16)?;
+                    column.delete(&item_key);
 
-                    self.maybe_flush(db, cf_handle)?;
+                    Ok(Some(ITEM_FROM_BYTES(value)?))
                 }
-            }
-            None => {
-                return Err(Error::new(format!("No shard for key {:?}", key)));
-            }
+                None => Ok(None),
+            },
         }
+    }
 
-        Ok(result)
+    /// Every item keyed by its shard then the epoch it was enqueued at as a
+    /// durable column holds them
+    pub fn export(&self) -> Result<Vec<Entry>, Error> {
+        match self.backend {
+            Backend::Memory(ref shards) => {
+                let mut entries: Vec<Entry> = shards
+                    .iter()
+                    .flat_map(|(key, shard)| {
+                        shard.iter().map(move |(epoch, item)| {
+                            Ok((
+                                create_item_key(*key, *epoch).to_vec(),
+                                ITEM_TO_BYTES(item.clone())?,
+                            ))
+                        })
+                    })
+                    .collect::<Result<_, Error>>()?;
+
+                entries.sort();
+
+                Ok(entries)
+            }
+            Backend::Durable(ref column) => column.entries_with_prefix(&[]),
+        }
     }
-}
 
-impl Drop for ShardedHeap {
-    fn drop(&mut self) {
-        match self.storage_type {
-            StorageType::Memory => match DB::destroy(&Options::default(), self.folder_path.clone())
-            {
-                Ok(_) => (),
-                Err(e) => println!("failed to delete storage dir: {:?}", e),
-            },
-            StorageType::Durable => (),
+    /// Pushes every item of an export back to the position it was exported from
+    pub fn import(&mut self, entries: Vec<Entry>) -> Result<(), Error> {
+        for (item_key, value) in entries {
+            expect_key_length(&item_key, 16)?;
+
+            let key = INTEGER_FROM_BYTES(item_key[..8].to_vec())?;
+            let epoch = INTEGER_FROM_BYTES(item_key[8..].to_vec())?;
+            let item = ITEM_FROM_BYTES(value)?;
+
+            if item.get_epoch() != epoch {
+                return Err(Error::corruption(format!(
+                    "Item keyed at epoch {:?} was enqueued at epoch {:?}",
+                    epoch,
+                    item.get_epoch()
+                )));
+            }
+
+            self.push(key, item)?;
         }
+
+        Ok(())
     }
 }
@@ -0,0 +1,40 @@
+use crate::database::{Entry, METADATA};
+use crate::error::{undecodable, Error};
+use crate::feature_space::APPLIED_INDEX_KEY;
+use crate::storage::{StorageType, INTEGER_FROM_BYTES};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Everything a queue stores laid out as the columns of a durable queue.
+/// Memory queues export the same bytes so a snapshot of either kind can be
+/// imported as either kind.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub storage_type: StorageType,
+    pub columns: BTreeMap<String, Vec<Entry>>,
+}
+
+impl QueueSnapshot {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<QueueSnapshot, Error> {
+        bincode::deserialize(bytes).map_err(undecodable)
+    }
+
+    /// The last replicated log entry applied to the queue it was exported
+    /// from or zero if none was
+    pub fn applied_index(&self) -> Result<u64, Error> {
+        let key = APPLIED_INDEX_KEY.to_be_bytes();
+
+        match self
+            .columns
+            .get(METADATA)
+            .and_then(|entries| entries.iter().find(|(entry_key, _)| entry_key[..] == key))
+        {
+            Some((_, value)) => INTEGER_FROM_BYTES(value.clone()),
+            None => Ok(0),
+        }
+    }
+}
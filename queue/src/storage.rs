@@ -1,9 +1,12 @@
+use crate::database::{Column, Entry};
 use crate::error::Error;
-use rocksdb::{Options, DB};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
-use uuid::Uuid;
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum StorageType {
     Memory,
     Durable,
@@ -14,198 +17,257 @@ pub type SerializeFn<V> = fn(value: Vec<u8>) -> Result<V, Error>;
 
 pub const INTEGER_TO_BYTES: DeserializeFn<u64> = |integer| Ok(integer.to_be_bytes().to_vec());
 pub const INTEGER_FROM_BYTES: SerializeFn<u64> = |bytes| {
-    let mut sized_bytes: [u8; 8] = Default::default();
-    sized_bytes.copy_from_slice(&bytes[0..8]);
+    let sized_bytes: [u8; 8] = bytes.as_slice().try_into().map_err(|_| {
+        Error::corruption(format!("Stored integer {:?} should be 8 bytes long", bytes))
+    })?;
     Ok(u64::from_be_bytes(sized_bytes))
 };
 
-pub struct Storage<V: Copy> {
-    storage_type: StorageType,
+pub const BOOL_TO_BYTES: DeserializeFn<bool> = |boolean| {
+    if boolean {
+        Ok(vec![1])
+    } else {
+        Ok(vec![0])
+    }
+};
+pub const BOOL_FROM_BYTES: SerializeFn<bool> = |bytes| {
+    if bytes == vec![1] {
+        Ok(true)
+    } else if bytes == vec![0] {
+        Ok(false)
+    } else {
+        Err(Error::corruption(format!(
+            "{:?} is not a valid boolean",
+            bytes
+        )))
+    }
+};
+
+fn no_element() -> Error {
+    Error::Empty {
+        message: "No element present".to_string(),
+    }
+}
+
+/// Checks that an imported or stored key is as long as the keys the storage
+/// writes
+pub fn expect_key_length(key: &[u8], length: usize) -> Result<(), Error> {
+    if key.len() != length {
+        return Err(Error::corruption(format!(
+            "Key {:?} should be {} bytes long",
+            key, length
+        )));
+    }
+
+    Ok(())
+}
+
+/// In memory values are kept as is so that memory queues never touch disk or
+/// pay for serialization. Durable storage is a column of the queue's database.
+enum Backend<V> {
+    Memory(BTreeMap<u64, V>),
+    Durable(Column),
+}
+
+pub struct Storage<V: Clone> {
+    backend: Backend<V>,
     size: AtomicUsize,
-    folder_path: String,
     to_bytes: DeserializeFn<V>,
     from_bytes: SerializeFn<V>,
 }
 
 impl<V> Storage<V>
 where
-    V: Copy,
+    V: Clone,
 {
-    pub fn new_integer(maybe_folder_path: Option<String>) -> Storage<u64> {
-        match maybe_folder_path {
-            Some(folder_path) => Storage {
-                storage_type: StorageType::Durable,
-                size: AtomicUsize::new(0),
-                folder_path,
-                to_bytes: INTEGER_TO_BYTES,
-                from_bytes: INTEGER_FROM_BYTES,
-            },
-            None => Storage {
-                storage_type: StorageType::Memory,
-                size: AtomicUsize::new(0),
-                folder_path: format!("/tmp/spqr/{:?}", Uuid::new_v4()),
-                to_bytes: INTEGER_TO_BYTES,
-                from_bytes: INTEGER_FROM_BYTES,
-            },
-        }
+    pub fn new_integer(maybe_column: Option<Column>) -> Result<Storage<u64>, Error> {
+        Storage::new(maybe_column, INTEGER_TO_BYTES, INTEGER_FROM_BYTES)
     }
 
-    pub fn new_bool(maybe_folder_path: Option<String>) -> Storage<bool> {
-        let to_bytes: DeserializeFn<bool> = |boolean| {
-            if boolean {
-                Ok(vec![1])
-            } else {
-                Ok(vec![0])
-            }
-        };
-        let from_bytes: SerializeFn<bool> = |bytes| {
-            if bytes == vec![1] {
-                Ok(true)
-            } else if bytes == vec![0] {
-                Ok(false)
-            } else {
-                Err(Error::new("Item not a valid boolean".to_string()))
-            }
-        };
-
-        match maybe_folder_path {
-            Some(folder_path) => Storage {
-                storage_type: StorageType::Durable,
-                size: AtomicUsize::new(0),
-                folder_path,
-                to_bytes,
-                from_bytes,
-            },
-            None => Storage {
-                storage_type: StorageType::Memory,
-                size: AtomicUsize::new(0),
-                folder_path: format!("/tmp/spqr/{:?}", Uuid::new_v4()),
-                to_bytes,
-                from_bytes,
-            },
-        }
+    pub fn new_bool(maybe_column: Option<Column>) -> Result<Storage<bool>, Error> {
+        Storage::new(maybe_column, BOOL_TO_BYTES, BOOL_FROM_BYTES)
     }
 
     pub fn new(
-        maybe_durable: Option<String>,
+        maybe_column: Option<Column>,
         to_bytes: DeserializeFn<V>,
         from_bytes: SerializeFn<V>,
-    ) -> Storage<V> {
-        match maybe_durable {
-            Some(folder_path) => Storage {
-                storage_type: StorageType::Durable,
-                size: AtomicUsize::new(0),
-                folder_path,
-                to_bytes,
-                from_bytes,
-            },
-            None => Storage {
-                storage_type: StorageType::Memory,
-                size: AtomicUsize::new(0),
-                folder_path: format!("/tmp/spqr/{:?}", Uuid::new_v4()),
-                to_bytes,
-                from_bytes,
-            },
+    ) -> Result<Storage<V>, Error> {
+        let backend = match maybe_column {
+            Some(column) => Backend::Durable(column),
+            None => Backend::Memory(BTreeMap::new()),
+        };
+
+        Ok(Storage {
+            backend,
+            size: AtomicUsize::new(0),
+            to_bytes,
+            from_bytes,
+        })
+    }
+
+    pub fn storage_type(&self) -> StorageType {
+        match self.backend {
+            Backend::Memory(_) => StorageType::Memory,
+            Backend::Durable(_) => StorageType::Durable,
         }
     }
 
-    fn _put(&mut self, db: &DB, key: &u64, value: V) -> Result<(), Error> {
+    fn _put(&self, column: &Column, key: &u64, value: V) -> Result<(), Error> {
         self.size.fetch_add(1, Relaxed);
 
         let bytes = (self.to_bytes)(value)?;
 
-        db.put(&key.to_be_bytes(), bytes)?;
+        column.put(&key.to_be_bytes(), bytes);
 
         Ok(())
     }
 
     pub fn put(&mut self, key: &u64, value: V) -> Result<(), Error> {
-        let db = &DB::open_default(self.folder_path.clone())?;
-
-        self._put(db, key, value)?;
-
-        match self.storage_type {
-            StorageType::Memory => Ok(()),
-            StorageType::Durable => {
-                db.flush()?;
+        match self.backend {
+            Backend::Memory(ref mut map) => {
+                self.size.fetch_add(1, Relaxed);
+                map.insert(*key, value);
 
                 Ok(())
             }
+            Backend::Durable(ref column) => self._put(column, key, value),
         }
     }
 
     pub fn put_if_absent(&mut self, key: &u64, value: V) -> Result<bool, Error> {
-        let db = &DB::open_default(self.folder_path.clone())?;
+        match self.backend {
+            Backend::Memory(ref mut map) => {
+                if map.contains_key(key) {
+                    return Ok(false);
+                }
 
-        match self._get(db, key) {
-            Err(Error::Empty { .. }) => (),
-            Err(e) => return Err(e),
-            Ok(_) => return Ok(false),
-        }
+                self.size.fetch_add(1, Relaxed);
+                map.insert(*key, value);
 
-        self._put(db, key, value)?;
+                Ok(true)
+            }
+            Backend::Durable(ref column) => {
+                match self._get(column, key) {
+                    Err(Error::Empty { .. }) => (),
+                    Err(e) => return Err(e),
+                    Ok(_) => return Ok(false),
+                }
 
-        match self.storage_type {
-            StorageType::Memory => Ok(true),
-            StorageType::Durable => {
-                db.flush()?;
+                self._put(column, key, value)?;
 
                 Ok(true)
             }
         }
     }
 
-    fn _get(&self, db: &DB, key: &u64) -> Result<V, Error> {
-        let maybe_bytes = db.get(&key.to_be_bytes())?;
+    fn _get(&self, column: &Column, key: &u64) -> Result<V, Error> {
+        let maybe_bytes = column.get(&key.to_be_bytes())?;
 
-        let bytes = maybe_bytes.ok_or_else(|| Error::Empty {
-            message: "No element present".to_string(),
-        })?;
+        let bytes = maybe_bytes.ok_or_else(no_element)?;
 
         (self.from_bytes)(bytes)
     }
 
     pub fn get(&self, key: &u64) -> Result<V, Error> {
-        let db = &DB::open_default(self.folder_path.clone())?;
-
-        self._get(db, key)
+        match self.backend {
+            Backend::Memory(ref map) => map.get(key).cloned().ok_or_else(no_element),
+            Backend::Durable(ref column) => self._get(column, key),
+        }
     }
 
     pub fn update(&mut self, key: &u64, f: fn(value: V) -> V) -> Result<V, Error> {
-        let db = &DB::open_default(self.folder_path.clone())?;
-        let value = self._get(db, key)?;
+        match self.backend {
+            Backend::Memory(ref mut map) => {
+                let value = map.get_mut(key).ok_or_else(no_element)?;
+
+                *value = (f)(value.clone());
 
-        let new_value = (f)(value);
+                Ok(value.clone())
+            }
+            Backend::Durable(ref column) => {
+                let value = self._get(column, key)?;
 
-        self._put(db, key, new_value)?;
+                let new_value = (f)(value);
 
-        match self.storage_type {
-            StorageType::Memory => Ok(new_value),
-            StorageType::Durable => {
-                db.flush()?;
+                self._put(column, key, new_value.clone())?;
 
                 Ok(new_value)
             }
         }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.size.load(Relaxed) == 0
+    pub fn delete(&mut self, key: &u64) -> Result<(), Error> {
+        match self.backend {
+            Backend::Memory(ref mut map) => {
+                map.remove(key);
+
+                Ok(())
+            }
+            Backend::Durable(ref column) => {
+                column.delete(&key.to_be_bytes());
+
+                Ok(())
+            }
+        }
     }
-}
 
-impl<V> Drop for Storage<V>
-where
-    V: Copy,
-{
-    fn drop(&mut self) {
-        match self.storage_type {
-            StorageType::Memory => match DB::destroy(&Options::default(), self.folder_path.clone())
-            {
-                Ok(_) => (),
-                Err(e) => println!("failed to delete storage dir: {:?}", e),
-            },
-            StorageType::Durable => (),
+    pub fn get_all(&self) -> Result<Vec<(u64, V)>, Error> {
+        match self.backend {
+            Backend::Memory(ref map) => Ok(map
+                .iter()
+                .map(|(key, value)| (*key, value.clone()))
+                .collect()),
+            Backend::Durable(ref column) => {
+                let mut entries: Vec<(u64, V)> = vec![];
+
+                for (key, value) in column.entries_with_prefix(&[])? {
+                    let integer_key = (INTEGER_FROM_BYTES)(key)?;
+
+                    entries.push((integer_key, (self.from_bytes)(value)?));
+                }
+
+                Ok(entries)
+            }
+        }
+    }
+
+    /// Every entry as the key and value bytes a durable column holds them as
+    /// so that memory and durable storage export alike.
+    pub fn export(&self) -> Result<Vec<Entry>, Error> {
+        match self.backend {
+            Backend::Memory(ref map) => map
+                .iter()
+                .map(|(key, value)| {
+                    Ok((key.to_be_bytes().to_vec(), (self.to_bytes)(value.clone())?))
+                })
+                .collect(),
+            Backend::Durable(ref column) => column.entries_with_prefix(&[]),
+        }
+    }
+
+    /// Puts every entry of an export
+    pub fn import(&mut self, entries: Vec<Entry>) -> Result<(), Error> {
+        for (key, value) in entries {
+            expect_key_length(&key, 8)?;
+
+            match self.backend {
+                Backend::Memory(_) => {
+                    let value = (self.from_bytes)(value)?;
+
+                    self.put(&(INTEGER_FROM_BYTES)(key)?, value)?;
+                }
+                Backend::Durable(ref column) => {
+                    self.size.fetch_add(1, Relaxed);
+                    column.put(&key, value);
+                }
+            }
         }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size.load(Relaxed) == 0
     }
 }
@@ -1,5 +1,12 @@
+use sp_queue::error::Error;
 use sp_queue::feature_space::FeatureValue;
+use sp_queue::item::DequeuedItem;
+use sp_queue::lease::at_time;
+use sp_queue::storage::StorageType;
 use sp_queue::SortingPriorityQueue;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 #[macro_use]
 extern crate lazy_static;
@@ -12,6 +19,17 @@ lazy_static! {
     static ref DEFAULT_FEATURE_NAMES: Vec<String> = vec![LEAF_FEATURE_NAME.to_string()];
 }
 
+/// The data of the next item with the epoch the queue is at after dequeuing it
+fn dequeue_data(queue: &SortingPriorityQueue) -> (Option<Vec<u8>>, u64) {
+    let (maybe_item, epoch) = queue.dequeue().unwrap();
+
+    (maybe_item.map(DequeuedItem::into_data), epoch)
+}
+
+fn peek_data(queue: &SortingPriorityQueue) -> Option<Vec<u8>> {
+    queue.peek().unwrap().map(DequeuedItem::into_data)
+}
+
 #[test]
 fn must_be_empty_at_creation() {
     let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
@@ -20,17 +38,17 @@ fn must_be_empty_at_creation() {
 
 #[test]
 fn must_contain_enqueued_item() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
     let expected_element: Option<Vec<u8>> = Some(vec![1]);
 
     queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
 
-    assert_eq!(queue.peek().unwrap(), expected_element);
+    assert_eq!(peek_data(&queue), expected_element);
 }
 
 #[test]
 fn must_increment_the_epoch_for_each_state_change() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
 
     queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
 
@@ -43,20 +61,20 @@ fn must_increment_the_epoch_for_each_state_change() {
 
 #[test]
 fn peek_must_not_alter_contents() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
     let expected_element: Option<Vec<u8>> = Some(vec![1]);
 
     queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
     queue.enqueue(vec![2], DEFAULT_FEATURES.clone()).unwrap();
 
-    assert_eq!(queue.peek().unwrap(), expected_element);
-    assert_eq!(queue.peek().unwrap(), expected_element);
+    assert_eq!(peek_data(&queue), expected_element);
+    assert_eq!(peek_data(&queue), expected_element);
     assert_eq!(queue.size().unwrap(), 2);
 }
 
 #[test]
 fn must_decrease_size_when_items_are_removed() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
 
     queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
     queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
@@ -68,7 +86,7 @@ fn must_decrease_size_when_items_are_removed() {
 
 #[test]
 fn must_increase_size_when_items_are_enqueueed() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
 
     queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
     queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
@@ -78,33 +96,33 @@ fn must_increase_size_when_items_are_enqueueed() {
 
 #[test]
 fn must_return_dequeue_item() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
     let dequeue_item = vec![1];
 
     queue
         .enqueue(dequeue_item.clone(), DEFAULT_FEATURES.clone())
         .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(dequeue_item), 2));
+    assert_eq!(dequeue_data(&queue), (Some(dequeue_item), 2));
 }
 
 #[test]
 fn must_remove_dequeue_item_after_returning() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
     let dequeue_item = vec![1];
 
     queue
         .enqueue(dequeue_item.clone(), DEFAULT_FEATURES.clone())
         .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(dequeue_item), 2));
+    assert_eq!(dequeue_data(&queue), (Some(dequeue_item), 2));
 
-    assert_eq!(queue.dequeue().unwrap(), (None, 2));
+    assert_eq!(dequeue_data(&queue), (None, 2));
 }
 
 #[test]
 fn must_return_items_in_order() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
     let dequeue_item = vec![2];
     let not_dequeue_item = vec![1];
 
@@ -116,12 +134,12 @@ fn must_return_items_in_order() {
         .enqueue(not_dequeue_item.clone(), DEFAULT_FEATURES.clone())
         .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(dequeue_item), 3));
+    assert_eq!(dequeue_data(&queue), (Some(dequeue_item), 3));
 }
 
 #[test]
 fn must_balance_selection_by_leaf_feature() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
 
     let first_item: Vec<u8> = vec![2];
     let unseen_item: Vec<u8> = vec![1];
@@ -140,9 +158,9 @@ fn must_balance_selection_by_leaf_feature() {
         )
         .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(first_item), 4));
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 4));
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(fairest_item), 5));
+    assert_eq!(dequeue_data(&queue), (Some(fairest_item), 5));
 }
 
 #[test]
@@ -150,7 +168,7 @@ fn must_balance_selection_by_feature_heirarchy() {
     let feature_names: Vec<String> =
         vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
 
-    let mut queue = SortingPriorityQueue::new(feature_names).unwrap();
+    let queue = SortingPriorityQueue::new(feature_names).unwrap();
 
     let first_item: Vec<u8> = vec![2];
     let unseen_item: Vec<u8> = vec![1];
@@ -184,9 +202,9 @@ fn must_balance_selection_by_feature_heirarchy() {
         )
         .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(first_item), 4));
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 4));
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(fairest_item), 5));
+    assert_eq!(dequeue_data(&queue), (Some(fairest_item), 5));
 }
 
 #[test]
@@ -194,7 +212,7 @@ fn should_be_drained_by_feature_heirarchy() {
     let feature_names: Vec<String> =
         vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
 
-    let mut queue = SortingPriorityQueue::new(feature_names).unwrap();
+    let queue = SortingPriorityQueue::new(feature_names).unwrap();
 
     let first_item: Vec<u8> = vec![4];
     let second_last_item: Vec<u8> = vec![3];
@@ -238,34 +256,46 @@ fn should_be_drained_by_feature_heirarchy() {
         )
         .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(first_item), 5));
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 5));
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(fairest_item), 6));
+    assert_eq!(dequeue_data(&queue), (Some(fairest_item), 6));
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(second_last_item), 7));
+    assert_eq!(dequeue_data(&queue), (Some(second_last_item), 7));
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(last_item), 8));
+    assert_eq!(dequeue_data(&queue), (Some(last_item), 8));
 
-    assert_eq!(queue.dequeue().unwrap(), (None, 8));
+    assert_eq!(dequeue_data(&queue), (None, 8));
     assert_eq!(queue.size().unwrap(), 0);
 }
 
 #[test]
 fn must_validate_features_size() {
-    let mut queue = SortingPriorityQueue::new(vec![]).unwrap();
+    let queue = SortingPriorityQueue::new(vec![]).unwrap();
 
     let result = queue.enqueue(vec![1], DEFAULT_FEATURES.clone());
 
-    assert_eq!(result.is_err(), true);
+    assert_eq!(
+        result,
+        Err(Error::InvalidDimension {
+            expected: 0,
+            actual: 1
+        })
+    );
 }
 
 #[test]
 fn must_validate_features_exist_in_space() {
-    let mut queue = SortingPriorityQueue::new(vec!["Different Name".to_string()]).unwrap();
+    let queue = SortingPriorityQueue::new(vec!["Different Name".to_string()]).unwrap();
 
     let result = queue.enqueue(vec![1], DEFAULT_FEATURES.clone());
 
-    assert_eq!(result.is_err(), true);
+    assert_eq!(
+        result,
+        Err(Error::UnknownFeatureNames {
+            expected: vec!["Different Name".to_string()],
+            actual: DEFAULT_FEATURE_NAMES.to_vec()
+        })
+    );
 }
 
 #[test]
@@ -273,7 +303,7 @@ fn must_guarantee_fair_retrieval_by_feature_value_regardless_of_path() {
     let feature_names: Vec<String> =
         vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
 
-    let mut queue = SortingPriorityQueue::new(feature_names).unwrap();
+    let queue = SortingPriorityQueue::new(feature_names).unwrap();
 
     let first_item = vec![3];
     let last_item = vec![2];
@@ -309,15 +339,15 @@ fn must_guarantee_fair_retrieval_by_feature_value_regardless_of_path() {
 
     assert_eq!(queue.size().unwrap(), 3);
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(first_item), 4));
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 4));
 
     assert_eq!(queue.size().unwrap(), 2);
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(fairest_item), 5));
+    assert_eq!(dequeue_data(&queue), (Some(fairest_item), 5));
 
     assert_eq!(queue.size().unwrap(), 1);
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(last_item), 6));
+    assert_eq!(dequeue_data(&queue), (Some(last_item), 6));
 }
 
 #[test]
@@ -325,7 +355,7 @@ fn must_guarantee_fair_retrieval_after_items_have_been_removed() {
     let feature_names: Vec<String> =
         vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
 
-    let mut queue = SortingPriorityQueue::new(feature_names).unwrap();
+    let queue = SortingPriorityQueue::new(feature_names).unwrap();
 
     let first_item = vec![3];
     let last_item = vec![2];
@@ -341,7 +371,7 @@ fn must_guarantee_fair_retrieval_after_items_have_been_removed() {
         )
         .unwrap();
     assert_eq!(queue.size().unwrap(), 1);
-    assert_eq!(queue.dequeue().unwrap(), (Some(first_item), 2));
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 2));
 
     queue
         .enqueue(
@@ -363,8 +393,8 @@ fn must_guarantee_fair_retrieval_after_items_have_been_removed() {
         .unwrap();
 
     assert_eq!(queue.size().unwrap(), 2);
-    assert_eq!(queue.dequeue().unwrap(), (Some(fairest_item), 5));
-    assert_eq!(queue.dequeue().unwrap(), (Some(last_item), 6));
+    assert_eq!(dequeue_data(&queue), (Some(fairest_item), 5));
+    assert_eq!(dequeue_data(&queue), (Some(last_item), 6));
     assert_eq!(queue.size().unwrap(), 0);
 }
 
@@ -373,7 +403,7 @@ fn after_being_drained_must_accept_and_return_new_items() {
     let feature_names: Vec<String> =
         vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
 
-    let mut queue = SortingPriorityQueue::new(feature_names).unwrap();
+    let queue = SortingPriorityQueue::new(feature_names).unwrap();
 
     let first_item = vec![4];
     let last_item = vec![2];
@@ -388,9 +418,9 @@ fn after_being_drained_must_accept_and_return_new_items() {
         )
         .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(first_item), 2));
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 2));
 
-    assert_eq!(queue.dequeue().unwrap(), (None, 2));
+    assert_eq!(dequeue_data(&queue), (None, 2));
     assert_eq!(queue.size().unwrap(), 0);
 
     queue
@@ -403,15 +433,15 @@ fn after_being_drained_must_accept_and_return_new_items() {
         )
         .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(last_item), 4));
+    assert_eq!(dequeue_data(&queue), (Some(last_item), 4));
 
-    assert_eq!(queue.dequeue().unwrap(), (None, 4));
+    assert_eq!(dequeue_data(&queue), (None, 4));
     assert_eq!(queue.size().unwrap(), 0);
 }
 
 #[test]
 fn must_increment_step_for_each_enqueue() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
 
     let enqueue_result = queue.enqueue(vec![1], DEFAULT_FEATURES.clone());
 
@@ -424,7 +454,7 @@ fn must_increment_step_for_each_enqueue() {
 
 #[test]
 fn must_increment_step_for_each_dequeue() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
 
     let item: Vec<u8> = vec![1];
 
@@ -432,7 +462,7 @@ fn must_increment_step_for_each_dequeue() {
 
     assert_eq!(enqueue_result.unwrap(), 1);
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(item), 2));
+    assert_eq!(dequeue_data(&queue), (Some(item), 2));
 }
 
 #[test]
@@ -440,7 +470,7 @@ fn must_maintain_epoch_between_instances_when_durable() {
     let directory = "/tmp/durable".to_string();
     std::fs::create_dir_all(directory.clone()).unwrap();
 
-    let mut queue =
+    let queue =
         SortingPriorityQueue::new_durable(DEFAULT_FEATURE_NAMES.to_vec(), directory.clone())
             .unwrap();
 
@@ -468,7 +498,7 @@ fn must_maintain_epoch_between_instances_when_durable() {
 
 #[test]
 fn must_not_maintain_epoch_between_instances_when_not_durable() {
-    let mut queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
 
     let item: Vec<u8> = vec![1];
 
@@ -496,7 +526,7 @@ fn must_maintain_feature_space_between_instances_when_durable_and_queue_empty()
     let feature_names: Vec<String> =
         vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
 
-    let mut queue =
+    let queue =
         SortingPriorityQueue::new_durable(feature_names.clone(), directory.clone()).unwrap();
 
     let first_item: Vec<u8> = vec![4];
@@ -513,11 +543,11 @@ fn must_maintain_feature_space_between_instances_when_durable_and_queue_empty()
         )
         .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(first_item), 2));
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 2));
 
     drop(queue);
 
-    let mut queue = SortingPriorityQueue::new_durable(feature_names, directory.clone()).unwrap();
+    let queue = SortingPriorityQueue::new_durable(feature_names, directory.clone()).unwrap();
 
     queue
         .enqueue(
@@ -537,11 +567,11 @@ fn must_maintain_feature_space_between_instances_when_durable_and_queue_empty()
             ],
         )
         .unwrap();
-    assert_eq!(queue.dequeue().unwrap(), (Some(fairest_item), 5));
+    assert_eq!(dequeue_data(&queue), (Some(fairest_item), 5));
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(last_item), 6));
+    assert_eq!(dequeue_data(&queue), (Some(last_item), 6));
 
-    assert_eq!(queue.dequeue().unwrap(), (None, 6));
+    assert_eq!(dequeue_data(&queue), (None, 6));
 
     match std::fs::remove_dir_all(directory.clone()) {
         Ok(_) => (),
@@ -559,7 +589,7 @@ fn must_retain_items_between_instances_when_durable() {
     }
     std::fs::create_dir_all(directory.clone()).unwrap();
 
-    let mut queue =
+    let queue =
         SortingPriorityQueue::new_durable(DEFAULT_FEATURE_NAMES.to_vec(), directory.clone())
             .unwrap();
 
@@ -571,14 +601,626 @@ fn must_retain_items_between_instances_when_durable() {
 
     drop(queue);
 
-    let mut queue =
+    let queue =
+        SortingPriorityQueue::new_durable(DEFAULT_FEATURE_NAMES.to_vec(), directory.clone())
+            .unwrap();
+
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 2));
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+#[test]
+fn must_retain_items_after_closing_when_durable() {
+    let directory = "/tmp/durable8".to_string();
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+
+    let queue =
+        SortingPriorityQueue::new_durable(DEFAULT_FEATURE_NAMES.to_vec(), directory.clone())
+            .unwrap();
+
+    let item: Vec<u8> = vec![8];
+
+    queue
+        .enqueue(item.clone(), DEFAULT_FEATURES.clone())
+        .unwrap();
+
+    queue.close().unwrap();
+
+    let queue = SortingPriorityQueue::open_durable(directory.clone()).unwrap();
+
+    assert_eq!(dequeue_data(&queue), (Some(item), 2));
+
+    queue.destroy().unwrap();
+}
+
+#[test]
+fn must_hide_leased_item_until_acked() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let item: Vec<u8> = vec![1];
+
+    queue
+        .enqueue(item.clone(), DEFAULT_FEATURES.clone())
+        .unwrap();
+
+    let (maybe_lease, epoch) = queue.dequeue_with_lease(Duration::from_secs(60)).unwrap();
+    let lease = maybe_lease.unwrap();
+
+    assert_eq!(lease.get_item().get_data(), &item);
+    assert_eq!(epoch, 2);
+    assert_eq!(queue.size().unwrap(), 0);
+    assert_eq!(peek_data(&queue), None);
+
+    queue.ack(lease.get_id()).unwrap();
+
+    assert_eq!(dequeue_data(&queue), (None, 2));
+}
+
+#[test]
+fn must_return_nacked_item_to_front_of_queue() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+    let first_item: Vec<u8> = vec![1];
+    let second_item: Vec<u8> = vec![2];
+
+    queue
+        .enqueue(first_item.clone(), DEFAULT_FEATURES.clone())
+        .unwrap();
+    queue
+        .enqueue(second_item.clone(), DEFAULT_FEATURES.clone())
+        .unwrap();
+
+    let (maybe_lease, _) = queue.dequeue_with_lease(Duration::from_secs(60)).unwrap();
+    let lease = maybe_lease.unwrap();
+
+    assert_eq!(queue.nack(lease.get_id()).unwrap(), 4);
+    assert_eq!(queue.size().unwrap(), 2);
+
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 5));
+    assert_eq!(dequeue_data(&queue), (Some(second_item), 6));
+}
+
+#[test]
+fn must_requeue_item_with_original_features_when_lease_expires() {
+    let feature_names: Vec<String> =
+        vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
+    let features = vec![
+        FeatureValue::new(ROOT_FEATURE_NAME.to_string(), 1),
+        FeatureValue::new(LEAF_FEATURE_NAME.to_string(), 2),
+    ];
+
+    let queue = SortingPriorityQueue::new(feature_names).unwrap();
+    let item: Vec<u8> = vec![1];
+
+    queue.enqueue(item.clone(), features.clone()).unwrap();
+
+    let (maybe_lease, _) = queue.dequeue_with_lease(Duration::from_millis(0)).unwrap();
+    let lease = maybe_lease.unwrap();
+
+    assert_eq!(lease.get_features(), &features);
+
+    assert_eq!(queue.requeue_expired_leases().unwrap(), 1);
+    assert_eq!(queue.size().unwrap(), 1);
+    assert!(queue.ack(lease.get_id()).is_err());
+
+    let (maybe_lease, _) = queue.dequeue_with_lease(Duration::from_secs(60)).unwrap();
+
+    assert_eq!(maybe_lease.unwrap().get_features(), &features);
+}
+
+#[test]
+fn must_only_be_able_to_dequeue_with_items_or_expired_leases() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    assert!(!queue.can_dequeue().unwrap());
+
+    queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
+    assert!(queue.can_dequeue().unwrap());
+
+    let (maybe_lease, _) = queue.dequeue_with_lease(Duration::from_secs(60)).unwrap();
+    assert!(!queue.can_dequeue().unwrap());
+
+    queue
+        .extend_lease(maybe_lease.unwrap().get_id(), Duration::from_millis(0))
+        .unwrap();
+    assert!(queue.can_dequeue().unwrap());
+}
+
+#[test]
+fn must_extend_active_lease() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
+
+    let (maybe_lease, _) = queue.dequeue_with_lease(Duration::from_secs(60)).unwrap();
+    let lease = maybe_lease.unwrap();
+
+    let extended_lease = queue
+        .extend_lease(lease.get_id(), Duration::from_secs(120))
+        .unwrap();
+
+    assert_eq!(extended_lease.get_id(), lease.get_id());
+    assert!(extended_lease.get_deadline() > lease.get_deadline());
+}
+
+#[test]
+fn must_requeue_leases_in_order_of_deadline_when_durable() {
+    let directory = "/tmp/durable10".to_string();
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+
+    let queue =
+        SortingPriorityQueue::new_durable(DEFAULT_FEATURE_NAMES.to_vec(), directory.clone())
+            .unwrap();
+
+    for data in 1..=3 {
+        queue.enqueue(vec![data], DEFAULT_FEATURES.clone()).unwrap();
+    }
+
+    at_time(1000, || {
+        let lease_ids: Vec<u64> = [100, 300, 200]
+            .iter()
+            .map(|lease_millis| {
+                let (maybe_lease, _) = queue
+                    .dequeue_with_lease(Duration::from_millis(*lease_millis))
+                    .unwrap();
+
+                maybe_lease.unwrap().get_id()
+            })
+            .collect();
+
+        // The third item's lease now ends after the second's
+        queue
+            .extend_lease(lease_ids[2], Duration::from_millis(500))
+            .unwrap();
+    });
+
+    queue.close().unwrap();
+
+    let queue = SortingPriorityQueue::open_durable(directory).unwrap();
+
+    assert_eq!(at_time(1050, || queue.requeue_expired_leases().unwrap()), 0);
+    assert_eq!(at_time(1250, || queue.requeue_expired_leases().unwrap()), 1);
+    assert_eq!(at_time(1250, || dequeue_data(&queue).0), Some(vec![1]));
+    assert_eq!(at_time(1400, || queue.requeue_expired_leases().unwrap()), 1);
+    assert_eq!(at_time(1400, || dequeue_data(&queue).0), Some(vec![2]));
+    assert_eq!(at_time(1600, || queue.requeue_expired_leases().unwrap()), 1);
+    assert_eq!(at_time(1600, || dequeue_data(&queue).0), Some(vec![3]));
+
+    queue.destroy().unwrap();
+}
+
+#[test]
+fn must_reject_unknown_lease() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    assert_eq!(queue.ack(1), Err(Error::LeaseNotFound { lease_id: 1 }));
+    assert_eq!(queue.nack(1), Err(Error::LeaseNotFound { lease_id: 1 }));
+    assert!(matches!(
+        queue.extend_lease(1, Duration::from_secs(1)),
+        Err(Error::LeaseNotFound { lease_id: 1 })
+    ));
+}
+
+#[test]
+fn must_reopen_durable_queue_from_stored_features() {
+    let directory = "/tmp/durable4".to_string();
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+    std::fs::create_dir_all(directory.clone()).unwrap();
+
+    let queue =
+        SortingPriorityQueue::new_durable(DEFAULT_FEATURE_NAMES.to_vec(), directory.clone())
+            .unwrap();
+
+    let first_item: Vec<u8> = vec![4];
+
+    queue
+        .enqueue(first_item.clone(), DEFAULT_FEATURES.clone())
+        .unwrap();
+
+    drop(queue);
+
+    let queue = SortingPriorityQueue::open_durable(directory.clone()).unwrap();
+
+    assert_eq!(
+        queue.feature_names().unwrap(),
+        DEFAULT_FEATURE_NAMES.to_vec()
+    );
+    assert_eq!(dequeue_data(&queue), (Some(first_item), 2));
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+#[test]
+fn must_fail_to_reopen_incomplete_durable_queue() {
+    let directory = "/tmp/durable5".to_string();
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+    std::fs::create_dir_all(directory.clone()).unwrap();
+
+    assert!(matches!(
+        SortingPriorityQueue::open_durable(directory.clone()),
+        Err(Error::NotInitialised { .. })
+    ));
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+#[test]
+fn must_keep_durable_queue_in_one_database() {
+    let directory = "/tmp/durable7".to_string();
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+    std::fs::create_dir_all(directory.clone()).unwrap();
+
+    let queue =
+        SortingPriorityQueue::new_durable(DEFAULT_FEATURE_NAMES.to_vec(), directory.clone())
+            .unwrap();
+
+    queue.enqueue(vec![1], DEFAULT_FEATURES.to_vec()).unwrap();
+
+    let subdirectories = std::fs::read_dir(directory.clone())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().is_dir())
+        .count();
+
+    assert_eq!(subdirectories, 0);
+
+    drop(queue);
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+#[test]
+fn must_not_write_to_disk_when_not_durable() {
+    let count_temporary_directories = || {
+        std::fs::read_dir("/tmp/spqr")
+            .map(|entries| entries.count())
+            .unwrap_or(0)
+    };
+    let directories_before = count_temporary_directories();
+
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
+    queue.dequeue().unwrap();
+
+    assert_eq!(queue.storage_type(), StorageType::Memory);
+    assert_eq!(count_temporary_directories(), directories_before);
+}
+
+#[test]
+fn must_count_items_for_each_feature_value() {
+    let feature_names: Vec<String> =
+        vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
+
+    let queue = SortingPriorityQueue::new(feature_names).unwrap();
+
+    assert_eq!(queue.root_index().unwrap(), None);
+
+    for (root_value, leaf_value) in [(1, 1), (1, 2), (2, 1)].iter() {
+        queue
+            .enqueue(
+                vec![1],
+                vec![
+                    FeatureValue::new(ROOT_FEATURE_NAME.to_string(), *root_value),
+                    FeatureValue::new(LEAF_FEATURE_NAME.to_string(), *leaf_value),
+                ],
+            )
+            .unwrap();
+    }
+
+    assert!(queue.root_index().unwrap().is_some());
+    assert_eq!(
+        queue.feature_value_counts().unwrap(),
+        vec![
+            (FeatureValue::new(LEAF_FEATURE_NAME.to_string(), 1), 2),
+            (FeatureValue::new(LEAF_FEATURE_NAME.to_string(), 2), 1),
+            (FeatureValue::new(ROOT_FEATURE_NAME.to_string(), 1), 2),
+            (FeatureValue::new(ROOT_FEATURE_NAME.to_string(), 2), 1),
+        ]
+    );
+}
+
+#[test]
+fn must_remove_storage_when_destroyed() {
+    let directory = "/tmp/durable6".to_string();
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+    std::fs::create_dir_all(directory.clone()).unwrap();
+
+    let queue =
         SortingPriorityQueue::new_durable(DEFAULT_FEATURE_NAMES.to_vec(), directory.clone())
             .unwrap();
 
-    assert_eq!(queue.dequeue().unwrap(), (Some(first_item), 2));
+    queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
+
+    queue.destroy().unwrap();
+
+    assert!(!std::path::Path::new(&directory).exists());
+}
+
+#[test]
+fn must_not_remove_the_parent_of_a_hostile_queue_folder() {
+    let parent = "/tmp/hostile_parent".to_string();
+
+    match std::fs::remove_dir_all(parent.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+    std::fs::create_dir_all(format!("{}/queue", parent)).unwrap();
+
+    // Resolves to the parent itself
+    let queue = SortingPriorityQueue::new_durable(
+        DEFAULT_FEATURE_NAMES.to_vec(),
+        format!("{}/queue/..", parent),
+    )
+    .unwrap();
+
+    assert!(queue.destroy().is_err());
+    assert!(std::path::Path::new(&parent).join("queue").exists());
+
+    std::fs::remove_dir_all(parent).unwrap();
+}
+
+fn two_feature_items() -> Vec<(Vec<u8>, Vec<FeatureValue>)> {
+    (0..6)
+        .map(|item: u8| {
+            (
+                vec![item],
+                vec![
+                    FeatureValue::new(ROOT_FEATURE_NAME.to_string(), (item % 2) as usize),
+                    FeatureValue::new(LEAF_FEATURE_NAME.to_string(), (item % 3) as usize),
+                ],
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn must_dequeue_many_in_the_same_order_as_single_dequeues() {
+    let feature_names: Vec<String> =
+        vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
+
+    let single_queue = SortingPriorityQueue::new(feature_names.clone()).unwrap();
+    let batch_queue = SortingPriorityQueue::new(feature_names).unwrap();
+
+    for (item, features) in two_feature_items() {
+        single_queue.enqueue(item, features).unwrap();
+    }
+    assert_eq!(
+        batch_queue.enqueue_batch(two_feature_items()).unwrap(),
+        vec![1, 2, 3, 4, 5, 6]
+    );
+
+    let mut single_items: Vec<Vec<u8>> = vec![];
+    while let (Some(item), _) = single_queue.dequeue().unwrap() {
+        single_items.push(item.into_data());
+    }
+
+    let (first_items, _) = batch_queue.dequeue_many(4).unwrap();
+    let (rest_items, epoch) = batch_queue.dequeue_many(4).unwrap();
+
+    assert_eq!(first_items.len(), 4);
+    assert_eq!(rest_items.len(), 2);
+    assert_eq!(
+        [first_items, rest_items]
+            .concat()
+            .into_iter()
+            .map(DequeuedItem::into_data)
+            .collect::<Vec<Vec<u8>>>(),
+        single_items
+    );
+    assert_eq!(epoch, single_queue.get_epoch().unwrap());
+}
+
+#[test]
+fn must_enqueue_nothing_when_any_batch_item_is_invalid() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    let result = queue.enqueue_batch(vec![
+        (vec![1], DEFAULT_FEATURES.clone()),
+        (
+            vec![2],
+            vec![FeatureValue::new(ROOT_FEATURE_NAME.to_string(), 1)],
+        ),
+    ]);
+
+    assert!(result.is_err());
+    assert_eq!(queue.size().unwrap(), 0);
+    assert_eq!(queue.get_epoch().unwrap(), 0);
+}
+
+#[test]
+fn must_lease_many_items() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    queue
+        .enqueue_batch(vec![
+            (vec![1], DEFAULT_FEATURES.clone()),
+            (vec![2], DEFAULT_FEATURES.clone()),
+        ])
+        .unwrap();
+
+    let (leases, _) = queue
+        .dequeue_many_with_lease(3, Duration::from_secs(60))
+        .unwrap();
+
+    assert_eq!(
+        leases
+            .iter()
+            .map(|lease| lease.get_item().get_data().clone())
+            .collect::<Vec<Vec<u8>>>(),
+        vec![vec![1], vec![2]]
+    );
+    assert_eq!(queue.size().unwrap(), 0);
+
+    queue.nack(leases[1].get_id()).unwrap();
+
+    assert_eq!(dequeue_data(&queue).0, Some(vec![2]));
+}
+
+#[test]
+fn must_commit_batches_when_durable() {
+    let directory = "/tmp/durable8".to_string();
 
     match std::fs::remove_dir_all(directory.clone()) {
         Ok(_) => (),
         Err(e) => println!("{:?}", e),
     }
+    std::fs::create_dir_all(directory.clone()).unwrap();
+
+    let feature_names: Vec<String> =
+        vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()];
+
+    let queue = SortingPriorityQueue::new_durable(feature_names, directory.clone()).unwrap();
+
+    queue.enqueue_batch(two_feature_items()).unwrap();
+    let (first_items, _) = queue.dequeue_many(2).unwrap();
+
+    drop(queue);
+
+    let queue = SortingPriorityQueue::open_durable(directory.clone()).unwrap();
+
+    let (rest_items, _) = queue.dequeue_many(10).unwrap();
+
+    assert_eq!(first_items.len(), 2);
+    assert_eq!(rest_items.len(), 4);
+
+    queue.destroy().unwrap();
+}
+
+#[test]
+fn must_return_nothing_when_dequeued_before_any_enqueue() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    assert_eq!(peek_data(&queue), None);
+    assert_eq!(dequeue_data(&queue), (None, 0));
+}
+
+#[test]
+fn must_wake_waiting_dequeue_when_item_is_enqueued() {
+    let queue = Arc::new(SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap());
+
+    let enqueuing_queue = queue.clone();
+    let enqueuer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        enqueuing_queue
+            .enqueue(vec![1], DEFAULT_FEATURES.clone())
+            .unwrap();
+    });
+
+    let (maybe_item, _) = queue.dequeue_wait(Duration::from_secs(10)).unwrap();
+
+    enqueuer.join().unwrap();
+
+    assert_eq!(maybe_item.map(DequeuedItem::into_data), Some(vec![1]));
+}
+
+#[test]
+fn must_stop_waiting_for_an_item_after_timeout() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
+    queue.dequeue().unwrap();
+
+    assert_eq!(
+        queue.dequeue_wait(Duration::from_millis(20)).unwrap(),
+        (None, 2)
+    );
+}
+
+#[test]
+fn must_wake_waiting_dequeue_when_lease_expires() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
+    queue.dequeue_with_lease(Duration::from_millis(50)).unwrap();
+
+    let (maybe_item, _) = queue.dequeue_wait(Duration::from_secs(10)).unwrap();
+
+    assert_eq!(maybe_item.map(DequeuedItem::into_data), Some(vec![1]));
+}
+
+#[test]
+fn must_return_dequeued_item_with_the_features_it_was_enqueued_with() {
+    let queue = SortingPriorityQueue::new(DEFAULT_FEATURE_NAMES.to_vec()).unwrap();
+
+    let epoch = at_time(1234, || {
+        queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap()
+    });
+    let expected_item = DequeuedItem::new(vec![1], DEFAULT_FEATURES.clone(), epoch, 1234);
+
+    assert_eq!(queue.peek().unwrap(), Some(expected_item.clone()));
+    assert_eq!(queue.dequeue().unwrap(), (Some(expected_item), 2));
+}
+
+#[test]
+fn must_keep_enqueue_details_of_nacked_item_when_durable() {
+    let directory = "/tmp/durable9".to_string();
+
+    match std::fs::remove_dir_all(directory.clone()) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+
+    let queue =
+        SortingPriorityQueue::new_durable(DEFAULT_FEATURE_NAMES.to_vec(), directory.clone())
+            .unwrap();
+
+    at_time(1234, || {
+        queue.enqueue(vec![1], DEFAULT_FEATURES.clone()).unwrap();
+    });
+    let (maybe_lease, _) = queue.dequeue_with_lease(Duration::from_secs(60)).unwrap();
+    let lease = maybe_lease.unwrap();
+    queue.nack(lease.get_id()).unwrap();
+
+    queue.close().unwrap();
+
+    let queue = SortingPriorityQueue::open_durable(directory).unwrap();
+    let (maybe_item, _) = queue.dequeue().unwrap();
+
+    assert_eq!(
+        maybe_item,
+        Some(DequeuedItem::new(
+            vec![1],
+            DEFAULT_FEATURES.clone(),
+            1,
+            1234
+        ))
+    );
+    assert_eq!(lease.get_item(), &maybe_item.unwrap());
+
+    queue.destroy().unwrap();
 }
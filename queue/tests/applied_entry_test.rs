@@ -0,0 +1,87 @@
+use sp_queue::feature_space::FeatureValue;
+use sp_queue::snapshot::QueueSnapshot;
+use sp_queue::{applying_entry, SortingPriorityQueue};
+
+fn feature_names() -> Vec<String> {
+    vec!["feature".to_string()]
+}
+
+fn features(value: usize) -> Vec<FeatureValue> {
+    vec![FeatureValue::new("feature".to_string(), value)]
+}
+
+fn remove_directory(directory: &str) {
+    match std::fs::remove_dir_all(directory) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+#[test]
+fn must_record_no_entry_for_writes_made_outside_of_a_log() {
+    let queue = SortingPriorityQueue::new(feature_names()).unwrap();
+
+    queue.enqueue(vec![1], features(1)).unwrap();
+
+    assert_eq!(queue.applied_index().unwrap(), 0);
+}
+
+#[test]
+fn must_record_the_entry_a_queue_was_created_by() {
+    let queue = applying_entry(3, || SortingPriorityQueue::new(feature_names())).unwrap();
+
+    assert_eq!(queue.applied_index().unwrap(), 3);
+}
+
+#[test]
+fn must_keep_the_last_entry_a_durable_queue_applied_across_a_restart() {
+    let directory = "/tmp/applied_entry1".to_string();
+    remove_directory(&directory);
+
+    let queue = applying_entry(3, || {
+        SortingPriorityQueue::new_durable(feature_names(), directory.clone())
+    })
+    .unwrap();
+    applying_entry(5, || queue.enqueue(vec![1], features(1))).unwrap();
+    queue.close().unwrap();
+
+    let queue = SortingPriorityQueue::open_durable(directory.clone()).unwrap();
+
+    assert_eq!(queue.applied_index().unwrap(), 5);
+    assert_eq!(queue.size().unwrap(), 1);
+
+    queue.destroy().unwrap();
+}
+
+#[test]
+fn must_record_the_entry_of_an_operation_that_failed_without_its_writes() {
+    let directory = "/tmp/applied_entry2".to_string();
+    remove_directory(&directory);
+
+    let queue = SortingPriorityQueue::new_durable(feature_names(), directory.clone()).unwrap();
+    queue.enqueue(vec![1], features(1)).unwrap();
+
+    assert!(applying_entry(8, || queue.ack(42)).is_err());
+    queue.close().unwrap();
+
+    let queue = SortingPriorityQueue::open_durable(directory.clone()).unwrap();
+
+    assert_eq!(queue.applied_index().unwrap(), 8);
+    assert_eq!(queue.size().unwrap(), 1);
+
+    queue.destroy().unwrap();
+}
+
+#[test]
+fn must_carry_the_applied_entry_in_a_snapshot() {
+    let queue = SortingPriorityQueue::new(feature_names()).unwrap();
+    applying_entry(9, || queue.enqueue(vec![1], features(1))).unwrap();
+
+    let snapshot = QueueSnapshot::from_bytes(&queue.export().unwrap().to_bytes().unwrap()).unwrap();
+
+    assert_eq!(snapshot.applied_index().unwrap(), 9);
+
+    let imported = SortingPriorityQueue::import(snapshot, String::new()).unwrap();
+
+    assert_eq!(imported.applied_index().unwrap(), 9);
+}
@@ -0,0 +1,202 @@
+use sp_queue::feature_space::{create_hash, FeatureSpace, FeatureValue};
+use sp_queue::SortingPriorityQueue;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::thread;
+
+static LEAF_FEATURE_NAME: &str = "leaf";
+static ROOT_FEATURE_NAME: &str = "root";
+
+const THREADS: usize = 8;
+
+const ITEMS_PER_VALUE: usize = 50;
+
+fn assert_send_and_sync<T: Send + Sync>() {}
+
+fn feature_names() -> Vec<String> {
+    vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()]
+}
+
+fn features(root_value: usize, leaf_value: usize) -> Vec<FeatureValue> {
+    vec![
+        FeatureValue::new(ROOT_FEATURE_NAME.to_string(), root_value),
+        FeatureValue::new(LEAF_FEATURE_NAME.to_string(), leaf_value),
+    ]
+}
+
+/// Every combination of two root values and two leaf values
+fn all_features() -> Vec<Vec<FeatureValue>> {
+    vec![
+        features(1, 1),
+        features(1, 2),
+        features(2, 1),
+        features(2, 2),
+    ]
+}
+
+fn feature_space_with_items() -> FeatureSpace {
+    let feature_space = FeatureSpace::new(feature_names(), None).unwrap();
+
+    for _ in 0..ITEMS_PER_VALUE {
+        for features in all_features() {
+            let leaf_index = create_hash(&features);
+
+            feature_space.add_item(features, leaf_index).unwrap();
+        }
+    }
+
+    feature_space
+}
+
+/// Runs the operation on every thread at once collecting what each returns
+fn on_every_thread<T: Send + 'static>(
+    operation: impl Fn() -> Vec<T> + Send + Sync + 'static,
+) -> Vec<T> {
+    let operation = Arc::new(operation);
+
+    let handles: Vec<thread::JoinHandle<Vec<T>>> = (0..THREADS)
+        .map(|_| {
+            let operation = operation.clone();
+
+            thread::spawn(move || operation())
+        })
+        .collect();
+
+    handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect()
+}
+
+fn use_every_leaf(feature_space: &FeatureSpace) -> Vec<(u64, u64)> {
+    let mut used = vec![];
+
+    while let Some((leaf_index, epoch_step)) = feature_space.use_next_leaf_feature().unwrap() {
+        used.push((epoch_step, leaf_index));
+    }
+
+    used
+}
+
+#[test]
+fn feature_space_and_queue_must_be_send_and_sync() {
+    assert_send_and_sync::<FeatureSpace>();
+    assert_send_and_sync::<SortingPriorityQueue>();
+}
+
+#[test]
+fn must_claim_a_distinct_epoch_step_for_every_concurrent_use() {
+    let feature_space = Arc::new(feature_space_with_items());
+    let shared_feature_space = feature_space.clone();
+
+    let used = on_every_thread(move || use_every_leaf(&shared_feature_space));
+
+    let epoch_steps: HashSet<u64> = used.iter().map(|(epoch_step, _)| *epoch_step).collect();
+
+    assert_eq!(used.len(), ITEMS_PER_VALUE * all_features().len());
+    assert_eq!(epoch_steps.len(), used.len());
+    assert_eq!(feature_space.total_items().unwrap(), 0);
+}
+
+#[test]
+fn must_use_leaves_in_the_same_order_concurrently_as_sequentially() {
+    let feature_space = Arc::new(feature_space_with_items());
+
+    let mut expected = use_every_leaf(&feature_space_with_items());
+    let mut used = on_every_thread(move || use_every_leaf(&feature_space));
+
+    // Each use is ordered by the epoch step it claimed
+    expected.sort();
+    used.sort();
+
+    assert_eq!(used, expected);
+}
+
+#[test]
+fn must_use_each_item_once_while_items_are_added_concurrently() {
+    let feature_space = Arc::new(FeatureSpace::new(feature_names(), None).unwrap());
+    let adding_feature_space = feature_space.clone();
+    let using_feature_space = feature_space.clone();
+
+    let adder = thread::spawn(move || {
+        let mut added = vec![];
+
+        for _ in 0..ITEMS_PER_VALUE {
+            for features in all_features() {
+                let leaf_index = create_hash(&features);
+
+                added.push(adding_feature_space.add_item(features, leaf_index).unwrap());
+            }
+        }
+
+        added
+    });
+
+    let mut used = on_every_thread(move || use_every_leaf(&using_feature_space));
+    let added = adder.join().unwrap();
+
+    used.extend(use_every_leaf(&feature_space));
+
+    let epoch_steps: HashSet<u64> = added
+        .iter()
+        .copied()
+        .chain(used.iter().map(|(epoch_step, _)| *epoch_step))
+        .collect();
+
+    assert_eq!(used.len(), added.len());
+    assert_eq!(epoch_steps.len(), added.len() + used.len());
+    assert_eq!(
+        feature_space.epoch_step().unwrap(),
+        (added.len() + used.len()) as u64
+    );
+}
+
+#[test]
+fn must_dequeue_every_item_exactly_once_across_threads() {
+    let queue = Arc::new(SortingPriorityQueue::new(feature_names()).unwrap());
+    let shared_queue = queue.clone();
+
+    let items: Vec<(Vec<u8>, Vec<FeatureValue>)> = (0..ITEMS_PER_VALUE)
+        .flat_map(|i| {
+            all_features()
+                .into_iter()
+                .enumerate()
+                .map(move |(j, features)| (vec![i as u8, j as u8], features))
+        })
+        .collect();
+    let enqueued_items = items.clone();
+
+    let enqueuer = thread::spawn(move || {
+        for (item, features) in enqueued_items {
+            queue.enqueue(item, features).unwrap();
+        }
+
+        queue
+    });
+
+    let mut dequeued: Vec<Vec<u8>> = on_every_thread(move || {
+        let mut dequeued = vec![];
+
+        for _ in 0..ITEMS_PER_VALUE {
+            if let (Some(item), _) = shared_queue.dequeue().unwrap() {
+                dequeued.push(item.into_data());
+            }
+        }
+
+        dequeued
+    });
+
+    let queue = enqueuer.join().unwrap();
+
+    while let (Some(item), _) = queue.dequeue().unwrap() {
+        dequeued.push(item.into_data());
+    }
+
+    let mut expected: Vec<Vec<u8>> = items.into_iter().map(|(item, _)| item).collect();
+
+    dequeued.sort();
+    expected.sort();
+
+    assert_eq!(dequeued, expected);
+    assert_eq!(queue.size().unwrap(), 0);
+}
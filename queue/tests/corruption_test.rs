@@ -0,0 +1,153 @@
+use rocksdb::{IteratorMode, Options, DB};
+use sp_queue::database::{ITEMS, METADATA, NODE_VALUE_ITEMS_AT_INDEX};
+use sp_queue::error::Error;
+use sp_queue::feature_space::FeatureValue;
+use sp_queue::SortingPriorityQueue;
+use std::time::Duration;
+
+fn feature_names() -> Vec<String> {
+    vec!["root".to_string(), "leaf".to_string()]
+}
+
+fn features(root: usize, leaf: usize) -> Vec<FeatureValue> {
+    vec![
+        FeatureValue::new("root".to_string(), root),
+        FeatureValue::new("leaf".to_string(), leaf),
+    ]
+}
+
+fn remove_directory(directory: &str) {
+    match std::fs::remove_dir_all(directory) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+/// Leaves a closed durable queue holding a few items at the directory
+/// returning its root index
+fn create_queue(directory: &str) -> u64 {
+    remove_directory(directory);
+
+    let queue = SortingPriorityQueue::new_durable(feature_names(), directory.to_string()).unwrap();
+    queue.enqueue(vec![1], features(1, 1)).unwrap();
+    queue.enqueue(vec![2], features(1, 2)).unwrap();
+    queue.enqueue(vec![3], features(2, 1)).unwrap();
+
+    let root_index = queue.root_index().unwrap().unwrap();
+    queue.close().unwrap();
+
+    root_index
+}
+
+/// Rewrites every entry of the column family behind the queue's back. Entries
+/// the rewrite returns none for are deleted.
+fn rewrite_column(directory: &str, name: &str, rewrite: impl Fn(&[u8], &[u8]) -> Option<Vec<u8>>) {
+    let options = Options::default();
+    let names = DB::list_cf(&options, directory).unwrap();
+    let db = DB::open_cf(&options, directory, names).unwrap();
+    let column = db.cf_handle(name).unwrap();
+
+    let entries: Vec<_> = db.iterator_cf(column, IteratorMode::Start).collect();
+
+    for (key, value) in entries {
+        match rewrite(&key, &value) {
+            Some(new_value) => db.put_cf(column, key, new_value).unwrap(),
+            None => db.delete_cf(column, key).unwrap(),
+        }
+    }
+}
+
+fn assert_corrupt<V>(result: Result<V, Error>) {
+    match result {
+        Err(Error::Corruption { .. }) => (),
+        Err(e) => panic!("Expected a corruption error not {:?}", e),
+        Ok(_) => panic!("Expected a corruption error"),
+    }
+}
+
+#[test]
+fn must_return_corruption_when_a_node_loses_its_value_counts() {
+    let directory = "/tmp/corruption_value_counts";
+    let root_index = create_queue(directory);
+
+    // Every node below the root claims to hold no items while the root still
+    // counts them
+    rewrite_column(directory, NODE_VALUE_ITEMS_AT_INDEX, |key, value| {
+        if key[..8] == root_index.to_be_bytes() {
+            Some(value.to_vec())
+        } else {
+            Some(0u64.to_be_bytes().to_vec())
+        }
+    });
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+
+    assert_corrupt(queue.peek());
+    assert_corrupt(queue.dequeue());
+    assert_corrupt(queue.dequeue_with_lease(Duration::from_secs(60)));
+    // The failed operations leave the queue usable
+    assert_eq!(queue.size().unwrap(), 3);
+    assert_corrupt(queue.dequeue());
+
+    queue.destroy().unwrap();
+}
+
+#[test]
+fn must_return_corruption_when_counted_items_are_missing() {
+    let directory = "/tmp/corruption_missing_items";
+    create_queue(directory);
+
+    rewrite_column(directory, ITEMS, |_, _| None);
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+
+    assert_corrupt(queue.peek());
+    assert_corrupt(queue.dequeue());
+    assert_corrupt(queue.dequeue_with_lease(Duration::from_secs(60)));
+    assert_eq!(queue.size().unwrap(), 3);
+
+    queue.destroy().unwrap();
+}
+
+#[test]
+fn must_return_corruption_when_stored_integers_are_truncated() {
+    let directory = "/tmp/corruption_truncated_integers";
+    create_queue(directory);
+
+    rewrite_column(directory, METADATA, |_, value| Some(value[..3].to_vec()));
+
+    assert_corrupt(SortingPriorityQueue::open_durable(directory.to_string()));
+
+    remove_directory(directory);
+}
+
+#[test]
+fn must_return_corruption_when_stored_keys_are_truncated() {
+    let directory = "/tmp/corruption_truncated_keys";
+    create_queue(directory);
+
+    let options = Options::default();
+    let names = DB::list_cf(&options, directory).unwrap();
+    let db = DB::open_cf(&options, directory, names).unwrap();
+    let column = db.cf_handle(NODE_VALUE_ITEMS_AT_INDEX).unwrap();
+    let keys: Vec<Box<[u8]>> = db
+        .iterator_cf(column, IteratorMode::Start)
+        .map(|(key, _)| key)
+        .collect();
+
+    // Each key keeps only its node prefix and one byte of its value
+    for key in keys {
+        let value = db.get_cf(column, &key).unwrap().unwrap();
+        db.delete_cf(column, &key).unwrap();
+        db.put_cf(column, &key[..9], value).unwrap();
+    }
+
+    drop(db);
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+
+    assert_corrupt(queue.dequeue());
+    assert_corrupt(queue.peek());
+
+    queue.destroy().unwrap();
+}
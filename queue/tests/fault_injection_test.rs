@@ -0,0 +1,156 @@
+use sp_queue::database::FAULT_AFTER_WRITES_VARIABLE;
+use sp_queue::feature_space::FeatureValue;
+use sp_queue::SortingPriorityQueue;
+use std::process::Command;
+use std::time::Duration;
+
+static DIRECTORY_VARIABLE: &str = "SPQ_FAULT_DIRECTORY";
+
+static ROOT_FEATURE_NAME: &str = "root";
+
+static LEAF_FEATURE_NAME: &str = "leaf";
+
+const ITEMS: u8 = 6;
+
+const MAX_FAULTS: usize = 10_000;
+
+fn feature_names() -> Vec<String> {
+    vec![ROOT_FEATURE_NAME.to_string(), LEAF_FEATURE_NAME.to_string()]
+}
+
+fn features(item: u8) -> Vec<FeatureValue> {
+    vec![
+        FeatureValue::new(ROOT_FEATURE_NAME.to_string(), (item % 2) as usize),
+        FeatureValue::new(LEAF_FEATURE_NAME.to_string(), (item % 3) as usize),
+    ]
+}
+
+fn remove_directory(directory: &str) {
+    match std::fs::remove_dir_all(directory) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+/// Run by the harness in a child process that aborts part way through
+#[test]
+#[ignore]
+fn crash_workload() {
+    let directory = match std::env::var(DIRECTORY_VARIABLE) {
+        Ok(directory) => directory,
+        Err(_) => return,
+    };
+
+    let queue = SortingPriorityQueue::new_durable(feature_names(), directory).unwrap();
+
+    for item in 0..ITEMS {
+        queue.enqueue(vec![item], features(item)).unwrap();
+    }
+
+    queue.dequeue().unwrap();
+
+    let (lease, _) = queue.dequeue_with_lease(Duration::from_secs(3600)).unwrap();
+    queue.nack(lease.unwrap().get_id()).unwrap();
+
+    let (lease, _) = queue.dequeue_with_lease(Duration::from_secs(3600)).unwrap();
+    queue.ack(lease.unwrap().get_id()).unwrap();
+
+    queue.dequeue().unwrap();
+}
+
+/// Reopens the queue left behind by a crash and checks that the counts in the
+/// feature space agree with the items that can actually be dequeued.
+fn check_recovered_queue(directory: &str, fault: usize) {
+    let queue = match SortingPriorityQueue::open_durable(directory.to_string()) {
+        Ok(queue) => queue,
+        Err(e) => {
+            // Only a crash before the queue's creation was committed may leave
+            // nothing to reopen
+            assert!(
+                e.to_string().contains("is incomplete"),
+                "Fault {:?} left an unopenable queue: {}",
+                fault,
+                e
+            );
+            return;
+        }
+    };
+
+    let size = queue.size().unwrap();
+
+    // Nothing has been enqueued so there is no tree to dequeue from
+    if queue.root_index().unwrap().is_none() {
+        assert_eq!(size, 0);
+        return;
+    }
+
+    let mut dequeued: Vec<u8> = vec![];
+
+    while let (Some(item), _) = queue.dequeue().unwrap() {
+        let item = item.into_data();
+        assert_eq!(item.len(), 1);
+        assert!(
+            !dequeued.contains(&item[0]),
+            "Fault {:?} duplicated item {:?}",
+            fault,
+            item
+        );
+        assert!(item[0] < ITEMS);
+
+        dequeued.push(item[0]);
+    }
+
+    assert_eq!(
+        dequeued.len() as u64,
+        size,
+        "Fault {:?} left a size that does not match the items",
+        fault
+    );
+    assert_eq!(queue.size().unwrap(), 0);
+    assert_eq!(queue.peek().unwrap(), None);
+    assert!(queue
+        .feature_value_counts()
+        .unwrap()
+        .iter()
+        .all(|(_, count)| *count == 0));
+}
+
+/// Aborts the workload at each write in turn. An abort simulates the process
+/// crashing, not the host losing power: writes RocksDB handed to the operating
+/// system survive it whether or not they were synced to disk.
+#[test]
+fn must_recover_consistent_queue_after_crash_at_every_write() {
+    let directory = std::env::temp_dir()
+        .join(format!("fault_injection_{}", std::process::id()))
+        .to_str()
+        .unwrap()
+        .to_string();
+    let test_binary = std::env::current_exe().unwrap();
+    let mut faults = 0;
+
+    for fault in 1..MAX_FAULTS {
+        remove_directory(&directory);
+        std::fs::create_dir_all(directory.clone()).unwrap();
+
+        let status = Command::new(&test_binary)
+            .args(["crash_workload", "--exact", "--ignored", "--test-threads=1"])
+            .env(DIRECTORY_VARIABLE, &directory)
+            .env(FAULT_AFTER_WRITES_VARIABLE, fault.to_string())
+            .output()
+            .unwrap()
+            .status;
+
+        check_recovered_queue(&directory, fault);
+
+        if status.success() {
+            break;
+        }
+
+        faults += 1;
+    }
+
+    assert!(faults > 0);
+    assert!(faults < MAX_FAULTS - 1, "Workload never completed");
+
+    remove_directory(&directory);
+}
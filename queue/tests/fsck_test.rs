@@ -0,0 +1,204 @@
+use rocksdb::{ColumnFamily, IteratorMode, Options, DB};
+use sp_queue::database::{ITEMS, METADATA, NODE_VALUE_ITEMS_AT_INDEX};
+use sp_queue::feature_space::FeatureValue;
+use sp_queue::fsck;
+use sp_queue::SortingPriorityQueue;
+use std::time::Duration;
+
+fn feature_names() -> Vec<String> {
+    vec!["root".to_string(), "leaf".to_string()]
+}
+
+fn features(root: usize, leaf: usize) -> Vec<FeatureValue> {
+    vec![
+        FeatureValue::new("root".to_string(), root),
+        FeatureValue::new("leaf".to_string(), leaf),
+    ]
+}
+
+fn remove_directory(directory: &str) {
+    match std::fs::remove_dir_all(directory) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+/// Leaves a closed durable queue at the directory holding five items and
+/// one leased item
+fn create_queue(directory: &str) {
+    remove_directory(directory);
+
+    let queue = SortingPriorityQueue::new_durable(feature_names(), directory.to_string()).unwrap();
+    queue.enqueue(vec![1], features(1, 1)).unwrap();
+    queue.enqueue(vec![2], features(1, 2)).unwrap();
+    queue.enqueue(vec![3], features(2, 1)).unwrap();
+    queue.enqueue(vec![4], features(1, 1)).unwrap();
+    queue.enqueue(vec![5], features(2, 2)).unwrap();
+    queue.enqueue(vec![6], features(2, 2)).unwrap();
+    queue.dequeue_with_lease(Duration::from_secs(3600)).unwrap();
+    queue.close().unwrap();
+}
+
+fn open_column<T>(directory: &str, name: &str, f: impl FnOnce(&DB, &ColumnFamily) -> T) -> T {
+    let options = Options::default();
+    let names = DB::list_cf(&options, directory).unwrap();
+    let db = DB::open_cf(&options, directory, names).unwrap();
+
+    f(&db, db.cf_handle(name).unwrap())
+}
+
+/// Every entry of the column family as the queue left it
+fn entries(directory: &str, name: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+    open_column(directory, name, |db, column| {
+        db.iterator_cf(column, IteratorMode::Start)
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect()
+    })
+}
+
+/// Writes to the column family behind the queue's back. Entries without a
+/// value are deleted.
+fn write(directory: &str, name: &str, writes: Vec<(Vec<u8>, Option<Vec<u8>>)>) {
+    open_column(directory, name, |db, column| {
+        for (key, maybe_value) in writes {
+            match maybe_value {
+                Some(value) => db.put_cf(column, key, value).unwrap(),
+                None => db.delete_cf(column, key).unwrap(),
+            }
+        }
+    })
+}
+
+/// The items held under the first leaf
+fn first_leaf_items(directory: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let items = entries(directory, ITEMS);
+    let leaf = items[0].0[..8].to_vec();
+
+    items
+        .into_iter()
+        .filter(|(key, _)| key[..8] == leaf[..])
+        .collect()
+}
+
+fn drain(queue: &SortingPriorityQueue) -> Vec<Vec<u8>> {
+    let mut items = vec![];
+
+    while let (Some(item), _) = queue.dequeue().unwrap() {
+        items.push(item.into_data());
+    }
+
+    items
+}
+
+#[test]
+fn must_find_nothing_wrong_with_a_healthy_queue() {
+    let directory = "/tmp/fsck_healthy";
+    create_queue(directory);
+
+    let report = fsck::check(directory.to_string()).unwrap();
+
+    remove_directory(directory);
+
+    assert!(report.is_consistent(), "{:?}", report);
+}
+
+#[test]
+fn must_find_and_repair_counts_that_disagree_with_the_items() {
+    let directory = "/tmp/fsck_counts";
+    create_queue(directory);
+
+    let counts = entries(directory, NODE_VALUE_ITEMS_AT_INDEX)
+        .into_iter()
+        .map(|(key, _)| (key, Some(7u64.to_be_bytes().to_vec())))
+        .collect();
+    write(directory, NODE_VALUE_ITEMS_AT_INDEX, counts);
+    // The total items are kept under key zero
+    write(
+        directory,
+        METADATA,
+        vec![(
+            0u64.to_be_bytes().to_vec(),
+            Some(0u64.to_be_bytes().to_vec()),
+        )],
+    );
+
+    let found = fsck::check(directory.to_string()).unwrap();
+    let repaired = fsck::repair(directory.to_string()).unwrap();
+    let after_repair = fsck::check(directory.to_string()).unwrap();
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+    let size = queue.size().unwrap();
+    let items = drain(&queue);
+    queue.destroy().unwrap();
+
+    assert!(!found.is_consistent());
+    assert!(found.is_repairable());
+    assert_eq!(repaired, found);
+    assert!(after_repair.is_consistent(), "{:?}", after_repair);
+    assert_eq!(size, 5);
+    assert_eq!(items.len(), 5);
+}
+
+#[test]
+fn must_rebuild_counts_for_items_lost_from_the_heap() {
+    let directory = "/tmp/fsck_lost_items";
+    create_queue(directory);
+
+    let lost = first_leaf_items(directory);
+    write(
+        directory,
+        ITEMS,
+        lost.iter().map(|(key, _)| (key.clone(), None)).collect(),
+    );
+
+    let found = fsck::check(directory.to_string()).unwrap();
+    fsck::repair(directory.to_string()).unwrap();
+    let after_repair = fsck::check(directory.to_string()).unwrap();
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+    let size = queue.size().unwrap();
+    let items = drain(&queue);
+    queue.destroy().unwrap();
+
+    assert!(!found.is_consistent());
+    assert!(found.is_repairable());
+    assert!(after_repair.is_consistent(), "{:?}", after_repair);
+    assert_eq!(size, 5 - lost.len() as u64);
+    assert_eq!(items.len(), 5 - lost.len());
+}
+
+#[test]
+fn must_report_items_no_node_leads_to_as_not_repairable() {
+    let directory = "/tmp/fsck_orphaned_items";
+    create_queue(directory);
+
+    // Moves the items of a leaf under one the feature space has never seen
+    let moved = first_leaf_items(directory);
+    let mut writes = vec![];
+
+    for (key, value) in moved.iter() {
+        let mut orphaned_key = u64::MAX.to_be_bytes().to_vec();
+        orphaned_key.extend_from_slice(&key[8..]);
+
+        writes.push((key.clone(), None));
+        writes.push((orphaned_key, Some(value.clone())));
+    }
+
+    write(directory, ITEMS, writes);
+
+    let found = fsck::check(directory.to_string()).unwrap();
+    fsck::repair(directory.to_string()).unwrap();
+    let after_repair = fsck::check(directory.to_string()).unwrap();
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+    let size = queue.size().unwrap();
+    let items = drain(&queue);
+    queue.destroy().unwrap();
+
+    assert!(!found.is_repairable());
+    assert_eq!(after_repair.problems.len(), 1);
+    assert!(!after_repair.is_repairable());
+    // The rest of the queue serves once the counts are rebuilt
+    assert_eq!(size, 5 - moved.len() as u64);
+    assert_eq!(items.len(), 5 - moved.len());
+}
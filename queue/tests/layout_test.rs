@@ -0,0 +1,241 @@
+use rocksdb::{Options, DB};
+use sp_queue::database::{Entry, FEATURE_NAMES, ITEMS, LEAF_VALUES, LEASES};
+use sp_queue::error::Error;
+use sp_queue::feature_space::FeatureValue;
+use sp_queue::item::ITEM_FROM_BYTES;
+use sp_queue::snapshot::QueueSnapshot;
+use sp_queue::storage::StorageType;
+use sp_queue::SortingPriorityQueue;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+fn features(names: &[&str], values: &[usize]) -> Vec<FeatureValue> {
+    names
+        .iter()
+        .zip(values.iter())
+        .map(|(name, value)| FeatureValue::new(name.to_string(), *value))
+        .collect()
+}
+
+/// The columns of a memory queue holding a few items
+fn filled_columns(names: &[&str]) -> BTreeMap<String, Vec<Entry>> {
+    let queue =
+        SortingPriorityQueue::new(names.iter().map(|name| name.to_string()).collect()).unwrap();
+
+    for (data, leaf) in [1, 2, 3, 4].iter().zip([1, 2, 1, 2].iter()) {
+        let mut values = vec![1; names.len()];
+        values[names.len() - 1] = *leaf;
+        queue
+            .enqueue(vec![*data], features(names, &values))
+            .unwrap();
+    }
+
+    queue.export().unwrap().columns
+}
+
+/// What a queue holding the columns hands out
+fn drain(queue: &SortingPriorityQueue) -> Vec<(Vec<u8>, Vec<FeatureValue>, u64)> {
+    let mut items = vec![];
+
+    while let (Some(item), _) = queue.dequeue().unwrap() {
+        assert_eq!(item.get_enqueued_at(), 0);
+        items.push((
+            item.get_data().clone(),
+            item.get_features().clone(),
+            item.get_epoch(),
+        ));
+    }
+
+    items
+}
+
+/// What a queue upgraded from the columns hands out. Layout 0 never stored
+/// the features of items.
+fn expected_drain(columns: BTreeMap<String, Vec<Entry>>) -> Vec<(Vec<u8>, Vec<FeatureValue>, u64)> {
+    let snapshot = QueueSnapshot {
+        storage_type: StorageType::Memory,
+        columns,
+    };
+    let queue = SortingPriorityQueue::import(snapshot, String::new()).unwrap();
+    let mut items = vec![];
+
+    while let (Some(item), _) = queue.dequeue().unwrap() {
+        items.push((item.get_data().clone(), vec![], item.get_epoch()));
+    }
+
+    items
+}
+
+fn split_item_key(key: &[u8]) -> (u64, u64) {
+    let mut leaf = [0; 8];
+    let mut epoch = [0; 8];
+    leaf.copy_from_slice(&key[..8]);
+    epoch.copy_from_slice(&key[8..]);
+
+    (u64::from_be_bytes(leaf), u64::from_be_bytes(epoch))
+}
+
+/// Writes the columns out in layout 0: a RocksDB instance per feature space
+/// store with the raw data of the items in a column family per leaf of the
+/// folder itself. Stores layout 0 did not have are left out.
+fn write_database_per_store(directory: &str, columns: BTreeMap<String, Vec<Entry>>) {
+    remove_directory(directory);
+
+    for (name, entries) in columns.iter() {
+        if [ITEMS, FEATURE_NAMES, LEAF_VALUES, LEASES].contains(&name.as_str()) {
+            continue;
+        }
+
+        let store = DB::open_default(Path::new(directory).join(name)).unwrap();
+
+        for (key, value) in entries {
+            store.put(key, value).unwrap();
+        }
+    }
+
+    let mut items = DB::open_default(directory).unwrap();
+
+    for (key, value) in columns[ITEMS].iter() {
+        let (leaf, epoch) = split_item_key(key);
+        let item = ITEM_FROM_BYTES(value.clone()).unwrap();
+
+        if items.cf_handle(&leaf.to_string()).is_none() {
+            items
+                .create_cf(leaf.to_string(), &Options::default())
+                .unwrap();
+        }
+
+        let shard = items.cf_handle(&leaf.to_string()).unwrap();
+        items
+            .put_cf(shard, epoch.to_be_bytes(), item.into_data())
+            .unwrap();
+    }
+}
+
+fn remove_directory(directory: &str) {
+    match std::fs::remove_dir_all(directory) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+fn assert_unsupported<V>(result: Result<V, Error>) {
+    match result {
+        Err(Error::UnsupportedLayout { .. }) => (),
+        Err(e) => panic!("Expected an unsupported layout error not {:?}", e),
+        Ok(_) => panic!("Expected an unsupported layout error"),
+    }
+}
+
+#[test]
+fn must_upgrade_a_queue_stored_as_a_database_per_store() {
+    let directory = "/tmp/layout_database_per_store";
+    let columns = filled_columns(&["root", "leaf"]);
+    write_database_per_store(directory, columns.clone());
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+    assert_eq!(queue.size().unwrap(), 4);
+    queue.close().unwrap();
+
+    assert!(!Path::new(directory).join("metadata").exists());
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+    let items = drain(&queue);
+    queue.destroy().unwrap();
+
+    assert_eq!(items, expected_drain(columns));
+}
+
+#[test]
+fn must_stamp_a_queue_closed_before_its_layout_was_recorded() {
+    let directory = "/tmp/layout_unstamped";
+    remove_directory(directory);
+
+    let queue =
+        SortingPriorityQueue::new_durable(vec!["leaf".to_string()], directory.to_string()).unwrap();
+    queue.close().unwrap();
+
+    let names = DB::list_cf(&Options::default(), directory).unwrap();
+    let db = DB::open_cf(&Options::default(), directory, names).unwrap();
+    db.delete(b"layout_version").unwrap();
+    drop(db);
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+    assert_eq!(queue.size().unwrap(), 0);
+    queue.close().unwrap();
+
+    let names = DB::list_cf(&Options::default(), directory).unwrap();
+    let db = DB::open_cf(&Options::default(), directory, names).unwrap();
+    assert_eq!(
+        db.get(b"layout_version").unwrap(),
+        Some(1u64.to_be_bytes().to_vec())
+    );
+    drop(db);
+
+    remove_directory(directory);
+}
+
+#[test]
+fn must_refuse_a_queue_stored_in_a_newer_layout() {
+    let directory = "/tmp/layout_newer";
+    remove_directory(directory);
+
+    let queue =
+        SortingPriorityQueue::new_durable(vec!["leaf".to_string()], directory.to_string()).unwrap();
+    queue.close().unwrap();
+
+    let names = DB::list_cf(&Options::default(), directory).unwrap();
+    let db = DB::open_cf(&Options::default(), directory, names).unwrap();
+    db.put(b"layout_version", 2u64.to_be_bytes()).unwrap();
+    drop(db);
+
+    assert_unsupported(SortingPriorityQueue::open_durable(directory.to_string()));
+    remove_directory(directory);
+}
+
+#[test]
+fn must_upgrade_a_queue_of_more_than_two_features_stored_as_a_database_per_store() {
+    let directory = "/tmp/layout_database_per_store_of_three";
+    let columns = filled_columns(&["root", "middle", "leaf"]);
+    write_database_per_store(directory, columns.clone());
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+    let items = drain(&queue);
+    queue.destroy().unwrap();
+
+    assert_eq!(items, expected_drain(columns));
+}
+
+#[test]
+fn must_open_a_queue_stored_before_feature_names_were() {
+    let directory = "/tmp/layout_baseline";
+    let columns = filled_columns(&["root", "leaf"]);
+    write_database_per_store(directory, columns.clone());
+    let names = vec!["root".to_string(), "leaf".to_string()];
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+    assert_eq!(queue.feature_names().unwrap(), Vec::<String>::new());
+    assert!(queue.has_feature_names(&names).unwrap());
+
+    let data: Vec<Vec<u8>> = drain(&queue).into_iter().map(|item| item.0).collect();
+    let expected: Vec<Vec<u8>> = expected_drain(columns)
+        .into_iter()
+        .map(|item| item.0)
+        .collect();
+    assert_eq!(data, expected);
+
+    assert!(matches!(
+        queue.enqueue(vec![5], features(&["leaf", "root"], &[1, 1])),
+        Err(Error::UnknownFeatureNames { .. })
+    ));
+    queue
+        .enqueue(vec![5], features(&["root", "leaf"], &[1, 1]))
+        .unwrap();
+    queue.close().unwrap();
+
+    let queue = SortingPriorityQueue::open_durable(directory.to_string()).unwrap();
+    let feature_names = queue.feature_names().unwrap();
+    queue.destroy().unwrap();
+
+    assert_eq!(feature_names, names);
+}
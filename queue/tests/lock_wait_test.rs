@@ -0,0 +1,22 @@
+use sp_queue::{timing_lock_waits, SortingPriorityQueue};
+
+#[test]
+fn must_return_the_result_of_a_timed_operation() {
+    let queue = SortingPriorityQueue::new(vec!["feature".to_string()]).unwrap();
+
+    let (size, _) = timing_lock_waits(|| queue.size());
+
+    assert_eq!(size.unwrap(), 0);
+}
+
+#[test]
+fn must_count_nested_lock_waits_towards_the_outer_operation() {
+    let queue = SortingPriorityQueue::new(vec!["feature".to_string()]).unwrap();
+
+    let ((_, inner), outer) = timing_lock_waits(|| {
+        queue.size().unwrap();
+        timing_lock_waits(|| queue.get_epoch())
+    });
+
+    assert!(outer >= inner);
+}
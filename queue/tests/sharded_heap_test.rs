@@ -0,0 +1,51 @@
+use sp_queue::database::{Column, Database, ITEMS};
+use sp_queue::item::DequeuedItem;
+use sp_queue::sharded_heap::ShardedHeap;
+
+fn remove_directory(directory: &str) {
+    match std::fs::remove_dir_all(directory) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+fn item(data: u8, epoch: u64) -> DequeuedItem {
+    DequeuedItem::new(vec![data], vec![], epoch, 0)
+}
+
+/// Runs the check against a memory heap and a durable heap
+fn for_each_backend(directory: &str, check: impl Fn(ShardedHeap)) {
+    check(ShardedHeap::new(None).unwrap());
+
+    remove_directory(directory);
+    let database = Database::create(directory.to_string()).unwrap();
+    check(ShardedHeap::new(Some(Column::new(database, ITEMS))).unwrap());
+    remove_directory(directory);
+}
+
+#[test]
+fn must_find_nothing_in_a_shard_that_never_held_an_item() {
+    for_each_backend("/tmp/sharded_heap_missing_shard", |mut heap| {
+        assert_eq!(heap.peek(7).unwrap(), None);
+        assert_eq!(heap.pop(7).unwrap(), None);
+
+        heap.push(1, item(1, 0)).unwrap();
+
+        assert_eq!(heap.peek(7).unwrap(), None);
+        assert_eq!(heap.pop(7).unwrap(), None);
+        assert_eq!(heap.peek(1).unwrap(), Some(item(1, 0)));
+    });
+}
+
+#[test]
+fn must_find_nothing_in_an_emptied_shard() {
+    for_each_backend("/tmp/sharded_heap_emptied_shard", |mut heap| {
+        heap.push(1, item(1, 0)).unwrap();
+        heap.push(1, item(2, 1)).unwrap();
+
+        assert_eq!(heap.pop(1).unwrap(), Some(item(1, 0)));
+        assert_eq!(heap.pop(1).unwrap(), Some(item(2, 1)));
+        assert_eq!(heap.peek(1).unwrap(), None);
+        assert_eq!(heap.pop(1).unwrap(), None);
+    });
+}
@@ -0,0 +1,184 @@
+use sp_queue::database::ITEMS;
+use sp_queue::error::Error;
+use sp_queue::feature_space::FeatureValue;
+use sp_queue::item::{DequeuedItem, ITEM_FROM_BYTES, ITEM_TO_BYTES};
+use sp_queue::snapshot::QueueSnapshot;
+use sp_queue::storage::StorageType;
+use sp_queue::SortingPriorityQueue;
+use std::time::Duration;
+
+fn feature_names() -> Vec<String> {
+    vec!["root".to_string(), "leaf".to_string()]
+}
+
+fn features(root: usize, leaf: usize) -> Vec<FeatureValue> {
+    vec![
+        FeatureValue::new("root".to_string(), root),
+        FeatureValue::new("leaf".to_string(), leaf),
+    ]
+}
+
+fn fill(queue: &SortingPriorityQueue) {
+    queue.enqueue(vec![1], features(1, 1)).unwrap();
+    queue.enqueue(vec![2], features(1, 2)).unwrap();
+    queue.enqueue(vec![3], features(2, 1)).unwrap();
+    queue.enqueue(vec![4], features(1, 1)).unwrap();
+    queue.enqueue(vec![5], features(2, 2)).unwrap();
+}
+
+fn drain(queue: &SortingPriorityQueue) -> Vec<Vec<u8>> {
+    let mut items = vec![];
+
+    while let (Some(item), _) = queue.dequeue().unwrap() {
+        items.push(item.into_data());
+    }
+
+    items
+}
+
+fn without_enqueue_times(mut snapshot: QueueSnapshot) -> QueueSnapshot {
+    for entry in snapshot.columns.get_mut(ITEMS).unwrap().iter_mut() {
+        let item = ITEM_FROM_BYTES(entry.1.clone()).unwrap();
+        let epoch = item.get_epoch();
+        let features = item.get_features().clone();
+        entry.1 = ITEM_TO_BYTES(DequeuedItem::new(item.into_data(), features, epoch, 0)).unwrap();
+    }
+
+    snapshot
+}
+
+fn remove_directory(directory: &str) {
+    match std::fs::remove_dir_all(directory) {
+        Ok(_) => (),
+        Err(e) => println!("{:?}", e),
+    }
+}
+
+#[test]
+fn must_import_a_memory_queue_as_it_was_exported() {
+    let expected = SortingPriorityQueue::new(feature_names()).unwrap();
+    let queue = SortingPriorityQueue::new(feature_names()).unwrap();
+    fill(&expected);
+    fill(&queue);
+
+    let snapshot = queue.export().unwrap();
+    let imported = SortingPriorityQueue::import(snapshot, String::new()).unwrap();
+
+    assert_eq!(imported.storage_type(), StorageType::Memory);
+    assert_eq!(imported.feature_names().unwrap(), feature_names());
+    assert_eq!(imported.size().unwrap(), 5);
+    assert_eq!(imported.get_epoch().unwrap(), queue.get_epoch().unwrap());
+    assert_eq!(drain(&imported), drain(&expected));
+}
+
+#[test]
+fn must_import_a_durable_queue_as_it_was_exported() {
+    let directory = "/tmp/snapshot_durable".to_string();
+    let imported_directory = "/tmp/snapshot_durable_imported".to_string();
+    remove_directory(&directory);
+    remove_directory(&imported_directory);
+
+    let expected = SortingPriorityQueue::new(feature_names()).unwrap();
+    let queue = SortingPriorityQueue::new_durable(feature_names(), directory.clone()).unwrap();
+    fill(&expected);
+    fill(&queue);
+
+    let snapshot = queue.export().unwrap();
+    let imported = SortingPriorityQueue::import(snapshot, imported_directory.clone()).unwrap();
+
+    assert_eq!(imported.storage_type(), StorageType::Durable);
+    assert_eq!(imported.get_epoch().unwrap(), queue.get_epoch().unwrap());
+
+    drop(imported);
+
+    let reopened = SortingPriorityQueue::open_durable(imported_directory.clone()).unwrap();
+    let items = drain(&reopened);
+
+    drop(reopened);
+    queue.destroy().unwrap();
+    remove_directory(&imported_directory);
+
+    assert_eq!(items, drain(&expected));
+}
+
+#[test]
+fn must_export_memory_and_durable_queues_alike() {
+    let directory = "/tmp/snapshot_alike".to_string();
+    remove_directory(&directory);
+
+    let memory = SortingPriorityQueue::new(feature_names()).unwrap();
+    let durable = SortingPriorityQueue::new_durable(feature_names(), directory).unwrap();
+    fill(&memory);
+    fill(&durable);
+    memory.dequeue().unwrap();
+    durable.dequeue().unwrap();
+
+    let memory_snapshot = without_enqueue_times(memory.export().unwrap());
+    let durable_snapshot = without_enqueue_times(durable.export().unwrap());
+
+    durable.destroy().unwrap();
+
+    assert_eq!(memory_snapshot.columns, durable_snapshot.columns);
+}
+
+#[test]
+fn must_keep_leases_through_an_import() {
+    let queue = SortingPriorityQueue::new(feature_names()).unwrap();
+    fill(&queue);
+
+    let (maybe_lease, _) = queue.dequeue_with_lease(Duration::from_secs(60)).unwrap();
+    let lease = maybe_lease.unwrap();
+
+    let bytes = queue.export().unwrap().to_bytes().unwrap();
+    let snapshot = QueueSnapshot::from_bytes(&bytes).unwrap();
+    let imported = SortingPriorityQueue::import(snapshot, String::new()).unwrap();
+
+    assert_eq!(imported.size().unwrap(), 4);
+
+    imported.nack(lease.get_id()).unwrap();
+
+    assert_eq!(imported.size().unwrap(), 5);
+}
+
+#[test]
+fn must_reject_a_snapshot_with_an_unknown_column() {
+    let queue = SortingPriorityQueue::new(feature_names()).unwrap();
+
+    let mut snapshot = queue.export().unwrap();
+    snapshot.columns.insert("unknown".to_string(), vec![]);
+
+    assert!(matches!(
+        SortingPriorityQueue::import(snapshot, String::new()),
+        Err(Error::Corruption { .. })
+    ));
+}
+
+#[test]
+fn must_import_a_snapshot_of_a_queue_created_before_feature_names_were_stored() {
+    let queue = SortingPriorityQueue::new(feature_names()).unwrap();
+    fill(&queue);
+
+    let mut snapshot = queue.export().unwrap();
+    snapshot.columns.remove("feature_names");
+    let imported = SortingPriorityQueue::import(snapshot, String::new()).unwrap();
+
+    assert!(imported.has_feature_names(&feature_names()).unwrap());
+    assert_eq!(drain(&imported), drain(&queue));
+}
+
+#[test]
+fn must_reject_a_snapshot_without_the_feature_names_hash() {
+    let queue = SortingPriorityQueue::new(feature_names()).unwrap();
+
+    let mut snapshot = queue.export().unwrap();
+    for (name, entries) in snapshot.columns.iter_mut() {
+        if name == "metadata" {
+            entries.retain(|(key, _)| *key != 4u64.to_be_bytes().to_vec());
+        }
+    }
+
+    assert!(matches!(
+        SortingPriorityQueue::import(snapshot, String::new()),
+        Err(Error::Corruption { .. })
+    ));
+}
@@ -0,0 +1,37 @@
+use crate::NodeId;
+use std::error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Standard { message: String },
+    NotLeader { leader: Option<NodeId> },
+}
+
+impl Error {
+    pub fn new(message: String) -> Error {
+        Error::Standard { message }
+    }
+}
+
+impl<E: error::Error> From<E> for Error {
+    fn from(e: E) -> Error {
+        Error::new(e.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Error::Standard { message } => message.fmt(formatter),
+            Error::NotLeader {
+                leader: Some(leader),
+            } => {
+                write!(formatter, "Not the leader. The leader is node {}", leader)
+            }
+            Error::NotLeader { leader: None } => {
+                write!(formatter, "Not the leader. No leader is known")
+            }
+        }
+    }
+}
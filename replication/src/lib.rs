@@ -0,0 +1,7 @@
+pub mod error;
+pub mod log;
+pub mod message;
+pub mod node;
+
+/// Identifies a node within a cluster. Every node must be given a different id.
+pub type NodeId = u64;
@@ -0,0 +1,397 @@
+use crate::error::Error;
+use crate::message::{Entry, Snapshot};
+use crate::NodeId;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+const HARD_STATE_FILE: &str = "hard_state";
+
+const ENTRIES_FILE: &str = "entries";
+
+const SNAPSHOT_FILE: &str = "snapshot";
+
+/// Each entry on disk is its length as four big endian bytes then the entry
+const LENGTH_PREFIX: usize = 4;
+
+/// The term and vote must survive a restart so that a node never votes twice
+/// in the same term
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<NodeId>,
+}
+
+/// Where the log is kept on disk alongside the offset each entry starts at
+struct Files {
+    folder_path: PathBuf,
+    entries: File,
+    offsets: Vec<u64>,
+}
+
+/// The entries of a node and its hard state. A node is only told an entry is
+/// stored once it has reached disk so a durable log syncs every change before
+/// returning. Entries are indexed from one with zero standing for the empty
+/// log. Once a snapshot is saved the log only holds the entries after it.
+pub struct RaftLog {
+    hard_state: HardState,
+    maybe_snapshot: Option<Snapshot>,
+    entries: Vec<Entry>,
+    maybe_files: Option<Files>,
+}
+
+/// Writes the bytes to a temporary file and renames it over the named file
+/// so a crash leaves one or the other
+fn write_atomically(folder_path: &Path, name: &str, bytes: &[u8]) -> Result<(), Error> {
+    let temporary_path = folder_path.join(format!("{}.tmp", name));
+    let mut temporary_file = File::create(&temporary_path)?;
+
+    temporary_file.write_all(bytes)?;
+    temporary_file.sync_all()?;
+    fs::rename(temporary_path, folder_path.join(name))?;
+    File::open(folder_path)?.sync_all()?;
+
+    Ok(())
+}
+
+fn read_snapshot(folder_path: &Path) -> Result<Option<Snapshot>, Error> {
+    match fs::read(folder_path.join(SNAPSHOT_FILE)) {
+        Ok(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Each entry prefixed with its length as it is written to disk
+fn to_bytes(entries: &[Entry]) -> Result<Vec<Vec<u8>>, Error> {
+    entries
+        .iter()
+        .map(|entry| {
+            let entry_bytes = bincode::serialize(entry)?;
+            let mut bytes = (entry_bytes.len() as u32).to_be_bytes().to_vec();
+            bytes.extend_from_slice(&entry_bytes);
+
+            Ok(bytes)
+        })
+        .collect()
+}
+
+fn read_hard_state(folder_path: &Path) -> Result<HardState, Error> {
+    match fs::read(folder_path.join(HARD_STATE_FILE)) {
+        Ok(bytes) => Ok(bincode::deserialize(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HardState::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads every whole entry from the first index on returning them with the
+/// offset each starts at. A crash part way through an append leaves a partial
+/// entry at the end which is dropped as it was never reported as stored. A
+/// crash after a snapshot is saved but before the entries it covers are
+/// removed leaves entries before the first index which are skipped.
+fn read_entries(bytes: &[u8], first_index: u64) -> Result<(Vec<Entry>, Vec<u64>, u64), Error> {
+    let mut entries = vec![];
+    let mut offsets = vec![];
+    let mut offset = 0;
+
+    while bytes.len() - offset >= LENGTH_PREFIX {
+        let length_bytes: [u8; LENGTH_PREFIX] = bytes[offset..offset + LENGTH_PREFIX].try_into()?;
+        let length = u32::from_be_bytes(length_bytes) as usize;
+        let start = offset + LENGTH_PREFIX;
+
+        if bytes.len() - start < length {
+            break;
+        }
+
+        let entry: Entry = bincode::deserialize(&bytes[start..start + length])?;
+
+        if entry.index < first_index && entries.is_empty() {
+            offset = start + length;
+            continue;
+        }
+
+        let expected_index = first_index + entries.len() as u64;
+
+        if entry.index != expected_index {
+            return Err(Error::new(format!(
+                "Raft log entry at offset {} has index {} but should have index {}",
+                offset, entry.index, expected_index
+            )));
+        }
+
+        offsets.push(offset as u64);
+        entries.push(entry);
+        offset = start + length;
+    }
+
+    Ok((entries, offsets, offset as u64))
+}
+
+impl RaftLog {
+    pub fn in_memory() -> RaftLog {
+        RaftLog {
+            hard_state: HardState::default(),
+            maybe_snapshot: None,
+            entries: vec![],
+            maybe_files: None,
+        }
+    }
+
+    /// Opens the log kept in the folder creating it if needed
+    pub fn open(folder_path: impl AsRef<Path>) -> Result<RaftLog, Error> {
+        let folder_path = folder_path.as_ref().to_path_buf();
+        fs::create_dir_all(&folder_path)?;
+
+        let hard_state = read_hard_state(&folder_path)?;
+        let maybe_snapshot = read_snapshot(&folder_path)?;
+        let first_index = maybe_snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.index + 1)
+            .unwrap_or(1);
+
+        let mut entries_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(folder_path.join(ENTRIES_FILE))?;
+        let mut bytes = vec![];
+        entries_file.read_to_end(&mut bytes)?;
+
+        let (entries, offsets, length) = read_entries(&bytes, first_index)?;
+        entries_file.set_len(length)?;
+        entries_file.sync_all()?;
+
+        let appended_entries_file = OpenOptions::new()
+            .append(true)
+            .open(folder_path.join(ENTRIES_FILE))?;
+
+        Ok(RaftLog {
+            hard_state,
+            maybe_snapshot,
+            entries,
+            maybe_files: Some(Files {
+                folder_path,
+                entries: appended_entries_file,
+                offsets,
+            }),
+        })
+    }
+
+    pub fn term(&self) -> u64 {
+        self.hard_state.term
+    }
+
+    pub fn voted_for(&self) -> Option<NodeId> {
+        self.hard_state.voted_for
+    }
+
+    /// Records the term and the vote cast in it
+    pub fn set_term_and_vote(&mut self, term: u64, voted_for: Option<NodeId>) -> Result<(), Error> {
+        let hard_state = HardState { term, voted_for };
+
+        if let Some(ref files) = self.maybe_files {
+            write_atomically(
+                &files.folder_path,
+                HARD_STATE_FILE,
+                &bincode::serialize(&hard_state)?,
+            )?;
+        }
+
+        self.hard_state = hard_state;
+
+        Ok(())
+    }
+
+    /// The latest snapshot saved. The entries it covers are no longer held.
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.maybe_snapshot.as_ref()
+    }
+
+    /// The index of the last entry covered by the snapshot or zero without one
+    pub fn snapshot_index(&self) -> u64 {
+        self.maybe_snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.index)
+            .unwrap_or(0)
+    }
+
+    /// The index of the first entry the log still holds
+    pub fn first_index(&self) -> u64 {
+        self.snapshot_index() + 1
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index() + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        match self.entries.last() {
+            Some(entry) => entry.term,
+            None => self.term_at(self.snapshot_index()).unwrap_or(0),
+        }
+    }
+
+    /// The term of the entry at the index. The empty log has term zero at
+    /// index zero and the snapshot's term stands in for the last entry it
+    /// covers. Earlier entries are unknown.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        match self.maybe_snapshot {
+            Some(ref snapshot) if index == snapshot.index => Some(snapshot.term),
+            _ if index == 0 => Some(0),
+            _ => self.entry(index).map(|entry| entry.term),
+        }
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        if index < self.first_index() {
+            return None;
+        }
+
+        self.entries.get((index - self.first_index()) as usize)
+    }
+
+    /// Up to max entries starting at the index. Entries covered by the
+    /// snapshot are left out.
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<Entry> {
+        let start = index.saturating_sub(self.first_index()) as usize;
+
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    /// Adds the entries after the last one. Each entry must carry the index
+    /// that follows the one before it.
+    pub fn append(&mut self, entries: Vec<Entry>) -> Result<(), Error> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        for (position, entry) in entries.iter().enumerate() {
+            if entry.index != self.last_index() + position as u64 + 1 {
+                return Err(Error::new(format!(
+                    "Cannot append entry {} after entry {}",
+                    entry.index,
+                    self.last_index() + position as u64
+                )));
+            }
+        }
+
+        if let Some(ref mut files) = self.maybe_files {
+            let mut offset = files.entries.metadata()?.len();
+            let mut bytes = vec![];
+
+            for entry_bytes in to_bytes(&entries)? {
+                files.offsets.push(offset);
+                offset += entry_bytes.len() as u64;
+
+                bytes.extend_from_slice(&entry_bytes);
+            }
+
+            files.entries.write_all(&bytes)?;
+            files.entries.sync_data()?;
+        }
+
+        self.entries.extend(entries);
+
+        Ok(())
+    }
+
+    /// Removes the entry at the index and every entry after it
+    fn truncate(&mut self, index: u64) -> Result<(), Error> {
+        let kept = index.saturating_sub(self.first_index()) as usize;
+
+        if kept >= self.entries.len() {
+            return Ok(());
+        }
+
+        if let Some(ref mut files) = self.maybe_files {
+            files.entries.set_len(files.offsets[kept])?;
+            files.entries.sync_data()?;
+            files.offsets.truncate(kept);
+        }
+
+        self.entries.truncate(kept);
+
+        Ok(())
+    }
+
+    /// Merges entries sent by the leader. Entries the log already holds are
+    /// skipped. The first entry that conflicts with one held replaces it and
+    /// everything after it as the leader's log is always the one kept.
+    pub fn merge(&mut self, entries: Vec<Entry>) -> Result<(), Error> {
+        let mut new_entries = vec![];
+
+        for entry in entries {
+            if !new_entries.is_empty() {
+                new_entries.push(entry);
+                continue;
+            }
+
+            // Entries the snapshot covers were committed so already agree
+            if entry.index <= self.snapshot_index() {
+                continue;
+            }
+
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.truncate(entry.index)?;
+                    new_entries.push(entry);
+                }
+                None => new_entries.push(entry),
+            }
+        }
+
+        self.append(new_entries)
+    }
+    /// Replaces the entries the snapshot covers with it. Entries after it are
+    /// kept if the log agrees with the snapshot on its last entry and are
+    /// otherwise dropped as the snapshot is of committed entries. The
+    /// snapshot is saved before the entries are rewritten so a crash between
+    /// the two only leaves entries that are skipped on reopening or that the
+    /// leader replaces. Snapshots older than the one held are ignored.
+    pub fn save_snapshot(&mut self, snapshot: Snapshot) -> Result<(), Error> {
+        if snapshot.index <= self.snapshot_index() {
+            return Ok(());
+        }
+
+        let kept_entries = if self.term_at(snapshot.index) == Some(snapshot.term) {
+            self.entries_from(snapshot.index + 1, self.entries.len())
+        } else {
+            vec![]
+        };
+
+        if let Some(ref mut files) = self.maybe_files {
+            write_atomically(
+                &files.folder_path,
+                SNAPSHOT_FILE,
+                &bincode::serialize(&snapshot)?,
+            )?;
+
+            let mut offsets = vec![];
+            let mut offset = 0;
+            let mut bytes = vec![];
+
+            for entry_bytes in to_bytes(&kept_entries)? {
+                offsets.push(offset);
+                offset += entry_bytes.len() as u64;
+
+                bytes.extend_from_slice(&entry_bytes);
+            }
+
+            write_atomically(&files.folder_path, ENTRIES_FILE, &bytes)?;
+
+            files.entries = OpenOptions::new()
+                .append(true)
+                .open(files.folder_path.join(ENTRIES_FILE))?;
+            files.offsets = offsets;
+        }
+
+        self.maybe_snapshot = Some(snapshot);
+        self.entries = kept_entries;
+
+        Ok(())
+    }
+}
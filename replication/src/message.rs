@@ -0,0 +1,76 @@
+use crate::error::Error;
+use crate::NodeId;
+use serde::{Deserialize, Serialize};
+
+/// A command in the replicated log. The command is opaque to the log. An
+/// empty command is the marker each new leader appends to commit the entries
+/// of earlier terms and is never applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Vec<u8>,
+}
+
+/// The state of the application once every command up to and including the
+/// index has been applied. The log drops the entries a snapshot covers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Body {
+    RequestVote {
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        granted: bool,
+    },
+    AppendEntries {
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// The last index the follower now holds from the leader on success or
+    /// the index the leader should try next on failure
+    AppendResult {
+        success: bool,
+        last_index: u64,
+    },
+    /// Sent in place of entries the leader no longer holds. The follower
+    /// answers with an append result.
+    InstallSnapshot {
+        snapshot: Snapshot,
+    },
+    /// Asks the followers to confirm the sender still leads them before the
+    /// reads up to the id are served
+    ReadIndex {
+        read_id: u64,
+    },
+    ReadIndexResult {
+        read_id: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub term: u64,
+    pub body: Body,
+}
+
+impl Message {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Message, Error> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
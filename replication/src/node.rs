@@ -0,0 +1,695 @@
+use crate::error::Error;
+use crate::log::RaftLog;
+use crate::message::{Body, Entry, Message, Snapshot};
+use crate::NodeId;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub id: NodeId,
+    /// Every other node in the cluster
+    pub peers: Vec<NodeId>,
+    /// A follower that hears nothing from a leader for between this many and
+    /// twice this many ticks stands for election
+    pub election_ticks: u64,
+    /// A leader sends entries or an empty append this often
+    pub heartbeat_ticks: u64,
+    pub max_entries_per_message: usize,
+}
+
+impl Config {
+    pub fn new(id: NodeId, peers: Vec<NodeId>) -> Config {
+        Config {
+            id,
+            peers,
+            election_ticks: 10,
+            heartbeat_ticks: 3,
+            max_entries_per_message: 64,
+        }
+    }
+}
+
+/// A read waiting for a majority to confirm that this node still leads them
+struct PendingRead {
+    id: u64,
+    confirmed_by: HashSet<NodeId>,
+    waited_ticks: u64,
+}
+
+/// One member of a raft cluster. The node does no IO of its own beyond its
+/// log. Time passes by calling tick, messages from other nodes are handed to
+/// step and the messages it wants sent are collected with take_messages.
+/// Entries become available from take_committed once a majority of the
+/// cluster has stored them and are handed out in log order exactly once.
+/// The application compacts the log by handing back a snapshot of what it
+/// has applied. A snapshot from take_installed_snapshot must be restored
+/// before the entries handed out after it are applied.
+/// A read asked for with read_index is linearizable once take_ready_reads
+/// hands it out and every committed entry has been applied.
+pub struct RaftNode {
+    config: Config,
+    log: RaftLog,
+    role: Role,
+    leader: Option<NodeId>,
+    votes: HashSet<NodeId>,
+    commit_index: u64,
+    taken_index: u64,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    elapsed_ticks: u64,
+    election_timeout: u64,
+    random_state: u64,
+    outbox: Vec<Message>,
+    maybe_installed_snapshot: Option<Snapshot>,
+    next_read_id: u64,
+    pending_reads: Vec<PendingRead>,
+    ready_reads: Vec<u64>,
+}
+
+impl RaftNode {
+    pub fn new(config: Config, log: RaftLog) -> RaftNode {
+        // Each node is seeded differently so that their election timeouts
+        // spread out and one of them usually stands alone
+        let random_state = (config.id + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        // A log that starts from a snapshot was committed up to it
+        let snapshot_index = log.snapshot_index();
+        let maybe_installed_snapshot = log.snapshot().cloned();
+
+        let mut node = RaftNode {
+            config,
+            log,
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            commit_index: snapshot_index,
+            taken_index: snapshot_index,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            elapsed_ticks: 0,
+            election_timeout: 0,
+            random_state,
+            outbox: vec![],
+            maybe_installed_snapshot,
+            next_read_id: 1,
+            pending_reads: vec![],
+            ready_reads: vec![],
+        };
+
+        node.reset_election_timeout();
+
+        node
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.config.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.log.term()
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn log(&self) -> &RaftLog {
+        &self.log
+    }
+
+    fn quorum(&self) -> usize {
+        let cluster_size = self.config.peers.len() + 1;
+
+        cluster_size / 2 + 1
+    }
+
+    fn reset_election_timeout(&mut self) {
+        // xorshift is plenty to spread timeouts
+        self.random_state ^= self.random_state << 13;
+        self.random_state ^= self.random_state >> 7;
+        self.random_state ^= self.random_state << 17;
+
+        self.elapsed_ticks = 0;
+        self.election_timeout =
+            self.config.election_ticks + self.random_state % self.config.election_ticks.max(1);
+    }
+
+    fn send(&mut self, to: NodeId, body: Body) {
+        self.outbox.push(Message {
+            from: self.config.id,
+            to,
+            term: self.log.term(),
+            body,
+        });
+    }
+
+    /// Advances time by one tick. Leaders send heartbeats and everyone else
+    /// stands for election once they have waited long enough.
+    pub fn tick(&mut self) -> Result<(), Error> {
+        self.elapsed_ticks += 1;
+
+        if self.role == Role::Leader {
+            if self.elapsed_ticks >= self.config.heartbeat_ticks {
+                self.elapsed_ticks = 0;
+                self.broadcast_append();
+                self.broadcast_read();
+            }
+
+            // A leader cut off from the majority does not hold on to reads
+            // it has not had confirmed within the longest election timeout
+            let election_ticks = self.config.election_ticks;
+            for read in self.pending_reads.iter_mut() {
+                read.waited_ticks += 1;
+            }
+            self.pending_reads
+                .retain(|read| read.waited_ticks < 2 * election_ticks);
+
+            return Ok(());
+        }
+
+        if self.elapsed_ticks >= self.election_timeout {
+            self.campaign()?;
+        }
+
+        Ok(())
+    }
+
+    fn campaign(&mut self) -> Result<(), Error> {
+        let term = self.log.term() + 1;
+        self.log.set_term_and_vote(term, Some(self.config.id))?;
+
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = HashSet::new();
+        self.votes.insert(self.config.id);
+        self.reset_election_timeout();
+
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+
+        let last_log_index = self.log.last_index();
+        let last_log_term = self.log.last_term();
+
+        for peer in self.config.peers.clone() {
+            self.send(
+                peer,
+                Body::RequestVote {
+                    last_log_index,
+                    last_log_term,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<(), Error> {
+        if term > self.log.term() {
+            self.log.set_term_and_vote(term, None)?;
+        }
+
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes = HashSet::new();
+        self.pending_reads.clear();
+        self.reset_election_timeout();
+
+        Ok(())
+    }
+
+    /// A new leader appends an empty entry as entries from earlier terms are
+    /// only committed once an entry from its own term is
+    fn become_leader(&mut self) -> Result<(), Error> {
+        self.role = Role::Leader;
+        self.leader = Some(self.config.id);
+        self.elapsed_ticks = 0;
+
+        let next_index = self.log.last_index() + 1;
+
+        for peer in self.config.peers.iter() {
+            self.next_index.insert(*peer, next_index);
+            self.match_index.insert(*peer, 0);
+        }
+
+        self.append_command(vec![])?;
+
+        Ok(())
+    }
+
+    fn append_command(&mut self, command: Vec<u8>) -> Result<u64, Error> {
+        let index = self.log.last_index() + 1;
+
+        self.log.append(vec![Entry {
+            term: self.log.term(),
+            index,
+            command,
+        }])?;
+
+        self.broadcast_append();
+        self.advance_commit_index();
+
+        Ok(index)
+    }
+
+    /// Appends a command to the log if this node is the leader returning the
+    /// index it will be committed at. The command may still be lost if the
+    /// node stops being leader before it is committed in which case a
+    /// different entry is committed at the index.
+    pub fn propose(&mut self, command: Vec<u8>) -> Result<u64, Error> {
+        if self.role != Role::Leader {
+            return Err(Error::NotLeader {
+                leader: self.leader,
+            });
+        }
+
+        self.append_command(command)
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.config.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    /// Sends the entries the peer is missing or the snapshot when the log no
+    /// longer holds them
+    fn send_append(&mut self, peer: NodeId) {
+        let next_index = *self
+            .next_index
+            .get(&peer)
+            .unwrap_or(&(self.log.last_index() + 1));
+
+        if next_index < self.log.first_index() {
+            if let Some(snapshot) = self.log.snapshot().cloned() {
+                self.send(peer, Body::InstallSnapshot { snapshot });
+
+                return;
+            }
+        }
+
+        let prev_log_index = next_index - 1;
+        let prev_log_term = self.log.term_at(prev_log_index).unwrap_or(0);
+        let entries = self
+            .log
+            .entries_from(next_index, self.config.max_entries_per_message);
+
+        self.send(
+            peer,
+            Body::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit: self.commit_index,
+            },
+        );
+    }
+
+    /// Commits the highest entry from the current term that a majority holds
+    fn advance_commit_index(&mut self) {
+        let term = self.log.term();
+
+        for index in (self.commit_index + 1..=self.log.last_index()).rev() {
+            if self.log.term_at(index) != Some(term) {
+                break;
+            }
+
+            let holders = 1 + self
+                .match_index
+                .values()
+                .filter(|match_index| **match_index >= index)
+                .count();
+
+            if holders >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+
+        self.release_reads();
+    }
+
+    /// Asks for a linearizable read returning its id. The read is handed out
+    /// by take_ready_reads once a majority has confirmed this node still
+    /// leads them and an entry from its own term has been committed, so that
+    /// the commit index covers every entry committed before the read was
+    /// asked for. A read is dropped if this node stops being the leader or
+    /// a majority does not answer in time.
+    pub fn read_index(&mut self) -> Result<u64, Error> {
+        if self.role != Role::Leader {
+            return Err(Error::NotLeader {
+                leader: self.leader,
+            });
+        }
+
+        let id = self.next_read_id;
+        self.next_read_id += 1;
+
+        self.pending_reads.push(PendingRead {
+            id,
+            confirmed_by: HashSet::new(),
+            waited_ticks: 0,
+        });
+
+        self.broadcast_read();
+        self.release_reads();
+
+        Ok(id)
+    }
+
+    fn broadcast_read(&mut self) {
+        if let Some(read_id) = self.pending_reads.last().map(|read| read.id) {
+            for peer in self.config.peers.clone() {
+                self.send(peer, Body::ReadIndex { read_id });
+            }
+        }
+    }
+
+    /// Moves the reads a majority has confirmed to the ready reads. Reads are
+    /// confirmed in the order they were asked for.
+    fn release_reads(&mut self) {
+        if self.role != Role::Leader || self.log.term_at(self.commit_index) != Some(self.log.term())
+        {
+            return;
+        }
+
+        let quorum = self.quorum();
+        let confirmed = self
+            .pending_reads
+            .iter()
+            .take_while(|read| read.confirmed_by.len() + 1 >= quorum)
+            .count();
+
+        self.ready_reads
+            .extend(self.pending_reads.drain(..confirmed).map(|read| read.id));
+    }
+
+    /// Handles a message from another node
+    pub fn step(&mut self, message: Message) -> Result<(), Error> {
+        if message.term > self.log.term() {
+            let leader = match message.body {
+                Body::AppendEntries { .. }
+                | Body::InstallSnapshot { .. }
+                | Body::ReadIndex { .. } => Some(message.from),
+                _ => None,
+            };
+
+            self.become_follower(message.term, leader)?;
+        }
+
+        if message.term < self.log.term() {
+            // Tell a stale leader or candidate about the newer term
+            match message.body {
+                Body::RequestVote { .. } => self.send(message.from, Body::Vote { granted: false }),
+                Body::AppendEntries { .. }
+                | Body::InstallSnapshot { .. }
+                | Body::ReadIndex { .. } => self.send(
+                    message.from,
+                    Body::AppendResult {
+                        success: false,
+                        last_index: self.log.last_index(),
+                    },
+                ),
+                _ => (),
+            }
+
+            return Ok(());
+        }
+
+        match message.body {
+            Body::RequestVote {
+                last_log_index,
+                last_log_term,
+            } => self.handle_request_vote(message.from, last_log_index, last_log_term),
+            Body::Vote { granted } => self.handle_vote(message.from, granted),
+            Body::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.handle_append_entries(
+                message.from,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            ),
+            Body::AppendResult {
+                success,
+                last_index,
+            } => {
+                self.handle_append_result(message.from, success, last_index);
+
+                Ok(())
+            }
+            Body::InstallSnapshot { snapshot } => {
+                self.handle_install_snapshot(message.from, snapshot)
+            }
+            Body::ReadIndex { read_id } => self.handle_read_index(message.from, read_id),
+            Body::ReadIndexResult { read_id } => {
+                self.handle_read_index_result(message.from, read_id);
+
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_request_vote(
+        &mut self,
+        candidate: NodeId,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> Result<(), Error> {
+        let can_vote = match self.log.voted_for() {
+            Some(voted_for) => voted_for == candidate,
+            None => self.leader.is_none(),
+        };
+        let candidate_is_up_to_date =
+            (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+        let granted = can_vote && candidate_is_up_to_date;
+
+        if granted {
+            self.log
+                .set_term_and_vote(self.log.term(), Some(candidate))?;
+            self.reset_election_timeout();
+        }
+
+        self.send(candidate, Body::Vote { granted });
+
+        Ok(())
+    }
+
+    fn handle_vote(&mut self, voter: NodeId, granted: bool) -> Result<(), Error> {
+        if self.role != Role::Candidate || !granted {
+            return Ok(());
+        }
+
+        self.votes.insert(voter);
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader()?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_append_entries(
+        &mut self,
+        leader: NodeId,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<(), Error> {
+        if self.role != Role::Follower || self.leader != Some(leader) {
+            self.become_follower(self.log.term(), Some(leader))?;
+        }
+
+        self.reset_election_timeout();
+
+        // Entries up to the snapshot were committed so agree with the leader's
+        let is_compacted = prev_log_index < self.log.snapshot_index();
+
+        if !is_compacted && self.log.term_at(prev_log_index) != Some(prev_log_term) {
+            // Have the leader step back to the entry before the mismatch or
+            // straight to the end of a log that is too short
+            let last_index = (prev_log_index - 1).min(self.log.last_index());
+            self.send(
+                leader,
+                Body::AppendResult {
+                    success: false,
+                    last_index,
+                },
+            );
+
+            return Ok(());
+        }
+
+        let last_index = (prev_log_index + entries.len() as u64).max(self.log.snapshot_index());
+        self.log.merge(entries)?;
+
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(last_index);
+        }
+
+        self.send(
+            leader,
+            Body::AppendResult {
+                success: true,
+                last_index,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Replaces the log up to the snapshot unless everything it covers has
+    /// already been committed here
+    fn handle_install_snapshot(&mut self, leader: NodeId, snapshot: Snapshot) -> Result<(), Error> {
+        if self.role != Role::Follower || self.leader != Some(leader) {
+            self.become_follower(self.log.term(), Some(leader))?;
+        }
+
+        self.reset_election_timeout();
+
+        let last_index = snapshot.index;
+
+        if snapshot.index > self.commit_index {
+            self.log.save_snapshot(snapshot.clone())?;
+            self.commit_index = snapshot.index;
+            self.taken_index = snapshot.index;
+            self.maybe_installed_snapshot = Some(snapshot);
+        }
+
+        self.send(
+            leader,
+            Body::AppendResult {
+                success: true,
+                last_index,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Confirms the leader of the current term like an empty append would
+    fn handle_read_index(&mut self, leader: NodeId, read_id: u64) -> Result<(), Error> {
+        if self.role != Role::Follower || self.leader != Some(leader) {
+            self.become_follower(self.log.term(), Some(leader))?;
+        }
+
+        self.reset_election_timeout();
+        self.send(leader, Body::ReadIndexResult { read_id });
+
+        Ok(())
+    }
+
+    /// A follower answering a read confirms every read asked for before it
+    fn handle_read_index_result(&mut self, follower: NodeId, read_id: u64) {
+        if self.role != Role::Leader {
+            return;
+        }
+
+        for read in self.pending_reads.iter_mut() {
+            if read.id <= read_id {
+                read.confirmed_by.insert(follower);
+            }
+        }
+
+        self.release_reads();
+    }
+
+    fn handle_append_result(&mut self, follower: NodeId, success: bool, last_index: u64) {
+        if self.role != Role::Leader {
+            return;
+        }
+
+        if success {
+            let match_index = self.match_index.entry(follower).or_insert(0);
+            *match_index = (*match_index).max(last_index);
+            let next_index = *match_index + 1;
+            self.next_index.insert(follower, next_index);
+
+            self.advance_commit_index();
+
+            if next_index <= self.log.last_index() {
+                self.send_append(follower);
+            }
+        } else {
+            let next_index = self.next_index.entry(follower).or_insert(1);
+            *next_index = (*next_index - 1).min(last_index + 1).max(1);
+
+            self.send_append(follower);
+        }
+    }
+
+    /// Every message the node has asked to send since the last call
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// The snapshot the log started from or the leader sent since the last
+    /// call. It must be restored before the entries take_committed hands out
+    /// next are applied.
+    pub fn take_installed_snapshot(&mut self) -> Option<Snapshot> {
+        self.maybe_installed_snapshot.take()
+    }
+
+    /// Replaces the entries up to the index with the application's state once
+    /// it has applied them. Entries must have been handed out by
+    /// take_committed before they can be compacted.
+    pub fn compact(&mut self, index: u64, data: Vec<u8>) -> Result<(), Error> {
+        if index <= self.log.snapshot_index() {
+            return Ok(());
+        }
+
+        if index > self.taken_index {
+            return Err(Error::new(format!(
+                "Cannot compact the log up to entry {} as only {} have been applied",
+                index, self.taken_index
+            )));
+        }
+
+        let term = self
+            .log
+            .term_at(index)
+            .ok_or_else(|| Error::new(format!("Entry {} is not in the log", index)))?;
+
+        self.log.save_snapshot(Snapshot { index, term, data })
+    }
+
+    /// The ids of the reads confirmed since the last call in the order they
+    /// were asked for. Each may be served once every entry up to the commit
+    /// index has been applied.
+    pub fn take_ready_reads(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.ready_reads)
+    }
+
+    /// Every entry committed since the last call in log order
+    pub fn take_committed(&mut self) -> Vec<Entry> {
+        let count = (self.commit_index - self.taken_index) as usize;
+        let entries = self.log.entries_from(self.taken_index + 1, count);
+
+        self.taken_index += entries.len() as u64;
+
+        entries
+    }
+}
@@ -0,0 +1,575 @@
+use spq_replication::error::Error;
+use spq_replication::log::RaftLog;
+use spq_replication::message::{Body, Entry, Message, Snapshot};
+use spq_replication::node::{Config, RaftNode, Role};
+use spq_replication::NodeId;
+use std::collections::HashSet;
+
+/// Enough ticks for any election to finish
+const SETTLE_TICKS: usize = 100;
+
+/// Nodes in one process joined by a network that can be split in two
+struct Cluster {
+    nodes: Vec<RaftNode>,
+    isolated: HashSet<NodeId>,
+    committed: Vec<Vec<Entry>>,
+}
+
+impl Cluster {
+    fn new(size: u64) -> Cluster {
+        let ids: Vec<NodeId> = (0..size).collect();
+
+        Cluster {
+            nodes: ids
+                .iter()
+                .map(|id| {
+                    let peers = ids.iter().copied().filter(|peer| peer != id).collect();
+
+                    RaftNode::new(Config::new(*id, peers), RaftLog::in_memory())
+                })
+                .collect(),
+            isolated: HashSet::new(),
+            committed: ids.iter().map(|_| vec![]).collect(),
+        }
+    }
+
+    fn node(&mut self, id: NodeId) -> &mut RaftNode {
+        &mut self.nodes[id as usize]
+    }
+
+    /// Cuts the nodes off from the rest. They can still reach each other.
+    fn partition(&mut self, ids: &[NodeId]) {
+        self.isolated = ids.iter().copied().collect();
+    }
+
+    fn heal(&mut self) {
+        self.isolated.clear();
+    }
+
+    fn can_reach(&self, from: NodeId, to: NodeId) -> bool {
+        self.isolated.contains(&from) == self.isolated.contains(&to)
+    }
+
+    /// Delivers messages until none are left. Messages across the partition
+    /// are dropped.
+    fn deliver(&mut self) {
+        loop {
+            let mut messages: Vec<Message> = vec![];
+
+            for node in self.nodes.iter_mut() {
+                messages.extend(node.take_messages());
+            }
+
+            if messages.is_empty() {
+                break;
+            }
+
+            for message in messages {
+                if self.can_reach(message.from, message.to) {
+                    let to = message.to;
+                    self.node(to).step(message).unwrap();
+                }
+            }
+        }
+
+        for (id, node) in self.nodes.iter_mut().enumerate() {
+            // The committed entries stand in for the state of the application
+            if let Some(snapshot) = node.take_installed_snapshot() {
+                self.committed[id] = bincode::deserialize(&snapshot.data).unwrap();
+            }
+
+            self.committed[id].extend(node.take_committed());
+        }
+    }
+
+    fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            for node in self.nodes.iter_mut() {
+                node.tick().unwrap();
+            }
+
+            self.deliver();
+        }
+    }
+
+    /// The leaders on the side of the partition that holds the node
+    fn leaders_reachable_from(&self, id: NodeId) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .filter(|node| node.is_leader() && self.can_reach(id, node.id()))
+            .map(|node| node.id())
+            .collect()
+    }
+
+    fn leader_reachable_from(&self, id: NodeId) -> NodeId {
+        let leaders = self.leaders_reachable_from(id);
+
+        assert_eq!(leaders.len(), 1, "expected one leader in {:?}", leaders);
+
+        leaders[0]
+    }
+
+    fn propose(&mut self, id: NodeId, command: &[u8]) -> Result<u64, Error> {
+        let result = self.node(id).propose(command.to_vec());
+        self.deliver();
+
+        result
+    }
+
+    /// Compacts the node's log up to the last entry it has had committed
+    fn compact(&mut self, id: NodeId) {
+        let committed = &self.committed[id as usize];
+        let index = committed.last().map(|entry| entry.index).unwrap_or(0);
+        let data = bincode::serialize(committed).unwrap();
+
+        self.node(id).compact(index, data).unwrap();
+    }
+
+    /// The commands each node has had committed skipping leader markers
+    fn committed_commands(&self, id: NodeId) -> Vec<Vec<u8>> {
+        self.committed[id as usize]
+            .iter()
+            .filter(|entry| !entry.command.is_empty())
+            .map(|entry| entry.command.clone())
+            .collect()
+    }
+}
+
+fn temporary_folder(name: &str) -> std::path::PathBuf {
+    let folder_path = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&folder_path);
+
+    folder_path
+}
+
+#[test]
+fn must_elect_exactly_one_leader() {
+    let mut cluster = Cluster::new(3);
+
+    cluster.run(SETTLE_TICKS);
+
+    let leader = cluster.leader_reachable_from(0);
+    let term = cluster.node(leader).term();
+
+    for id in 0..3 {
+        assert_eq!(cluster.node(id).leader(), Some(leader));
+        assert_eq!(cluster.node(id).term(), term);
+    }
+}
+
+#[test]
+fn must_commit_immediately_in_a_single_node_cluster() {
+    let mut cluster = Cluster::new(1);
+
+    cluster.run(SETTLE_TICKS);
+    cluster.propose(0, b"only").unwrap();
+
+    assert_eq!(cluster.committed_commands(0), vec![b"only".to_vec()]);
+}
+
+#[test]
+fn must_commit_proposals_on_every_node_in_order() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let leader = cluster.leader_reachable_from(0);
+
+    cluster.propose(leader, b"first").unwrap();
+    cluster.propose(leader, b"second").unwrap();
+    cluster.run(SETTLE_TICKS);
+
+    for id in 0..3 {
+        assert_eq!(
+            cluster.committed_commands(id),
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+    }
+}
+
+#[test]
+fn must_reject_proposals_on_followers_naming_the_leader() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let leader = cluster.leader_reachable_from(0);
+    let follower = (leader + 1) % 3;
+
+    assert_eq!(
+        cluster.propose(follower, b"command"),
+        Err(Error::NotLeader {
+            leader: Some(leader)
+        })
+    );
+}
+
+#[test]
+fn must_commit_once_a_majority_holds_an_entry() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let leader = cluster.leader_reachable_from(0);
+    let follower = (leader + 1) % 3;
+
+    // The leader and one follower are a majority of three
+    cluster.partition(&[follower]);
+    cluster.propose(leader, b"command").unwrap();
+
+    assert_eq!(
+        cluster.committed_commands(leader),
+        vec![b"command".to_vec()]
+    );
+    assert!(cluster.committed_commands(follower).is_empty());
+}
+
+#[test]
+fn must_not_commit_in_a_partitioned_minority() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(SETTLE_TICKS);
+    let leader = cluster.leader_reachable_from(0);
+    let follower = (leader + 1) % 5;
+
+    cluster.partition(&[leader, follower]);
+    cluster.propose(leader, b"lost").unwrap();
+    cluster.run(SETTLE_TICKS);
+
+    for id in 0..5 {
+        assert!(cluster.committed_commands(id).is_empty());
+    }
+}
+
+#[test]
+fn must_fail_over_to_a_new_leader_in_the_majority() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let old_leader = cluster.leader_reachable_from(0);
+    let follower = (old_leader + 1) % 3;
+
+    cluster.propose(old_leader, b"before").unwrap();
+    cluster.partition(&[old_leader]);
+    cluster.propose(old_leader, b"lost").unwrap();
+    cluster.run(SETTLE_TICKS);
+
+    let new_leader = cluster.leader_reachable_from(follower);
+    assert_ne!(new_leader, old_leader);
+    assert!(cluster.node(new_leader).term() > cluster.node(old_leader).term());
+
+    cluster.propose(new_leader, b"after").unwrap();
+    cluster.heal();
+    cluster.run(SETTLE_TICKS);
+
+    // The entry only the old leader held is replaced by the new leader's
+    assert_eq!(cluster.node(old_leader).role(), Role::Follower);
+    assert_eq!(cluster.node(old_leader).leader(), Some(new_leader));
+
+    for id in 0..3 {
+        assert_eq!(
+            cluster.committed_commands(id),
+            vec![b"before".to_vec(), b"after".to_vec()]
+        );
+    }
+}
+
+#[test]
+fn must_refuse_votes_to_candidates_missing_committed_entries() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let leader = cluster.leader_reachable_from(0);
+    let lagging = (leader + 1) % 3;
+    let term = cluster.node(leader).term();
+
+    cluster.partition(&[lagging]);
+    cluster.propose(leader, b"committed").unwrap();
+    cluster.heal();
+
+    // A vote request from the lagging node's next term is refused
+    let up_to_date = (leader + 2) % 3;
+    cluster
+        .node(up_to_date)
+        .step(Message {
+            from: lagging,
+            to: up_to_date,
+            term: term + 1,
+            body: Body::RequestVote {
+                last_log_index: 1,
+                last_log_term: term,
+            },
+        })
+        .unwrap();
+
+    let replies = cluster.node(up_to_date).take_messages();
+
+    assert!(replies.contains(&Message {
+        from: up_to_date,
+        to: lagging,
+        term: term + 1,
+        body: Body::Vote { granted: false },
+    }));
+}
+
+#[test]
+fn must_confirm_reads_once_a_majority_follows_the_leader() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let leader = cluster.leader_reachable_from(0);
+    let follower = (leader + 1) % 3;
+
+    // One follower is enough to make a majority of three
+    cluster.partition(&[follower]);
+    let read_id = cluster.node(leader).read_index().unwrap();
+    cluster.deliver();
+
+    assert_eq!(cluster.node(leader).take_ready_reads(), vec![read_id]);
+}
+
+#[test]
+fn must_confirm_reads_immediately_in_a_single_node_cluster() {
+    let mut cluster = Cluster::new(1);
+    cluster.run(SETTLE_TICKS);
+
+    let read_id = cluster.node(0).read_index().unwrap();
+
+    assert_eq!(cluster.node(0).take_ready_reads(), vec![read_id]);
+}
+
+#[test]
+fn must_reject_reads_on_followers_naming_the_leader() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let leader = cluster.leader_reachable_from(0);
+    let follower = (leader + 1) % 3;
+
+    assert_eq!(
+        cluster.node(follower).read_index(),
+        Err(Error::NotLeader {
+            leader: Some(leader)
+        })
+    );
+}
+
+#[test]
+fn must_not_confirm_reads_on_a_leader_cut_off_from_the_majority() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let old_leader = cluster.leader_reachable_from(0);
+    let follower = (old_leader + 1) % 3;
+
+    cluster.partition(&[old_leader]);
+    cluster.node(old_leader).read_index().unwrap();
+    cluster.run(SETTLE_TICKS);
+
+    // The majority elected a new leader while the old one still believes it
+    // leads so only a read confirmed by a majority is safe to serve
+    assert_ne!(cluster.leader_reachable_from(follower), old_leader);
+    assert!(cluster.node(old_leader).is_leader());
+    assert!(cluster.node(old_leader).take_ready_reads().is_empty());
+}
+
+#[test]
+fn must_restore_term_vote_and_entries_after_restart() {
+    let folder_path = temporary_folder("spq_raft_restart");
+
+    {
+        let mut log = RaftLog::open(&folder_path).unwrap();
+
+        log.set_term_and_vote(3, Some(2)).unwrap();
+        log.append(vec![
+            Entry {
+                term: 2,
+                index: 1,
+                command: b"first".to_vec(),
+            },
+            Entry {
+                term: 3,
+                index: 2,
+                command: b"second".to_vec(),
+            },
+        ])
+        .unwrap();
+    }
+
+    let log = RaftLog::open(&folder_path).unwrap();
+
+    assert_eq!(log.term(), 3);
+    assert_eq!(log.voted_for(), Some(2));
+    assert_eq!(log.last_index(), 2);
+    assert_eq!(log.entry(2).unwrap().command, b"second".to_vec());
+}
+
+#[test]
+fn must_replace_conflicting_entries_on_disk() {
+    let folder_path = temporary_folder("spq_raft_conflict");
+
+    {
+        let mut log = RaftLog::open(&folder_path).unwrap();
+
+        log.append(vec![
+            Entry {
+                term: 1,
+                index: 1,
+                command: b"kept".to_vec(),
+            },
+            Entry {
+                term: 1,
+                index: 2,
+                command: b"replaced".to_vec(),
+            },
+        ])
+        .unwrap();
+        log.merge(vec![Entry {
+            term: 2,
+            index: 2,
+            command: b"replacement".to_vec(),
+        }])
+        .unwrap();
+    }
+
+    let log = RaftLog::open(&folder_path).unwrap();
+
+    assert_eq!(log.last_index(), 2);
+    assert_eq!(log.entry(1).unwrap().command, b"kept".to_vec());
+    assert_eq!(log.entry(2).unwrap().command, b"replacement".to_vec());
+}
+
+#[test]
+fn must_keep_committing_after_compacting_the_log() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let leader = cluster.leader_reachable_from(0);
+
+    cluster.propose(leader, b"first").unwrap();
+    cluster.propose(leader, b"second").unwrap();
+    cluster.compact(leader);
+    cluster.propose(leader, b"third").unwrap();
+    cluster.run(SETTLE_TICKS);
+
+    let log = cluster.node(leader).log();
+    assert_eq!(log.first_index(), log.last_index());
+
+    for id in 0..3 {
+        assert_eq!(
+            cluster.committed_commands(id),
+            vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+    }
+}
+
+#[test]
+fn must_send_a_snapshot_to_a_follower_missing_compacted_entries() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(SETTLE_TICKS);
+    let leader = cluster.leader_reachable_from(0);
+    let lagging = (leader + 1) % 3;
+
+    cluster.partition(&[lagging]);
+    cluster.propose(leader, b"first").unwrap();
+    cluster.propose(leader, b"second").unwrap();
+    cluster.compact(leader);
+    cluster.heal();
+    cluster.propose(leader, b"third").unwrap();
+    cluster.run(SETTLE_TICKS);
+
+    assert_eq!(
+        cluster.node(lagging).log().snapshot_index(),
+        cluster.node(leader).log().snapshot_index()
+    );
+    assert_eq!(
+        cluster.committed_commands(lagging),
+        vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+    );
+}
+
+#[test]
+fn must_refuse_to_compact_entries_not_yet_applied() {
+    let mut cluster = Cluster::new(1);
+    cluster.run(SETTLE_TICKS);
+    let last_index = cluster.node(0).log().last_index();
+
+    assert!(cluster.node(0).compact(last_index + 1, vec![]).is_err());
+}
+
+#[test]
+fn must_restore_the_snapshot_and_later_entries_after_restart() {
+    let folder_path = temporary_folder("spq_raft_snapshot");
+
+    {
+        let mut log = RaftLog::open(&folder_path).unwrap();
+
+        log.append(vec![
+            Entry {
+                term: 1,
+                index: 1,
+                command: b"first".to_vec(),
+            },
+            Entry {
+                term: 2,
+                index: 2,
+                command: b"second".to_vec(),
+            },
+            Entry {
+                term: 2,
+                index: 3,
+                command: b"third".to_vec(),
+            },
+        ])
+        .unwrap();
+        log.save_snapshot(Snapshot {
+            index: 2,
+            term: 2,
+            data: b"state".to_vec(),
+        })
+        .unwrap();
+        log.append(vec![Entry {
+            term: 3,
+            index: 4,
+            command: b"fourth".to_vec(),
+        }])
+        .unwrap();
+    }
+
+    let log = RaftLog::open(&folder_path).unwrap();
+
+    assert_eq!(log.snapshot().unwrap().data, b"state".to_vec());
+    assert_eq!(log.first_index(), 3);
+    assert_eq!(log.last_index(), 4);
+    assert_eq!(log.term_at(2), Some(2));
+    assert_eq!(log.entry(2), None);
+    assert_eq!(log.entry(3).unwrap().command, b"third".to_vec());
+    assert_eq!(log.entry(4).unwrap().command, b"fourth".to_vec());
+
+    // The node hands the snapshot back to be restored before anything else
+    let mut node = RaftNode::new(Config::new(0, vec![]), log);
+
+    assert_eq!(node.take_installed_snapshot().unwrap().index, 2);
+    assert_eq!(node.commit_index(), 2);
+}
+
+#[test]
+fn must_drop_entries_that_disagree_with_a_saved_snapshot() {
+    let mut log = RaftLog::in_memory();
+
+    log.append(vec![
+        Entry {
+            term: 1,
+            index: 1,
+            command: b"kept".to_vec(),
+        },
+        Entry {
+            term: 1,
+            index: 2,
+            command: b"stale".to_vec(),
+        },
+        Entry {
+            term: 1,
+            index: 3,
+            command: b"stale".to_vec(),
+        },
+    ])
+    .unwrap();
+    log.save_snapshot(Snapshot {
+        index: 2,
+        term: 2,
+        data: vec![],
+    })
+    .unwrap();
+
+    assert_eq!(log.last_index(), 2);
+    assert_eq!(log.last_term(), 2);
+    assert_eq!(log.entry(3), None);
+}
@@ -0,0 +1,508 @@
+use log::{info, LevelFilter};
+use serde::Deserialize;
+use sp_queue::database::Tuning;
+use sp_queue::storage::StorageType;
+use spq_replication::NodeId;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "[::0]:9090";
+
+const DEFAULT_METRICS_ADDRESS: &str = "[::0]:9091";
+
+const DEFAULT_DATA_ROOT: &str = "/var/lib/spqr/";
+
+/// The largest message gRPC clients send by default
+const DEFAULT_MAX_ITEM_SIZE: usize = 4 << 20;
+
+const DEFAULT_MAX_QUEUE_COUNT: usize = 10_000;
+
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+const DEFAULT_SHUTDOWN_DELAY_MS: u64 = 2000;
+
+const DEFAULT_MIN_FREE_DISK_BYTES: u64 = 100 << 20;
+
+/// The queue type used when a create queue request asks for the server's
+/// default
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueType {
+    Memory,
+    Durable,
+}
+
+impl FromStr for QueueType {
+    type Err = String;
+
+    fn from_str(queue_type: &str) -> Result<QueueType, String> {
+        match queue_type {
+            "memory" => Ok(QueueType::Memory),
+            "durable" => Ok(QueueType::Durable),
+            _ => Err(format!(
+                "Invalid queue type {:?} expected memory or durable",
+                queue_type
+            )),
+        }
+    }
+}
+
+impl From<QueueType> for StorageType {
+    fn from(queue_type: QueueType) -> StorageType {
+        match queue_type {
+            QueueType::Memory => StorageType::Memory,
+            QueueType::Durable => StorageType::Durable,
+        }
+    }
+}
+
+/// The id of a node and the address of every node it shares queues with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Members {
+    pub id: NodeId,
+    pub addresses: HashMap<NodeId, String>,
+}
+
+/// How a server shares its queues with other servers. A node either
+/// replicates or shards, never both.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cluster {
+    Standalone,
+    /// Every node holds a copy of every queue
+    Replicated(Members),
+    /// Each queue is held by the one node that owns it
+    Sharded(Members),
+}
+
+/// Parses a comma separated list of id=address pairs
+pub fn parse_addresses(peers: &str) -> Result<HashMap<NodeId, String>, String> {
+    let mut addresses = HashMap::new();
+
+    for peer in peers.split(',').filter(|peer| !peer.trim().is_empty()) {
+        let mut parts = peer.trim().splitn(2, '=');
+        let (peer_id, address) = match (parts.next(), parts.next()) {
+            (Some(peer_id), Some(address)) => (peer_id, address),
+            _ => return Err(format!("Invalid peer {:?} expected id=address", peer)),
+        };
+        let peer_id = peer_id
+            .parse::<NodeId>()
+            .map_err(|e| format!("Invalid peer id {:?}: {}", peer_id, e))?;
+
+        addresses.insert(peer_id, address.to_string());
+    }
+
+    Ok(addresses)
+}
+
+/// Every setting may be given as a flag or an environment variable. Flags
+/// take precedence over environment variables which take precedence over the
+/// config file.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "spq_server", about = "A sorting priority queue server")]
+struct Args {
+    /// A TOML file holding any of the settings below
+    #[structopt(long, env = "SPQ_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// The address to serve gRPC on [default: [::0]:9090]
+    #[structopt(long, env = "SPQ_LISTEN_ADDRESS")]
+    listen_address: Option<SocketAddr>,
+
+    /// The address to serve Prometheus metrics on over HTTP at /metrics [default: [::0]:9091]
+    #[structopt(long, env = "SPQ_METRICS_ADDRESS")]
+    metrics_address: Option<SocketAddr>,
+
+    /// Where durable queues and cluster state are kept [default: /var/lib/spqr/]
+    #[structopt(long, env = "SPQ_DATA_ROOT", parse(from_os_str))]
+    data_root: Option<PathBuf>,
+
+    /// memory or durable. Used for queues created with the SERVER_DEFAULT type [default: durable]
+    #[structopt(long, env = "SPQ_DEFAULT_QUEUE_TYPE")]
+    default_queue_type: Option<QueueType>,
+
+    /// The largest item in bytes that may be enqueued [default: 4194304]
+    #[structopt(long, env = "SPQ_MAX_ITEM_SIZE")]
+    max_item_size: Option<usize>,
+
+    /// The most queues the server holds at once [default: 10000]
+    #[structopt(long, env = "SPQ_MAX_QUEUE_COUNT")]
+    max_queue_count: Option<usize>,
+
+    /// off, error, warn, info, debug or trace [default: info]
+    #[structopt(long, env = "SPQ_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// The OTLP collector to export traces to e.g. http://localhost:4317. Traces are not recorded without one
+    #[structopt(long, env = "SPQ_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// How long the server reports it is not serving and turns away new
+    /// requests before it stops listening [default: 2000]
+    #[structopt(long, env = "SPQ_SHUTDOWN_DELAY_MS")]
+    shutdown_delay_ms: Option<u64>,
+
+    /// The server reports it is not serving while fewer bytes than this are
+    /// free under the data root [default: 104857600]
+    #[structopt(long, env = "SPQ_MIN_FREE_DISK_BYTES")]
+    min_free_disk_bytes: Option<u64>,
+
+    /// The most files each RocksDB instance keeps open. -1 keeps them all open
+    #[structopt(long, env = "SPQ_ROCKSDB_MAX_OPEN_FILES", allow_hyphen_values = true)]
+    rocksdb_max_open_files: Option<i32>,
+
+    /// Bytes written to a column family before it is flushed to disk
+    #[structopt(long, env = "SPQ_ROCKSDB_WRITE_BUFFER_SIZE")]
+    rocksdb_write_buffer_size: Option<usize>,
+
+    /// The most write buffers held in memory per column family
+    #[structopt(long, env = "SPQ_ROCKSDB_MAX_WRITE_BUFFER_NUMBER")]
+    rocksdb_max_write_buffer_number: Option<i32>,
+
+    /// Threads each RocksDB instance flushes and compacts with
+    #[structopt(long, env = "SPQ_ROCKSDB_MAX_BACKGROUND_JOBS")]
+    rocksdb_max_background_jobs: Option<i32>,
+
+    /// Whether each write waits for the write ahead log to reach the disk
+    #[structopt(long, env = "SPQ_ROCKSDB_SYNC_WRITES")]
+    rocksdb_sync_writes: Option<bool>,
+
+    /// The id of this node in a replicated or sharded cluster
+    #[structopt(long, env = "SPQ_NODE_ID")]
+    node_id: Option<NodeId>,
+
+    /// Replicate every queue across these nodes given as comma separated
+    /// id=address pairs e.g. 1=http://spq-1:9090,2=http://spq-2:9090
+    #[structopt(long, env = "SPQ_PEERS")]
+    peers: Option<String>,
+
+    /// Spread the queues across these nodes given in the same form as the peers
+    #[structopt(long, env = "SPQ_SHARDS")]
+    shards: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RocksDbFile {
+    max_open_files: Option<i32>,
+    write_buffer_size: Option<usize>,
+    max_write_buffer_number: Option<i32>,
+    max_background_jobs: Option<i32>,
+    sync_writes: Option<bool>,
+}
+
+/// The config file uses the flag names with underscores. RocksDB options go
+/// in a [rocksdb] table without the prefix.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    listen_address: Option<SocketAddr>,
+    metrics_address: Option<SocketAddr>,
+    data_root: Option<PathBuf>,
+    default_queue_type: Option<QueueType>,
+    max_item_size: Option<usize>,
+    max_queue_count: Option<usize>,
+    log_level: Option<String>,
+    otlp_endpoint: Option<String>,
+    shutdown_delay_ms: Option<u64>,
+    min_free_disk_bytes: Option<u64>,
+    node_id: Option<NodeId>,
+    peers: Option<String>,
+    shards: Option<String>,
+    #[serde(default)]
+    rocksdb: RocksDbFile,
+}
+
+impl File {
+    fn read(path: &Path) -> Result<File, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {:?}: {}", path, e))?;
+
+        toml::from_str(&text).map_err(|e| format!("Invalid config file {:?}: {}", path, e))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen_address: SocketAddr,
+    pub metrics_address: SocketAddr,
+    pub data_root: PathBuf,
+    pub default_queue_type: StorageType,
+    pub max_item_size: usize,
+    pub max_queue_count: usize,
+    pub log_level: LevelFilter,
+    pub otlp_endpoint: Option<String>,
+    pub shutdown_delay: Duration,
+    pub min_free_disk_bytes: u64,
+    pub tuning: Tuning,
+    pub cluster: Cluster,
+}
+
+/// Fails unless the name can be used as a folder under the data root. Names
+/// that are empty, hold a path separator or start with a dot, which covers
+/// `.`, `..` and the quarantine folder, are refused.
+pub fn check_queue_name(queue_name: &str) -> Result<(), String> {
+    if queue_name.is_empty() {
+        return Err("Queue names must not be empty".to_string());
+    }
+
+    if queue_name.contains('/') || queue_name.contains('\\') {
+        return Err(format!(
+            "Queue name {:?} must not contain a path separator",
+            queue_name
+        ));
+    }
+
+    if queue_name.starts_with('.') {
+        return Err(format!(
+            "Queue name {:?} must not start with a dot",
+            queue_name
+        ));
+    }
+
+    Ok(())
+}
+
+/// The folder a durable queue of the name is kept in under the queue root.
+/// Fails for names that would not be a folder directly under it.
+pub fn queue_path(queue_root: &Path, queue_name: &str) -> Result<String, String> {
+    check_queue_name(queue_name)?;
+
+    let path = queue_root.join(queue_name);
+
+    if path.parent() != Some(queue_root) {
+        return Err(format!(
+            "Queue name {:?} is not a folder directly under {:?}",
+            queue_name, queue_root
+        ));
+    }
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+impl Config {
+    /// Reads the flags, the environment and the config file if one is named
+    pub fn load() -> Result<Config, String> {
+        Config::load_from(std::env::args_os())
+    }
+
+    /// As load but with the given command line, the first of which is the
+    /// name of the program
+    pub fn load_from<I>(command_line: I) -> Result<Config, String>
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString> + Clone,
+    {
+        let args = Args::from_iter(command_line);
+
+        let file = match args.config {
+            Some(ref path) => File::read(path)?,
+            None => File::default(),
+        };
+
+        Config::resolve(args, file)
+    }
+
+    fn resolve(args: Args, file: File) -> Result<Config, String> {
+        let defaults = Tuning::default();
+
+        let listen_address = match args.listen_address.or(file.listen_address) {
+            Some(listen_address) => listen_address,
+            None => DEFAULT_LISTEN_ADDRESS
+                .parse()
+                .map_err(|e| format!("Invalid default listen address: {}", e))?,
+        };
+        let metrics_address = match args.metrics_address.or(file.metrics_address) {
+            Some(metrics_address) => metrics_address,
+            None => DEFAULT_METRICS_ADDRESS
+                .parse()
+                .map_err(|e| format!("Invalid default metrics address: {}", e))?,
+        };
+        let log_level = args
+            .log_level
+            .or(file.log_level)
+            .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string());
+        let cluster = Cluster::resolve(
+            args.node_id.or(file.node_id),
+            args.peers.or(file.peers),
+            args.shards.or(file.shards),
+        )?;
+
+        let config = Config {
+            listen_address,
+            metrics_address,
+            data_root: args
+                .data_root
+                .or(file.data_root)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_ROOT)),
+            default_queue_type: args
+                .default_queue_type
+                .or(file.default_queue_type)
+                .unwrap_or(QueueType::Durable)
+                .into(),
+            max_item_size: args
+                .max_item_size
+                .or(file.max_item_size)
+                .unwrap_or(DEFAULT_MAX_ITEM_SIZE),
+            max_queue_count: args
+                .max_queue_count
+                .or(file.max_queue_count)
+                .unwrap_or(DEFAULT_MAX_QUEUE_COUNT),
+            log_level: LevelFilter::from_str(&log_level)
+                .map_err(|_| format!("Invalid log level {:?}", log_level))?,
+            otlp_endpoint: args.otlp_endpoint.or(file.otlp_endpoint),
+            shutdown_delay: Duration::from_millis(
+                args.shutdown_delay_ms
+                    .or(file.shutdown_delay_ms)
+                    .unwrap_or(DEFAULT_SHUTDOWN_DELAY_MS),
+            ),
+            min_free_disk_bytes: args
+                .min_free_disk_bytes
+                .or(file.min_free_disk_bytes)
+                .unwrap_or(DEFAULT_MIN_FREE_DISK_BYTES),
+            tuning: Tuning {
+                max_open_files: args
+                    .rocksdb_max_open_files
+                    .or(file.rocksdb.max_open_files)
+                    .unwrap_or(defaults.max_open_files),
+                write_buffer_size: args
+                    .rocksdb_write_buffer_size
+                    .or(file.rocksdb.write_buffer_size)
+                    .unwrap_or(defaults.write_buffer_size),
+                max_write_buffer_number: args
+                    .rocksdb_max_write_buffer_number
+                    .or(file.rocksdb.max_write_buffer_number)
+                    .unwrap_or(defaults.max_write_buffer_number),
+                max_background_jobs: args
+                    .rocksdb_max_background_jobs
+                    .or(file.rocksdb.max_background_jobs)
+                    .unwrap_or(defaults.max_background_jobs),
+                sync_writes: args
+                    .rocksdb_sync_writes
+                    .or(file.rocksdb.sync_writes)
+                    .unwrap_or(defaults.sync_writes),
+            },
+            cluster,
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.metrics_address == self.listen_address {
+            return Err("The metrics address must differ from the listen address".to_string());
+        }
+
+        if self.data_root.as_os_str().is_empty() {
+            return Err("The data root must not be empty".to_string());
+        }
+
+        if self.max_item_size == 0 {
+            return Err("The max item size must be at least one byte".to_string());
+        }
+
+        if self.max_queue_count == 0 {
+            return Err("The max queue count must be at least one".to_string());
+        }
+
+        // RocksDB keeps some files open for itself
+        if self.tuning.max_open_files != -1 && self.tuning.max_open_files < 16 {
+            return Err(format!(
+                "The RocksDB max open files must be -1 or at least 16 not {}",
+                self.tuning.max_open_files
+            ));
+        }
+
+        if self.tuning.write_buffer_size < 64 << 10 {
+            return Err(format!(
+                "The RocksDB write buffer size must be at least 65536 bytes not {}",
+                self.tuning.write_buffer_size
+            ));
+        }
+
+        if self.tuning.max_write_buffer_number < 1 {
+            return Err("The RocksDB max write buffer number must be at least one".to_string());
+        }
+
+        if self.tuning.max_background_jobs < 1 {
+            return Err("The RocksDB max background jobs must be at least one".to_string());
+        }
+
+        match self.cluster {
+            Cluster::Standalone => {}
+            Cluster::Replicated(ref members) => {
+                if !members.addresses.contains_key(&members.id) {
+                    return Err(format!("The peers must include this node {}", members.id));
+                }
+            }
+            // A node joining a sharded cluster may only know of the nodes
+            // already in it
+            Cluster::Sharded(ref members) => {
+                if members.addresses.is_empty() {
+                    return Err("The shards must name at least one node".to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn log(&self) {
+        info!("Listen address: {}", self.listen_address);
+        info!("Metrics address: {}", self.metrics_address);
+        info!("Data root: {:?}", self.data_root);
+        info!("Default queue type: {:?}", self.default_queue_type);
+        info!("Max item size: {} bytes", self.max_item_size);
+        info!("Max queue count: {}", self.max_queue_count);
+        info!("Log level: {}", self.log_level);
+        info!("OTLP endpoint: {:?}", self.otlp_endpoint);
+        info!("Shutdown delay: {:?}", self.shutdown_delay);
+        info!("Min free disk: {} bytes", self.min_free_disk_bytes);
+        info!("RocksDB: {:?}", self.tuning);
+
+        match self.cluster {
+            Cluster::Standalone => info!("Cluster: standalone"),
+            Cluster::Replicated(ref members) => info!(
+                "Cluster: replicated as node {} with peers {:?}",
+                members.id, members.addresses
+            ),
+            Cluster::Sharded(ref members) => info!(
+                "Cluster: sharded as node {} with shards {:?}",
+                members.id, members.addresses
+            ),
+        }
+    }
+}
+
+impl Cluster {
+    fn resolve(
+        maybe_node_id: Option<NodeId>,
+        maybe_peers: Option<String>,
+        maybe_shards: Option<String>,
+    ) -> Result<Cluster, String> {
+        let members = |addresses: &str| -> Result<Members, String> {
+            Ok(Members {
+                id: maybe_node_id.ok_or_else(|| {
+                    "The node id must be set alongside the peers or shards".to_string()
+                })?,
+                addresses: parse_addresses(addresses)?,
+            })
+        };
+
+        match (maybe_peers, maybe_shards) {
+            (None, None) => Ok(Cluster::Standalone),
+            (Some(peers), None) => Ok(Cluster::Replicated(members(&peers)?)),
+            (None, Some(shards)) => Ok(Cluster::Sharded(members(&shards)?)),
+            (Some(_), Some(_)) => Err(
+                "The peers and shards cannot both be set as a node either replicates or shards"
+                    .to_string(),
+            ),
+        }
+    }
+}
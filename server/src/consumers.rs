@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// A subscriber alongside the leases it has been sent but not yet completed
+struct Consumer {
+    quota: usize,
+    lease_deadlines: HashMap<u64, u64>,
+    completed: Arc<Notify>,
+}
+
+/// Tracks every subscriber of a queue so that a subscriber is only sent more
+/// items once it has completed earlier ones. A lease is complete once it is
+/// acked, nacked or its deadline passes.
+#[derive(Default)]
+pub struct ConsumerRegistry {
+    next_consumer_id: u64,
+    consumers: HashMap<u64, Consumer>,
+}
+
+impl ConsumerRegistry {
+    /// Adds a subscriber returning its id and the signal raised each time one
+    /// of its leases is completed.
+    pub fn register(&mut self, quota: usize) -> (u64, Arc<Notify>) {
+        let consumer_id = self.next_consumer_id;
+        let completed = Arc::new(Notify::new());
+
+        self.next_consumer_id += 1;
+        self.consumers.insert(
+            consumer_id,
+            Consumer {
+                quota,
+                lease_deadlines: HashMap::new(),
+                completed: completed.clone(),
+            },
+        );
+
+        (consumer_id, completed)
+    }
+
+    pub fn deregister(&mut self, consumer_id: u64) {
+        self.consumers.remove(&consumer_id);
+    }
+
+    /// How many more leases the consumer may be sent. Leases whose deadline
+    /// has passed are dropped first as the queue has already taken them back.
+    pub fn available_quota(&mut self, consumer_id: u64, now: u64) -> usize {
+        match self.consumers.get_mut(&consumer_id) {
+            Some(consumer) => {
+                consumer
+                    .lease_deadlines
+                    .retain(|_, deadline| *deadline > now);
+
+                consumer
+                    .quota
+                    .saturating_sub(consumer.lease_deadlines.len())
+            }
+            None => 0,
+        }
+    }
+
+    /// The soonest deadline of the consumer's leases after which it will have
+    /// quota again even if it completes nothing
+    pub fn next_deadline(&self, consumer_id: u64) -> Option<u64> {
+        self.consumers
+            .get(&consumer_id)
+            .and_then(|consumer| consumer.lease_deadlines.values().min().copied())
+    }
+
+    pub fn add_lease(&mut self, consumer_id: u64, lease_id: u64, deadline: u64) {
+        if let Some(consumer) = self.consumers.get_mut(&consumer_id) {
+            consumer.lease_deadlines.insert(lease_id, deadline);
+        }
+    }
+
+    pub fn extend_lease(&mut self, lease_id: u64, deadline: u64) {
+        for consumer in self.consumers.values_mut() {
+            if let Some(lease_deadline) = consumer.lease_deadlines.get_mut(&lease_id) {
+                *lease_deadline = deadline;
+            }
+        }
+    }
+
+    /// Frees the quota held by a lease and wakes the consumer that held it
+    pub fn complete_lease(&mut self, lease_id: u64) {
+        for consumer in self.consumers.values_mut() {
+            if consumer.lease_deadlines.remove(&lease_id).is_some() {
+                consumer.completed.notify();
+            }
+        }
+    }
+}
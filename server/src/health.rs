@@ -0,0 +1,200 @@
+use log::{error, info, warn};
+use sp_queue::SortingPriorityQueue;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::{task, time};
+use tonic::{Code, Status};
+
+/// How often the free space under the data root is looked at
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Problems {
+    maybe_disk_full: Option<String>,
+    /// Why each queue that failed to load or whose storage is failing is not
+    /// serving, by queue name
+    failed_queues: BTreeMap<String, String>,
+    /// Why each queue found to be corrupt is not serving, by queue name.
+    /// Corruption does not go away when a later write succeeds.
+    corrupt_queues: BTreeMap<String, String>,
+}
+
+impl Problems {
+    fn queue_problem(&self, queue_name: &str) -> Option<String> {
+        self.failed_queues
+            .get(queue_name)
+            .or_else(|| self.corrupt_queues.get(queue_name))
+            .cloned()
+    }
+}
+
+/// Everything found wrong with the server's storage. The server is not
+/// serving while the disk is full or a queue has failed to load or write, and
+/// a queue is not serving while the disk is full or the queue itself has
+/// failed or is corrupt.
+#[derive(Default)]
+pub struct Health {
+    problems: Mutex<Problems>,
+}
+
+impl Health {
+    fn problems(&self) -> MutexGuard<'_, Problems> {
+        // The problems are plain data so they remain usable if a holder panicked
+        self.problems
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Why the server is not serving if it is not. A corrupt queue is only
+    /// reported under its own name as the other queues keep serving.
+    pub fn server_problem(&self) -> Option<String> {
+        let problems = self.problems();
+
+        problems.maybe_disk_full.clone().or_else(|| {
+            problems
+                .failed_queues
+                .iter()
+                .next()
+                .map(|(name, reason)| format!("Queue {:?} failed: {}", name, reason))
+        })
+    }
+
+    /// Why the named queue is not serving if it is not
+    pub fn queue_problem(&self, queue_name: &str) -> Option<String> {
+        let problems = self.problems();
+
+        problems
+            .maybe_disk_full
+            .clone()
+            .or_else(|| problems.queue_problem(queue_name))
+    }
+
+    pub fn queue_failed(&self, queue_name: &str, reason: String) {
+        let previous = self
+            .problems()
+            .failed_queues
+            .insert(queue_name.to_string(), reason.clone());
+
+        if previous.is_none() {
+            error!("Queue {:?} is not serving: {}", queue_name, reason);
+        }
+    }
+
+    /// Marks the named queue as degraded until it is created again, is
+    /// deleted or moves to another node. The other queues keep serving.
+    fn queue_corrupt(&self, queue_name: &str, reason: String) {
+        let previous = self
+            .problems()
+            .corrupt_queues
+            .insert(queue_name.to_string(), reason.clone());
+
+        if previous.is_none() {
+            error!("Queue {:?} is corrupt: {}", queue_name, reason);
+        }
+    }
+
+    /// Clears any failure or corruption of the named queue. Called once the
+    /// queue is created again, is deleted or moves to another node.
+    pub fn forget(&self, queue_name: &str) {
+        let mut problems = self.problems();
+        let failed = problems.failed_queues.remove(queue_name).is_some();
+        let corrupt = problems.corrupt_queues.remove(queue_name).is_some();
+
+        if failed || corrupt {
+            info!("Queue {:?} is serving again", queue_name);
+        }
+    }
+
+    /// Clears a storage failure of the named queue once it has written
+    /// successfully
+    fn storage_recovered(&self, queue_name: &str) {
+        let mut problems = self.problems();
+
+        if problems.failed_queues.remove(queue_name).is_some()
+            && !problems.corrupt_queues.contains_key(queue_name)
+        {
+            info!("Queue {:?} is serving again", queue_name);
+        }
+    }
+
+    /// Records whether the queue's storage failed or the queue was found to
+    /// be corrupt during the last operation run on it
+    pub fn observe<T>(
+        &self,
+        queue_name: &str,
+        queue: &SortingPriorityQueue,
+        result: &Result<T, Status>,
+    ) {
+        if let Err(status) = result {
+            if status.code() == Code::DataLoss {
+                self.queue_corrupt(queue_name, status.message().to_string());
+            }
+        }
+
+        match queue.storage_failure() {
+            Ok(Some(failure)) => self.queue_failed(queue_name, failure),
+            Ok(None) => self.storage_recovered(queue_name),
+            Err(e) => self.queue_failed(queue_name, e.into_string()),
+        }
+    }
+
+    fn set_disk_full(&self, maybe_disk_full: Option<String>) {
+        let mut problems = self.problems();
+
+        match (&problems.maybe_disk_full, &maybe_disk_full) {
+            (None, Some(reason)) => error!("Not serving: {}", reason),
+            (Some(_), None) => info!("The disk has space again"),
+            _ => (),
+        }
+
+        problems.maybe_disk_full = maybe_disk_full;
+    }
+}
+
+/// The bytes an unprivileged process may still write to the file system
+/// holding the path. The casts are needed as the field widths differ
+/// between platforms.
+#[allow(clippy::unnecessary_cast)]
+fn available_bytes(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Marks the server as not serving while the file system holding the data
+/// root has less than the min free bytes left
+pub async fn check_disk(health: Arc<Health>, data_root: PathBuf, min_free_bytes: u64) {
+    let mut ticks = time::interval(DISK_CHECK_INTERVAL);
+
+    loop {
+        ticks.tick().await;
+
+        let path = data_root.clone();
+        let available = match task::spawn_blocking(move || available_bytes(&path)).await {
+            Ok(available) => available,
+            Err(e) => Err(io::Error::other(e.to_string())),
+        };
+
+        match available {
+            Ok(available) if available < min_free_bytes => health.set_disk_full(Some(format!(
+                "Only {} bytes are free under {:?} which is less than the {} required",
+                available, data_root, min_free_bytes
+            ))),
+            Ok(_) => health.set_disk_full(None),
+            Err(e) => warn!(
+                "Failed to check the free space under {:?}: {}",
+                data_root, e
+            ),
+        }
+    }
+}
@@ -1,192 +1,1743 @@
+// Handlers and the helpers they share fail with a tonic Status, which is
+// larger than clippy would like an error to be
+#![allow(clippy::result_large_err)]
+
+use prost::Message as _;
+use std::convert::TryFrom;
 use std::pin::Pin;
 use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::stream::Stream;
-use tokio::stream::StreamExt;
-use tokio::time;
+use tokio::sync::{mpsc, oneshot, watch, Notify, RwLock};
+use tokio::{future, task, time};
 use tonic::{transport::Server, Code, Request, Response, Status};
 mod spq_generated {
     tonic::include_proto!("spq_generated");
 }
+mod config;
+mod consumers;
+mod health;
+mod membership;
+mod metrics;
+mod replica;
+mod shards;
+mod telemetry;
+use config::{Cluster, Config};
+use consumers::ConsumerRegistry;
+use health::Health;
+use log::{error, info, warn};
+use metrics::QueueStats;
+use replica::{apply_committed, Applied, Applier, RaftEndpoint, Replica};
+use shards::{ShardMembers, Sharding};
+use sp_queue::database::set_tuning;
 use sp_queue::error::Error;
 use sp_queue::feature_space::FeatureValue;
-use sp_queue::SortingPriorityQueue;
+use sp_queue::item::DequeuedItem as QueueItem;
+use sp_queue::lease::{at_time, now_millis};
+use sp_queue::snapshot::QueueSnapshot;
+use sp_queue::storage::StorageType;
+use sp_queue::{applying_entry, timing_lock_waits, SortingPriorityQueue};
+use spq_generated::command::Operation;
 use spq_generated::health_check_response::ServingStatus;
 use spq_generated::health_service_server::{HealthService, HealthServiceServer};
+use spq_generated::raft_service_server::RaftServiceServer;
+use spq_generated::shard_service_server::{ShardService, ShardServiceServer};
 use spq_generated::sorting_priority_queue_service_server::{
     SortingPriorityQueueService, SortingPriorityQueueServiceServer,
 };
-use spq_generated::Feature;
 use spq_generated::{
-    CreateQueueRequest, DequeueRequest, EnqueueRequest, EnqueueResponse, GetEpochRequest,
-    GetEpochResponse, GetSizeRequest, GetSizeResponse, HealthCheckRequest, HealthCheckResponse,
-    ItemResponse, PeekRequest, QueueResponse,
+    AckRequest, AckResponse, ClusterMember, ClusterStatusRequest, ClusterStatusResponse, Command,
+    CreateQueueRequest, DeleteQueueRequest, DequeueBatchRequest, DequeueRequest, DequeuedItem,
+    DescribeQueueRequest, DescribeQueueResponse, EnqueueBatchRequest, EnqueueRequest,
+    EnqueueResponse, ExtendLeaseRequest, FeatureValueCount, GetEpochRequest, GetEpochResponse,
+    GetSizeRequest, GetSizeResponse, HealthCheckRequest, HealthCheckResponse, ImportQueueRequest,
+    ImportQueueResponse, ItemBatchResponse, ItemResponse, LeaseResponse, ListQueuesRequest,
+    ListQueuesResponse, NackRequest, NackResponse, PeekRequest, QueueCopy, QueueResponse,
+    QueueSummary, ReplicaSnapshot, SubscribeRequest, UpdateMembersRequest, UpdateMembersResponse,
 };
+use spq_generated::{Feature, Type};
+use spq_replication::log::RaftLog;
+use std::any::Any;
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{instrument, Instrument, Span};
+
+const QUARANTINE_DIRECTORY: &str = ".quarantine";
+
+const RAFT_DIRECTORY: &str = ".raft";
+
+/// Where a replicated node keeps its queues so that they are never mixed up
+/// with the queues it held before it joined a cluster
+const REPLICATED_DIRECTORY: &str = ".replicated";
+
+/// Subscribers waiting on an empty queue look again after this long even if
+/// nothing was enqueued so that items whose lease expired are picked up.
+const SUBSCRIBER_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Items for subscribers that did not ask for a lease are still leased for
+/// this long while they are handed to the stream so a failed send can return
+/// them to the queue.
+const DISPATCH_LEASE_DURATION: Duration = Duration::from_secs(30);
+
+/// How often health watchers look for a change of status
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a sharded node looks for queues it no longer owns and retries
+/// handoffs that failed
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A queue alongside the state shared by its subscribers. The queue locks
+/// itself for each operation. The items added signal holds the epoch of the
+/// last operation that added items. Subscribers take items one at a time while
+/// holding the dispatch turn and as its lock is fair each subscriber with
+/// spare quota is served in turn. The turn is released while waiting for
+/// items so that a subscriber or parked dequeue on an empty queue does not
+/// hold up the others.
+pub struct QueueEntry {
+    queue: SortingPriorityQueue,
+    items_added: watch::Sender<u64>,
+    items_added_receiver: watch::Receiver<u64>,
+    consumers: Mutex<ConsumerRegistry>,
+    dispatch_turn: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl QueueEntry {
+    fn new(queue: SortingPriorityQueue) -> QueueEntry {
+        let (items_added, items_added_receiver) = watch::channel(0);
+
+        QueueEntry {
+            queue,
+            items_added,
+            items_added_receiver,
+            consumers: Mutex::new(ConsumerRegistry::default()),
+            dispatch_turn: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+}
+
+/// A registered subscriber and the handles it waits on
+struct Subscription {
+    consumer_id: u64,
+    completed: Arc<Notify>,
+    items_added: watch::Receiver<u64>,
+    dispatch_turn: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Requests wait on the locks rather than failing when they are contended.
+/// The map is only write locked to add or remove a queue and queue operations
+/// run on the blocking pool as they may wait on the queue's lock or on disk.
+/// In a cluster every mutation is a command proposed to the replica and
+/// applied once committed. Reads are served by the leader once a majority
+/// confirms it still leads and it has applied every committed entry.
+/// When sharded each node serves the queues it holds and redirects requests
+/// for the queues other nodes own.
+#[derive(Clone)]
+pub struct DefaultSortingPriorityQueueService {
+    queues: Arc<RwLock<HashMap<String, Arc<QueueEntry>>>>,
+    maybe_replica: Option<Arc<Replica>>,
+    maybe_sharding: Option<Arc<Sharding>>,
+    config: Arc<Config>,
+    queue_root: PathBuf,
+    health: Arc<Health>,
+    shutting_down: watch::Receiver<bool>,
+}
+
+/// A queue taken out of service to be handed to its new owner. The same
+/// snapshot and transfer id are sent until the owner accepts them.
+struct Handoff {
+    entry: QueueEntry,
+    snapshot: Vec<u8>,
+    transfer_id: String,
+}
+
+/// Moves a queue directory that could not be loaded out of the way so that it
+/// is kept for inspection but is not picked up on the next boot.
+fn quarantine_queue(data_root: &Path, queue_name: &str) -> Result<(), std::io::Error> {
+    let quarantine_root = data_root.join(QUARANTINE_DIRECTORY);
+    fs::create_dir_all(&quarantine_root)?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or(0);
+
+    fs::rename(
+        data_root.join(queue_name),
+        quarantine_root.join(format!("{}.{}", queue_name, timestamp)),
+    )
+}
+
+/// Rebuilds every durable queue found under the data root, upgrading any
+/// stored in an older layout. Queues that fail to open are quarantined rather
+/// than stopping the server and are reported as not serving until a queue of
+/// the same name is created or deleted. Queues in a layout this version cannot
+/// read are left in place for a version that can.
+fn load_queues(data_root: &Path, health: &Health) -> HashMap<String, Arc<QueueEntry>> {
+    let mut queues = HashMap::new();
+
+    let entries = match fs::read_dir(data_root) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("No queues loaded from {:?}: {:?}", data_root, e);
+            return queues;
+        }
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let queue_name = entry.file_name().to_string_lossy().to_string();
+
+        if queue_name.starts_with('.') || !entry.path().is_dir() {
+            continue;
+        }
+
+        match SortingPriorityQueue::open_durable(entry.path().to_string_lossy().to_string()) {
+            Ok(queue) => {
+                info!("Loaded queue {:?}", queue_name);
+                queues.insert(queue_name, Arc::new(QueueEntry::new(queue)));
+            }
+            Err(e @ Error::UnsupportedLayout { .. }) => {
+                error!("Left queue {:?} in place: {}", queue_name, e);
+                health.queue_failed(&queue_name, format!("Failed to load: {}", e));
+            }
+            Err(e) => {
+                health.queue_failed(&queue_name, format!("Failed to load: {}", e));
+
+                match quarantine_queue(data_root, &queue_name) {
+                    Ok(_) => warn!("Quarantined queue {:?}", queue_name),
+                    Err(e) => error!("Failed to quarantine queue {:?}: {:?}", queue_name, e),
+                }
+            }
+        }
+    }
+
+    queues
+}
+
+/// Runs the operation as the log entry at the index if it is applying one
+fn as_entry<T>(maybe_index: Option<u64>, operation: impl FnOnce() -> T) -> T {
+    match maybe_index {
+        Some(index) => applying_entry(index, operation),
+        None => operation(),
+    }
+}
+
+/// Fails if the queue was written to by the log entry at the index or a
+/// later one. A restarted replica is handed the entries after its log's
+/// snapshot again and its durable queues already hold some of them.
+fn check_unapplied(queue: &SortingPriorityQueue, maybe_index: Option<u64>) -> Result<(), Status> {
+    match maybe_index {
+        Some(index) if to_status(queue.applied_index())? >= index => Err(Status::new(
+            Code::AlreadyExists,
+            format!("Entry {} was applied before this node restarted", index),
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl DefaultSortingPriorityQueueService {
+    /// Runs a mutation. In a cluster it waits until the command is committed
+    /// and applied on this node. Otherwise it is applied straight away.
+    async fn execute<T: 'static>(&self, operation: Operation) -> Result<Response<T>, Status> {
+        self.check_serving()?;
+
+        let command = Command {
+            now_ms: now_millis(),
+            operation: Some(operation),
+        };
+
+        let applied = match self.maybe_replica {
+            Some(ref replica) => replica.propose(command).await?,
+            None => self.run_command(None, command).await?,
+        };
+
+        applied
+            .downcast::<Response<T>>()
+            .map(|response| *response)
+            .map_err(|_| {
+                Status::new(
+                    Code::Internal,
+                    "Applying the command returned an unexpected response",
+                )
+            })
+    }
+
+    /// Waits until a read of this node's queues sees every write acknowledged
+    /// before it. In a cluster only the leader answers reads once a majority
+    /// has confirmed it still leads and it has applied every committed entry.
+    /// Any other node redirects them to the leader like writes.
+    async fn check_linearizable(&self) -> Result<(), Status> {
+        match self.maybe_replica {
+            Some(ref replica) => replica.read_barrier().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Whether this node leads the cluster and the queue has nothing for a
+    /// dequeue to hand out. Subscribers and parked requests retry while a
+    /// queue is empty so proposing each of their dequeues would fill the log
+    /// with commands that change nothing. An item committed but not yet
+    /// applied on the leader is left for the next attempt.
+    async fn is_leader_of_drained_queue(&self, queue_name: &str) -> Result<bool, Status> {
+        fn op(queue: &SortingPriorityQueue) -> Result<Response<bool>, Status> {
+            Ok(Response::new(!to_status(queue.can_dequeue())?))
+        }
+
+        match self.maybe_replica {
+            Some(ref replica) if replica.is_leader() => Ok(self
+                .get_queue_run_read_op::<bool>(queue_name, "can_dequeue", op)
+                .await?
+                .into_inner()),
+            _ => Ok(false),
+        }
+    }
+
+    async fn execute_dequeue(
+        &self,
+        request: DequeueRequest,
+    ) -> Result<Response<ItemResponse>, Status> {
+        if self.is_leader_of_drained_queue(&request.queue_name).await? {
+            return Ok(Response::new(ItemResponse::default()));
+        }
+
+        self.execute(Operation::Dequeue(request)).await
+    }
+
+    async fn get_entry(&self, queue_name: &str) -> Result<Arc<QueueEntry>, Status> {
+        let maybe_entry = self.queues.read().await.get(queue_name).cloned();
+
+        self.route(queue_name, maybe_entry.is_some())?;
+
+        maybe_entry.ok_or_else(|| {
+            Status::new(
+                Code::NotFound,
+                format!("Queue {:?} could not be found", queue_name),
+            )
+        })
+    }
+
+    /// Whether a sharded node should serve a request for the queue. A queue
+    /// it holds is served until it is handed off even if another node now
+    /// owns it. Requests for a queue being handed off are turned away.
+    fn route(&self, queue_name: &str, is_held: bool) -> Result<(), Status> {
+        match self.maybe_sharding {
+            Some(ref sharding) if sharding.is_moving(queue_name) => Err(shards::moving(queue_name)),
+            Some(ref sharding) if !is_held && !sharding.is_owner(queue_name) => {
+                Err(sharding.not_owner(queue_name))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        *self.shutting_down.borrow()
+    }
+
+    /// Turns away requests that arrive once the server has started to shut
+    /// down. Commands committed by the cluster are still applied.
+    fn check_serving(&self) -> Result<(), Status> {
+        if self.is_shutting_down() {
+            return Err(shutting_down());
+        }
+
+        Ok(())
+    }
+
+    /// Takes every queue out of service once the requests running on it
+    /// finish and closes it so that everything it wrote is flushed to disk
+    async fn close_queues(&self) {
+        let entries: Vec<(String, Arc<QueueEntry>)> = self.queues.write().await.drain().collect();
+
+        for (name, shared_entry) in entries {
+            let entry = unshare(shared_entry).await;
+
+            close_queue(&name, entry).await;
+        }
+    }
+
+    /// What every queue this node holds contains for the metrics endpoint.
+    /// Queues that fail to report are left out.
+    async fn queue_stats(self) -> Vec<QueueStats> {
+        let entries: Vec<(String, Arc<QueueEntry>)> = self
+            .queues
+            .read()
+            .await
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+        let queue_root = self.queue_root.clone();
+
+        let result = run_blocking(move || {
+            let mut all_stats = vec![];
+
+            for (name, entry) in entries {
+                let maybe_disk_bytes = match entry.queue.storage_type() {
+                    StorageType::Memory => None,
+                    StorageType::Durable => config::queue_path(&queue_root, &name)
+                        .ok()
+                        .and_then(|path| metrics::directory_bytes(Path::new(&path)).ok()),
+                };
+                let stats = entry.queue.size().and_then(|size| {
+                    Ok(QueueStats {
+                        name: name.clone(),
+                        size,
+                        epoch: entry.queue.get_epoch()?,
+                        maybe_disk_bytes,
+                        feature_value_counts: entry.queue.feature_value_counts()?,
+                    })
+                });
+
+                match stats {
+                    Ok(stats) => all_stats.push(stats),
+                    Err(e) => warn!("Failed to gather metrics for queue {:?}: {}", name, e),
+                }
+            }
+
+            Ok(all_stats)
+        })
+        .await;
+
+        result.unwrap_or_default()
+    }
+
+    fn check_item_size(&self, item: &[u8]) -> Result<(), Status> {
+        if item.len() > self.config.max_item_size {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Item of {} bytes is larger than the max item size of {} bytes",
+                    item.len(),
+                    self.config.max_item_size
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn check_queue_count(&self, queue_count: usize) -> Result<(), Status> {
+        if queue_count >= self.config.max_queue_count {
+            return Err(Status::new(
+                Code::ResourceExhausted,
+                format!(
+                    "The server already holds the most queues it may hold, {}",
+                    self.config.max_queue_count
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Refuses a new queue on the leader before it is proposed once the
+    /// cluster holds the most queues it may. Creations proposed at the same
+    /// time may each pass and take the cluster slightly past the limit. A
+    /// follower leaves the check to the leader it redirects to.
+    async fn check_replicated_queue_count(&self, queue_name: &str) -> Result<(), Status> {
+        match self.maybe_replica {
+            Some(ref replica) if replica.is_leader() => {
+                let queues = self.queues.read().await;
+
+                if queues.contains_key(queue_name) {
+                    return Ok(());
+                }
+
+                self.check_queue_count(queues.len())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn sharding(&self) -> Result<&Arc<Sharding>, Status> {
+        self.maybe_sharding.as_ref().ok_or_else(|| {
+            Status::new(
+                Code::FailedPrecondition,
+                "This server is not part of a sharded cluster",
+            )
+        })
+    }
+
+    async fn get_queue_run_read_op<Res: Send + 'static>(
+        &self,
+        queue_name: &str,
+        operation: &'static str,
+        f: fn(queue: &SortingPriorityQueue) -> Result<Response<Res>, Status>,
+    ) -> Result<Response<Res>, Status> {
+        self.check_serving()?;
+
+        let started = Instant::now();
+        let entry = self.get_entry(queue_name).await?;
+        let map_wait = started.elapsed();
+        let queue_name = queue_name.to_string();
+        let health = self.health.clone();
+
+        run_blocking(move || {
+            let result = run_measured(&queue_name, operation, started, map_wait, || {
+                (f)(&entry.queue)
+            });
+            health.observe(&queue_name, &entry.queue, &result);
+
+            result
+        })
+        .await
+    }
+
+    /// Runs an operation that may change the queue. Subscribers are woken
+    /// whenever the operation leaves more items in the queue than it found,
+    /// which covers enqueues, nacks and requeued leases.
+    /// The operation runs as if the time were now_ms so that every replica
+    /// agrees on lease deadlines, and as the log entry at the index if it is
+    /// applying one. An entry the queue already holds is not applied again.
+    async fn get_queue_run_op<Req: Clone + Send + 'static, Res: Send + 'static>(
+        &self,
+        queue_name: &str,
+        operation: &'static str,
+        request: &Req,
+        now_ms: u64,
+        maybe_index: Option<u64>,
+        f: fn(request: &Req, queue: &SortingPriorityQueue) -> Result<Response<Res>, Status>,
+    ) -> Result<Response<Res>, Status> {
+        let started = Instant::now();
+        let entry = self.get_entry(queue_name).await?;
+        let map_wait = started.elapsed();
+        let request = request.clone();
+        let queue_name = queue_name.to_string();
+        let health = self.health.clone();
+
+        run_blocking(move || {
+            let result = run_measured(&queue_name, operation, started, map_wait, || {
+                check_unapplied(&entry.queue, maybe_index)?;
+
+                at_time(now_ms, || {
+                    let size_before = to_status(entry.queue.size())?;
+                    let response = as_entry(maybe_index, || (f)(&request, &entry.queue))?;
+
+                    if to_status(entry.queue.size())? > size_before {
+                        // Only fails when there are no subscribers to wake
+                        let _ = entry
+                            .items_added
+                            .broadcast(to_status(entry.queue.get_epoch())?);
+                    }
+
+                    Ok(response)
+                })
+            });
+            health.observe(&queue_name, &entry.queue, &result);
+
+            result
+        })
+        .await
+    }
+
+    async fn apply_create_queue(
+        &self,
+        create_queue_request: CreateQueueRequest,
+        maybe_index: Option<u64>,
+    ) -> Result<Response<QueueResponse>, Status> {
+        let storage_type = to_storage_type(create_queue_request.queue_type)?;
+        let mut queues = self.queues.write().await;
+
+        self.route(
+            &create_queue_request.name,
+            queues.contains_key(&create_queue_request.name),
+        )?;
+
+        if let Some(entry) = queues.get(&create_queue_request.name) {
+            check_unapplied(&entry.queue, maybe_index)?;
+
+            if !to_status(
+                entry
+                    .queue
+                    .has_feature_names(&create_queue_request.features),
+            )? || entry.queue.storage_type() != storage_type
+            {
+                return Err(Status::new(
+                    Code::AlreadyExists,
+                    format!(
+                        "Queue {:?} already exists with different features or type",
+                        create_queue_request.name
+                    ),
+                ));
+            }
+        } else {
+            // A committed entry was checked on the leader and must be applied
+            // on every node alike whatever each one holds
+            if maybe_index.is_none() {
+                self.check_queue_count(queues.len())?;
+            }
+
+            let features = create_queue_request.features.clone();
+            let folder_path = config::queue_path(&self.queue_root, &create_queue_request.name)
+                .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+
+            let created = run_blocking(move || {
+                Ok(as_entry(maybe_index, || match storage_type {
+                    StorageType::Memory => SortingPriorityQueue::new(features),
+                    StorageType::Durable => {
+                        SortingPriorityQueue::new_durable(features, folder_path)
+                    }
+                }))
+            })
+            .await?;
+
+            let queue = match created {
+                Ok(queue) => queue,
+                Err(e) => {
+                    if e.is_storage() {
+                        self.health
+                            .queue_failed(&create_queue_request.name, e.to_string());
+                    }
+
+                    return to_status(Err(e));
+                }
+            };
+            self.health.forget(&create_queue_request.name);
+
+            queues.insert(
+                create_queue_request.name.clone(),
+                Arc::new(QueueEntry::new(queue)),
+            );
+        }
+
+        Ok(Response::new(QueueResponse {
+            name: create_queue_request.name,
+        }))
+    }
+
+    async fn apply_delete_queue(
+        &self,
+        delete_queue_request: DeleteQueueRequest,
+        maybe_index: Option<u64>,
+    ) -> Result<Response<QueueResponse>, Status> {
+        let maybe_entry = {
+            let mut queues = self.queues.write().await;
+
+            self.route(
+                &delete_queue_request.name,
+                queues.contains_key(&delete_queue_request.name),
+            )?;
+
+            if let Some(entry) = queues.get(&delete_queue_request.name) {
+                check_unapplied(&entry.queue, maybe_index)?;
+            }
+
+            queues.remove(&delete_queue_request.name)
+        };
+
+        // Deleting a queue that failed to load is how its failure is cleared
+        self.health.forget(&delete_queue_request.name);
+
+        match maybe_entry {
+            Some(shared_entry) => {
+                let entry = unshare(shared_entry).await;
+
+                run_blocking(move || to_status(entry.queue.destroy())).await?;
+
+                if let Some(ref sharding) = self.maybe_sharding {
+                    sharding.clear_transfer(&delete_queue_request.name);
+                }
+
+                Ok(Response::new(QueueResponse {
+                    name: delete_queue_request.name,
+                }))
+            }
+            None => Err(Status::new(
+                Code::NotFound,
+                format!("Queue {:?} could not be found", delete_queue_request.name),
+            )),
+        }
+    }
+
+    /// Hands each queue this node holds but no longer owns to its owner.
+    /// Handoffs that fail are retried on the next pass until they succeed or
+    /// this node owns the queue again. Queues still waiting to be handed off
+    /// when the server shuts down are closed and handed off after a restart
+    /// under the same transfer id.
+    async fn rebalance(self, sharding: Arc<Sharding>) {
+        let mut handoffs: HashMap<String, Handoff> = HashMap::new();
+        let mut ticks = time::interval(REBALANCE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = shutdown_started(self.shutting_down.clone()) => {
+                    for (name, handoff) in handoffs.drain() {
+                        close_queue(&name, handoff.entry).await;
+                    }
+
+                    return;
+                }
+            }
+
+            let moved: Vec<String> = self
+                .queues
+                .read()
+                .await
+                .keys()
+                .filter(|name| !sharding.is_owner(name))
+                .cloned()
+                .collect();
+
+            for name in moved {
+                match self.start_handoff(&sharding, &name).await {
+                    Ok(handoff) => {
+                        handoffs.insert(name, handoff);
+                    }
+                    Err(status) => warn!("Failed to hand off queue {:?}: {}", name, status),
+                }
+            }
+
+            let pending: Vec<String> = handoffs.keys().cloned().collect();
+
+            for name in pending {
+                if let Some(handoff) = handoffs.remove(&name) {
+                    if let Some(handoff) = self.finish_handoff(&sharding, &name, handoff).await {
+                        handoffs.insert(name, handoff);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes the queue out of service once the operations already running
+    /// on it finish and exports everything it holds
+    async fn start_handoff(&self, sharding: &Sharding, name: &str) -> Result<Handoff, Status> {
+        sharding.start_moving(name);
+
+        let shared_entry = match self.queues.write().await.remove(name) {
+            Some(shared_entry) => shared_entry,
+            None => {
+                sharding.finish_moving(name);
+
+                return Err(Status::new(
+                    Code::NotFound,
+                    format!("Queue {:?} could not be found", name),
+                ));
+            }
+        };
+        let entry = unshare(shared_entry).await;
+
+        let (entry, exported) = run_blocking(move || {
+            let exported = entry
+                .queue
+                .export()
+                .and_then(|snapshot| Ok((snapshot.to_bytes()?, entry.queue.get_epoch()?)));
+
+            Ok((entry, exported))
+        })
+        .await?;
+
+        match exported {
+            Ok((snapshot, epoch)) => Ok(Handoff {
+                entry,
+                snapshot,
+                transfer_id: format!("{}-{}", sharding.id(), epoch),
+            }),
+            Err(e) => {
+                self.return_entry(sharding, name, entry).await;
+
+                to_status(Err(e))
+            }
+        }
+    }
+
+    /// Sends the queue to its owner. The local copy is only destroyed once
+    /// the owner has stored it. Returns the handoff if it should be retried.
+    async fn finish_handoff(
+        &self,
+        sharding: &Sharding,
+        name: &str,
+        handoff: Handoff,
+    ) -> Option<Handoff> {
+        let (owner, address) = match sharding.owner(name) {
+            Some((owner, _)) if owner == sharding.id() => {
+                self.return_entry(sharding, name, handoff.entry).await;
+                return None;
+            }
+            Some(owner) => owner,
+            None => return Some(handoff),
+        };
+
+        let request = ImportQueueRequest {
+            name: name.to_string(),
+            snapshot: handoff.snapshot.clone(),
+            transfer_id: handoff.transfer_id.clone(),
+        };
+        let result = match shards::connect(&address) {
+            Ok(mut client) => client.import_queue(Request::new(request)).await,
+            Err(status) => Err(status),
+        };
+
+        match result {
+            Ok(_) => {
+                let entry = handoff.entry;
+
+                if let Err(status) = run_blocking(move || to_status(entry.queue.destroy())).await {
+                    error!("Failed to remove moved queue {:?}: {}", name, status);
+                }
+
+                sharding.clear_transfer(name);
+                sharding.finish_moving(name);
+                self.health.forget(name);
+                info!("Moved queue {:?} to node {}", name, owner);
+
+                None
+            }
+            Err(status) if status.code() == Code::AlreadyExists => {
+                // Both copies are kept rather than losing either
+                error!(
+                    "Node {} already holds a different queue {:?}. Keeping this copy: {}",
+                    owner,
+                    name,
+                    status.message()
+                );
+                self.return_entry(sharding, name, handoff.entry).await;
+
+                None
+            }
+            Err(status) => {
+                warn!(
+                    "Failed to move queue {:?} to node {}. Retrying: {}",
+                    name, owner, status
+                );
+
+                Some(handoff)
+            }
+        }
+    }
+
+    /// Puts a queue whose handoff was abandoned back into service
+    async fn return_entry(&self, sharding: &Sharding, name: &str, entry: QueueEntry) {
+        self.queues
+            .write()
+            .await
+            .insert(name.to_string(), Arc::new(entry));
+        sharding.finish_moving(name);
+    }
+
+    /// The signal raised when items are added to a queue and the fair lock
+    /// that requests waiting to take those items line up on
+    async fn queue_signals(
+        &self,
+        queue_name: &str,
+    ) -> Result<(watch::Receiver<u64>, Arc<tokio::sync::Mutex<()>>), Status> {
+        let entry = self.get_entry(queue_name).await?;
+
+        Ok((
+            entry.items_added_receiver.clone(),
+            entry.dispatch_turn.clone(),
+        ))
+    }
+
+    /// Repeats an attempt until it finds an item or the deadline passes. In
+    /// between attempts the request is parked until items are added. Given a
+    /// dispatch turn each attempt is made holding it, though it is released
+    /// while parked so that a waiting request never holds up the others.
+    async fn wait_for_item<F>(
+        &self,
+        mut items_added: watch::Receiver<u64>,
+        maybe_dispatch_turn: Option<Arc<tokio::sync::Mutex<()>>>,
+        deadline: time::Instant,
+        attempt: impl Fn() -> F,
+    ) -> Result<Response<ItemResponse>, Status>
+    where
+        F: Future<Output = Result<Response<ItemResponse>, Status>>,
+    {
+        loop {
+            let now = time::Instant::now();
+            let response = {
+                let _turn = match &maybe_dispatch_turn {
+                    Some(dispatch_turn) => {
+                        time::timeout_at(deadline, dispatch_turn.lock()).await.ok()
+                    }
+                    None => None,
+                };
+
+                attempt().await?
+            };
+
+            if response.get_ref().has_item || now >= deadline || self.is_shutting_down() {
+                return Ok(response);
+            }
+
+            // A closed signal means the queue was deleted which the next
+            // attempt reports
+            tokio::select! {
+                _ = time::timeout_at(
+                    deadline.min(now + SUBSCRIBER_RECHECK_INTERVAL),
+                    items_added.recv(),
+                ) => {}
+                _ = shutdown_started(self.shutting_down.clone()) => {}
+            }
+        }
+    }
+
+    async fn subscribe_to_queue(
+        &self,
+        queue_name: &str,
+        quota: usize,
+    ) -> Result<Subscription, Status> {
+        let entry = self.get_entry(queue_name).await?;
+        let (consumer_id, completed) = lock_consumers(&entry).register(quota);
+
+        Ok(Subscription {
+            consumer_id,
+            completed,
+            items_added: entry.items_added_receiver.clone(),
+            dispatch_turn: entry.dispatch_turn.clone(),
+        })
+    }
+
+    /// Updates the subscribers of a queue. Nothing is done if the queue has
+    /// since been deleted as its subscribers went with it.
+    async fn with_consumers<T>(
+        &self,
+        queue_name: &str,
+        f: impl FnOnce(&mut ConsumerRegistry) -> T,
+    ) -> Option<T> {
+        self.queues
+            .read()
+            .await
+            .get(queue_name)
+            .map(|entry| f(&mut lock_consumers(entry)))
+    }
+
+    /// Waits until the subscriber may be sent another item. Returns false if
+    /// the queue has been deleted or the server is shutting down.
+    async fn wait_for_quota(&self, queue_name: &str, subscription: &Subscription) -> bool {
+        loop {
+            if self.is_shutting_down() {
+                return false;
+            }
+
+            let now = now_millis();
+            let maybe_quota = self
+                .with_consumers(queue_name, |consumers| {
+                    (
+                        consumers.available_quota(subscription.consumer_id, now),
+                        consumers.next_deadline(subscription.consumer_id),
+                    )
+                })
+                .await;
+
+            let wait_millis = match maybe_quota {
+                None => return false,
+                Some((available, _)) if available > 0 => return true,
+                Some((_, Some(deadline))) => deadline.saturating_sub(now),
+                Some((_, None)) => SUBSCRIBER_RECHECK_INTERVAL.as_millis() as u64,
+            };
+
+            let _ = time::timeout(
+                Duration::from_millis(wait_millis).min(SUBSCRIBER_RECHECK_INTERVAL),
+                subscription.completed.notified(),
+            )
+            .await;
+        }
+    }
+
+    /// Streams items to a subscriber until it disconnects or the queue is
+    /// deleted. Each item is leased before it is sent. The lease is nacked if
+    /// the subscriber has gone and acked once sent if the subscriber did not
+    /// ask for a lease. Leased items count against the subscriber's quota
+    /// until they are completed.
+    async fn dispatch_to_subscriber(
+        self,
+        request: SubscribeRequest,
+        mut subscription: Subscription,
+        mut sender: mpsc::Sender<Result<ItemResponse, Status>>,
+    ) {
+        let is_leased = request.lease_timeout_ms != 0;
+        let dequeue_request = DequeueRequest {
+            queue_name: request.queue_name.clone(),
+            lease_timeout_ms: if is_leased {
+                request.lease_timeout_ms
+            } else {
+                DISPATCH_LEASE_DURATION.as_millis() as i64
+            },
+            wait_timeout_ms: 0,
+        };
+
+        'subscribed: loop {
+            if !self
+                .wait_for_quota(&request.queue_name, &subscription)
+                .await
+            {
+                let status = if self.is_shutting_down() {
+                    shutting_down()
+                } else {
+                    Status::new(
+                        Code::NotFound,
+                        format!("Queue {:?} could not be found", request.queue_name),
+                    )
+                };
+                let _ = sender.try_send(Err(status));
+                break;
+            }
+
+            let item_response = loop {
+                // A slot in the stream is reserved before an item is taken off
+                // the queue so that no item waits on a subscriber that has gone
+                if future::poll_fn(|cx| sender.poll_ready(cx)).await.is_err() {
+                    break 'subscribed;
+                }
+
+                // The turn is only held to take an item so the next subscriber
+                // in line may take one while this one is sent or waits
+                let dequeued = {
+                    let _turn = subscription.dispatch_turn.lock().await;
+
+                    self.dequeue(Request::new(dequeue_request.clone())).await
+                };
+
+                match dequeued {
+                    Ok(response) if response.get_ref().has_item => break response.into_inner(),
+                    Ok(_) => {
+                        // A closed signal means the queue was deleted and
+                        // shutting down turns away the next dequeue, either of
+                        // which is reported to the subscriber
+                        tokio::select! {
+                            _ = time::timeout(
+                                SUBSCRIBER_RECHECK_INTERVAL,
+                                subscription.items_added.recv(),
+                            ) => {}
+                            _ = shutdown_started(self.shutting_down.clone()) => {}
+                        }
+                    }
+                    Err(status) => {
+                        let _ = sender.try_send(Err(status));
+                        break 'subscribed;
+                    }
+                }
+            };
+
+            let lease_id = item_response.lease_id;
+            let sent_response = if is_leased {
+                let lease_deadline = item_response.lease_deadline_ms as u64;
+                self.with_consumers(&request.queue_name, |consumers| {
+                    consumers.add_lease(subscription.consumer_id, lease_id as u64, lease_deadline)
+                })
+                .await;
+
+                item_response
+            } else {
+                ItemResponse {
+                    lease_id: 0,
+                    lease_deadline_ms: 0,
+                    ..item_response
+                }
+            };
+
+            if sender.try_send(Ok(sent_response)).is_err() {
+                let _ = self
+                    .nack(Request::new(NackRequest {
+                        queue_name: request.queue_name.clone(),
+                        lease_id,
+                    }))
+                    .await;
+                break;
+            }
+
+            if !is_leased {
+                let _ = self
+                    .ack(Request::new(AckRequest {
+                        queue_name: request.queue_name.clone(),
+                        lease_id,
+                    }))
+                    .await;
+            }
+        }
+
+        self.with_consumers(&request.queue_name, |consumers| {
+            consumers.deregister(subscription.consumer_id)
+        })
+        .await;
+    }
+}
+
+/// Runs work that may block off the async runtime. The work stays in the
+/// caller's span so that the queue's own spans join the request's trace.
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, Status> + Send + 'static,
+) -> Result<T, Status> {
+    let span = Span::current();
+
+    task::spawn_blocking(move || span.in_scope(f))
+        .await
+        .map_err(|err| Status::new(Code::Internal, err.to_string()))?
+}
+
+/// Runs an operation on a queue recording how long it took since it started
+/// and how long it waited for the map of queues and for the queue's lock
+fn run_measured<T>(
+    queue_name: &str,
+    operation: &str,
+    started: Instant,
+    map_wait: Duration,
+    f: impl FnOnce() -> Result<T, Status>,
+) -> Result<T, Status> {
+    let (result, lock_wait) = timing_lock_waits(f);
+    let code = match result {
+        Ok(_) => Code::Ok,
+        Err(ref status) => status.code(),
+    };
+    metrics::record_operation(
+        queue_name,
+        operation,
+        code,
+        started.elapsed(),
+        map_wait + lock_wait,
+    );
+
+    result
+}
+
+fn shutting_down() -> Status {
+    Status::new(Code::Unavailable, "The server is shutting down")
+}
+
+/// Resolves once the server starts to shut down
+async fn shutdown_started(mut shutting_down: watch::Receiver<bool>) {
+    while !*shutting_down.borrow() {
+        if shutting_down.recv().await.is_none() {
+            return;
+        }
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Failed to listen for SIGTERM: {:?}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate.recv() => info!("Received SIGTERM"),
+    }
+}
+
+async fn close_queue(name: &str, entry: QueueEntry) {
+    match run_blocking(move || to_status(entry.queue.close())).await {
+        Ok(_) => info!("Closed queue {:?}", name),
+        Err(status) => error!("Failed to close queue {:?}: {}", name, status),
+    }
+}
+
+/// Operations that found a queue before it was removed from the map hold it
+/// until they finish so it cannot be destroyed or moved until then
+async fn unshare(mut shared_entry: Arc<QueueEntry>) -> QueueEntry {
+    loop {
+        match Arc::try_unwrap(shared_entry) {
+            Ok(entry) => return entry,
+            Err(still_shared) => {
+                shared_entry = still_shared;
+                // Tokio marks yield_now must_use, which newer compilers read
+                // as applying to the unit it resolves to
+                let () = task::yield_now().await;
+            }
+        }
+    }
+}
+
+/// Writes a received queue under a staging directory and only renames it
+/// into place once the transfer is recorded, so a crash part way through
+/// leaves nothing that is loaded at the next boot.
+fn import_queue(
+    sharding: &Sharding,
+    request: &ImportQueueRequest,
+    folder_path: String,
+) -> Result<SortingPriorityQueue, Status> {
+    let snapshot = QueueSnapshot::from_bytes(&request.snapshot)
+        .map_err(|e| Status::new(Code::InvalidArgument, e.into_string()))?;
+    let storage_type = snapshot.storage_type;
+    let incoming_path = sharding.incoming_path(&request.name);
+
+    if incoming_path.exists() {
+        fs::remove_dir_all(&incoming_path).map_err(to_internal)?;
+    }
+
+    let queue = to_status(SortingPriorityQueue::import(
+        snapshot,
+        incoming_path.to_string_lossy().to_string(),
+    ))?;
+
+    sharding
+        .record_transfer(&request.name, &request.transfer_id)
+        .map_err(to_internal)?;
+
+    match storage_type {
+        StorageType::Memory => Ok(queue),
+        StorageType::Durable => {
+            // The database must be closed before its directory is moved
+            drop(queue);
+            fs::rename(&incoming_path, &folder_path).map_err(to_internal)?;
+
+            to_status(SortingPriorityQueue::open_durable(folder_path))
+        }
+    }
+}
+
+fn to_internal(e: std::io::Error) -> Status {
+    Status::new(Code::Internal, e.to_string())
+}
+
+fn lock_consumers(entry: &QueueEntry) -> MutexGuard<'_, ConsumerRegistry> {
+    // The registry is plain data so it remains usable if a holder panicked
+    entry
+        .consumers
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn boxed<T: Send + 'static>(result: Result<T, Status>) -> Applied {
+    result.map(|response| Box::new(response) as Box<dyn Any + Send>)
+}
+
+impl DefaultSortingPriorityQueueService {
+    /// Applies a command straight away or as the log entry at the index
+    async fn run_command(&self, maybe_index: Option<u64>, command: Command) -> Applied {
+        let now_ms = command.now_ms;
+
+        match command.operation {
+            Some(Operation::CreateQueue(request)) => {
+                boxed(self.apply_create_queue(request, maybe_index).await)
+            }
+            Some(Operation::DeleteQueue(request)) => {
+                boxed(self.apply_delete_queue(request, maybe_index).await)
+            }
+            Some(Operation::Enqueue(request)) => boxed(
+                self.get_queue_run_op(
+                    &request.queue_name,
+                    "enqueue",
+                    &request,
+                    now_ms,
+                    maybe_index,
+                    apply_enqueue,
+                )
+                .await,
+            ),
+            Some(Operation::EnqueueBatch(request)) => boxed(
+                self.get_queue_run_op(
+                    &request.queue_name,
+                    "enqueue_batch",
+                    &request,
+                    now_ms,
+                    maybe_index,
+                    apply_enqueue_batch,
+                )
+                .await,
+            ),
+            Some(Operation::Dequeue(request)) => boxed(
+                self.get_queue_run_op(
+                    &request.queue_name,
+                    "dequeue",
+                    &request,
+                    now_ms,
+                    maybe_index,
+                    apply_dequeue,
+                )
+                .await,
+            ),
+            Some(Operation::DequeueBatch(request)) => boxed(
+                self.get_queue_run_op(
+                    &request.queue_name,
+                    "dequeue_batch",
+                    &request,
+                    now_ms,
+                    maybe_index,
+                    apply_dequeue_batch,
+                )
+                .await,
+            ),
+            Some(Operation::Ack(request)) => boxed(
+                self.get_queue_run_op(
+                    &request.queue_name,
+                    "ack",
+                    &request,
+                    now_ms,
+                    maybe_index,
+                    apply_ack,
+                )
+                .await,
+            ),
+            Some(Operation::Nack(request)) => boxed(
+                self.get_queue_run_op(
+                    &request.queue_name,
+                    "nack",
+                    &request,
+                    now_ms,
+                    maybe_index,
+                    apply_nack,
+                )
+                .await,
+            ),
+            Some(Operation::ExtendLease(request)) => boxed(
+                self.get_queue_run_op(
+                    &request.queue_name,
+                    "extend_lease",
+                    &request,
+                    now_ms,
+                    maybe_index,
+                    apply_extend_lease,
+                )
+                .await,
+            ),
+            None => Err(Status::new(
+                Code::InvalidArgument,
+                "Command has no operation",
+            )),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Applier for DefaultSortingPriorityQueueService {
+    async fn apply(&self, index: u64, command: Command) -> Applied {
+        self.run_command(Some(index), command).await
+    }
+
+    async fn snapshot(&self) -> Result<Vec<u8>, Status> {
+        let entries: Vec<(String, Arc<QueueEntry>)> = self
+            .queues
+            .read()
+            .await
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+
+        let queues = run_blocking(move || {
+            entries
+                .into_iter()
+                .map(|(name, entry)| {
+                    let snapshot = to_status(
+                        entry
+                            .queue
+                            .export()
+                            .and_then(|snapshot| snapshot.to_bytes()),
+                    )?;
+
+                    Ok(QueueCopy { name, snapshot })
+                })
+                .collect::<Result<Vec<QueueCopy>, Status>>()
+        })
+        .await?;
+
+        let mut bytes = vec![];
+        ReplicaSnapshot { queues }
+            .encode(&mut bytes)
+            .map_err(|e| Status::new(Code::Internal, e.to_string()))?;
 
-pub struct DefaultSortingPriorityQueueService {
-    queues: RwLock<HashMap<String, RwLock<SortingPriorityQueue>>>,
-}
+        Ok(bytes)
+    }
 
-impl DefaultSortingPriorityQueueService {
-    fn get_queue_run_read_op<Res>(
-        &self,
-        queue_name: &str,
-        f: fn(queue: &SortingPriorityQueue) -> Result<Response<Res>, Status>,
-    ) -> Result<Response<Res>, Status> {
-        let queues = self
-            .queues
-            .try_read()
-            .map_err(|_| Status::new(Code::Unavailable, "Update in progress please retry"))?;
+    /// Brings the queues to those of the snapshot. A queue that holds every
+    /// entry its copy in the snapshot holds is kept, as is a queue missing
+    /// from the snapshot that was written to after it was taken, so a
+    /// restarted node goes on from the queues it has on disk. Every other
+    /// queue is destroyed and replaced by its copy if it has one. The map
+    /// stays write locked throughout so no request sees a mix of the two.
+    /// Subscribers of the queues destroyed are ended.
+    async fn restore(&self, index: u64, snapshot: Vec<u8>) -> Result<(), Status> {
+        let replica_snapshot = ReplicaSnapshot::decode(&snapshot[..])
+            .map_err(|e| Status::new(Code::Internal, e.to_string()))?;
+        let mut copies = HashMap::new();
+
+        for QueueCopy { name, snapshot } in replica_snapshot.queues {
+            copies.insert(name, to_status(QueueSnapshot::from_bytes(&snapshot))?);
+        }
+
+        let mut queues = self.queues.write().await;
+        let entries: Vec<(String, Arc<QueueEntry>)> = queues.drain().collect();
 
-        match queues.get(queue_name) {
-            Some(queue_lock) => {
-                let queue = queue_lock.try_read().map_err(|_| {
-                    Status::new(Code::Unavailable, "Update in progress please retry")
-                })?;
+        for (name, shared_entry) in entries {
+            // A queue that cannot say what it holds is replaced
+            let applied_index = shared_entry.queue.applied_index().unwrap_or(0);
+            let is_current = match copies.get(&name) {
+                Some(copy) => applied_index >= to_status(copy.applied_index())?,
+                None => applied_index > index,
+            };
 
-                (f)(&queue)
+            if is_current {
+                copies.remove(&name);
+                queues.insert(name, shared_entry);
+                continue;
             }
-            None => Err(Status::new(
-                Code::NotFound,
-                format!("Queue {:?} could not be found", queue_name),
-            )),
+
+            let entry = unshare(shared_entry).await;
+            run_blocking(move || to_status(entry.queue.destroy())).await?;
         }
-    }
-    fn get_queue_run_op<Req, Res>(
-        &self,
-        queue_name: &str,
-        request: &Req,
-        f: fn(request: &Req, queue: &mut SortingPriorityQueue) -> Result<Response<Res>, Status>,
-    ) -> Result<Response<Res>, Status> {
-        let queues = self
-            .queues
-            .try_read()
-            .map_err(|_| Status::new(Code::Unavailable, "Update in progress please retry"))?;
 
-        match queues.get(queue_name) {
-            Some(queue_lock) => {
-                let mut queue = queue_lock.try_write().map_err(|_| {
-                    Status::new(Code::Unavailable, "Update in progress please retry")
-                })?;
+        let kept = queues.len();
 
-                (f)(request, &mut queue)
-            }
-            None => Err(Status::new(
-                Code::NotFound,
-                format!("Queue {:?} could not be found", queue_name),
-            )),
+        for (name, copy) in copies {
+            let folder_path = config::queue_path(&self.queue_root, &name)
+                .map_err(|e| Status::new(Code::Internal, e))?;
+
+            let queue = run_blocking(move || {
+                // Left by a queue that failed to load
+                if Path::new(&folder_path).exists() {
+                    fs::remove_dir_all(&folder_path).map_err(to_internal)?;
+                }
+
+                to_status(SortingPriorityQueue::import(copy, folder_path))
+            })
+            .await?;
+
+            self.health.forget(&name);
+            queues.insert(name, Arc::new(QueueEntry::new(queue)));
         }
+
+        info!(
+            "Restored {} queues from the snapshot at entry {} and kept {}",
+            queues.len() - kept,
+            index,
+            kept
+        );
+
+        Ok(())
+    }
+}
+
+fn apply_enqueue(
+    request: &EnqueueRequest,
+    queue: &SortingPriorityQueue,
+) -> Result<Response<EnqueueResponse>, Status> {
+    to_status(
+        queue.enqueue(
+            request.item.clone(),
+            request
+                .features
+                .clone()
+                .into_iter()
+                .map(to_feature_value)
+                .collect(),
+        ),
+    )?;
+    let size = to_status(queue.size())?;
+
+    Ok(Response::new(EnqueueResponse { size: size as i64 }))
+}
+
+fn apply_enqueue_batch(
+    request: &EnqueueBatchRequest,
+    queue: &SortingPriorityQueue,
+) -> Result<Response<EnqueueResponse>, Status> {
+    let items = request
+        .items
+        .iter()
+        .map(|batch_item| {
+            (
+                batch_item.item.clone(),
+                batch_item
+                    .features
+                    .clone()
+                    .into_iter()
+                    .map(to_feature_value)
+                    .collect(),
+            )
+        })
+        .collect();
+
+    to_status(queue.enqueue_batch(items))?;
+    let size = to_status(queue.size())?;
+
+    Ok(Response::new(EnqueueResponse { size: size as i64 }))
+}
+
+fn apply_dequeue(
+    request: &DequeueRequest,
+    queue: &SortingPriorityQueue,
+) -> Result<Response<ItemResponse>, Status> {
+    if request.lease_timeout_ms != 0 {
+        let lease_duration = to_lease_duration(request.lease_timeout_ms)?;
+        let (maybe_lease, _) = to_status(queue.dequeue_with_lease(lease_duration))?;
+        let size = to_status(queue.size())?;
+
+        return Ok(Response::new(match maybe_lease {
+            Some(lease) => ItemResponse {
+                lease_id: lease.get_id() as i64,
+                lease_deadline_ms: lease.get_deadline() as i64,
+                ..to_item_response(lease.get_item().clone(), size)
+            },
+            None => ItemResponse {
+                size: size as i64,
+                ..Default::default()
+            },
+        }));
     }
+
+    let (maybe_next, _) = to_status(queue.dequeue())?;
+    let size = to_status(queue.size())?;
+
+    Ok(Response::new(maybe_item_response(maybe_next, size)))
+}
+
+fn apply_dequeue_batch(
+    request: &DequeueBatchRequest,
+    queue: &SortingPriorityQueue,
+) -> Result<Response<ItemBatchResponse>, Status> {
+    let count = to_positive_count(request.count, "batch count")?;
+
+    let items = if request.lease_timeout_ms != 0 {
+        let lease_duration = to_lease_duration(request.lease_timeout_ms)?;
+        let (leases, _) = to_status(queue.dequeue_many_with_lease(count, lease_duration))?;
+
+        leases
+            .into_iter()
+            .map(|lease| DequeuedItem {
+                lease_id: lease.get_id() as i64,
+                lease_deadline_ms: lease.get_deadline() as i64,
+                ..to_dequeued_item(lease.get_item().clone())
+            })
+            .collect()
+    } else {
+        let (items, _) = to_status(queue.dequeue_many(count))?;
+
+        items.into_iter().map(to_dequeued_item).collect()
+    };
+    let size = to_status(queue.size())?;
+
+    Ok(Response::new(ItemBatchResponse {
+        items,
+        size: size as i64,
+    }))
+}
+
+fn apply_ack(
+    request: &AckRequest,
+    queue: &SortingPriorityQueue,
+) -> Result<Response<AckResponse>, Status> {
+    to_status(queue.ack(request.lease_id as u64))?;
+    let size = to_status(queue.size())?;
+
+    Ok(Response::new(AckResponse { size: size as i64 }))
+}
+
+fn apply_nack(
+    request: &NackRequest,
+    queue: &SortingPriorityQueue,
+) -> Result<Response<NackResponse>, Status> {
+    to_status(queue.nack(request.lease_id as u64))?;
+    let size = to_status(queue.size())?;
+
+    Ok(Response::new(NackResponse { size: size as i64 }))
+}
+
+fn apply_extend_lease(
+    request: &ExtendLeaseRequest,
+    queue: &SortingPriorityQueue,
+) -> Result<Response<LeaseResponse>, Status> {
+    let lease_duration = to_lease_duration(request.lease_timeout_ms)?;
+    let lease = to_status(queue.extend_lease(request.lease_id as u64, lease_duration))?;
+
+    Ok(Response::new(LeaseResponse {
+        lease_id: lease.get_id() as i64,
+        lease_deadline_ms: lease.get_deadline() as i64,
+    }))
 }
 
 fn to_feature_value(feature: Feature) -> FeatureValue {
     FeatureValue::new(feature.name, feature.value as usize)
 }
 
+fn to_feature(feature_value: &FeatureValue) -> Feature {
+    Feature {
+        name: feature_value.get_name().clone(),
+        value: feature_value.get_value() as i64,
+    }
+}
+
+/// An item with the features, epoch and time it was enqueued with. The lease
+/// fields are left for leased items to fill in.
+fn to_item_response(item: QueueItem, size: u64) -> ItemResponse {
+    ItemResponse {
+        has_item: true,
+        features: item.get_features().iter().map(to_feature).collect(),
+        enqueue_epoch: item.get_epoch() as i64,
+        enqueued_at_ms: item.get_enqueued_at() as i64,
+        item: item.into_data(),
+        size: size as i64,
+        ..Default::default()
+    }
+}
+
+fn maybe_item_response(maybe_item: Option<QueueItem>, size: u64) -> ItemResponse {
+    match maybe_item {
+        Some(item) => to_item_response(item, size),
+        None => ItemResponse {
+            size: size as i64,
+            ..Default::default()
+        },
+    }
+}
+
+fn to_dequeued_item(item: QueueItem) -> DequeuedItem {
+    DequeuedItem {
+        features: item.get_features().iter().map(to_feature).collect(),
+        enqueue_epoch: item.get_epoch() as i64,
+        enqueued_at_ms: item.get_enqueued_at() as i64,
+        item: item.into_data(),
+        ..Default::default()
+    }
+}
+
+/// Server default types are resolved before a create queue request is
+/// applied so that every replica creates the same type of queue
+fn to_storage_type(queue_type: i32) -> Result<StorageType, Status> {
+    match Type::from_i32(queue_type) {
+        Some(Type::InMemory) => Ok(StorageType::Memory),
+        Some(Type::Durable) => Ok(StorageType::Durable),
+        Some(Type::ServerDefault) | None => Err(Status::new(
+            Code::InvalidArgument,
+            format!("Invalid queue type {:?}", queue_type),
+        )),
+    }
+}
+
+fn from_storage_type(storage_type: StorageType) -> Type {
+    match storage_type {
+        StorageType::Memory => Type::InMemory,
+        StorageType::Durable => Type::Durable,
+    }
+}
+
+fn to_duration(millis: i64, description: &str) -> Result<Duration, Status> {
+    u64::try_from(millis)
+        .map(Duration::from_millis)
+        .map_err(|_| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("Invalid {} {:?}", description, millis),
+            )
+        })
+}
+
+fn to_lease_duration(lease_timeout_ms: i64) -> Result<Duration, Status> {
+    to_duration(lease_timeout_ms, "lease timeout")
+}
+
+fn to_positive_count(count: i64, description: &str) -> Result<usize, Status> {
+    match usize::try_from(count) {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(Status::new(
+            Code::InvalidArgument,
+            format!("Invalid {} {:?}", description, count),
+        )),
+    }
+}
+
+/// Clients retry internal and unavailable errors. Corruption is reported as
+/// data loss as retrying cannot fix it.
 fn to_status<V>(result: Result<V, Error>) -> Result<V, Status> {
-    result.map_err(|err| match err {
-        Error::Standard { message } => Status::new(Code::Internal, message),
-        Error::Empty { message } => Status::new(Code::Internal, message),
+    result.map_err(|err| {
+        let code = match err {
+            Error::InvalidDimension { .. } | Error::UnknownFeatureNames { .. } => {
+                Code::InvalidArgument
+            }
+            Error::NotInitialised { .. } | Error::UnsupportedLayout { .. } => {
+                Code::FailedPrecondition
+            }
+            Error::LeaseNotFound { .. } => Code::NotFound,
+            Error::Corruption { .. } => Code::DataLoss,
+            Error::Standard { .. } | Error::Empty { .. } | Error::Storage { .. } => Code::Internal,
+        };
+
+        Status::new(code, err.into_string())
     })
 }
 
 #[tonic::async_trait]
 impl SortingPriorityQueueService for DefaultSortingPriorityQueueService {
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().name))]
     async fn create_queue(
         &self,
         _request: Request<CreateQueueRequest>,
     ) -> Result<Response<QueueResponse>, Status> {
-        let create_queue_request = _request.get_ref();
-        let mut queues = self
-            .queues
-            .try_write()
-            .map_err(|_| Status::new(Code::Unavailable, "Update in progress please retry"))?;
+        let mut request = _request.into_inner();
 
-        let queue = to_status(SortingPriorityQueue::new_durable(
-            create_queue_request.features.clone(),
-            "/var/lib/spqr/".to_string() + &create_queue_request.name,
-        ))?;
+        // Refused before it is proposed so that no replica applies it
+        config::check_queue_name(&request.name)
+            .map_err(|e| Status::new(Code::InvalidArgument, e))?;
 
-        queues
-            .entry(create_queue_request.name.clone())
-            .or_insert_with(|| RwLock::new(queue));
+        if request.queue_type == Type::ServerDefault as i32 {
+            request.queue_type = from_storage_type(self.config.default_queue_type) as i32;
+        }
 
-        Ok(Response::new(QueueResponse {
-            name: create_queue_request.name.clone(),
-        }))
+        self.check_replicated_queue_count(&request.name).await?;
+
+        self.execute(Operation::CreateQueue(request)).await
     }
 
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
     async fn enqueue(
         &self,
         _request: Request<EnqueueRequest>,
     ) -> Result<Response<EnqueueResponse>, Status> {
-        fn op(
-            request: &EnqueueRequest,
-            queue: &mut SortingPriorityQueue,
-        ) -> Result<Response<EnqueueResponse>, Status> {
-            queue
-                .enqueue(
-                    request.item.clone(),
-                    request
-                        .features
-                        .clone()
-                        .into_iter()
-                        .map(to_feature_value)
-                        .collect(),
-                )
-                .map_err(|err| Status::new(Code::Internal, err))?;
-            let size = to_status(queue.size())?;
+        self.check_item_size(&_request.get_ref().item)?;
 
-            Ok(Response::new(EnqueueResponse { size: size as i64 }))
-        }
-
-        let enqueue_request = _request.get_ref();
-        self.get_queue_run_op::<EnqueueRequest, EnqueueResponse>(
-            &enqueue_request.queue_name,
-            &enqueue_request,
-            op,
-        )
+        self.execute(Operation::Enqueue(_request.into_inner()))
+            .await
     }
 
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
     async fn dequeue(
         &self,
         _request: Request<DequeueRequest>,
     ) -> Result<Response<ItemResponse>, Status> {
-        fn op(
-            _request: &DequeueRequest,
-            queue: &mut SortingPriorityQueue,
-        ) -> Result<Response<ItemResponse>, Status> {
-            let (maybe_next, _) = to_status(queue.dequeue())?;
-            let size = to_status(queue.size())?;
+        let request = _request.into_inner();
 
-            Ok(Response::new(ItemResponse {
-                has_item: maybe_next.is_some(),
-                item: maybe_next.unwrap_or_default(),
-                size: size as i64,
-            }))
+        if request.wait_timeout_ms == 0 {
+            return self.execute_dequeue(request).await;
         }
 
-        let request = _request.get_ref();
-        self.get_queue_run_op::<DequeueRequest, ItemResponse>(&request.queue_name, &request, op)
+        let deadline = time::Instant::now() + to_duration(request.wait_timeout_ms, "wait timeout")?;
+        let (items_added, dispatch_turn) = self.queue_signals(&request.queue_name).await?;
+
+        // Parked requests line up with subscribers on the fair dispatch turn
+        // so they are served in the order they arrived
+        self.wait_for_item(items_added, Some(dispatch_turn), deadline, || {
+            self.execute_dequeue(request.clone())
+        })
+        .await
     }
 
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
     async fn peek(&self, _request: Request<PeekRequest>) -> Result<Response<ItemResponse>, Status> {
         fn op(queue: &SortingPriorityQueue) -> Result<Response<ItemResponse>, Status> {
             let maybe_next = to_status(queue.peek())?;
             let size = to_status(queue.size())?;
 
-            Ok(Response::new(ItemResponse {
-                has_item: maybe_next.is_some(),
-                item: maybe_next.unwrap_or_default(),
-                size: size as i64,
-            }))
+            Ok(Response::new(maybe_item_response(maybe_next, size)))
         }
 
         let request = _request.get_ref();
-        self.get_queue_run_read_op::<ItemResponse>(&request.queue_name, op)
+        // Waiting only moves on to later state so one check covers the retries
+        self.check_linearizable().await?;
+
+        if request.wait_timeout_ms == 0 {
+            return self
+                .get_queue_run_read_op::<ItemResponse>(&request.queue_name, "peek", op)
+                .await;
+        }
+
+        let deadline = time::Instant::now() + to_duration(request.wait_timeout_ms, "wait timeout")?;
+        let (items_added, _) = self.queue_signals(&request.queue_name).await?;
+
+        self.wait_for_item(items_added, None, deadline, || {
+            self.get_queue_run_read_op::<ItemResponse>(&request.queue_name, "peek", op)
+        })
+        .await
     }
 
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
     async fn get_size(
         &self,
         _request: Request<GetSizeRequest>,
@@ -198,9 +1749,12 @@ impl SortingPriorityQueueService for DefaultSortingPriorityQueueService {
         }
 
         let request = _request.get_ref();
-        self.get_queue_run_read_op::<GetSizeResponse>(&request.queue_name, op)
+        self.check_linearizable().await?;
+        self.get_queue_run_read_op::<GetSizeResponse>(&request.queue_name, "get_size", op)
+            .await
     }
 
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
     async fn get_epoch(
         &self,
         _request: Request<GetEpochRequest>,
@@ -214,12 +1768,376 @@ impl SortingPriorityQueueService for DefaultSortingPriorityQueueService {
         }
 
         let request = _request.get_ref();
-        self.get_queue_run_read_op::<GetEpochResponse>(&request.queue_name, op)
+        self.check_linearizable().await?;
+        self.get_queue_run_read_op::<GetEpochResponse>(&request.queue_name, "get_epoch", op)
+            .await
+    }
+
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
+    async fn ack(&self, _request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        let request = _request.get_ref();
+        let response = self.execute(Operation::Ack(request.clone())).await?;
+        self.with_consumers(&request.queue_name, |consumers| {
+            consumers.complete_lease(request.lease_id as u64)
+        })
+        .await;
+
+        Ok(response)
+    }
+
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
+    async fn nack(&self, _request: Request<NackRequest>) -> Result<Response<NackResponse>, Status> {
+        let request = _request.get_ref();
+        let response = self.execute(Operation::Nack(request.clone())).await?;
+        self.with_consumers(&request.queue_name, |consumers| {
+            consumers.complete_lease(request.lease_id as u64)
+        })
+        .await;
+
+        Ok(response)
+    }
+
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
+    async fn extend_lease(
+        &self,
+        _request: Request<ExtendLeaseRequest>,
+    ) -> Result<Response<LeaseResponse>, Status> {
+        let request = _request.get_ref();
+        let response: Response<LeaseResponse> = self
+            .execute(Operation::ExtendLease(request.clone()))
+            .await?;
+        self.with_consumers(&request.queue_name, |consumers| {
+            consumers.extend_lease(
+                request.lease_id as u64,
+                response.get_ref().lease_deadline_ms as u64,
+            )
+        })
+        .await;
+
+        Ok(response)
+    }
+
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().name))]
+    async fn delete_queue(
+        &self,
+        _request: Request<DeleteQueueRequest>,
+    ) -> Result<Response<QueueResponse>, Status> {
+        self.execute(Operation::DeleteQueue(_request.into_inner()))
+            .await
+    }
+
+    #[instrument(skip(self, _request))]
+    async fn list_queues(
+        &self,
+        _request: Request<ListQueuesRequest>,
+    ) -> Result<Response<ListQueuesResponse>, Status> {
+        self.check_linearizable().await?;
+
+        let entries: Vec<(String, Arc<QueueEntry>)> = self
+            .queues
+            .read()
+            .await
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+
+        let mut queue_summaries = run_blocking(move || {
+            let mut queue_summaries = vec![];
+
+            for (name, entry) in entries {
+                queue_summaries.push(QueueSummary {
+                    name,
+                    size: to_status(entry.queue.size())? as i64,
+                    epoch: to_status(entry.queue.get_epoch())? as i64,
+                });
+            }
+
+            Ok(queue_summaries)
+        })
+        .await?;
+
+        queue_summaries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Response::new(ListQueuesResponse {
+            queues: queue_summaries,
+        }))
+    }
+
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().name))]
+    async fn describe_queue(
+        &self,
+        _request: Request<DescribeQueueRequest>,
+    ) -> Result<Response<DescribeQueueResponse>, Status> {
+        fn op(queue: &SortingPriorityQueue) -> Result<Response<DescribeQueueResponse>, Status> {
+            let maybe_root_index = to_status(queue.root_index())?;
+            let feature_value_counts = to_status(queue.feature_value_counts())?
+                .into_iter()
+                .map(|(feature_value, count)| FeatureValueCount {
+                    name: feature_value.get_name().clone(),
+                    value: feature_value.get_value() as i64,
+                    count: count as i64,
+                })
+                .collect();
+
+            Ok(Response::new(DescribeQueueResponse {
+                name: String::new(),
+                queue_type: from_storage_type(queue.storage_type()) as i32,
+                features: to_status(queue.feature_names())?,
+                dimension: to_status(queue.dimension())? as i64,
+                has_root_index: maybe_root_index.is_some(),
+                root_index: maybe_root_index.unwrap_or_default(),
+                feature_value_counts,
+            }))
+        }
+
+        let request = _request.get_ref();
+        self.check_linearizable().await?;
+        let mut response = self
+            .get_queue_run_read_op::<DescribeQueueResponse>(&request.name, "describe_queue", op)
+            .await?;
+        response.get_mut().name = request.name.clone();
+
+        Ok(response)
+    }
+
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
+    async fn enqueue_batch(
+        &self,
+        _request: Request<EnqueueBatchRequest>,
+    ) -> Result<Response<EnqueueResponse>, Status> {
+        for batch_item in _request.get_ref().items.iter() {
+            self.check_item_size(&batch_item.item)?;
+        }
+
+        self.execute(Operation::EnqueueBatch(_request.into_inner()))
+            .await
+    }
+
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
+    async fn dequeue_batch(
+        &self,
+        _request: Request<DequeueBatchRequest>,
+    ) -> Result<Response<ItemBatchResponse>, Status> {
+        let request = _request.into_inner();
+        to_positive_count(request.count, "batch count")?;
+
+        if self.is_leader_of_drained_queue(&request.queue_name).await? {
+            return Ok(Response::new(ItemBatchResponse::default()));
+        }
+
+        self.execute(Operation::DequeueBatch(request)).await
+    }
+
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
+    async fn cluster_status(
+        &self,
+        _request: Request<ClusterStatusRequest>,
+    ) -> Result<Response<ClusterStatusResponse>, Status> {
+        if let Some(ref sharding) = self.maybe_sharding {
+            return Ok(Response::new(sharded_status(
+                sharding,
+                &_request.get_ref().queue_name,
+            )));
+        }
+
+        let membership = match self.maybe_replica {
+            Some(ref replica) => replica.membership(),
+            None => return Ok(Response::new(ClusterStatusResponse::default())),
+        };
+
+        let members = membership
+            .members(now_millis())
+            .into_iter()
+            .map(|member| ClusterMember {
+                id: member.id,
+                address: member.address,
+                is_self: member.is_self,
+                is_leader: member.is_leader,
+                is_reachable: member.is_reachable,
+                last_seen_ms: member.maybe_last_seen_ms.unwrap_or_default() as i64,
+            })
+            .collect();
+
+        // Every queue is owned by the leader of the one raft group
+        let owner_address = if _request.get_ref().queue_name.is_empty() {
+            String::new()
+        } else {
+            membership
+                .leader_address()
+                .map(str::to_string)
+                .unwrap_or_default()
+        };
+
+        Ok(Response::new(ClusterStatusResponse {
+            is_replicated: true,
+            node_id: membership.id(),
+            term: membership.term(),
+            has_leader: membership.leader().is_some(),
+            leader_id: membership.leader().unwrap_or_default(),
+            members,
+            owner_address,
+            ..ClusterStatusResponse::default()
+        }))
+    }
+
+    type SubscribeStream =
+        Pin<Box<dyn Stream<Item = Result<ItemResponse, Status>> + Send + Sync + 'static>>;
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().queue_name))]
+    async fn subscribe(
+        &self,
+        _request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        self.check_serving()?;
+
+        let request = _request.into_inner();
+        let max_in_flight = to_positive_count(request.max_in_flight, "max in flight")?;
+        to_lease_duration(request.lease_timeout_ms)?;
+
+        // Watch before the first dequeue so that no enqueue is missed
+        let subscription = self
+            .subscribe_to_queue(&request.queue_name, max_in_flight)
+            .await?;
+        let (sender, receiver) = mpsc::channel(max_in_flight);
+
+        tokio::spawn(
+            self.clone()
+                .dispatch_to_subscriber(request, subscription, sender)
+                .in_current_span(),
+        );
+
+        Ok(Response::new(Box::pin(receiver)))
+    }
+}
+
+fn sharded_status(sharding: &Sharding, queue_name: &str) -> ClusterStatusResponse {
+    let members = sharding.members();
+
+    let mut cluster_members: Vec<ClusterMember> = members
+        .addresses
+        .iter()
+        .map(|(id, address)| ClusterMember {
+            id: *id,
+            address: address.clone(),
+            is_self: *id == sharding.id(),
+            is_leader: false,
+            is_reachable: *id == sharding.id(),
+            last_seen_ms: 0,
+        })
+        .collect();
+    cluster_members.sort_by_key(|member| member.id);
+
+    let owner_address = if queue_name.is_empty() {
+        String::new()
+    } else {
+        sharding
+            .owner(queue_name)
+            .map(|(_, address)| address)
+            .unwrap_or_default()
+    };
+
+    ClusterStatusResponse {
+        node_id: sharding.id(),
+        members: cluster_members,
+        owner_address,
+        is_sharded: true,
+        members_version: members.version,
+        ..ClusterStatusResponse::default()
+    }
+}
+
+#[tonic::async_trait]
+impl ShardService for DefaultSortingPriorityQueueService {
+    #[instrument(skip(self, _request))]
+    async fn update_members(
+        &self,
+        _request: Request<UpdateMembersRequest>,
+    ) -> Result<Response<UpdateMembersResponse>, Status> {
+        let sharding = self.sharding()?;
+        let members = ShardMembers::from_request(_request.into_inner());
+
+        if members.addresses.is_empty() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Members must name at least one node",
+            ));
+        }
+
+        if let Some(addresses) = sharding.adopt(members.clone()).map_err(to_internal)? {
+            info!("Adopted members version {}", members.version);
+            shards::gossip(&members, addresses);
+        }
+
+        Ok(Response::new(UpdateMembersResponse {
+            version: sharding.members().version,
+        }))
+    }
+
+    /// Stores a queue handed off by its previous owner. A transfer that was
+    /// already stored is acknowledged again so that a lost acknowledgement
+    /// does not leave the queue on both nodes. Any other queue of the same
+    /// name is a conflict.
+    #[instrument(skip(self, _request), fields(queue = %_request.get_ref().name))]
+    async fn import_queue(
+        &self,
+        _request: Request<ImportQueueRequest>,
+    ) -> Result<Response<ImportQueueResponse>, Status> {
+        let sharding = self.sharding()?.clone();
+        let request = _request.into_inner();
+        let mut queues = self.queues.write().await;
+
+        if queues.contains_key(&request.name) || sharding.is_moving(&request.name) {
+            return if sharding.transfer_of(&request.name).as_deref()
+                == Some(request.transfer_id.as_str())
+            {
+                Ok(Response::new(ImportQueueResponse {}))
+            } else {
+                Err(Status::new(
+                    Code::AlreadyExists,
+                    format!("Queue {:?} already exists on this node", request.name),
+                ))
+            };
+        }
+
+        let name = request.name.clone();
+        let folder_path = config::queue_path(&self.queue_root, &name)
+            .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+        let queue = run_blocking(move || import_queue(&sharding, &request, folder_path)).await?;
+
+        info!("Received queue {:?}", name);
+        self.health.forget(&name);
+        queues.insert(name, Arc::new(QueueEntry::new(queue)));
+
+        Ok(Response::new(ImportQueueResponse {}))
     }
 }
 
-#[derive(Default)]
-pub struct DefaultHealthService {}
+/// Reports on the whole server when asked about the empty service name and
+/// on a single queue when asked about its name. Neither is serving once the
+/// server starts to shut down.
+pub struct DefaultHealthService {
+    spq_service: DefaultSortingPriorityQueueService,
+}
+
+impl DefaultHealthService {
+    async fn status(&self, service: &str) -> ServingStatus {
+        let health = &self.spq_service.health;
+
+        if self.spq_service.is_shutting_down() {
+            ServingStatus::NotServing
+        } else if service.is_empty() {
+            match health.server_problem() {
+                Some(_) => ServingStatus::NotServing,
+                None => ServingStatus::Serving,
+            }
+        } else if health.queue_problem(service).is_some() {
+            ServingStatus::NotServing
+        } else if self.spq_service.queues.read().await.contains_key(service) {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::ServiceUnknown
+        }
+    }
+}
 
 #[tonic::async_trait]
 impl HealthService for DefaultHealthService {
@@ -227,47 +2145,205 @@ impl HealthService for DefaultHealthService {
         &self,
         _request: Request<HealthCheckRequest>,
     ) -> Result<Response<HealthCheckResponse>, Status> {
-        Ok(Response::new(HealthCheckResponse {
-            status: ServingStatus::Serving as i32,
-        }))
+        let service = &_request.get_ref().service;
+
+        match self.status(service).await {
+            ServingStatus::ServiceUnknown => Err(Status::new(
+                Code::NotFound,
+                format!("Queue {:?} could not be found", service),
+            )),
+            status => Ok(Response::new(HealthCheckResponse {
+                status: status as i32,
+            })),
+        }
     }
 
     type WatchStream =
         Pin<Box<dyn Stream<Item = Result<HealthCheckResponse, Status>> + Send + Sync + 'static>>;
+    /// Sends the status straight away and then each time it changes. The
+    /// status is looked at every second and as soon as the server starts to
+    /// shut down. The stream ends once the server is shutting down so that it
+    /// does not hold up the shutdown.
     async fn watch(
         &self,
         _request: Request<HealthCheckRequest>,
     ) -> Result<Response<Self::WatchStream>, Status> {
-        let health_stream: Self::WatchStream = Box::pin({
-            time::interval(Duration::from_secs(1)).map(move |_| {
-                Ok(HealthCheckResponse {
-                    status: ServingStatus::Serving as i32,
-                })
-            })
+        let (mut sender, receiver) = mpsc::channel(1);
+        let service = _request.into_inner().service;
+        let health_service = DefaultHealthService {
+            spq_service: self.spq_service.clone(),
+        };
+
+        tokio::spawn(async move {
+            let mut ticks = time::interval(HEALTH_CHECK_INTERVAL);
+            let mut maybe_last_status = None;
+
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    _ = shutdown_started(health_service.spq_service.shutting_down.clone()) => {}
+                }
+
+                let status = health_service.status(&service).await;
+
+                if maybe_last_status != Some(status) {
+                    let response = HealthCheckResponse {
+                        status: status as i32,
+                    };
+
+                    if sender.send(Ok(response)).await.is_err() {
+                        break;
+                    }
+
+                    maybe_last_status = Some(status);
+                }
+
+                if health_service.spq_service.is_shutting_down() {
+                    break;
+                }
+            }
         });
 
-        Ok(Response::new(health_stream))
+        Ok(Response::new(Box::pin(receiver)))
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::0]:9090".parse()?;
+    let config = Arc::new(Config::load()?);
+
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .init();
+    config.log();
+
+    set_tuning(config.tuning);
+
+    // Spans are only recorded when there is a collector to send them to
+    let _telemetry = match config.otlp_endpoint {
+        Some(ref endpoint) => Some(telemetry::init(endpoint)?),
+        None => None,
+    };
+    fs::create_dir_all(&config.data_root)
+        .map_err(|e| format!("Failed to create data root {:?}: {}", config.data_root, e))?;
+
+    let (shutdown, shutting_down) = watch::channel(false);
+    let health = Arc::new(Health::default());
+    let mut maybe_rebalancer = None;
+
+    let (spq_service, raft_endpoint) = match config.cluster.clone() {
+        Cluster::Replicated(members) => {
+            info!("Joining cluster as node {}", members.id);
+
+            // The queues go on from the entries they hold. Memory queues and
+            // anything not yet on disk are rebuilt from the log's snapshot
+            // and the entries after it.
+            let queue_root = config.data_root.join(REPLICATED_DIRECTORY);
+            fs::create_dir_all(&queue_root)
+                .map_err(|e| format!("Failed to create {:?}: {}", queue_root, e))?;
+            let queues = load_queues(&queue_root, &health);
+            let log =
+                RaftLog::open(config.data_root.join(RAFT_DIRECTORY)).map_err(|e| e.to_string())?;
+            let (replica, committed) = Replica::start(members, log);
+            let raft_endpoint = replica.endpoint();
+
+            let spq_service = DefaultSortingPriorityQueueService {
+                queues: Arc::new(RwLock::new(queues)),
+                maybe_replica: Some(Arc::new(replica)),
+                maybe_sharding: None,
+                config: config.clone(),
+                queue_root,
+                health: health.clone(),
+                shutting_down: shutting_down.clone(),
+            };
+            tokio::spawn(apply_committed(committed, spq_service.clone()));
+
+            (spq_service, raft_endpoint)
+        }
+        cluster => {
+            let maybe_sharding = match cluster {
+                Cluster::Sharded(members) => {
+                    info!("Joining sharded cluster as node {}", members.id);
 
-    let spq_service = DefaultSortingPriorityQueueService {
-        queues: RwLock::new(HashMap::new()),
+                    Some(Arc::new(Sharding::open(members, &config.data_root)?))
+                }
+                _ => None,
+            };
+
+            let spq_service = DefaultSortingPriorityQueueService {
+                queues: Arc::new(RwLock::new(load_queues(&config.data_root, &health))),
+                maybe_replica: None,
+                maybe_sharding: maybe_sharding.clone(),
+                config: config.clone(),
+                queue_root: config.data_root.clone(),
+                health: health.clone(),
+                shutting_down: shutting_down.clone(),
+            };
+
+            if let Some(sharding) = maybe_sharding {
+                maybe_rebalancer = Some(tokio::spawn(spq_service.clone().rebalance(sharding)));
+            }
+
+            (spq_service, RaftEndpoint::default())
+        }
+    };
+    let health_service = DefaultHealthService {
+        spq_service: spq_service.clone(),
+    };
+    tokio::spawn(health::check_disk(
+        health,
+        config.data_root.clone(),
+        config.min_free_disk_bytes,
+    ));
+
+    // Requests are turned away and health checks fail for the shutdown delay
+    // before the server stops listening so that load balancers stop sending
+    // requests first. Requests already running are then allowed to finish.
+    let shutdown_delay = config.shutdown_delay;
+    let drain = async move {
+        shutdown_signal().await;
+        info!("Draining for {:?}", shutdown_delay);
+
+        let _ = shutdown.broadcast(true);
+        time::delay_for(shutdown_delay).await;
+
+        info!("Waiting for running requests");
     };
-    let health_service = DefaultHealthService::default();
 
-    println!("Booting");
+    let (stop_metrics, metrics_stopped) = oneshot::channel::<()>();
+    let metrics_service = spq_service.clone();
+    let metrics_server = tokio::spawn(metrics::serve(
+        config.metrics_address,
+        move || metrics_service.clone().queue_stats(),
+        async move {
+            let _ = metrics_stopped.await;
+        },
+    ));
+
+    info!("Booting");
 
     Server::builder()
-        .add_service(SortingPriorityQueueServiceServer::new(spq_service))
+        .trace_fn(telemetry::request_span)
+        .add_service(SortingPriorityQueueServiceServer::new(spq_service.clone()))
+        .add_service(ShardServiceServer::new(spq_service.clone()))
+        .add_service(RaftServiceServer::new(raft_endpoint))
         .add_service(HealthServiceServer::new(health_service))
-        .serve(addr)
+        .serve_with_shutdown(config.listen_address, drain)
         .await?;
 
-    println!("Shutting down");
+    // Metrics are served until every running request has finished
+    let _ = stop_metrics.send(());
+    if let Err(e) = metrics_server.await? {
+        error!("The metrics server failed: {}", e);
+    }
+
+    if let Some(rebalancer) = maybe_rebalancer {
+        rebalancer.await?;
+    }
+
+    spq_service.close_queues().await;
+
+    info!("Shut down");
 
     Ok(())
 }
@@ -0,0 +1,99 @@
+use spq_replication::NodeId;
+use std::collections::HashMap;
+
+/// A node counts as reachable if it has been heard from this recently. Leaders
+/// send heartbeats far more often than this.
+const REACHABLE_WITHIN_MS: u64 = 2000;
+
+pub struct Member {
+    pub id: NodeId,
+    pub address: String,
+    pub is_self: bool,
+    pub is_leader: bool,
+    pub is_reachable: bool,
+    pub maybe_last_seen_ms: Option<u64>,
+}
+
+/// What this node knows of the cluster. Every node starts from the same seed
+/// list of ids and addresses. A node is seen when a raft message arrives from
+/// it or when a message sent to it is delivered. Followers only talk to the
+/// leader so they may not have seen the other followers.
+#[derive(Clone)]
+pub struct Membership {
+    id: NodeId,
+    addresses: HashMap<NodeId, String>,
+    term: u64,
+    leader: Option<NodeId>,
+    last_seen_ms: HashMap<NodeId, u64>,
+}
+
+impl Membership {
+    pub fn new(id: NodeId, addresses: HashMap<NodeId, String>) -> Membership {
+        Membership {
+            id,
+            addresses,
+            term: 0,
+            leader: None,
+            last_seen_ms: HashMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn address(&self, id: NodeId) -> Option<&str> {
+        self.addresses.get(&id).map(String::as_str)
+    }
+
+    pub fn leader_address(&self) -> Option<&str> {
+        self.leader.and_then(|leader| self.address(leader))
+    }
+
+    pub fn observe(&mut self, term: u64, leader: Option<NodeId>) {
+        self.term = term;
+        self.leader = leader;
+    }
+
+    pub fn record_seen(&mut self, id: NodeId, now_ms: u64) {
+        self.last_seen_ms.insert(id, now_ms);
+    }
+
+    /// Every node in the seed list ordered by id
+    pub fn members(&self, now_ms: u64) -> Vec<Member> {
+        let mut members: Vec<Member> = self
+            .addresses
+            .iter()
+            .map(|(id, address)| {
+                let is_self = *id == self.id;
+                let maybe_last_seen_ms = self.last_seen_ms.get(id).copied();
+
+                Member {
+                    id: *id,
+                    address: address.clone(),
+                    is_self,
+                    is_leader: self.leader == Some(*id),
+                    is_reachable: is_self
+                        || maybe_last_seen_ms
+                            .map(|last_seen_ms| {
+                                now_ms.saturating_sub(last_seen_ms) <= REACHABLE_WITHIN_MS
+                            })
+                            .unwrap_or(false),
+                    maybe_last_seen_ms,
+                }
+            })
+            .collect();
+
+        members.sort_by_key(|member| member.id);
+
+        members
+    }
+}
@@ -0,0 +1,232 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use log::error;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use sp_queue::feature_space::FeatureValue;
+use std::convert::Infallible;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tonic::Code;
+
+const METRICS_PATH: &str = "/metrics";
+
+/// Waits for a queue's lock are usually far shorter than the operations
+/// holding it
+const LOCK_WAIT_BUCKETS: [f64; 8] = [0.000_001, 0.000_01, 0.000_1, 0.001, 0.01, 0.1, 1.0, 10.0];
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref OPERATIONS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "spq_operations_total",
+            "Operations run on each queue by the code they returned"
+        ),
+        &["queue", "operation", "code"],
+    ));
+    static ref OPERATION_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "spq_operation_duration_seconds",
+            "How long operations took to run on each queue"
+        ),
+        &["queue", "operation"],
+    ));
+    static ref LOCK_WAIT_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "spq_lock_wait_seconds",
+            "How long operations waited for each queue's lock"
+        )
+        .buckets(LOCK_WAIT_BUCKETS.to_vec()),
+        &["queue", "operation"],
+    ));
+    static ref QUEUE_SIZE: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("spq_queue_size", "Items in each queue"),
+        &["queue"],
+    ));
+    static ref QUEUE_EPOCH: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("spq_queue_epoch", "The epoch of each queue"),
+        &["queue"],
+    ));
+    static ref QUEUE_DISK_BYTES: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "spq_queue_disk_bytes",
+            "Bytes each durable queue's RocksDB instance takes on disk"
+        ),
+        &["queue"],
+    ));
+    static ref FEATURE_VALUE_ITEMS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "spq_feature_value_items",
+            "Items waiting under each value of each feature of each queue"
+        ),
+        &["queue", "feature", "value"],
+    ));
+    /// Scrapes replace every queue gauge so they are taken one at a time
+    static ref SCRAPE: Mutex<()> = Mutex::new(());
+}
+
+fn register<M: Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+    // The metrics are fixed so these only fail if a definition above is wrong
+    let metric = metric.expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered twice");
+
+    metric
+}
+
+/// What a queue holds as of a scrape
+pub struct QueueStats {
+    pub name: String,
+    pub size: u64,
+    pub epoch: u64,
+    pub maybe_disk_bytes: Option<u64>,
+    pub feature_value_counts: Vec<(FeatureValue, u64)>,
+}
+
+/// Records an operation run on a queue and the time it spent waiting for
+/// the queue's lock
+pub fn record_operation(
+    queue_name: &str,
+    operation: &str,
+    code: Code,
+    duration: Duration,
+    lock_wait: Duration,
+) {
+    OPERATIONS
+        .with_label_values(&[queue_name, operation, &format!("{:?}", code)])
+        .inc();
+    OPERATION_SECONDS
+        .with_label_values(&[queue_name, operation])
+        .observe(duration.as_secs_f64());
+    LOCK_WAIT_SECONDS
+        .with_label_values(&[queue_name, operation])
+        .observe(lock_wait.as_secs_f64());
+}
+
+/// The bytes taken by every file under the path
+pub fn directory_bytes(path: &Path) -> io::Result<u64> {
+    let mut bytes = 0;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        bytes += if metadata.is_dir() {
+            directory_bytes(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(bytes)
+}
+
+fn set_queue_stats(all_stats: Vec<QueueStats>) {
+    // Queues that no longer exist are dropped along with their values
+    QUEUE_SIZE.reset();
+    QUEUE_EPOCH.reset();
+    QUEUE_DISK_BYTES.reset();
+    FEATURE_VALUE_ITEMS.reset();
+
+    for stats in all_stats {
+        let name = stats.name.as_str();
+
+        QUEUE_SIZE.with_label_values(&[name]).set(stats.size as i64);
+        QUEUE_EPOCH
+            .with_label_values(&[name])
+            .set(stats.epoch as i64);
+
+        if let Some(disk_bytes) = stats.maybe_disk_bytes {
+            QUEUE_DISK_BYTES
+                .with_label_values(&[name])
+                .set(disk_bytes as i64);
+        }
+
+        for (feature_value, count) in stats.feature_value_counts {
+            FEATURE_VALUE_ITEMS
+                .with_label_values(&[
+                    name,
+                    feature_value.get_name().as_str(),
+                    &feature_value.get_value().to_string(),
+                ])
+                .set(count as i64);
+        }
+    }
+}
+
+fn render(all_stats: Vec<QueueStats>) -> Response<Body> {
+    let _scrape = SCRAPE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    set_queue_stats(all_stats);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    match encoder.encode(&REGISTRY.gather(), &mut buffer) {
+        Ok(_) => Response::builder()
+            .header(header::CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buffer)),
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+        }
+    }
+    .unwrap_or_default()
+}
+
+async fn respond<S, F>(request: Request<Body>, queue_stats: S) -> Response<Body>
+where
+    S: Fn() -> F,
+    F: Future<Output = Vec<QueueStats>>,
+{
+    if request.method() != Method::GET || request.uri().path() != METRICS_PATH {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+
+        return response;
+    }
+
+    render(queue_stats().await)
+}
+
+/// Serves the metrics over HTTP at /metrics until the shutdown resolves. The
+/// queue stats are gathered afresh for every scrape.
+pub async fn serve<S, F>(
+    address: SocketAddr,
+    queue_stats: S,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), hyper::Error>
+where
+    S: Fn() -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Vec<QueueStats>> + Send + 'static,
+{
+    let make_service = make_service_fn(move |_| {
+        let queue_stats = queue_stats.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let queue_stats = queue_stats.clone();
+
+                async move { Ok::<_, Infallible>(respond(request, queue_stats).await) }
+            }))
+        }
+    });
+
+    Server::try_bind(&address)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
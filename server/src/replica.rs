@@ -0,0 +1,574 @@
+use crate::config::Members;
+use crate::membership::Membership;
+use crate::spq_generated::raft_service_client::RaftServiceClient;
+use crate::spq_generated::raft_service_server::RaftService;
+use crate::spq_generated::{Command, RaftAck, RaftMessage};
+use log::error;
+use prost::Message as _;
+use sp_queue::lease::now_millis;
+use spq_replication::error::Error;
+use spq_replication::log::RaftLog;
+use spq_replication::message::{Entry, Message, Snapshot};
+use spq_replication::node::{Config, RaftNode};
+use spq_replication::NodeId;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Response, Status};
+
+/// How often the raft node is ticked. Elections start after ten to twenty
+/// ticks without hearing from a leader.
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a request waits for its command to be committed and applied or
+/// for its read to be confirmed
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// How many entries are applied before the queues are snapshotted and the
+/// log is compacted up to them
+const SNAPSHOT_INTERVAL: u64 = 10_000;
+
+/// Metadata set on requests rejected by a follower so that clients can retry
+/// them against the leader
+pub const LEADER_ID_KEY: &str = "spq-leader-id";
+pub const LEADER_ADDRESS_KEY: &str = "spq-leader-address";
+
+/// What applying a command returned. The response type depends on the
+/// command so it is handed back to the request that proposed it boxed.
+pub type Applied = Result<Box<dyn Any + Send>, Status>;
+
+/// Applies committed commands to the queues. Every replica applies the same
+/// commands in the same order. A restarted replica is handed the entries after
+/// the snapshot its log starts from again so the applier must pass over those
+/// its queues already hold.
+#[tonic::async_trait]
+pub trait Applier: Send + Sync + 'static {
+    /// Applies the command of the entry at the index
+    async fn apply(&self, index: u64, command: Command) -> Applied;
+
+    /// Every queue as the commands applied so far have left it
+    async fn snapshot(&self) -> Result<Vec<u8>, Status>;
+
+    /// Brings the queues to the state of a snapshot taken once the entry at
+    /// the index was applied
+    async fn restore(&self, index: u64, snapshot: Vec<u8>) -> Result<(), Status>;
+}
+
+/// The nodes other than this one
+fn peers(members: &Members) -> Vec<NodeId> {
+    members
+        .addresses
+        .keys()
+        .copied()
+        .filter(|peer| *peer != members.id)
+        .collect()
+}
+
+struct Proposal {
+    command: Vec<u8>,
+    reply: oneshot::Sender<Applied>,
+}
+
+/// Whether a read may be served from this node's queues
+type ReadReply = oneshot::Sender<Result<(), Status>>;
+
+/// A committed entry and, on the node that proposed it, where the result of
+/// applying it is sent. A snapshot stands in for every entry up to its index.
+/// A confirmed read follows every entry committed before it was confirmed.
+pub enum Committed {
+    Entry {
+        entry: Entry,
+        maybe_reply: Option<oneshot::Sender<Applied>>,
+    },
+    Snapshot(Snapshot),
+    Read(ReadReply),
+}
+
+/// The queues once every entry up to the index has been applied
+pub struct Compaction {
+    index: u64,
+    data: Vec<u8>,
+}
+
+/// The committed entries in log order and where the snapshots that compact
+/// the log are sent back to the driver
+pub struct CommittedLog {
+    committed: mpsc::UnboundedReceiver<Committed>,
+    compactions: mpsc::UnboundedSender<Compaction>,
+}
+
+/// The handle requests use to replicate their commands. The raft node itself
+/// is owned by a driver task that ticks it, steps it with messages from the
+/// other nodes and sends the messages it produces.
+pub struct Replica {
+    proposals: mpsc::Sender<Proposal>,
+    reads: mpsc::Sender<ReadReply>,
+    incoming: mpsc::Sender<Message>,
+    membership: Arc<Mutex<Membership>>,
+}
+
+fn lock_membership(membership: &Mutex<Membership>) -> MutexGuard<'_, Membership> {
+    // The view is plain data so it remains usable if a holder panicked
+    membership
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn stopped() -> Status {
+    Status::new(Code::Unavailable, "Replication has stopped")
+}
+
+fn lost() -> Status {
+    Status::new(
+        Code::Unavailable,
+        "Leadership changed before the command was committed. It was not applied",
+    )
+}
+
+fn superseded() -> Status {
+    Status::new(
+        Code::Unavailable,
+        "Leadership changed and the leader's snapshot replaced the command. It may have been applied",
+    )
+}
+
+/// Redirects a request to the leader naming it in both the message and the
+/// metadata of the status
+fn not_leader(membership: &Membership) -> Status {
+    let mut metadata = MetadataMap::new();
+
+    let message = match (membership.leader(), membership.leader_address()) {
+        (Some(leader), Some(address)) => {
+            metadata.insert(LEADER_ID_KEY, MetadataValue::from(leader));
+
+            if let Ok(address_value) = MetadataValue::from_str(address) {
+                metadata.insert(LEADER_ADDRESS_KEY, address_value);
+            }
+
+            format!(
+                "Not the leader. Requests must be sent to node {} at {}",
+                leader, address
+            )
+        }
+        _ => "Not the leader. No leader has been elected yet".to_string(),
+    };
+
+    Status::with_metadata(Code::Unavailable, message, metadata)
+}
+
+impl Replica {
+    /// Starts the driver for a node with the log. The returned log of
+    /// committed entries is passed to apply_committed. A log that starts
+    /// from a snapshot hands it out first.
+    pub fn start(members: Members, log: RaftLog) -> (Replica, CommittedLog) {
+        let (proposals, proposal_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (reads, read_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (incoming, incoming_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (committed, committed_receiver) = mpsc::unbounded_channel();
+        let (compactions, compaction_receiver) = mpsc::unbounded_channel();
+
+        let node = RaftNode::new(Config::new(members.id, peers(&members)), log);
+        let membership = Arc::new(Mutex::new(Membership::new(members.id, members.addresses)));
+
+        tokio::spawn(drive(
+            node,
+            membership.clone(),
+            incoming_receiver,
+            proposal_receiver,
+            read_receiver,
+            compaction_receiver,
+            committed,
+        ));
+
+        (
+            Replica {
+                proposals,
+                reads,
+                incoming,
+                membership,
+            },
+            CommittedLog {
+                committed: committed_receiver,
+                compactions,
+            },
+        )
+    }
+
+    /// A copy of this node's view of the cluster
+    pub fn membership(&self) -> Membership {
+        lock_membership(&self.membership).clone()
+    }
+
+    /// Whether this node was leading the cluster when it last heard
+    pub fn is_leader(&self) -> bool {
+        let membership = lock_membership(&self.membership);
+
+        membership.leader() == Some(membership.id())
+    }
+
+    pub fn endpoint(&self) -> RaftEndpoint {
+        RaftEndpoint {
+            maybe_incoming: Some(self.incoming.clone()),
+        }
+    }
+
+    /// Replicates the command and returns what applying it on this node
+    /// returned. The command is only applied once a majority of the cluster
+    /// has stored it. A timeout does not mean the command was dropped as it
+    /// may still be committed later.
+    pub async fn propose(&self, command: Command) -> Applied {
+        let mut bytes = vec![];
+        command
+            .encode(&mut bytes)
+            .map_err(|err| Status::new(Code::Internal, err.to_string()))?;
+
+        let (reply, result) = oneshot::channel();
+
+        self.proposals
+            .clone()
+            .send(Proposal {
+                command: bytes,
+                reply,
+            })
+            .await
+            .map_err(|_| stopped())?;
+
+        match time::timeout(PROPOSAL_TIMEOUT, result).await {
+            Ok(Ok(applied)) => applied,
+            Ok(Err(_)) => Err(stopped()),
+            Err(_) => Err(Status::new(
+                Code::Unavailable,
+                "The command was not committed in time. It may still be applied",
+            )),
+        }
+    }
+
+    /// Waits until a read served from this node's queues is linearizable.
+    /// The leader has a majority confirm it still leads them and applies
+    /// every entry committed before the read. Any other node redirects the
+    /// read to the leader.
+    pub async fn read_barrier(&self) -> Result<(), Status> {
+        let (reply, result) = oneshot::channel();
+
+        self.reads
+            .clone()
+            .send(reply)
+            .await
+            .map_err(|_| stopped())?;
+
+        match time::timeout(PROPOSAL_TIMEOUT, result).await {
+            Ok(Ok(confirmed)) => confirmed,
+            Ok(Err(_)) => Err(stopped()),
+            Err(_) => Err(Status::new(
+                Code::Unavailable,
+                "A majority of the cluster did not confirm the leader in time",
+            )),
+        }
+    }
+}
+
+fn connect(membership: &Membership) -> HashMap<NodeId, RaftServiceClient<Channel>> {
+    let mut clients = HashMap::new();
+
+    for member in membership.members(now_millis()) {
+        if member.is_self {
+            continue;
+        }
+
+        let (peer, address) = (member.id, member.address);
+        let endpoint = match Endpoint::from_shared(address.clone()) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                error!("Invalid address {:?} for node {}: {:?}", address, peer, e);
+                continue;
+            }
+        };
+
+        // Connections are made on first use so nodes may start in any order
+        match endpoint.connect_lazy() {
+            Ok(channel) => {
+                clients.insert(peer, RaftServiceClient::new(channel));
+            }
+            Err(e) => error!("Failed to connect to node {} at {}: {:?}", peer, address, e),
+        }
+    }
+
+    clients
+}
+
+/// Sends each message on its own task. Raft copes with messages that are lost
+/// or arrive out of order so failures are only retried by the node itself.
+/// A delivered message means the node it was sent to is reachable.
+fn send_messages(
+    clients: &HashMap<NodeId, RaftServiceClient<Channel>>,
+    membership: &Arc<Mutex<Membership>>,
+    messages: Vec<Message>,
+) {
+    for message in messages {
+        let to = message.to;
+        let mut client = match clients.get(&message.to) {
+            Some(client) => client.clone(),
+            None => continue,
+        };
+        let payload = match message.to_bytes() {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to encode raft message: {}", e);
+                continue;
+            }
+        };
+
+        let membership = membership.clone();
+
+        tokio::spawn(async move {
+            if client
+                .step(Request::new(RaftMessage { payload }))
+                .await
+                .is_ok()
+            {
+                lock_membership(&membership).record_seen(to, now_millis());
+            }
+        });
+    }
+}
+
+async fn drive(
+    mut node: RaftNode,
+    membership: Arc<Mutex<Membership>>,
+    mut incoming: mpsc::Receiver<Message>,
+    mut proposals: mpsc::Receiver<Proposal>,
+    mut reads: mpsc::Receiver<ReadReply>,
+    mut compactions: mpsc::UnboundedReceiver<Compaction>,
+    committed: mpsc::UnboundedSender<Committed>,
+) {
+    let clients = connect(&lock_membership(&membership));
+    let mut ticks = time::interval(TICK_INTERVAL);
+    // Proposals waiting to be committed by their index and the term they were
+    // proposed in. A different term at the index means the proposal was lost.
+    let mut pending: HashMap<u64, (u64, oneshot::Sender<Applied>)> = HashMap::new();
+    // Reads waiting for a majority to confirm this node leads them by id
+    let mut pending_reads: HashMap<u64, ReadReply> = HashMap::new();
+
+    loop {
+        let result: Result<(), Error> = tokio::select! {
+            _ = ticks.tick() => node.tick(),
+            Some(message) = incoming.recv() => {
+                lock_membership(&membership).record_seen(message.from, now_millis());
+
+                node.step(message)
+            }
+            Some(proposal) = proposals.recv() => {
+                match node.propose(proposal.command) {
+                    Ok(index) => {
+                        if let Some((_, replaced)) =
+                            pending.insert(index, (node.term(), proposal.reply))
+                        {
+                            let _ = replaced.send(Err(lost()));
+                        }
+
+                        Ok(())
+                    }
+                    Err(Error::NotLeader { leader }) => {
+                        let mut view = lock_membership(&membership);
+                        view.observe(node.term(), leader);
+                        let _ = proposal.reply.send(Err(not_leader(&view)));
+
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Some(reply) = reads.recv() => {
+                match node.read_index() {
+                    Ok(read_id) => {
+                        pending_reads.insert(read_id, reply);
+
+                        Ok(())
+                    }
+                    Err(Error::NotLeader { leader }) => {
+                        let mut view = lock_membership(&membership);
+                        view.observe(node.term(), leader);
+                        let _ = reply.send(Err(not_leader(&view)));
+
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Some(compaction) = compactions.recv() => node.compact(compaction.index, compaction.data),
+            else => break,
+        };
+
+        // The node cannot carry on safely once its log fails to persist
+        if let Err(e) = result {
+            error!("Replication stopped: {}", e);
+            break;
+        }
+
+        lock_membership(&membership).observe(node.term(), node.leader());
+        send_messages(&clients, &membership, node.take_messages());
+
+        if let Some(snapshot) = node.take_installed_snapshot() {
+            // Proposals the snapshot covers are answered by nobody as only the
+            // entries after it are handed out
+            let covered: Vec<u64> = pending
+                .keys()
+                .copied()
+                .filter(|index| *index <= snapshot.index)
+                .collect();
+
+            for index in covered {
+                if let Some((_, reply)) = pending.remove(&index) {
+                    let _ = reply.send(Err(superseded()));
+                }
+            }
+
+            if committed.send(Committed::Snapshot(snapshot)).is_err() {
+                return;
+            }
+        }
+
+        for entry in node.take_committed() {
+            let maybe_reply = match pending.remove(&entry.index) {
+                Some((term, reply)) if term == entry.term => Some(reply),
+                Some((_, reply)) => {
+                    let _ = reply.send(Err(lost()));
+                    None
+                }
+                None => None,
+            };
+
+            if committed
+                .send(Committed::Entry { entry, maybe_reply })
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        // Every entry committed so far was sent above so a confirmed read is
+        // answered once they have been applied
+        for read_id in node.take_ready_reads() {
+            if let Some(reply) = pending_reads.remove(&read_id) {
+                if committed.send(Committed::Read(reply)).is_err() {
+                    return;
+                }
+            }
+        }
+
+        // A node that stops leading drops its reads so they are redirected.
+        // Reads a majority did not confirm in time are given up by their
+        // requests.
+        if !node.is_leader() {
+            let view = lock_membership(&membership);
+
+            for (_, reply) in pending_reads.drain() {
+                let _ = reply.send(Err(not_leader(&view)));
+            }
+        } else {
+            pending_reads.retain(|_, reply| !reply.is_closed());
+        }
+    }
+}
+
+/// Applies committed entries in log order. The empty entries leaders append
+/// on election carry no command and are skipped. A snapshot replaces every
+/// queue before the entries after it are applied. A confirmed read is
+/// answered once the entries committed before it are applied. Once SNAPSHOT_INTERVAL
+/// entries have been applied since the last snapshot the queues are
+/// snapshotted and the log is compacted up to the last entry applied.
+pub async fn apply_committed(mut log: CommittedLog, applier: impl Applier) {
+    let mut snapshot_index = 0;
+
+    while let Some(committed) = log.committed.recv().await {
+        let (entry, maybe_reply) = match committed {
+            Committed::Entry { entry, maybe_reply } => (entry, maybe_reply),
+            Committed::Read(reply) => {
+                let _ = reply.send(Ok(()));
+                continue;
+            }
+            Committed::Snapshot(snapshot) => {
+                // Applying later entries to queues that were not restored
+                // would leave them differing from the rest of the cluster
+                if let Err(status) = applier.restore(snapshot.index, snapshot.data).await {
+                    error!(
+                        "Replication stopped. Failed to restore the snapshot at entry {}: {}",
+                        snapshot.index, status
+                    );
+                    return;
+                }
+
+                snapshot_index = snapshot.index;
+                continue;
+            }
+        };
+
+        if !entry.command.is_empty() {
+            let applied = match Command::decode(&entry.command[..]) {
+                Ok(command) => applier.apply(entry.index, command).await,
+                Err(e) => Err(Status::new(
+                    Code::Internal,
+                    format!("Failed to decode command {}: {}", entry.index, e),
+                )),
+            };
+
+            if let Some(reply) = maybe_reply {
+                let _ = reply.send(applied);
+            }
+        }
+
+        if entry.index - snapshot_index >= SNAPSHOT_INTERVAL {
+            // A snapshot that fails is tried again after another interval
+            snapshot_index = entry.index;
+
+            match applier.snapshot().await {
+                Ok(data) => {
+                    let compaction = Compaction {
+                        index: entry.index,
+                        data,
+                    };
+
+                    if log.compactions.send(compaction).is_err() {
+                        return;
+                    }
+                }
+                Err(status) => error!(
+                    "Failed to snapshot the queues at entry {}: {}",
+                    entry.index, status
+                ),
+            }
+        }
+    }
+}
+
+/// Receives raft messages from the other nodes
+#[derive(Default)]
+pub struct RaftEndpoint {
+    maybe_incoming: Option<mpsc::Sender<Message>>,
+}
+
+#[tonic::async_trait]
+impl RaftService for RaftEndpoint {
+    async fn step(&self, request: Request<RaftMessage>) -> Result<Response<RaftAck>, Status> {
+        let mut incoming = self.maybe_incoming.clone().ok_or_else(|| {
+            Status::new(
+                Code::FailedPrecondition,
+                "This server is not part of a replicated cluster",
+            )
+        })?;
+
+        let message = Message::from_bytes(&request.get_ref().payload)
+            .map_err(|err| Status::new(Code::InvalidArgument, err.to_string()))?;
+
+        incoming.send(message).await.map_err(|_| stopped())?;
+
+        Ok(Response::new(RaftAck {}))
+    }
+}
@@ -0,0 +1,380 @@
+use crate::config::{parse_addresses, Members};
+use crate::spq_generated::shard_service_client::ShardServiceClient;
+use crate::spq_generated::{ShardMember, UpdateMembersRequest};
+use fnv::FnvHasher;
+use log::warn;
+use spq_replication::NodeId;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::Endpoint;
+use tonic::{Code, Request, Status};
+
+/// Each node is placed on the ring this many times so that queues spread
+/// evenly and a change of members only moves the queues of the nodes next
+/// to it
+const VIRTUAL_NODES: u64 = 64;
+
+/// Metadata set on requests for a queue this node does not own so that
+/// clients can retry them against the owner
+pub const OWNER_ID_KEY: &str = "spq-owner-id";
+pub const OWNER_ADDRESS_KEY: &str = "spq-owner-address";
+
+/// Where the members are kept so that a restarted node does not fall back to
+/// the seed list
+const MEMBERS_FILE: &str = ".shards";
+
+/// Holds a file per received queue naming the transfer it arrived in
+const TRANSFERS_DIRECTORY: &str = ".transfers";
+
+/// Queues being received are written here first and only renamed into place
+/// once complete
+pub const INCOMING_PREFIX: &str = ".incoming-";
+
+/// Places bytes on the ring. Every node must agree on where a queue lands,
+/// whatever release or platform it runs, so this is FNV-1a over the bytes
+/// themselves rather than the standard library's hasher, whose output is
+/// not guaranteed to stay the same between releases.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// The nodes queues are spread across. Every change is given a higher
+/// version and nodes only ever move to a higher version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShardMembers {
+    pub version: u64,
+    pub addresses: HashMap<NodeId, String>,
+}
+
+impl ShardMembers {
+    fn to_text(&self) -> String {
+        let mut addresses: Vec<String> = self
+            .addresses
+            .iter()
+            .map(|(id, address)| format!("{}={}", id, address))
+            .collect();
+        addresses.sort();
+
+        format!("{}\n{}\n", self.version, addresses.join(","))
+    }
+
+    fn from_text(text: &str) -> Result<ShardMembers, String> {
+        let mut lines = text.lines();
+
+        let version = lines
+            .next()
+            .unwrap_or_default()
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid members version: {}", e))?;
+
+        Ok(ShardMembers {
+            version,
+            addresses: parse_addresses(lines.next().unwrap_or_default())?,
+        })
+    }
+
+    pub fn to_request(&self) -> UpdateMembersRequest {
+        let mut members: Vec<ShardMember> = self
+            .addresses
+            .iter()
+            .map(|(id, address)| ShardMember {
+                id: *id,
+                address: address.clone(),
+            })
+            .collect();
+        members.sort_by_key(|member| member.id);
+
+        UpdateMembersRequest {
+            version: self.version,
+            members,
+        }
+    }
+
+    pub fn from_request(request: UpdateMembersRequest) -> ShardMembers {
+        ShardMembers {
+            version: request.version,
+            addresses: request
+                .members
+                .into_iter()
+                .map(|member| (member.id, member.address))
+                .collect(),
+        }
+    }
+}
+
+/// A consistent hash ring. A queue belongs to the first node placed at or
+/// after the hash of its name.
+struct Ring {
+    points: BTreeMap<u64, NodeId>,
+}
+
+impl Ring {
+    fn new(members: &ShardMembers) -> Ring {
+        let mut points = BTreeMap::new();
+
+        for id in members.addresses.keys() {
+            for replica in 0..VIRTUAL_NODES {
+                let point = [id.to_be_bytes(), replica.to_be_bytes()].concat();
+
+                points.insert(hash(&point), *id);
+            }
+        }
+
+        Ring { points }
+    }
+
+    fn owner(&self, queue_name: &str) -> Option<NodeId> {
+        let point = hash(queue_name.as_bytes());
+
+        self.points
+            .range(point..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, id)| *id)
+    }
+}
+
+struct View {
+    members: ShardMembers,
+    ring: Ring,
+}
+
+/// What this node knows of the nodes sharing queues and which of its own
+/// queues are being handed to another node
+pub struct Sharding {
+    id: NodeId,
+    data_root: PathBuf,
+    view: Mutex<View>,
+    moving: Mutex<HashSet<String>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // The state is plain data so it remains usable if a holder panicked
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Sharding {
+    /// Starts from the members last stored under the data root or from the
+    /// seed list if none were. Queues left half received are removed.
+    pub fn open(config: Members, data_root: &Path) -> Result<Sharding, String> {
+        let data_root = data_root.to_path_buf();
+
+        remove_incoming(&data_root).map_err(|e| e.to_string())?;
+
+        let members = match fs::read_to_string(data_root.join(MEMBERS_FILE)) {
+            Ok(text) => ShardMembers::from_text(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ShardMembers {
+                version: 0,
+                addresses: config.addresses,
+            },
+            Err(e) => return Err(e.to_string()),
+        };
+
+        Ok(Sharding {
+            id: config.id,
+            data_root,
+            view: Mutex::new(View {
+                ring: Ring::new(&members),
+                members,
+            }),
+            moving: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn members(&self) -> ShardMembers {
+        lock(&self.view).members.clone()
+    }
+
+    /// The id and address of the node that owns the queue
+    pub fn owner(&self, queue_name: &str) -> Option<(NodeId, String)> {
+        let view = lock(&self.view);
+
+        view.ring.owner(queue_name).map(|id| {
+            let address = view.members.addresses.get(&id).cloned().unwrap_or_default();
+
+            (id, address)
+        })
+    }
+
+    pub fn is_owner(&self, queue_name: &str) -> bool {
+        self.owner(queue_name).map(|(id, _)| id) == Some(self.id)
+    }
+
+    /// Moves to the members if they are a higher version than the current
+    /// ones. Returns the addresses of every other node in either version as
+    /// they all need to hear of the change.
+    pub fn adopt(&self, members: ShardMembers) -> Result<Option<Vec<String>>, std::io::Error> {
+        let mut view = lock(&self.view);
+
+        if members.version <= view.members.version {
+            return Ok(None);
+        }
+
+        fs::create_dir_all(&self.data_root)?;
+        fs::write(self.data_root.join(MEMBERS_FILE), members.to_text())?;
+
+        let mut addresses: Vec<String> = view
+            .members
+            .addresses
+            .iter()
+            .chain(members.addresses.iter())
+            .filter(|(id, _)| **id != self.id)
+            .map(|(_, address)| address.clone())
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+
+        view.ring = Ring::new(&members);
+        view.members = members;
+
+        Ok(Some(addresses))
+    }
+
+    /// Marks a queue as being handed off. Returns false if it already was.
+    pub fn start_moving(&self, queue_name: &str) -> bool {
+        lock(&self.moving).insert(queue_name.to_string())
+    }
+
+    pub fn finish_moving(&self, queue_name: &str) {
+        lock(&self.moving).remove(queue_name);
+    }
+
+    pub fn is_moving(&self, queue_name: &str) -> bool {
+        lock(&self.moving).contains(queue_name)
+    }
+
+    fn transfer_path(&self, queue_name: &str) -> PathBuf {
+        self.data_root.join(TRANSFERS_DIRECTORY).join(queue_name)
+    }
+
+    /// Remembers the transfer a queue arrived in so that the same transfer
+    /// sent again is recognised rather than reported as a conflict
+    pub fn record_transfer(&self, queue_name: &str, transfer_id: &str) -> std::io::Result<()> {
+        fs::create_dir_all(self.data_root.join(TRANSFERS_DIRECTORY))?;
+        fs::write(self.transfer_path(queue_name), transfer_id)
+    }
+
+    pub fn transfer_of(&self, queue_name: &str) -> Option<String> {
+        fs::read_to_string(self.transfer_path(queue_name)).ok()
+    }
+
+    pub fn clear_transfer(&self, queue_name: &str) {
+        if let Err(e) = fs::remove_file(self.transfer_path(queue_name)) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to clear transfer of {:?}: {:?}", queue_name, e);
+            }
+        }
+    }
+
+    /// Where a queue being received is written before it is renamed into place
+    pub fn incoming_path(&self, queue_name: &str) -> PathBuf {
+        self.data_root
+            .join(format!("{}{}", INCOMING_PREFIX, queue_name))
+    }
+
+    /// Rejects a request for a queue owned by another node naming the owner
+    /// in both the message and the metadata of the status
+    pub fn not_owner(&self, queue_name: &str) -> Status {
+        let mut metadata = MetadataMap::new();
+
+        let message = match self.owner(queue_name) {
+            Some((owner, address)) => {
+                metadata.insert(OWNER_ID_KEY, MetadataValue::from(owner));
+
+                if let Ok(address_value) = MetadataValue::from_str(&address) {
+                    metadata.insert(OWNER_ADDRESS_KEY, address_value);
+                }
+
+                format!(
+                    "Queue {:?} is owned by node {} at {}",
+                    queue_name, owner, address
+                )
+            }
+            None => format!("No node owns queue {:?}", queue_name),
+        };
+
+        Status::with_metadata(Code::Unavailable, message, metadata)
+    }
+}
+
+pub fn moving(queue_name: &str) -> Status {
+    Status::new(
+        Code::Unavailable,
+        format!(
+            "Queue {:?} is being moved to another node. Retry shortly",
+            queue_name
+        ),
+    )
+}
+
+fn remove_incoming(data_root: &Path) -> std::io::Result<()> {
+    let entries = match fs::read_dir(data_root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(INCOMING_PREFIX)
+        {
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A client for the shard service of another node. Connections are made on
+/// first use.
+pub fn connect(address: &str) -> Result<ShardServiceClient<tonic::transport::Channel>, Status> {
+    let unavailable = |e: &dyn std::fmt::Display| {
+        Status::new(
+            Code::Unavailable,
+            format!("Failed to connect to {}: {}", address, e),
+        )
+    };
+
+    let channel = Endpoint::from_shared(address.to_string())
+        .map_err(|e| unavailable(&e))?
+        .connect_lazy()
+        .map_err(|e| unavailable(&e))?;
+
+    Ok(ShardServiceClient::new(channel))
+}
+
+/// Tells each node of the members on its own task. A node that adopts them
+/// tells every node it knows of in turn so one delivery is enough for the
+/// change to spread.
+pub fn gossip(members: &ShardMembers, addresses: Vec<String>) {
+    for address in addresses {
+        let request = members.to_request();
+
+        tokio::spawn(async move {
+            let result = match connect(&address) {
+                Ok(mut client) => client.update_members(Request::new(request)).await,
+                Err(status) => Err(status),
+            };
+
+            if let Err(status) = result {
+                warn!("Failed to send members to {}: {}", address, status);
+            }
+        });
+    }
+}
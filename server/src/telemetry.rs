@@ -0,0 +1,61 @@
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use tonic::codegen::http::HeaderMap;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+const SERVICE_NAME: &str = "spq";
+
+/// Reads W3C trace context from gRPC metadata, which arrives as HTTP/2 headers
+struct MetadataExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for MetadataExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Exports spans through OTLP to the collector at the endpoint. Spans that
+/// have not been exported yet are flushed when the returned guard is dropped.
+pub fn init(endpoint: &str) -> Result<opentelemetry_otlp::Uninstall, String> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                SERVICE_NAME,
+            )])),
+        )
+        .install()
+        .map_err(|e| format!("Failed to export traces to {:?}: {}", endpoint, e))?;
+
+    let subscriber = Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| format!("Failed to install the tracing subscriber: {}", e))?;
+
+    Ok(uninstall)
+}
+
+/// The span every gRPC request runs in. It continues the trace named by the
+/// request's traceparent header when there is one.
+pub fn request_span(headers: &HeaderMap) -> Span {
+    let parent_context = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(headers))
+    });
+
+    let span = tracing::info_span!("grpc_request");
+    span.set_parent(parent_context);
+
+    span
+}
@@ -0,0 +1,162 @@
+// The server is only built as a binary so its config is compiled in here
+#[allow(dead_code)]
+#[path = "../src/config.rs"]
+mod config;
+
+use config::{Cluster, Config, Members};
+use sp_queue::storage::StorageType;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+fn write_file(name: &str, text: &str) -> String {
+    let path = PathBuf::from(format!("/tmp/spq_config_test_{}.toml", name));
+    fs::write(&path, text).unwrap();
+
+    path.to_string_lossy().to_string()
+}
+
+fn load(flags: &[&str]) -> Result<Config, String> {
+    Config::load_from(["spq_server"].iter().chain(flags.iter()))
+}
+
+#[test]
+fn must_take_flags_over_the_environment_over_the_file() {
+    let path = write_file(
+        "precedence",
+        "max_queue_count = 1\nmax_item_size = 2\nlog_level = \"error\"\n",
+    );
+    // No other test reads these two settings
+    env::set_var("SPQ_MAX_ITEM_SIZE", "20");
+    env::set_var("SPQ_LOG_LEVEL", "warn");
+
+    let config = load(&["--config", &path, "--log-level", "debug"]).unwrap();
+
+    assert_eq!(config.max_queue_count, 1);
+    assert_eq!(config.max_item_size, 20);
+    assert_eq!(config.log_level, log::LevelFilter::Debug);
+}
+
+#[test]
+fn must_use_the_defaults_for_settings_given_nowhere() {
+    let config = load(&[]).unwrap();
+
+    assert_eq!(
+        config.listen_address,
+        "[::0]:9090".parse::<SocketAddr>().unwrap()
+    );
+    assert_eq!(config.data_root, PathBuf::from("/var/lib/spqr/"));
+    assert_eq!(config.default_queue_type, StorageType::Durable);
+    assert_eq!(config.max_queue_count, 10_000);
+    assert_eq!(config.cluster, Cluster::Standalone);
+}
+
+#[test]
+fn must_read_the_rocksdb_table_of_the_file() {
+    let path = write_file("rocksdb", "[rocksdb]\nwrite_buffer_size = 16777216\n");
+
+    let config = load(&["--config", &path]).unwrap();
+
+    assert_eq!(config.tuning.write_buffer_size, 16 << 20);
+}
+
+#[test]
+fn must_refuse_unknown_settings_in_the_file() {
+    let path = write_file("unknown", "max_queue_size = 1\n");
+
+    assert!(load(&["--config", &path]).is_err());
+}
+
+#[test]
+fn must_refuse_serving_metrics_on_the_listen_address() {
+    let result = load(&[
+        "--listen-address",
+        "127.0.0.1:9090",
+        "--metrics-address",
+        "127.0.0.1:9090",
+    ]);
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn must_refuse_limits_of_zero() {
+    assert!(load(&["--max-queue-count", "0"]).is_err());
+    assert!(load(&["--max-item-size", "0"]).is_err());
+}
+
+#[test]
+fn must_refuse_rocksdb_options_it_cannot_run_with() {
+    assert!(load(&["--rocksdb-max-open-files", "8"]).is_err());
+    assert!(load(&["--rocksdb-write-buffer-size", "1024"]).is_err());
+    assert!(load(&["--rocksdb-max-write-buffer-number", "0"]).is_err());
+    assert!(load(&["--rocksdb-max-background-jobs", "0"]).is_err());
+
+    assert!(load(&["--rocksdb-max-open-files", "-1"]).is_ok());
+}
+
+#[test]
+fn must_replicate_with_the_peers_of_the_file() {
+    let path = write_file(
+        "peers",
+        "node_id = 2\npeers = \"1=http://spq-1:9090,2=http://spq-2:9090\"\n",
+    );
+
+    let config = load(&["--config", &path]).unwrap();
+
+    let mut addresses = HashMap::new();
+    addresses.insert(1, "http://spq-1:9090".to_string());
+    addresses.insert(2, "http://spq-2:9090".to_string());
+    assert_eq!(
+        config.cluster,
+        Cluster::Replicated(Members { id: 2, addresses })
+    );
+}
+
+#[test]
+fn must_shard_across_nodes_that_leave_out_this_one() {
+    let config = load(&["--node-id", "2", "--shards", "1=http://shard-1:9090"]).unwrap();
+
+    let mut addresses = HashMap::new();
+    addresses.insert(1, "http://shard-1:9090".to_string());
+    assert_eq!(
+        config.cluster,
+        Cluster::Sharded(Members { id: 2, addresses })
+    );
+}
+
+#[test]
+fn must_refuse_to_both_replicate_and_shard() {
+    let path = write_file("both", "shards = \"1=http://shard-1:9090\"\n");
+
+    let result = load(&[
+        "--config",
+        &path,
+        "--node-id",
+        "1",
+        "--peers",
+        "1=http://spq-1:9090",
+    ]);
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn must_refuse_peers_without_a_node_id() {
+    assert!(load(&["--peers", "1=http://spq-1:9090"]).is_err());
+}
+
+#[test]
+fn must_refuse_peers_that_leave_out_this_node() {
+    let result = load(&["--node-id", "3", "--peers", "1=http://spq-1:9090"]);
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn must_refuse_malformed_peers() {
+    assert!(load(&["--node-id", "1", "--peers", "1:http://spq-1:9090"]).is_err());
+    assert!(load(&["--node-id", "1", "--shards", ","]).is_err());
+}